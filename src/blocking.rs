@@ -0,0 +1,125 @@
+use crate::sync::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// How long an idle blocking thread waits for another job before exiting.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// The side pool behind [`Scheduler::spawn_blocking`].
+///
+/// Threads are started on demand, up to `max_threads`. Beyond that, jobs
+/// queue until a thread is free. Idle threads exit after [`KEEP_ALIVE`], and
+/// all of them once the pool is dropped and the queue has run dry.
+///
+/// [`Scheduler::spawn_blocking`]: crate::Scheduler::spawn_blocking
+pub(crate) struct BlockingPool {
+    shared: Arc<Shared>,
+    max_threads: usize,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+    closed: bool,
+}
+
+impl BlockingPool {
+    pub(crate) fn new(max_threads: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::default(),
+                available: Condvar::new(),
+            }),
+            max_threads,
+        }
+    }
+
+    /// Queues `job`, starting another thread if none is free and the limit
+    /// allows it. `job` must not panic.
+    pub(crate) fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        let mut state = self.shared.state.lock();
+        state.queue.push_back(Box::new(job));
+        if state.queue.len() > state.idle && state.threads < self.max_threads {
+            state.threads += 1;
+            let shared = self.shared.clone();
+            thread::Builder::new()
+                .name("revent-loop-blocking".into())
+                .spawn(move || work(shared))
+                .expect("failed to spawn a blocking thread");
+        }
+        drop(state);
+        self.shared.available.notify_one();
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        self.shared.state.lock().closed = true;
+        self.shared.available.notify_all();
+    }
+}
+
+fn work(shared: Arc<Shared>) {
+    let mut state = shared.state.lock();
+    loop {
+        if let Some(job) = state.queue.pop_front() {
+            drop(state);
+            job();
+            state = shared.state.lock();
+            continue;
+        }
+        if state.closed {
+            break;
+        }
+        state.idle += 1;
+        let (next, timed_out) = shared.available.wait_timeout(state, KEEP_ALIVE);
+        state = next;
+        state.idle -= 1;
+        if timed_out && state.queue.is_empty() {
+            break;
+        }
+    }
+    state.threads -= 1;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    #[test]
+    fn never_runs_more_than_max_threads_at_once() {
+        let pool = BlockingPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (done_tx, done_rx) = mpsc::channel();
+        for _ in 0..6 {
+            let (running, peak, done_tx) = (running.clone(), peak.clone(), done_tx.clone());
+            pool.spawn(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+                done_tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..6 {
+            done_rx.recv().unwrap();
+        }
+
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert!(pool.shared.state.lock().threads <= 2);
+    }
+}
@@ -0,0 +1,525 @@
+use crate::watchdog::SlowTaskHandler;
+use crate::TaskId;
+use crate::{
+    AsyncTimer, Clock, CronZone, DependencyPolicy, OverflowPolicy, PanicPolicy, Scheduler,
+    SchedulerHooks, SchedulerPolicy, ShrinkPolicy, SleepStrategy, SpinThenPark, TaskMeta,
+    TimerBackend,
+};
+use std::any::Any;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How much of a wait [`SchedulerBuilder::high_resolution`] spends spinning
+/// unless told otherwise.
+const DEFAULT_SPIN: Duration = Duration::from_micros(200);
+
+/// Called with the id and name of the task that panicked, plus the payload.
+pub(crate) type PanicHook = Arc<dyn Fn(TaskId, Option<&str>, Box<dyn Any + Send>) + Send + Sync>;
+
+/// Called with a task whose callback ran past its execution timeout.
+pub(crate) type TimeoutHook = Arc<dyn Fn(TaskMeta) + Send + Sync>;
+
+/// Called with a task from `schedule_fallible` and the error it failed with.
+pub(crate) type ErrorHook = Arc<dyn Fn(TaskMeta, Box<dyn Error + Send>) + Send + Sync>;
+
+/// Called with a timer that started running late, and by how much.
+pub(crate) type DeadlineMissHook = Arc<dyn Fn(TaskMeta, Duration) + Send + Sync>;
+
+/// Settings a [`Scheduler`] is built with.
+pub(crate) struct Config {
+    pub(crate) on_panic: PanicHook,
+    pub(crate) panic_policy: PanicPolicy,
+    pub(crate) on_timeout: TimeoutHook,
+    pub(crate) on_error: ErrorHook,
+    /// `None` means wall-clock time, which lets the loop use interruptible
+    /// condvar waits instead of [`Clock::sleep`].
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    pub(crate) policy: SchedulerPolicy,
+    pub(crate) starvation_threshold: Option<Duration>,
+    pub(crate) hooks: Option<Arc<dyn SchedulerHooks>>,
+    pub(crate) max_pending: Option<usize>,
+    /// Room set aside up front in the ready and sleeping queues.
+    pub(crate) capacity: (usize, usize),
+    pub(crate) shrink: ShrinkPolicy,
+    /// `None` makes `schedule()` panic at the limit.
+    pub(crate) overflow: Option<OverflowPolicy>,
+    pub(crate) blocking_threads: usize,
+    /// At most this many callbacks per period, from
+    /// [`SchedulerBuilder::rate_limit`].
+    pub(crate) rate_limit: Option<(u32, Duration)>,
+    pub(crate) cron_zone: CronZone,
+    /// Seeds the generator behind jittered delays; random if unset.
+    pub(crate) seed: Option<u64>,
+    pub(crate) dependency_policy: DependencyPolicy,
+    /// How long one batch of ready tasks may run before the loop looks
+    /// around again; unlimited if unset.
+    pub(crate) max_batch_duration: Option<Duration>,
+    /// Callbacks running for longer than this are reported to the handler.
+    pub(crate) slow_task: Option<(Duration, SlowTaskHandler)>,
+    /// Timers starting later than this after their deadline are reported
+    /// to the hook.
+    pub(crate) deadline_miss: Option<(Duration, DeadlineMissHook)>,
+    /// Timer deadlines are rounded up to a multiple of this; zero leaves
+    /// them exact.
+    pub(crate) timer_granularity: Duration,
+    pub(crate) timer_backend: TimerBackend,
+    /// How the loop waits for a timer on the system clock; `None` is
+    /// [`ParkTimeout`](crate::ParkTimeout).
+    pub(crate) sleep: Option<Arc<dyn SleepStrategy>>,
+    /// The longest the loop waits between two looks at the channels from
+    /// [`Scheduler::poll_channel`].
+    pub(crate) channel_poll_interval: Duration,
+    /// How [`Scheduler::run_async`] gets woken for its next timer; `None`
+    /// is a helper thread.
+    pub(crate) async_timer: Option<Arc<dyn AsyncTimer>>,
+    /// How many finished tasks [`Scheduler::status`] remembers.
+    pub(crate) status_history: usize,
+    /// How many events each [`Scheduler::subscribe`] receiver holds.
+    pub(crate) event_buffer: usize,
+    /// How many events [`Scheduler::recent_events`] keeps.
+    pub(crate) event_history: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            on_panic: Arc::new(|id, name, payload| {
+                let message = crate::scheduler::panic_message(payload.as_ref());
+                match name {
+                    Some(name) => {
+                        eprintln!("revent_loop: task {} ({}) panicked: {}", id, name, message)
+                    }
+                    None => eprintln!("revent_loop: task {} panicked: {}", id, message),
+                }
+            }),
+            on_timeout: Arc::new(|meta| match &meta.name {
+                Some(name) => eprintln!(
+                    "revent_loop: task {} ({}) ran past its execution timeout",
+                    meta.id, name
+                ),
+                None => eprintln!(
+                    "revent_loop: task {} ran past its execution timeout",
+                    meta.id
+                ),
+            }),
+            on_error: Arc::new(|meta, error| match &meta.name {
+                Some(name) => {
+                    eprintln!("revent_loop: task {} ({}) failed: {}", meta.id, name, error)
+                }
+                None => eprintln!("revent_loop: task {} failed: {}", meta.id, error),
+            }),
+            panic_policy: PanicPolicy::Continue,
+            clock: None,
+            policy: SchedulerPolicy::Fifo,
+            starvation_threshold: None,
+            hooks: None,
+            max_pending: None,
+            capacity: (0, 0),
+            shrink: ShrinkPolicy::Never,
+            overflow: None,
+            blocking_threads: 16,
+            rate_limit: None,
+            cron_zone: CronZone::Utc,
+            seed: None,
+            dependency_policy: DependencyPolicy::CancelDependents,
+            max_batch_duration: None,
+            slow_task: None,
+            deadline_miss: None,
+            timer_granularity: Duration::ZERO,
+            timer_backend: TimerBackend::Heap,
+            sleep: None,
+            channel_poll_interval: Duration::from_millis(10),
+            async_timer: None,
+            status_history: 256,
+            event_buffer: 1024,
+            event_history: 256,
+        }
+    }
+}
+
+/// Configures a [`Scheduler`] before it is created.
+///
+/// ```
+/// use revent_loop::Scheduler;
+///
+/// let scheduler = Scheduler::builder()
+///     .on_panic(|id, _payload| eprintln!("task {} blew up", id))
+///     .build();
+/// scheduler.run().unwrap();
+/// ```
+#[derive(Default)]
+pub struct SchedulerBuilder {
+    config: Config,
+}
+
+impl SchedulerBuilder {
+    /// Called with the task id and panic payload whenever a callback panics.
+    ///
+    /// What the loop does next is up to the
+    /// [`panic_policy`](SchedulerBuilder::panic_policy). By default the panic
+    /// message is written to stderr, together with the task's name if it
+    /// has one.
+    pub fn on_panic(
+        mut self,
+        hook: impl Fn(TaskId, Box<dyn Any + Send>) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.on_panic = Arc::new(move |id, _name, payload| hook(id, payload));
+        self
+    }
+
+    /// Picks what the loop does once a callback panics. Defaults to
+    /// [`PanicPolicy::Continue`]; either of the others ends the run early
+    /// with [`RunError::StoppedOnPanic`](crate::RunError::StoppedOnPanic),
+    /// whose report records the policy in
+    /// [`RunReport::stopped_on_panic`](crate::RunReport::stopped_on_panic).
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.config.panic_policy = policy;
+        self
+    }
+
+    /// Called with a task whose callback ran for longer than its
+    /// [`TaskBuilder::exec_timeout`](crate::TaskBuilder::exec_timeout),
+    /// once the callback has returned.
+    ///
+    /// By default the task is reported on stderr. To hear about a stuck
+    /// callback while it is still running, see
+    /// [`slow_task_threshold`](SchedulerBuilder::slow_task_threshold).
+    pub fn on_timeout(mut self, hook: impl Fn(TaskMeta) + Send + Sync + 'static) -> Self {
+        self.config.on_timeout = Arc::new(hook);
+        self
+    }
+
+    /// Called with the task and the error whenever a callback from
+    /// [`Scheduler::schedule_fallible`] returns `Err`.
+    ///
+    /// By default the error is written to stderr, together with the task's
+    /// name if it has one.
+    pub fn on_error(
+        mut self,
+        hook: impl Fn(TaskMeta, Box<dyn Error + Send>) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.on_error = Arc::new(hook);
+        self
+    }
+
+    /// Uses `clock` for every deadline and timer wait instead of the system
+    /// clock.
+    ///
+    /// While waiting for a timer, a scheduler with a custom clock blocks in
+    /// [`Clock::sleep`], which newly scheduled work cannot interrupt.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.config.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Picks how ready tasks are ordered. Defaults to
+    /// [`SchedulerPolicy::Fifo`].
+    pub fn policy(mut self, policy: SchedulerPolicy) -> Self {
+        self.config.policy = policy;
+        self
+    }
+
+    /// Lets a ready task that has waited longer than `threshold` run ahead
+    /// of higher-priority work, so a steady stream of [`Priority::High`]
+    /// tasks can't starve the rest forever. Off by default.
+    ///
+    /// The boost only affects ordering; [`Task::priority`] still reports
+    /// the priority the task was created with.
+    ///
+    /// [`Priority::High`]: crate::Priority::High
+    /// [`Task::priority`]: crate::Task::priority
+    pub fn starvation_threshold(mut self, threshold: Duration) -> Self {
+        self.config.starvation_threshold = Some(threshold);
+        self
+    }
+
+    /// Reports every task that is scheduled, started and completed to
+    /// `hooks`.
+    pub fn hooks(mut self, hooks: impl SchedulerHooks + 'static) -> Self {
+        self.config.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Caps how many tasks may be waiting in the ready and sleeping queues
+    /// combined. Unlimited by default.
+    ///
+    /// At the limit, [`Scheduler::try_schedule`] hands the task back and
+    /// [`Scheduler::schedule`] panics, unless an
+    /// [`overflow_policy`](SchedulerBuilder::overflow_policy) says otherwise. Polls of futures spawned with
+    /// [`Scheduler::spawn_future`] are exempt, so a woken future can always
+    /// be polled again.
+    pub fn max_pending(mut self, limit: usize) -> Self {
+        self.config.max_pending = Some(limit);
+        self
+    }
+
+    /// Sets aside room for `ready` tasks in the ready queue and `sleeping`
+    /// timers in the sleeping queue, so that a burst of that size doesn't
+    /// grow them while their locks are held. See [`Scheduler::reserve_ready`]
+    /// for what the ready queue's room covers.
+    pub fn capacity(mut self, ready: usize, sleeping: usize) -> Self {
+        self.config.capacity = (ready, sleeping);
+        self
+    }
+
+    /// Whether queues give back the room a burst made them grow to. Defaults
+    /// to [`ShrinkPolicy::Never`]; [`Scheduler::shrink_to_fit`] shrinks them
+    /// on demand either way.
+    pub fn shrink_policy(mut self, policy: ShrinkPolicy) -> Self {
+        self.config.shrink = policy;
+        self
+    }
+
+    /// What [`Scheduler::schedule`] does when the
+    /// [`max_pending`](SchedulerBuilder::max_pending) limit is reached.
+    /// Without one it panics. [`Scheduler::try_schedule`] always hands the
+    /// task back instead.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.overflow = Some(policy);
+        self
+    }
+
+    /// The most threads [`Scheduler::spawn_blocking`] runs jobs on at once.
+    /// Further jobs wait for one of them to finish. Defaults to 16.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn blocking_threads(mut self, threads: usize) -> Self {
+        assert!(threads > 0, "blocking_threads must be at least 1");
+        self.config.blocking_threads = threads;
+        self
+    }
+
+    /// Runs at most `n` callbacks per `per`, in bursts of up to `n`.
+    ///
+    /// A ready task that would go over the limit is put back to sleep until
+    /// the next slot frees up, rather than run; it keeps its place relative
+    /// to the other held-back tasks. Each time that happens is counted in
+    /// [`Metrics::throttled`](crate::Metrics::throttled), and its return to
+    /// the ready queue counts as a fired timer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero or `per` is zero.
+    pub fn rate_limit(mut self, n: u32, per: Duration) -> Self {
+        assert!(n > 0, "rate_limit must allow at least one task per period");
+        assert!(!per.is_zero(), "rate_limit period must not be zero");
+        self.config.rate_limit = Some((n, per));
+        self
+    }
+
+    /// The time zone cron expressions are evaluated in. Defaults to UTC.
+    pub fn cron_zone(mut self, zone: CronZone) -> Self {
+        self.config.cron_zone = zone;
+        self
+    }
+
+    /// Seeds the random numbers behind jittered delays, such as
+    /// [`Scheduler::schedule_interval_jittered`] and
+    /// [`RetryPolicy::jitter`](crate::RetryPolicy::jitter), so that a test
+    /// sees the same delays on every run.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    /// What happens to tasks from [`Scheduler::schedule_after`] whose
+    /// prerequisite is cancelled. Defaults to
+    /// [`DependencyPolicy::CancelDependents`].
+    pub fn dependency_policy(mut self, policy: DependencyPolicy) -> Self {
+        self.config.dependency_policy = policy;
+        self
+    }
+
+    /// Bounds how long the loop spends working through ready tasks in one
+    /// go. Once a batch has run for `budget` on the scheduler's clock
+    /// (checked every few tasks), the loop steps back to promote timers and
+    /// check for [`Scheduler::shutdown`] and [`Scheduler::pause`] before
+    /// carrying on with the backlog. Each cut is counted in
+    /// [`Metrics::batches_cut`](crate::Metrics::batches_cut).
+    ///
+    /// Unlimited by default.
+    pub fn max_batch_duration(mut self, budget: Duration) -> Self {
+        self.config.max_batch_duration = Some(budget);
+        self
+    }
+
+    /// Watches for callbacks that run for longer than `threshold`, which
+    /// would otherwise hold up the whole loop without a trace.
+    ///
+    /// A monitor thread calls `handler` with the task's id, name and how
+    /// long it has been running so far, while the callback is still going,
+    /// so the handler can dump stacks or raise an alarm. Each call to a
+    /// callback is reported at most once, and one that returns before the
+    /// threshold never is. Time is measured on the system clock, whatever
+    /// [`clock`](SchedulerBuilder::clock) the scheduler uses.
+    ///
+    /// Off by default, and without it the loop does no timing at all.
+    ///
+    /// ```
+    /// use revent_loop::Scheduler;
+    /// use std::time::Duration;
+    ///
+    /// let scheduler = Scheduler::builder()
+    ///     .slow_task_threshold(Duration::from_secs(1), |id, name, elapsed| {
+    ///         eprintln!("task {} ({:?}) stuck for {:?}", id, name, elapsed)
+    ///     })
+    ///     .build();
+    /// scheduler.run().unwrap();
+    /// ```
+    pub fn slow_task_threshold(
+        mut self,
+        threshold: Duration,
+        handler: impl Fn(TaskId, Option<&str>, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.slow_task = Some((threshold, Arc::new(handler)));
+        self
+    }
+
+    /// Calls `hook` with every timer that starts running more than
+    /// `threshold` after its deadline, and how late it is, just before its
+    /// callback runs. A timer held up by a blocking callback, or by a busy
+    /// ready queue, is the usual cause.
+    ///
+    /// Every timer's lateness is recorded in [`Metrics`](crate::Metrics)
+    /// either way; this only adds the report.
+    pub fn on_deadline_miss(
+        mut self,
+        threshold: Duration,
+        hook: impl Fn(TaskMeta, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.deadline_miss = Some((threshold, Arc::new(hook)));
+        self
+    }
+
+    /// Rounds every timer's deadline up to the next multiple of
+    /// `granularity`, counted from when the scheduler was created, so that
+    /// timers falling due close together fire in a single wakeup. Timers
+    /// rounded to the same deadline keep the order they were scheduled in.
+    ///
+    /// The rounded deadline is the one the task reports, for example in
+    /// [`Scheduler::pending_tasks`]. Zero, the default, leaves deadlines
+    /// exact.
+    pub fn timer_granularity(mut self, granularity: Duration) -> Self {
+        self.config.timer_granularity = granularity;
+        self
+    }
+
+    /// Picks how sleeping timers are kept. Defaults to
+    /// [`TimerBackend::Heap`]; [`TimerBackend::Wheel`] suits tens of
+    /// thousands of timers that are mostly cancelled before they fire, such
+    /// as one timeout per connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a wheel's `tick` is zero or it has fewer than 2 `slots`.
+    pub fn timer_backend(mut self, backend: TimerBackend) -> Self {
+        if let TimerBackend::Wheel { tick, slots } = backend {
+            assert!(!tick.is_zero(), "timer wheel tick must not be zero");
+            assert!(slots >= 2, "timer wheel needs at least 2 slots");
+        }
+        self.config.timer_backend = backend;
+        self
+    }
+
+    /// Makes [`Scheduler::run`] hit timer deadlines more tightly, for audio,
+    /// games and the like. Off by default.
+    ///
+    /// A plain timed wait tends to overshoot by tens of microseconds to a
+    /// millisecond or more. In this mode the loop waits as usual for all but
+    /// the last 200µs (see [`high_resolution_spin`]) and then spins, at the
+    /// cost of keeping a core busy for that slice of every wait.
+    /// [`Scheduler::schedule`] and [`Scheduler::shutdown`] still end the
+    /// wait early. Shorthand for a [`SpinThenPark`]
+    /// [`sleep_strategy`](SchedulerBuilder::sleep_strategy); `false` goes
+    /// back to the default.
+    ///
+    /// [`high_resolution_spin`]: SchedulerBuilder::high_resolution_spin
+    pub fn high_resolution(mut self, enabled: bool) -> Self {
+        self.config.sleep = enabled
+            .then(|| -> Arc<dyn SleepStrategy> { Arc::new(SpinThenPark { spin: DEFAULT_SPIN }) });
+        self
+    }
+
+    /// Turns on [`high_resolution`](SchedulerBuilder::high_resolution) with
+    /// `slice` as the part of each wait spent spinning.
+    pub fn high_resolution_spin(self, slice: Duration) -> Self {
+        self.sleep_strategy(SpinThenPark { spin: slice })
+    }
+
+    /// How the loop waits for its next timer when there is nothing else to
+    /// do. Defaults to [`ParkTimeout`](crate::ParkTimeout).
+    ///
+    /// Only used with the system clock; a custom
+    /// [`clock`](SchedulerBuilder::clock) always sleeps through
+    /// [`Clock::sleep`].
+    pub fn sleep_strategy(mut self, strategy: impl SleepStrategy + 'static) -> Self {
+        self.config.sleep = Some(Arc::new(strategy));
+        self
+    }
+
+    /// How long a message on a channel from [`Scheduler::poll_channel`] may
+    /// wait at most before the loop looks for it. Defaults to 10ms.
+    ///
+    /// Only caps the loop's waits while such a channel is open: a shorter
+    /// interval picks messages up sooner at the cost of more wakeups.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn channel_poll_interval(mut self, interval: Duration) -> Self {
+        assert!(
+            !interval.is_zero(),
+            "channel poll interval must not be zero"
+        );
+        self.config.channel_poll_interval = interval;
+        self
+    }
+
+    /// How [`Scheduler::run_async`] gets woken when its next timer is due.
+    /// Defaults to a helper thread per run.
+    ///
+    /// With a timer set, a custom [`clock`](SchedulerBuilder::clock) no
+    /// longer sleeps through [`Clock::sleep`] under `run_async()`: the
+    /// future waits for `timer` like it would with the system clock.
+    pub fn async_timer(mut self, timer: impl AsyncTimer + 'static) -> Self {
+        self.config.async_timer = Some(Arc::new(timer));
+        self
+    }
+
+    /// How many of the tasks that finished last [`Scheduler::status`]
+    /// remembers as completed or cancelled. Defaults to 256; older ones
+    /// read as [`TaskState::Unknown`](crate::TaskState::Unknown). Zero
+    /// remembers none.
+    pub fn status_history(mut self, tasks: usize) -> Self {
+        self.config.status_history = tasks;
+        self
+    }
+
+    /// How many events each receiver from [`Scheduler::subscribe`] holds
+    /// before further ones are dropped. Defaults to 1024.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `events` is zero.
+    pub fn event_buffer(mut self, events: usize) -> Self {
+        assert!(events > 0, "event buffer must hold at least one event");
+        self.config.event_buffer = events;
+        self
+    }
+
+    /// How many of the last lifecycle events [`Scheduler::recent_events`]
+    /// and [`Scheduler::dump_state`] keep. Defaults to 256; zero keeps
+    /// none. The room is set aside once, when the scheduler is built.
+    pub fn event_history(mut self, events: usize) -> Self {
+        self.config.event_history = events;
+        self
+    }
+
+    /// Creates the scheduler.
+    pub fn build(self) -> Arc<Scheduler> {
+        Scheduler::with_config(self.config)
+    }
+}
@@ -0,0 +1,374 @@
+use crate::sync::Mutex;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The source of time for a [`Scheduler`](crate::Scheduler).
+///
+/// Every deadline the scheduler computes, and every wait for a timer, goes
+/// through its clock. The default is [`SystemClock`]; tests can swap in a
+/// clock they control.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// Blocks the calling thread for `duration` as measured by this clock.
+    fn sleep(&self, duration: Duration);
+
+    /// The current calendar time, for schedules such as
+    /// [`Scheduler::schedule_cron`](crate::Scheduler::schedule_cron) that
+    /// are tied to the time of day. Simulated clocks should move it in step
+    /// with [`Clock::now`].
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Wall-clock time: [`Instant::now`] and [`thread::sleep`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Simulated time that jumps ahead instead of waiting.
+///
+/// Whenever a scheduler using this clock would wait for its next timer,
+/// [`Clock::sleep`] simply moves the clock forward to that deadline, so
+/// hours of scheduled behaviour run in milliseconds with the same ordering
+/// they would have in real time. Clones share the same time.
+///
+/// ```
+/// use revent_loop::{Scheduler, Task, VirtualClock};
+/// use std::time::Duration;
+///
+/// let clock = VirtualClock::new();
+/// let scheduler = Scheduler::with_clock(clock.clone());
+/// scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(3600))));
+/// scheduler.run().unwrap();
+///
+/// assert_eq!(clock.elapsed(), Duration::from_secs(3600));
+/// ```
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    start: Instant,
+    /// The calendar time at `start`.
+    wall_start: SystemTime,
+    now: Arc<Mutex<Instant>>,
+}
+
+impl VirtualClock {
+    /// Creates a clock starting at the current wall-clock instant.
+    pub fn new() -> Self {
+        Self::starting_at(SystemTime::now())
+    }
+
+    /// Creates a clock whose [`Clock::wall_time`] starts out at `wall`.
+    pub fn starting_at(wall: SystemTime) -> Self {
+        let start = Instant::now();
+        Self {
+            start,
+            wall_start: wall,
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    /// How much simulated time has passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.now.lock() - self.start
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.wall_start + self.elapsed()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration)
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        (**self).wall_time()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub use mock::MockClock;
+
+#[cfg(any(test, feature = "test-util"))]
+mod mock {
+    use super::Clock;
+    use crate::sync::{Condvar, Mutex};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[derive(Debug)]
+    struct State {
+        start: Instant,
+        /// The calendar time at `start`.
+        wall_start: SystemTime,
+        now: Mutex<Instant>,
+        advanced: Condvar,
+    }
+
+    /// A clock that only moves when told to.
+    ///
+    /// Clones share the same time, so a test can keep one and hand another
+    /// to the scheduler. [`Clock::sleep`] blocks until other threads have
+    /// [advanced](MockClock::advance) the clock far enough; a scheduler using
+    /// this clock is usually driven with [`Scheduler::tick`] instead.
+    ///
+    /// [`Scheduler::tick`]: crate::Scheduler::tick
+    #[derive(Debug, Clone)]
+    pub struct MockClock {
+        state: Arc<State>,
+    }
+
+    impl MockClock {
+        /// Creates a clock frozen at the current wall-clock instant.
+        pub fn new() -> Self {
+            let start = Instant::now();
+            Self {
+                state: Arc::new(State {
+                    start,
+                    wall_start: SystemTime::now(),
+                    now: Mutex::new(start),
+                    advanced: Condvar::new(),
+                }),
+            }
+        }
+
+        /// Moves the clock forward by `duration`, releasing any sleepers
+        /// whose time has come.
+        pub fn advance(&self, duration: Duration) {
+            *self.state.now.lock() += duration;
+            self.state.advanced.notify_all();
+        }
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.state.now.lock()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            let now = self.state.now.lock();
+            let until = *now + duration;
+            let _now = self.state.advanced.wait_while(now, |now| *now < until);
+        }
+
+        fn wall_time(&self) -> SystemTime {
+            self.state.wall_start + (self.now() - self.state.start)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Scheduler, Task};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counting_task(count: &Arc<AtomicUsize>, delay: Duration) -> Task {
+        let count = count.clone();
+        Task::new(
+            move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            },
+            Some(delay),
+        )
+    }
+
+    #[test]
+    fn mock_clock_only_moves_when_advanced() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.now(), start + Duration::from_secs(3));
+    }
+
+    #[test]
+    fn deadlines_follow_the_mock_clock() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let count = Arc::new(AtomicUsize::new(0));
+
+        scheduler.schedule(counting_task(&count, Duration::from_secs(60)));
+        scheduler.schedule(counting_task(&count, Duration::from_secs(120)));
+
+        assert_eq!(scheduler.tick().executed, 0);
+        assert_eq!(
+            scheduler.tick().next_deadline,
+            Some(clock.now() + Duration::from_secs(60))
+        );
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(scheduler.tick().executed, 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(scheduler.tick().executed, 1);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(scheduler.tick().executed, 1);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn run_waits_for_the_mock_clock() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let count = Arc::new(AtomicUsize::new(0));
+        scheduler.schedule(counting_task(&count, Duration::from_secs(3600)));
+
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        clock.advance(Duration::from_secs(3600));
+        runner.join().unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn virtual_clock_skips_to_each_deadline() {
+        let clock = VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for delay in [
+            Duration::from_secs(7200),
+            Duration::from_secs(1),
+            Duration::from_secs(3600),
+        ] {
+            let fired = fired.clone();
+            let clock = clock.clone();
+            scheduler.schedule(Task::new(
+                move || fired.lock().push(clock.elapsed()),
+                Some(delay),
+            ));
+        }
+
+        let started = Instant::now();
+        scheduler.run().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(clock.elapsed(), Duration::from_secs(7200));
+        assert_eq!(
+            *fired.lock(),
+            vec![
+                Duration::from_secs(1),
+                Duration::from_secs(3600),
+                Duration::from_secs(7200),
+            ]
+        );
+    }
+
+    fn countdown(
+        n: usize,
+        scheduler: Arc<Scheduler>,
+        clock: VirtualClock,
+        log: Arc<Mutex<Vec<String>>>,
+    ) {
+        if n > 0 {
+            log.lock()
+                .push(format!("Down={} at {:?}", n, clock.elapsed()));
+            clock.sleep(Duration::from_secs(1));
+            let next = scheduler.clone();
+            scheduler.schedule(Task::new(
+                move || countdown(n - 1, next, clock, log),
+                Some(Duration::from_secs(2)),
+            ));
+        }
+    }
+
+    fn countup(
+        n: usize,
+        scheduler: Arc<Scheduler>,
+        clock: VirtualClock,
+        log: Arc<Mutex<Vec<String>>>,
+    ) {
+        if n > 0 {
+            log.lock()
+                .push(format!("Up={} at {:?}", n, clock.elapsed()));
+            clock.sleep(Duration::from_secs(2));
+            let next = scheduler.clone();
+            scheduler.schedule(Task::new(move || countup(n - 1, next, clock, log), None));
+        }
+    }
+
+    #[test]
+    fn countdown_and_countup_in_virtual_time() {
+        let clock = VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let (down, up) = (scheduler.clone(), scheduler.clone());
+        let (down_clock, up_clock) = (clock.clone(), clock.clone());
+        let (down_log, up_log) = (log.clone(), log.clone());
+        scheduler.schedule(Task::new(
+            move || countdown(5, down, down_clock, down_log),
+            None,
+        ));
+        scheduler.schedule(Task::new(move || countup(3, up, up_clock, up_log), None));
+        scheduler.run().unwrap();
+
+        assert_eq!(
+            *log.lock(),
+            vec![
+                "Down=5 at 0ns",
+                "Up=3 at 1s",
+                "Down=4 at 3s",
+                "Up=2 at 4s",
+                "Down=3 at 6s",
+                "Up=1 at 7s",
+                "Down=2 at 9s",
+                "Down=1 at 12s",
+            ]
+        );
+        // The final countdown(0) still waits out its two-second delay.
+        assert_eq!(clock.elapsed(), Duration::from_secs(15));
+    }
+}
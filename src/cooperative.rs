@@ -0,0 +1,386 @@
+use crate::sync::{Condvar, Mutex};
+use crate::{Metrics, RunError, RunReport, Scheduler};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wakes a [`Scheduler::run_async`] future once its next timer is due; see
+/// [`SchedulerBuilder::async_timer`].
+///
+/// By default a helper thread does this. An implementation can hand the
+/// job to whatever the runtime offers instead, and pair it with a custom
+/// [`Clock`](crate::Clock) if the runtime keeps its own time. Under tokio,
+/// for example, its timer takes over and no thread is left sleeping:
+///
+/// ```ignore
+/// struct TokioTimer(tokio::runtime::Handle);
+///
+/// impl AsyncTimer for TokioTimer {
+///     fn wake_at(&self, deadline: Instant, waker: Waker) {
+///         self.0.spawn(async move {
+///             tokio::time::sleep_until(deadline.into()).await;
+///             waker.wake();
+///         });
+///     }
+/// }
+///
+/// let scheduler = Scheduler::builder()
+///     .async_timer(TokioTimer(tokio::runtime::Handle::current()))
+///     .build();
+/// tokio::spawn(scheduler.run_async());
+/// ```
+///
+/// The crate has no `tokio` feature of its own; the example above is all
+/// the glue it takes.
+///
+/// [`SchedulerBuilder::async_timer`]: crate::SchedulerBuilder::async_timer
+pub trait AsyncTimer: Send + Sync {
+    /// Arranges for `waker` to be woken once `deadline`, on the scheduler's
+    /// clock, has passed. Each call replaces the one before. Waking early,
+    /// or for a deadline that has been replaced, only costs an extra poll.
+    fn wake_at(&self, deadline: Instant, waker: Waker);
+}
+
+/// What a run driven by [`Scheduler::run_async`] has done so far.
+pub(crate) struct Progress {
+    pub(crate) started: Instant,
+    pub(crate) before: Metrics,
+    pub(crate) executed: usize,
+    pub(crate) time_sleeping: Duration,
+}
+
+/// What [`Scheduler::run_turn`] wants the future to do next.
+pub(crate) enum Turn {
+    /// Poll again soon: there is more to run.
+    Yield,
+    /// Nothing to run until notified, or until the given instant.
+    Wait(Option<Instant>),
+    Done(Result<RunReport, RunError>),
+}
+
+/// The loop, driven as a future; see [`Scheduler::run_async`].
+///
+/// The loop is claimed on the first poll and let go once the future
+/// resolves or is dropped. Dropping it early leaves pending tasks queued,
+/// as [`Scheduler::shutdown`] would.
+#[must_use = "futures do nothing unless awaited"]
+pub struct RunAsync {
+    scheduler: Arc<Scheduler>,
+    /// Set while the future holds the loop.
+    progress: Option<Progress>,
+    resolved: bool,
+    /// Started on the first wait for a timer, unless the scheduler has an
+    /// [`AsyncTimer`].
+    alarm: Option<Alarm>,
+    /// When the last poll went to wait, for [`RunReport::time_sleeping`].
+    waiting_since: Option<Instant>,
+}
+
+impl RunAsync {
+    pub(crate) fn new(scheduler: Arc<Scheduler>) -> Self {
+        Self {
+            scheduler,
+            progress: None,
+            resolved: false,
+            alarm: None,
+            waiting_since: None,
+        }
+    }
+}
+
+impl Future for RunAsync {
+    type Output = Result<RunReport, RunError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        assert!(!this.resolved, "RunAsync polled after it resolved");
+        let scheduler = &this.scheduler;
+        let progress = match &mut this.progress {
+            Some(progress) => progress,
+            None => match scheduler.start_async_run() {
+                Ok(progress) => this.progress.insert(progress),
+                Err(error) => {
+                    this.resolved = true;
+                    return Poll::Ready(Err(error));
+                }
+            },
+        };
+        if let Some(since) = this.waiting_since.take() {
+            let slept = scheduler.now().saturating_duration_since(since);
+            scheduler.record_sleep(slept);
+            progress.time_sleeping += slept;
+        }
+
+        // Whichever thread polls is the loop thread for the time being.
+        let _loop_thread = scheduler.enter_loop();
+        match scheduler.run_turn(progress, cx.waker()) {
+            Turn::Yield => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Turn::Wait(until) => {
+                if let Some(until) = until {
+                    match scheduler.async_timer() {
+                        Some(timer) => timer.wake_at(until, cx.waker().clone()),
+                        None => this
+                            .alarm
+                            .get_or_insert_with(Alarm::start)
+                            .set(until, cx.waker().clone()),
+                    }
+                }
+                this.waiting_since = Some(scheduler.now());
+                Poll::Pending
+            }
+            Turn::Done(result) => {
+                this.progress = None;
+                this.resolved = true;
+                scheduler.release_loop();
+                Poll::Ready(result)
+            }
+        }
+    }
+}
+
+impl Drop for RunAsync {
+    fn drop(&mut self) {
+        if self.progress.is_some() {
+            self.scheduler.release_loop();
+        }
+    }
+}
+
+impl fmt::Debug for RunAsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunAsync")
+            .field("running", &self.progress.is_some())
+            .field("resolved", &self.resolved)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Wakes a [`RunAsync`] at its next deadline, from a thread of its own, so
+/// that it doesn't depend on the runtime's timer.
+struct Alarm {
+    shared: Arc<AlarmShared>,
+}
+
+#[derive(Default)]
+struct AlarmShared {
+    state: Mutex<AlarmState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct AlarmState {
+    due: Option<(Instant, Waker)>,
+    stopped: bool,
+}
+
+impl Alarm {
+    fn start() -> Self {
+        let shared = Arc::new(AlarmShared::default());
+        let ringing = shared.clone();
+        thread::Builder::new()
+            .name("revent-loop-alarm".into())
+            .spawn(move || ring(&ringing))
+            .expect("failed to spawn the alarm thread");
+        Self { shared }
+    }
+
+    /// Wakes `waker` at `at`, instead of whatever was set before.
+    fn set(&self, at: Instant, waker: Waker) {
+        self.shared.state.lock().due = Some((at, waker));
+        self.shared.changed.notify_one();
+    }
+}
+
+impl Drop for Alarm {
+    fn drop(&mut self) {
+        self.shared.state.lock().stopped = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+fn ring(shared: &AlarmShared) {
+    let mut state = shared.state.lock();
+    while !state.stopped {
+        let Some((at, _)) = &state.due else {
+            state = shared.changed.wait(state);
+            continue;
+        };
+        let remaining = at.saturating_duration_since(Instant::now());
+        if !remaining.is_zero() {
+            state = shared.changed.wait_timeout(state, remaining).0;
+            continue;
+        }
+        let (_, waker) = state.due.take().expect("just matched");
+        drop(state);
+        waker.wake();
+        state = shared.state.lock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sync::Mutex;
+    use crate::{AsyncTimer, Clock, RunError, Scheduler, Task, VirtualClock};
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use std::time::{Duration, Instant};
+
+    /// Counts its wakes, and unparks the thread that made it.
+    struct TestWaker {
+        thread: Thread,
+        wakes: AtomicUsize,
+    }
+
+    impl Wake for TestWaker {
+        fn wake(self: Arc<Self>) {
+            self.wakes.fetch_add(1, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    fn test_waker() -> (Arc<TestWaker>, Waker) {
+        let waker = Arc::new(TestWaker {
+            thread: thread::current(),
+            wakes: AtomicUsize::new(0),
+        });
+        (waker.clone(), Waker::from(waker))
+    }
+
+    /// A runtime of the simplest kind: polls on the calling thread, parking
+    /// in between.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let (_, waker) = test_waker();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn awaits_tasks_run_by_the_async_loop() {
+        let scheduler = Scheduler::new();
+        let start = Instant::now();
+        let immediate = scheduler.spawn(move || start.elapsed());
+        let fired = Arc::new(Mutex::new(None));
+        let record = fired.clone();
+        scheduler.schedule(Task::new(
+            move || *record.lock() = Some(start.elapsed()),
+            Some(Duration::from_millis(30)),
+        ));
+        let sleep = scheduler.sleep(Duration::from_millis(50));
+
+        let run = scheduler.run_async();
+        let runner = thread::spawn(move || block_on(run));
+        let (ran_at, woke_at) = block_on(async {
+            let ran_at = immediate.await.unwrap();
+            sleep.await;
+            (ran_at, start.elapsed())
+        });
+        let report = runner.join().unwrap().unwrap();
+
+        assert!(ran_at < Duration::from_millis(30), "{:?}", ran_at);
+        let fired = fired.lock().unwrap();
+        assert!(fired >= Duration::from_millis(30), "{:?}", fired);
+        assert!(fired < Duration::from_millis(130), "{:?}", fired);
+        assert!(woke_at >= Duration::from_millis(50), "{:?}", woke_at);
+        assert_eq!(report.tasks_executed, 3);
+        assert!(
+            report.time_sleeping >= Duration::from_millis(40),
+            "{:?}",
+            report
+        );
+    }
+
+    /// Fires every request straight away by jumping the clock to it, where
+    /// a real timer would fire it later.
+    struct Jump {
+        clock: VirtualClock,
+        requests: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl AsyncTimer for Jump {
+        fn wake_at(&self, deadline: Instant, waker: Waker) {
+            self.requests.lock().push(deadline);
+            self.clock
+                .advance(deadline.saturating_duration_since(self.clock.now()));
+            waker.wake();
+        }
+    }
+
+    #[test]
+    fn waits_for_timers_through_an_async_timer() {
+        let clock = VirtualClock::new();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = Scheduler::builder()
+            .clock(clock.clone())
+            .async_timer(Jump {
+                clock: clock.clone(),
+                requests: requests.clone(),
+            })
+            .build();
+        let start = clock.now();
+        let fired = Arc::new(Mutex::new(None));
+        let record = fired.clone();
+        let timing = clock.clone();
+        scheduler.schedule(Task::new(
+            move || *record.lock() = Some(timing.now()),
+            Some(Duration::from_millis(50)),
+        ));
+
+        let report = block_on(scheduler.run_async()).unwrap();
+        assert_eq!(report.tasks_executed, 1);
+        let due = start + Duration::from_millis(50);
+        assert_eq!(*fired.lock(), Some(due));
+        // The wait went to the timer rather than to `Clock::sleep`.
+        assert_eq!(*requests.lock(), [due]);
+    }
+
+    #[test]
+    fn yields_between_batches() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..200 {
+            let ran = ran.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                },
+                None,
+            ));
+        }
+
+        let mut run = pin!(scheduler.run_async());
+        let (counter, waker) = test_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(run.as_mut().poll(&mut cx).is_pending());
+        let first = ran.load(Ordering::SeqCst);
+        assert!(first > 0 && first < 200, "{}", first);
+        // Woken straight away, to carry on once the runtime has had a turn.
+        assert_eq!(counter.wakes.load(Ordering::SeqCst), 1);
+        // Meanwhile the loop is taken.
+        assert_eq!(scheduler.run().unwrap_err(), RunError::AlreadyRunning);
+
+        let report = loop {
+            if let Poll::Ready(report) = run.as_mut().poll(&mut cx) {
+                break report.unwrap();
+            }
+        };
+        assert_eq!(report.tasks_executed, 200);
+        scheduler.run().unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+use crate::CronParseError;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+/// How far ahead [`CronSchedule::next_after`] looks before giving up: long
+/// enough for any date that exists at all, including the 29th of February.
+const HORIZON: i64 = 8 * 366 * DAY;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// The time zone [`Scheduler::schedule_cron`] evaluates expressions in; see
+/// [`SchedulerBuilder::cron_zone`].
+///
+/// Only fixed offsets are supported, so a schedule in a zone with daylight
+/// saving time needs its offset updated when the clocks change.
+///
+/// [`Scheduler::schedule_cron`]: crate::Scheduler::schedule_cron
+/// [`SchedulerBuilder::cron_zone`]: crate::SchedulerBuilder::cron_zone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CronZone {
+    #[default]
+    Utc,
+    /// This many seconds ahead of UTC; negative west of Greenwich.
+    FixedOffset(i32),
+}
+
+impl CronZone {
+    fn offset(self) -> i64 {
+        match self {
+            Self::Utc => 0,
+            Self::FixedOffset(seconds) => i64::from(seconds),
+        }
+    }
+}
+
+/// A parsed five-field cron expression: minute, hour, day of month, month
+/// and day of week.
+///
+/// Each field is `*`, a value, a range `a-b` or a list of those separated by
+/// commas, optionally stepped with `/n`. Months and days of the week may
+/// also be given by their three-letter English names, and Sunday is either
+/// 0 or 7. As in classic cron, when both day fields are restricted a day
+/// matches if either one does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    /// Bit `d` for day `d` of the month.
+    days: u64,
+    /// Bit `m` for month `m`, January being 1.
+    months: u64,
+    /// Bit `w` for day `w` of the week, Sunday being 0.
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = CronParseError;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let error = |reason: String| CronParseError::new(expr, reason);
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(error(format!("expected 5 fields, found {}", fields.len())));
+        };
+        let mut weekdays = parse_field(weekday, "day of week", 0, 7, &WEEKDAYS).map_err(error)?;
+        // Both 0 and 7 mean Sunday.
+        if weekdays & 1 << 7 != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        let schedule = Self {
+            minutes: parse_field(minute, "minute", 0, 59, &[]).map_err(error)?,
+            hours: parse_field(hour, "hour", 0, 23, &[]).map_err(error)?,
+            days: parse_field(day, "day of month", 1, 31, &[]).map_err(error)?,
+            months: parse_field(month, "month", 1, 12, &MONTHS).map_err(error)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        };
+        if schedule.any_weekday && !schedule.day_in_any_month() {
+            return Err(error("no month has that day".into()));
+        }
+        Ok(schedule)
+    }
+}
+
+/// Parses one field into a bit set of the values it allows.
+fn parse_field(field: &str, name: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u32, String> {
+        let lower = text.to_ascii_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            // Names count from the field's lowest value.
+            Some(index) => index as u32 + min.min(1),
+            None => text
+                .parse()
+                .map_err(|_| format!("invalid {} `{}`", name, text))?,
+        };
+        if !(min..=max).contains(&value) {
+            return Err(format!(
+                "{} {} is out of range {}-{}",
+                name, value, min, max
+            ));
+        }
+        Ok(value)
+    };
+
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step `{}` in {}", step, name)),
+            },
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (value(first)?, value(last)?),
+                // `a/n` runs from `a` to the end of the range.
+                None if step > 1 => (value(range)?, max),
+                None => {
+                    let value = value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if first > last {
+            return Err(format!("{} range {}-{} is backwards", name, first, last));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl CronSchedule {
+    /// Whether some allowed month has one of the allowed days of the month.
+    fn day_in_any_month(&self) -> bool {
+        (1..=12)
+            .filter(|month| self.months & 1 << month != 0)
+            .any(|month| {
+                // February is checked in a leap year.
+                (1..=days_in_month(2000, month)).any(|day| self.days & 1 << day != 0)
+            })
+    }
+
+    fn day_matches(&self, day: u32, weekday: u32) -> bool {
+        let day = self.days & 1 << day != 0;
+        let weekday = self.weekdays & 1 << weekday != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching minute strictly after `after`, in `zone`.
+    pub(crate) fn next_after(&self, after: SystemTime, zone: CronZone) -> Option<SystemTime> {
+        let offset = zone.offset();
+        let start = unix_seconds(after) + offset;
+        let mut t = (start.div_euclid(MINUTE) + 1) * MINUTE;
+        while t < start + HORIZON {
+            let days = t.div_euclid(DAY);
+            let (year, month, day) = civil_from_days(days);
+            if self.months & 1 << month == 0 {
+                let (year, month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                t = days_from_civil(year, month, 1) * DAY;
+                continue;
+            }
+            // 1970-01-01 was a Thursday.
+            let weekday = (days + 4).rem_euclid(7) as u32;
+            if !self.day_matches(day, weekday) {
+                t = (days + 1) * DAY;
+                continue;
+            }
+            let seconds = t.rem_euclid(DAY);
+            if self.hours & 1 << (seconds / HOUR) == 0 {
+                t = days * DAY + (seconds / HOUR + 1) * HOUR;
+                continue;
+            }
+            if self.minutes & 1 << (seconds % HOUR / MINUTE) == 0 {
+                t += MINUTE;
+                continue;
+            }
+            return Some(from_unix_seconds(t - offset));
+        }
+        None
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs_f64().ceil() as i64),
+    }
+}
+
+fn from_unix_seconds(seconds: i64) -> SystemTime {
+    match u64::try_from(seconds) {
+        Ok(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+        Err(_) => UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()),
+    }
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date, after Howard
+/// Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Seconds since the epoch for a UTC date and time.
+    fn at(year: i64, month: u32, day: u32, hour: i64, minute: i64) -> SystemTime {
+        from_unix_seconds(days_from_civil(year, month, day) * DAY + hour * HOUR + minute * MINUTE)
+    }
+
+    fn next(expr: &str, after: SystemTime) -> Option<SystemTime> {
+        expr.parse::<CronSchedule>()
+            .unwrap()
+            .next_after(after, CronZone::Utc)
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() {
+        let start = at(2024, 2, 28, 23, 58);
+        assert_eq!(next("*/5 * * * *", start), Some(at(2024, 2, 29, 0, 0)));
+        assert_eq!(
+            next("30 9 * * mon-fri", start),
+            Some(at(2024, 2, 29, 9, 30))
+        );
+        assert_eq!(next("0 0 29 feb *", start), Some(at(2024, 2, 29, 0, 0)));
+        assert_eq!(
+            next("0 0 29 2 *", at(2024, 3, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+        // Restricted day of month and day of week: either one will do.
+        assert_eq!(next("0 12 1 * 0", start), Some(at(2024, 3, 1, 12, 0)));
+        assert_eq!(next("0 12 15 * 7", start), Some(at(2024, 3, 3, 12, 0)));
+        // Strictly after: a matching start minute is skipped.
+        assert_eq!(next("58 23 * * *", start), Some(at(2024, 2, 29, 23, 58)));
+    }
+
+    #[test]
+    fn fixed_offsets_shift_the_schedule() {
+        let schedule: CronSchedule = "0 9 * * *".parse().unwrap();
+        let start = at(2024, 1, 1, 0, 0);
+        assert_eq!(
+            schedule.next_after(start, CronZone::FixedOffset(2 * 3600)),
+            Some(at(2024, 1, 1, 7, 0))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expr in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * smarch *",
+            "0 0 30 feb *",
+        ] {
+            assert!(expr.parse::<CronSchedule>().is_err(), "{}", expr);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use crate::sync::Mutex;
+use crate::TaskId;
+use crate::{Scheduler, Task};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+type Callback = Arc<dyn Fn() + Send + Sync + 'static>;
+
+/// Runs a callback once calls have stopped for a while, from
+/// [`Scheduler::debounced`].
+///
+/// Every [`Debounced::call`] pushes the pending run out to `delay` from
+/// now, so a burst of calls ends in a single run, `delay` after the last
+/// one. Calls may come from any thread.
+pub struct Debounced {
+    scheduler: Weak<Scheduler>,
+    delay: Duration,
+    f: Callback,
+    /// The run most recently scheduled, which may have happened already.
+    pending: Mutex<Option<TaskId>>,
+}
+
+impl Debounced {
+    pub(crate) fn new(
+        scheduler: Weak<Scheduler>,
+        delay: Duration,
+        f: impl Fn() + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            scheduler,
+            delay,
+            f: Arc::new(f),
+            pending: Mutex::new(None),
+        }
+    }
+
+    /// Restarts the quiet period. Does nothing once the scheduler is gone.
+    pub fn call(&self) {
+        let Some(scheduler) = self.scheduler.upgrade() else {
+            return;
+        };
+        let mut pending = self.pending.lock();
+        if let Some(id) = *pending {
+            if scheduler.reschedule(id, self.delay) {
+                return;
+            }
+        }
+        let f = self.f.clone();
+        let handle = scheduler.schedule(Task::new(move || f(), Some(self.delay)));
+        *pending = Some(handle.id());
+    }
+
+    /// Drops the pending run, if there is one. Returns whether anything was
+    /// cancelled.
+    pub fn cancel(&self) -> bool {
+        let pending = self.pending.lock().take();
+        match (pending, self.scheduler.upgrade()) {
+            (Some(id), Some(scheduler)) => scheduler.cancel(id),
+            _ => false,
+        }
+    }
+}
+
+/// Runs a callback at most once per period, from [`Scheduler::throttled`].
+///
+/// The first [`Throttled::call`] runs the callback on the loop and opens a
+/// window of `period`; further calls inside the window are dropped, unless
+/// [`Throttled::trailing`] is enabled, in which case they add up to one
+/// more run when the window closes. Calls may come from any thread.
+pub struct Throttled {
+    inner: Arc<Throttle>,
+    trailing: bool,
+}
+
+struct Throttle {
+    scheduler: Weak<Scheduler>,
+    period: Duration,
+    f: Callback,
+    state: Mutex<ThrottleState>,
+}
+
+#[derive(Default)]
+struct ThrottleState {
+    /// When the current window closes.
+    open_until: Option<Instant>,
+    /// The leading or trailing run that has been scheduled but may not
+    /// have happened yet.
+    scheduled: Option<TaskId>,
+    /// Whether `scheduled` is a trailing run.
+    trailing_scheduled: bool,
+}
+
+impl Throttled {
+    pub(crate) fn new(
+        scheduler: Weak<Scheduler>,
+        period: Duration,
+        f: impl Fn() + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Throttle {
+                scheduler,
+                period,
+                f: Arc::new(f),
+                state: Mutex::default(),
+            }),
+            trailing: false,
+        }
+    }
+
+    /// Also runs the callback when a window closes, if it was called while
+    /// the window was open. Off by default.
+    pub fn trailing(mut self, enabled: bool) -> Self {
+        self.trailing = enabled;
+        self
+    }
+
+    /// Runs the callback unless it already ran within the last period. Does
+    /// nothing once the scheduler is gone.
+    pub fn call(&self) {
+        let Some(scheduler) = self.inner.scheduler.upgrade() else {
+            return;
+        };
+        let now = scheduler.now();
+        let mut state = self.inner.state.lock();
+        match state.open_until {
+            Some(until) if now < until => {
+                if self.trailing && !state.trailing_scheduled {
+                    let inner = self.inner.clone();
+                    let handle = scheduler
+                        .schedule(Task::new(move || inner.run_trailing(), Some(until - now)));
+                    state.scheduled = Some(handle.id());
+                    state.trailing_scheduled = true;
+                }
+            }
+            _ => {
+                state.open_until = Some(now + self.inner.period);
+                let f = self.inner.f.clone();
+                let handle = scheduler.schedule(Task::new(move || f(), None));
+                state.scheduled = Some(handle.id());
+            }
+        }
+    }
+
+    /// Drops the run that is scheduled but hasn't happened yet, if there is
+    /// one, and closes the current window. Returns whether anything was
+    /// cancelled.
+    pub fn cancel(&self) -> bool {
+        let scheduled = std::mem::take(&mut *self.inner.state.lock()).scheduled;
+        match (scheduled, self.inner.scheduler.upgrade()) {
+            (Some(id), Some(scheduler)) => scheduler.cancel(id),
+            _ => false,
+        }
+    }
+}
+
+impl Throttle {
+    /// A trailing run opens the next window.
+    fn run_trailing(&self) {
+        if let Some(scheduler) = self.scheduler.upgrade() {
+            let mut state = self.state.lock();
+            state.open_until = Some(scheduler.now() + self.period);
+            state.trailing_scheduled = false;
+        }
+        (self.f)()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{MockClock, Scheduler};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn counter() -> (Arc<AtomicUsize>, impl Fn() + Send + Sync + 'static) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counted = count.clone();
+        (count, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[test]
+    fn rapid_debounced_calls_run_once() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let (count, f) = counter();
+        let debounced = scheduler.debounced(Duration::from_millis(100), f);
+
+        for _ in 0..10 {
+            debounced.call();
+            clock.advance(Duration::from_millis(10));
+            scheduler.tick();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        // The last call was 10ms ago.
+        clock.advance(Duration::from_millis(90));
+        scheduler.tick();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        debounced.call();
+        assert!(debounced.cancel());
+        clock.advance(Duration::from_millis(100));
+        scheduler.tick();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    fn throttled_runs(trailing: bool) -> usize {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let (count, f) = counter();
+        let throttled = scheduler
+            .throttled(Duration::from_millis(100), f)
+            .trailing(trailing);
+
+        // Ten calls 30ms apart span three periods.
+        for _ in 0..10 {
+            throttled.call();
+            scheduler.tick();
+            clock.advance(Duration::from_millis(30));
+            scheduler.tick();
+        }
+        clock.advance(Duration::from_millis(100));
+        scheduler.tick();
+        count.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn throttled_calls_run_once_per_period() {
+        assert_eq!(throttled_runs(false), 3);
+        assert_eq!(throttled_runs(true), 4);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::Task;
+use crate::TaskId;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// How many finished task ids are remembered for
+/// [`Scheduler::schedule_after`](crate::Scheduler::schedule_after). Older
+/// ones are forgotten, oldest first.
+const FINISHED_MEMORY: usize = 1024;
+
+/// What happens to tasks waiting in
+/// [`Scheduler::schedule_after`](crate::Scheduler::schedule_after) when
+/// their prerequisite is cancelled; see
+/// [`SchedulerBuilder::dependency_policy`](crate::SchedulerBuilder::dependency_policy).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DependencyPolicy {
+    /// Cancel them too, and everything waiting for them in turn.
+    #[default]
+    CancelDependents,
+    /// Queue them as if the prerequisite had finished.
+    Release,
+}
+
+/// Tasks held back until another task has run, keyed by that task's id.
+#[derive(Default)]
+pub(crate) struct Dependencies {
+    waiting: HashMap<TaskId, Vec<Task>>,
+    /// The prerequisite of every waiting task, by the waiting task's id.
+    prerequisites: HashMap<TaskId, TaskId>,
+    finished: HashSet<TaskId>,
+    /// `finished` in the order the tasks ran, for forgetting the oldest.
+    finished_order: VecDeque<TaskId>,
+}
+
+impl Dependencies {
+    /// Holds `task` until `prerequisite` has run, or hands it back if it
+    /// already has.
+    pub(crate) fn park(&mut self, prerequisite: TaskId, task: Task) -> Option<Task> {
+        if self.finished.contains(&prerequisite) {
+            return Some(task);
+        }
+        self.prerequisites.insert(task.id, prerequisite);
+        self.waiting.entry(prerequisite).or_default().push(task);
+        None
+    }
+
+    /// Notes that `id` has run and takes out the tasks that were waiting
+    /// for it, in the order they were parked.
+    pub(crate) fn finished(&mut self, id: TaskId) -> Vec<Task> {
+        if self.finished.insert(id) {
+            self.finished_order.push_back(id);
+            if self.finished_order.len() > FINISHED_MEMORY {
+                let forgotten = self.finished_order.pop_front().unwrap();
+                self.finished.remove(&forgotten);
+            }
+        }
+        self.take_dependents(id)
+    }
+
+    /// `id` is never going to run. Returns the tasks to queue anyway and the
+    /// ones to drop, according to `policy`.
+    pub(crate) fn cancelled(
+        &mut self,
+        id: TaskId,
+        policy: DependencyPolicy,
+    ) -> (Vec<Task>, Vec<Task>) {
+        let dependents = self.take_dependents(id);
+        match policy {
+            DependencyPolicy::Release => (dependents, Vec::new()),
+            DependencyPolicy::CancelDependents => {
+                let mut dropped = Vec::new();
+                let mut next = dependents;
+                while !next.is_empty() {
+                    let ids: Vec<TaskId> = next.iter().map(|task| task.id).collect();
+                    dropped.append(&mut next);
+                    for id in ids {
+                        next.extend(self.take_dependents(id));
+                    }
+                }
+                (Vec::new(), dropped)
+            }
+        }
+    }
+
+    /// Takes out a waiting task by its own id.
+    pub(crate) fn unpark(&mut self, id: TaskId) -> Option<Task> {
+        let prerequisite = self.prerequisites.remove(&id)?;
+        let siblings = self.waiting.get_mut(&prerequisite)?;
+        let index = siblings.iter().position(|task| task.id == id)?;
+        let task = siblings.remove(index);
+        if siblings.is_empty() {
+            self.waiting.remove(&prerequisite);
+        }
+        Some(task)
+    }
+
+    /// Whether the task with id `id` is held back here.
+    pub(crate) fn is_waiting(&self, id: TaskId) -> bool {
+        self.prerequisites.contains_key(&id)
+    }
+
+    /// Takes out every waiting task.
+    pub(crate) fn take_all(&mut self) -> Vec<Task> {
+        self.prerequisites.clear();
+        self.waiting.drain().flat_map(|(_, tasks)| tasks).collect()
+    }
+
+    fn take_dependents(&mut self, id: TaskId) -> Vec<Task> {
+        let dependents = self.waiting.remove(&id).unwrap_or_default();
+        for task in &dependents {
+            self.prerequisites.remove(&task.id);
+        }
+        dependents
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn forgets_the_oldest_finished_tasks() {
+        let mut dependencies = Dependencies::default();
+        let first = TaskId::new();
+        dependencies.finished(first);
+        assert!(dependencies.park(first, Task::new(|| {}, None)).is_some());
+
+        for _ in 0..FINISHED_MEMORY {
+            dependencies.finished(TaskId::new());
+        }
+        assert!(dependencies.park(first, Task::new(|| {}, None)).is_none());
+        assert_eq!(dependencies.finished.len(), FINISHED_MEMORY);
+        assert_eq!(dependencies.take_all().len(), 1);
+        assert!(dependencies.prerequisites.is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::TaskId;
+use crate::{RunReport, Task};
+use std::any::Any;
+use std::fmt;
+
+/// What [`Scheduler::schedule`](crate::Scheduler::schedule) does with a
+/// task that arrives when the scheduler is full; see
+/// [`SchedulerBuilder::overflow_policy`](crate::SchedulerBuilder::overflow_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OverflowPolicy {
+    /// Cancel whichever pending task was scheduled first to make room.
+    DropOldest,
+    /// Drop the incoming task. The returned handle refers to a task that
+    /// never runs.
+    RejectNew,
+    /// Wait until the loop has taken a task off the queues.
+    ///
+    /// Blocking the loop thread on itself could never finish, so
+    /// `schedule()` panics instead when called from inside a task.
+    Block,
+}
+
+/// Why [`Scheduler::try_schedule`](crate::Scheduler::try_schedule) turned a
+/// task away.
+#[derive(Debug)]
+pub enum ScheduleError {
+    /// The scheduler already holds as many pending tasks as
+    /// [`SchedulerBuilder::max_pending`](crate::SchedulerBuilder::max_pending)
+    /// allows. The task is handed back untouched.
+    QueueFull(Task),
+    /// [`Scheduler::shutdown`](crate::Scheduler::shutdown) has been called,
+    /// and not undone by [`Scheduler::reset`](crate::Scheduler::reset), so
+    /// the loop would not run the task. The task is handed back untouched.
+    ShutDown(Task),
+}
+
+impl ScheduleError {
+    /// Takes back the task that could not be scheduled.
+    pub fn into_task(self) -> Task {
+        match self {
+            Self::QueueFull(task) | Self::ShutDown(task) => task,
+        }
+    }
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QueueFull(task) => {
+                write!(f, "scheduler queue is full, rejected task {}", task.id())
+            }
+            Self::ShutDown(task) => {
+                write!(f, "scheduler is shut down, rejected task {}", task.id())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// Returned by [`SchedulerHandle`](crate::SchedulerHandle) once the
+/// scheduler it points at has been dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SchedulerGone;
+
+impl fmt::Display for SchedulerGone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("scheduler has been dropped")
+    }
+}
+
+impl std::error::Error for SchedulerGone {}
+
+/// Why [`Scheduler::run`](crate::Scheduler::run) didn't run the loop, or
+/// didn't run it to the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RunError {
+    /// The loop is already running, either on another thread or further up
+    /// the calling thread's stack, from inside one of its callbacks.
+    AlreadyRunning,
+    /// A callback panicked, and the
+    /// [`PanicPolicy`](crate::PanicPolicy) ended the run early. The report
+    /// covers what ran up to then.
+    StoppedOnPanic(RunReport),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyRunning => f.write_str("scheduler loop is already running"),
+            Self::StoppedOnPanic(report) => match report.stopped_on_panic {
+                Some(policy) => write!(f, "scheduler loop stopped on a panic ({:?})", policy),
+                None => f.write_str("scheduler loop stopped on a panic"),
+            },
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/// Handed to a [`JoinHandle::then`](crate::JoinHandle::then) continuation,
+/// or returned by [`JoinHandle::try_join`](crate::JoinHandle::try_join),
+/// when the task didn't produce a value.
+#[derive(Debug)]
+pub struct JoinError {
+    pub(crate) id: TaskId,
+    pub(crate) kind: JoinErrorKind,
+    /// What the task panicked with, if it did.
+    pub(crate) payload: Option<Box<dyn Any + Send>>,
+}
+
+/// Why a task didn't produce a value; see [`JoinError::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JoinErrorKind {
+    /// The task was dropped without finishing, e.g. because it was
+    /// cancelled.
+    Cancelled,
+    /// The callback panicked; the payload is in the [`JoinError`].
+    Panicked,
+    /// The callback ran to the end, but took longer than its
+    /// [`TaskBuilder::exec_timeout`](crate::TaskBuilder::exec_timeout).
+    TimedOut,
+}
+
+impl JoinError {
+    pub(crate) fn new(id: TaskId, kind: JoinErrorKind) -> Self {
+        Self {
+            id,
+            kind,
+            payload: None,
+        }
+    }
+
+    /// The id of the task that didn't produce a value.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn kind(&self) -> JoinErrorKind {
+        self.kind
+    }
+
+    /// What the task panicked with, if it panicked with a message: a `&str`
+    /// or a `String`, as from `panic!`.
+    pub fn panic_message(&self) -> Option<&str> {
+        let payload = self.payload.as_deref()?;
+        payload
+            .downcast_ref::<&'static str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+    }
+
+    /// The payload the task panicked with, to inspect or to carry on
+    /// unwinding with through [`std::panic::resume_unwind`]. Hands the error
+    /// back if the task didn't panic.
+    pub fn into_panic(self) -> Result<Box<dyn Any + Send>, Self> {
+        match self.payload {
+            Some(payload) => Ok(payload),
+            None => Err(self),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            JoinErrorKind::Cancelled => write!(f, "task {} was cancelled", self.id),
+            JoinErrorKind::Panicked => match self.panic_message() {
+                Some(message) => write!(f, "task {} panicked: {}", self.id, message),
+                None => write!(f, "task {} panicked", self.id),
+            },
+            JoinErrorKind::TimedOut => {
+                write!(f, "task {} ran past its execution timeout", self.id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// Why [`Scheduler::schedule_cron`](crate::Scheduler::schedule_cron)
+/// rejected an expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronParseError {
+    expr: String,
+    reason: String,
+}
+
+impl CronParseError {
+    pub(crate) fn new(expr: &str, reason: String) -> Self {
+        Self {
+            expr: expr.to_owned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for CronParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid cron expression `{}`: {}",
+            self.expr, self.reason
+        )
+    }
+}
+
+impl std::error::Error for CronParseError {}
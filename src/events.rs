@@ -0,0 +1,174 @@
+use crate::sync::Mutex;
+use crate::TaskMeta;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+/// Something that happened to a task, from [`Scheduler::subscribe`].
+///
+/// Each event carries the task it is about and when it happened, on the
+/// scheduler's clock.
+///
+/// [`Scheduler::subscribe`]: crate::Scheduler::subscribe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchedulerEvent {
+    /// Queued, including each time an interval queues its next run.
+    Scheduled { task: TaskMeta, at: Instant },
+    /// Its delay ran out, and it moved to the ready queue.
+    TimerFired { task: TaskMeta, at: Instant },
+    /// Its callback is about to be called.
+    Started { task: TaskMeta, at: Instant },
+    /// Its callback returned, after `duration`.
+    Completed {
+        task: TaskMeta,
+        at: Instant,
+        duration: Duration,
+    },
+    /// Dropped before it could run (again).
+    Cancelled { task: TaskMeta, at: Instant },
+    /// Its callback panicked.
+    Panicked { task: TaskMeta, at: Instant },
+}
+
+impl SchedulerEvent {
+    /// The task the event is about.
+    pub fn task(&self) -> &TaskMeta {
+        match self {
+            Self::Scheduled { task, .. }
+            | Self::TimerFired { task, .. }
+            | Self::Started { task, .. }
+            | Self::Completed { task, .. }
+            | Self::Cancelled { task, .. }
+            | Self::Panicked { task, .. } => task,
+        }
+    }
+
+    /// When the event happened, on the scheduler's clock.
+    pub fn at(&self) -> Instant {
+        match self {
+            Self::Scheduled { at, .. }
+            | Self::TimerFired { at, .. }
+            | Self::Started { at, .. }
+            | Self::Completed { at, .. }
+            | Self::Cancelled { at, .. }
+            | Self::Panicked { at, .. } => *at,
+        }
+    }
+}
+
+/// The channels handed out by [`Scheduler::subscribe`]. Never held while
+/// taking another lock.
+///
+/// [`Scheduler::subscribe`]: crate::Scheduler::subscribe
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    senders: Mutex<Vec<SyncSender<SchedulerEvent>>>,
+    /// Whether `senders` is non-empty, so that a scheduler nobody listens
+    /// to doesn't build events just to throw them away.
+    active: AtomicBool,
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(&self, buffer: usize) -> Receiver<SchedulerEvent> {
+        let (sender, receiver) = mpsc::sync_channel(buffer);
+        self.senders.lock().push(sender);
+        self.active.store(true, Ordering::SeqCst);
+        receiver
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Offers `event` to every subscriber without waiting for any of them,
+    /// and returns how many had no room for it. Subscribers whose receiver
+    /// is gone are forgotten.
+    pub(crate) fn send(&self, event: SchedulerEvent) -> usize {
+        let mut senders = self.senders.lock();
+        let mut dropped = 0;
+        senders.retain(|sender| match sender.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                dropped += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        if senders.is_empty() {
+            self.active.store(false, Ordering::SeqCst);
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Scheduler, SchedulerEvent, Task};
+    use std::time::Duration;
+
+    fn kind(event: &SchedulerEvent) -> &'static str {
+        match event {
+            SchedulerEvent::Scheduled { .. } => "scheduled",
+            SchedulerEvent::TimerFired { .. } => "timer fired",
+            SchedulerEvent::Started { .. } => "started",
+            SchedulerEvent::Completed { .. } => "completed",
+            SchedulerEvent::Cancelled { .. } => "cancelled",
+            SchedulerEvent::Panicked { .. } => "panicked",
+        }
+    }
+
+    #[test]
+    fn follows_one_timer_through_a_workload() {
+        let scheduler = Scheduler::new();
+        let events = scheduler.subscribe();
+        scheduler.spawn(|| {});
+        let timer = scheduler.schedule(Task::new_named(
+            "timer",
+            || {},
+            Some(Duration::from_millis(10)),
+        ));
+        let cancelled = scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(60))));
+        scheduler.schedule(Task::new(|| panic!("boom"), None));
+        cancelled.cancel();
+        scheduler.run().unwrap();
+
+        let events: Vec<SchedulerEvent> = events.try_iter().collect();
+        let of = |id| {
+            events
+                .iter()
+                .filter(|event| event.task().id == id)
+                .map(kind)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            of(timer.id()),
+            ["scheduled", "timer fired", "started", "completed"]
+        );
+        assert_eq!(of(cancelled.id()), ["scheduled", "cancelled"]);
+        assert!(events.iter().any(|event| kind(event) == "panicked"));
+        assert!(events.windows(2).all(|pair| pair[0].at() <= pair[1].at()));
+        let named = events.iter().find(|event| event.task().id == timer.id());
+        assert_eq!(named.unwrap().task().name.as_deref(), Some("timer"));
+    }
+
+    #[test]
+    fn a_full_subscriber_loses_events_instead_of_blocking() {
+        let scheduler = Scheduler::builder().event_buffer(2).build();
+        let slow = scheduler.subscribe();
+        let gone = scheduler.subscribe();
+        drop(gone);
+        for _ in 0..10 {
+            scheduler.spawn(|| {});
+        }
+        scheduler.run().unwrap();
+
+        assert_eq!(slow.try_iter().count(), 2);
+        // Only the slow subscriber is counted; the dropped one is forgotten.
+        assert_eq!(scheduler.metrics().events_dropped, 30 - 2);
+        // Once nobody listens, nothing more is counted either.
+        drop(slow);
+        scheduler.spawn(|| {});
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.metrics().events_dropped, 30 - 2);
+    }
+}
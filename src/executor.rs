@@ -0,0 +1,198 @@
+use crate::sync::Mutex;
+use crate::{Scheduler, Task};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Wake, Waker};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// A future spawned onto a [`Scheduler`].
+///
+/// The future is polled from a regular ready task. When it returns
+/// `Pending` it leaves the queues entirely and only comes back once its
+/// waker fires, so idle futures cost nothing.
+pub(crate) struct FutureTask {
+    future: Mutex<Option<BoxFuture>>,
+    scheduler: Weak<Scheduler>,
+    /// Set while a poll is sitting in the ready queue, so repeated wakes
+    /// before the next poll don't enqueue duplicates.
+    queued: AtomicBool,
+}
+
+impl FutureTask {
+    pub(crate) fn new(
+        future: impl Future<Output = ()> + Send + 'static,
+        scheduler: Weak<Scheduler>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            future: Mutex::new(Some(Box::pin(future))),
+            scheduler,
+            queued: AtomicBool::new(false),
+        })
+    }
+
+    /// Queues a poll of this future, unless one is already queued.
+    pub(crate) fn enqueue(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if let Some(scheduler) = self.scheduler.upgrade() {
+            let task = self.clone();
+            scheduler.schedule_unbounded(Task::new(move || task.poll(), None));
+        }
+    }
+
+    fn poll(self: Arc<Self>) {
+        // Clear the flag first so a wake that arrives mid-poll queues
+        // another one.
+        self.queued.store(false, Ordering::Release);
+
+        let mut slot = self.future.lock();
+        if let Some(future) = slot.as_mut() {
+            let waker = Waker::from(self.clone());
+            let mut cx = Context::from_waker(&waker);
+            match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx))) {
+                Ok(Poll::Pending) => {}
+                Ok(Poll::Ready(())) => *slot = None,
+                Err(payload) => {
+                    // Drop the future before handing the panic on to the loop,
+                    // which reports it like any other task panic.
+                    *slot = None;
+                    drop(slot);
+                    panic::resume_unwind(payload);
+                }
+            }
+        }
+    }
+}
+
+impl Wake for FutureTask {
+    fn wake(self: Arc<Self>) {
+        self.enqueue();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.enqueue();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sync::Mutex;
+    use crate::Scheduler;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Waker};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Pending until `ready` is set, counting every poll it receives.
+    struct Manual {
+        ready: Arc<Mutex<(bool, Option<Waker>)>>,
+        polls: Arc<AtomicUsize>,
+    }
+
+    impl Future for Manual {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.polls.fetch_add(1, Ordering::SeqCst);
+            let mut state = self.ready.lock();
+            if state.0 {
+                Poll::Ready(())
+            } else {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Returns `Pending` once, waking itself straight away.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    async fn add_twice(start: u32) -> u32 {
+        let mut value = start;
+        YieldNow(false).await;
+        value += 1;
+        YieldNow(false).await;
+        value += 1;
+        value
+    }
+
+    #[test]
+    fn async_fn_chaining_two_awaits() {
+        let scheduler = Scheduler::new();
+        let result = Arc::new(Mutex::new(None));
+
+        let output = result.clone();
+        scheduler.spawn_future(async move {
+            *output.lock() = Some(add_twice(40).await);
+        });
+        scheduler.run().unwrap();
+
+        assert_eq!(*result.lock(), Some(42));
+    }
+
+    #[test]
+    fn pending_future_is_only_polled_when_woken() {
+        let scheduler = Scheduler::new();
+        let ready = Arc::new(Mutex::new((false, None::<Waker>)));
+        let polls = Arc::new(AtomicUsize::new(0));
+
+        let waker = {
+            let ready = ready.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                let mut state = ready.lock();
+                state.0 = true;
+                state.1.take().unwrap().wake();
+            })
+        };
+        scheduler.block_on(Manual {
+            ready,
+            polls: polls.clone(),
+        });
+        waker.join().unwrap();
+
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn block_on_returns_output() {
+        let scheduler = Scheduler::new();
+        assert_eq!(scheduler.block_on(add_twice(1)), 3);
+    }
+
+    #[test]
+    fn block_on_runs_other_tasks() {
+        let scheduler = Scheduler::new();
+        let result = Arc::new(Mutex::new(None));
+
+        let output = result.clone();
+        scheduler.spawn_future(async move {
+            *output.lock() = Some(add_twice(0).await);
+        });
+        scheduler.block_on(YieldNow(false));
+        scheduler.run().unwrap();
+
+        assert_eq!(*result.lock(), Some(2));
+    }
+}
@@ -0,0 +1,803 @@
+use crate::scheduler::panic_message;
+use crate::sync::{Condvar, Mutex};
+use crate::TaskId;
+use crate::{JoinError, JoinErrorKind, Scheduler, SchedulerGone, Task};
+use std::any::Any;
+use std::fmt;
+use std::future::{Future, IntoFuture};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
+
+/// A reference to a task that has been handed to a [`Scheduler`].
+///
+/// The handle does not keep the scheduler alive; once the scheduler is
+/// dropped, [`TaskHandle::cancel`] simply reports that nothing was removed.
+#[derive(Debug, Clone)]
+pub struct TaskHandle {
+    id: TaskId,
+    scheduler: Weak<Scheduler>,
+}
+
+impl TaskHandle {
+    pub(crate) fn new(id: TaskId, scheduler: Weak<Scheduler>) -> Self {
+        Self { id, scheduler }
+    }
+
+    /// The id of the task this handle refers to.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Removes the task from whichever queue it is waiting in.
+    ///
+    /// Returns `false` if the task has already started running (or finished),
+    /// in which case it is left alone.
+    pub fn cancel(&self) -> bool {
+        match self.scheduler.upgrade() {
+            Some(scheduler) => scheduler.cancel(self.id),
+            None => false,
+        }
+    }
+
+    /// Ties the task to the returned guard: once the guard is dropped, the
+    /// task is cancelled if it hasn't run yet.
+    ///
+    /// Handles themselves never cancel anything when dropped, so that
+    /// `scheduler.schedule(task);` keeps working as fire-and-forget. Use a
+    /// guard for work that belongs to some other object, such as a timeout
+    /// stored next to the request it guards.
+    pub fn cancel_on_drop(self) -> TaskGuard {
+        TaskGuard { handle: Some(self) }
+    }
+}
+
+/// Cancels its task when dropped, unless [`TaskGuard::detach`] was called;
+/// see [`TaskHandle::cancel_on_drop`].
+///
+/// It is safe to drop a guard anywhere, including inside a callback on the
+/// loop thread or from the destructor of another task's callback: the
+/// scheduler never drops callbacks while holding its own locks.
+#[derive(Debug)]
+#[must_use = "dropping a TaskGuard cancels its task right away"]
+pub struct TaskGuard {
+    handle: Option<TaskHandle>,
+}
+
+impl TaskGuard {
+    /// The id of the task this guard refers to.
+    pub fn id(&self) -> TaskId {
+        self.handle.as_ref().unwrap().id
+    }
+
+    /// Lets the task run even though the guard goes away, and hands back a
+    /// plain handle to it.
+    pub fn detach(mut self) -> TaskHandle {
+        self.handle.take().unwrap()
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.cancel();
+        }
+    }
+}
+
+/// The tasks handed to [`Scheduler::schedule_sequence`].
+#[derive(Debug, Clone)]
+pub struct SequenceHandle {
+    ids: Vec<TaskId>,
+    scheduler: Weak<Scheduler>,
+}
+
+impl SequenceHandle {
+    pub(crate) fn new(ids: Vec<TaskId>, scheduler: Weak<Scheduler>) -> Self {
+        Self { ids, scheduler }
+    }
+
+    /// The ids of the tasks, in the order they run.
+    pub fn ids(&self) -> &[TaskId] {
+        &self.ids
+    }
+
+    /// Cancels every task in the sequence that hasn't started yet and
+    /// returns how many there were. A task that is running is left to
+    /// finish, but nothing after it runs.
+    pub fn cancel(&self) -> usize {
+        match self.scheduler.upgrade() {
+            Some(scheduler) => scheduler.cancel_many(&self.ids),
+            None => 0,
+        }
+    }
+}
+
+/// A reference to a [`Scheduler`] that does not keep it alive, from
+/// [`Scheduler::handle`].
+///
+/// This is what a task that keeps rescheduling itself should capture.
+/// Capturing the `Arc<Scheduler>` instead means the queue owns a closure
+/// that owns the scheduler, so it can never be dropped while the task is
+/// pending.
+///
+/// ```
+/// use revent_loop::{Scheduler, SchedulerHandle, Task};
+/// use std::time::Duration;
+///
+/// fn poll(handle: SchedulerHandle) {
+///     println!("polling");
+///     let next = handle.clone();
+///     // Stops quietly once the scheduler is gone.
+///     let _ = handle.schedule(Task::new(move || poll(next), Some(Duration::from_secs(1))));
+/// }
+///
+/// let scheduler = Scheduler::new();
+/// poll(scheduler.handle());
+/// drop(scheduler);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SchedulerHandle {
+    scheduler: Weak<Scheduler>,
+}
+
+impl SchedulerHandle {
+    pub(crate) fn new(scheduler: Weak<Scheduler>) -> Self {
+        Self { scheduler }
+    }
+
+    /// Like [`Scheduler::schedule`], unless the scheduler has been dropped,
+    /// in which case `task` is dropped too.
+    pub fn schedule(&self, task: Task) -> Result<TaskHandle, SchedulerGone> {
+        Ok(self.upgrade()?.schedule(task))
+    }
+
+    /// The scheduler, if it is still alive.
+    pub fn upgrade(&self) -> Result<Arc<Scheduler>, SchedulerGone> {
+        self.scheduler.upgrade().ok_or(SchedulerGone)
+    }
+}
+
+/// Called with the task's result instead of storing it, from
+/// [`JoinHandle::then`].
+type Continuation<T> = Box<dyn FnOnce(Result<T, JoinError>) + Send>;
+
+enum Slot<T> {
+    Pending(Option<Continuation<T>>),
+    Done(T),
+    /// The task produced a value, but ran past its execution timeout.
+    TimedOut,
+    Panicked(Box<dyn Any + Send>),
+    /// The task was dropped without running, e.g. because it was cancelled.
+    Dropped,
+}
+
+struct JoinState<T> {
+    slot: Mutex<Slot<T>>,
+    finished: Condvar,
+    /// Left by a [`JoinFuture`] that found the task still pending.
+    waker: Mutex<Option<Waker>>,
+}
+
+/// Owned by the spawned task; fills in the result for the [`JoinHandle`].
+///
+/// If the task is dropped before it runs, the handle is told so instead of
+/// waiting forever.
+pub(crate) struct Completer<T> {
+    id: TaskId,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> Completer<T> {
+    pub(crate) fn complete(self, value: T) {
+        self.finish(Slot::Done(value));
+    }
+
+    pub(crate) fn time_out(self) {
+        self.finish(Slot::TimedOut);
+    }
+
+    /// Completes with what `f` returns. If `f` panics, the payload goes to
+    /// the handle, and the unwinding carries on with a copy of its message
+    /// so that the loop still reports the panic.
+    pub(crate) fn run(self, f: impl FnOnce() -> T) {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(value) => self.complete(value),
+            Err(payload) => self.panic(payload),
+        }
+    }
+
+    /// The panicking half of [`Completer::run`], for a panic caught
+    /// elsewhere.
+    pub(crate) fn panic(self, payload: Box<dyn Any + Send>) -> ! {
+        let message = panic_message(payload.as_ref()).to_owned();
+        self.finish(Slot::Panicked(payload));
+        panic::resume_unwind(Box::new(message))
+    }
+
+    fn finish(&self, result: Slot<T>) {
+        let mut slot = self.state.slot.lock();
+        let Slot::Pending(continuation) = &mut *slot else {
+            return;
+        };
+        match continuation.take() {
+            Some(continuation) => {
+                // Nobody is left to wait on the slot.
+                *slot = Slot::Dropped;
+                drop(slot);
+                continuation(result.into_result(self.id));
+            }
+            None => {
+                *slot = result;
+                drop(slot);
+                self.state.finished.notify_all();
+                if let Some(waker) = self.state.waker.lock().take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        self.finish(Slot::Dropped);
+    }
+}
+
+/// Waits for the value produced by a task created with
+/// [`Scheduler::spawn`] or [`Scheduler::spawn_blocking`].
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<JoinState<T>>,
+    scheduler: Weak<Scheduler>,
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("id", &self.id)
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn new(id: TaskId, scheduler: Weak<Scheduler>) -> (Self, Completer<T>) {
+        let state = Arc::new(JoinState {
+            slot: Mutex::new(Slot::Pending(None)),
+            finished: Condvar::new(),
+            waker: Mutex::new(None),
+        });
+        let completer = Completer {
+            id,
+            state: state.clone(),
+        };
+        let handle = Self {
+            id,
+            state,
+            scheduler,
+        };
+        (handle, completer)
+    }
+
+    /// The id of the spawned task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Whether the task has run (or been dropped), so that [`JoinHandle::join`]
+    /// would return without blocking.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.state.slot.lock(), Slot::Pending(_))
+    }
+
+    /// Blocks until the task has executed and returns its value.
+    ///
+    /// The task only makes progress while [`Scheduler::run`] is executing,
+    /// normally on another thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the task was cancelled or timed out, and if called from the
+    /// thread that is running the scheduler while the task is still
+    /// pending, since waiting there would block the loop that has to run
+    /// it. If the task panicked, its panic is resumed with the original
+    /// payload.
+    pub fn join(self) -> T {
+        self.try_join()
+            .unwrap_or_else(|error| match error.into_panic() {
+                Ok(payload) => panic::resume_unwind(payload),
+                Err(error) => panic!("{}", error),
+            })
+    }
+
+    /// Like [`JoinHandle::join`], but returns a [`JoinError`] instead of
+    /// panicking when the task didn't produce a value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from the thread that is running the scheduler while
+    /// the task is still pending.
+    pub fn try_join(self) -> Result<T, JoinError> {
+        if !self.is_finished() {
+            let on_loop = self
+                .scheduler
+                .upgrade()
+                .is_some_and(|scheduler| scheduler.is_loop_thread());
+            assert!(
+                !on_loop,
+                "JoinHandle::join called on the scheduler thread for a pending task"
+            );
+        }
+        let mut slot = self.state.slot.lock();
+        while let Slot::Pending(_) = *slot {
+            slot = self.state.finished.wait(slot);
+        }
+        std::mem::replace(&mut *slot, Slot::Dropped).into_result(self.id)
+    }
+
+    /// Schedules `f` on `scheduler` once the task has finished, handing it
+    /// the task's value, and returns a handle to what `f` returns.
+    ///
+    /// If the task is cancelled or panics, `f` still runs, with a
+    /// [`JoinError`], so a chain of continuations always resolves. The same
+    /// goes for a continuation that is cancelled itself, through the id of
+    /// the handle returned here. If the task has already finished, `f` is
+    /// scheduled right away.
+    ///
+    /// ```
+    /// use revent_loop::Scheduler;
+    ///
+    /// let scheduler = Scheduler::new();
+    /// let length = scheduler
+    ///     .spawn(|| "hello".to_owned())
+    ///     .then(&scheduler, |greeting| greeting.map_or(0, |greeting| greeting.len()));
+    /// scheduler.run().unwrap();
+    /// assert_eq!(length.join(), 5);
+    /// ```
+    pub fn then<U: Send + 'static>(
+        self,
+        scheduler: &Scheduler,
+        f: impl FnOnce(Result<T, JoinError>) -> U + Send + 'static,
+    ) -> JoinHandle<U>
+    where
+        T: Send + 'static,
+    {
+        let weak = scheduler.handle().scheduler;
+        let (handle, completer) = JoinHandle::new(TaskId::new(), weak.clone());
+        let id = handle.id;
+        let continuation = move |result| {
+            let mut task = Task::new(move || completer.run(|| f(result)), None);
+            task.id = id;
+            // Without a scheduler the task is dropped, which resolves the
+            // handle as dropped too.
+            if let Some(scheduler) = weak.upgrade() {
+                scheduler.schedule(task);
+            }
+        };
+
+        let mut slot = self.state.slot.lock();
+        match &mut *slot {
+            Slot::Pending(pending) => *pending = Some(Box::new(continuation)),
+            finished => {
+                let result = std::mem::replace(finished, Slot::Dropped).into_result(self.id);
+                drop(slot);
+                continuation(result);
+            }
+        }
+        handle
+    }
+}
+
+/// Awaits the task's value from async code, as a
+/// [`Result`] like [`JoinHandle::try_join`] returns.
+///
+/// Unlike `join()`, awaiting never blocks, so it works from any executor,
+/// the loop's own [`Scheduler::block_on`] included.
+impl<T> IntoFuture for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+    type IntoFuture = JoinFuture<T>;
+
+    fn into_future(self) -> JoinFuture<T> {
+        JoinFuture { handle: self }
+    }
+}
+
+/// The future behind `.await` on a [`JoinHandle`].
+#[must_use = "futures do nothing unless awaited"]
+pub struct JoinFuture<T> {
+    handle: JoinHandle<T>,
+}
+
+impl<T> fmt::Debug for JoinFuture<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("JoinFuture").field(&self.handle).finish()
+    }
+}
+
+impl<T> JoinFuture<T> {
+    /// The id of the spawned task.
+    pub fn id(&self) -> TaskId {
+        self.handle.id
+    }
+}
+
+impl<T> Future for JoinFuture<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let state = &self.handle.state;
+        let mut slot = state.slot.lock();
+        if let Slot::Pending(_) = *slot {
+            // Left under the slot's lock, which the task finishes under, so
+            // it either sees the waker or we see the result.
+            *state.waker.lock() = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(std::mem::replace(&mut *slot, Slot::Dropped).into_result(self.handle.id))
+    }
+}
+
+impl<T> Slot<T> {
+    fn into_result(self, id: TaskId) -> Result<T, JoinError> {
+        match self {
+            Slot::Done(value) => Ok(value),
+            Slot::TimedOut => Err(JoinError::new(id, JoinErrorKind::TimedOut)),
+            Slot::Panicked(payload) => Err(JoinError {
+                payload: Some(payload),
+                ..JoinError::new(id, JoinErrorKind::Panicked)
+            }),
+            Slot::Pending(_) | Slot::Dropped => Err(JoinError::new(id, JoinErrorKind::Cancelled)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{JoinHandle, SchedulerHandle};
+    use crate::sync::Mutex;
+    use crate::{JoinErrorKind, Scheduler, SchedulerGone, Task};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn reschedule_forever(handle: SchedulerHandle, ticks: Arc<AtomicUsize>) {
+        ticks.fetch_add(1, Ordering::SeqCst);
+        let next = handle.clone();
+        let _ = handle.schedule(Task::new(
+            move || reschedule_forever(next, ticks),
+            Some(Duration::from_secs(60)),
+        ));
+    }
+
+    #[test]
+    fn scheduler_handle_does_not_keep_the_scheduler_alive() {
+        let scheduler = Scheduler::new();
+        let handle = scheduler.handle();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        reschedule_forever(handle.clone(), ticks.clone());
+        assert_eq!(scheduler.sleeping_len(), 1);
+        assert_eq!(Arc::strong_count(&ticks), 2);
+
+        drop(scheduler);
+        // The pending task, and everything it captured, went with it.
+        assert_eq!(Arc::strong_count(&ticks), 1);
+        assert!(handle.upgrade().is_err());
+        let rejected = handle.schedule(Task::new(|| {}, None));
+        assert_eq!(rejected.unwrap_err(), SchedulerGone);
+    }
+
+    #[test]
+    fn dropping_a_guard_cancels_its_task() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        let guard = scheduler
+            .schedule(Task::new(
+                move || flag.store(true, Ordering::SeqCst),
+                Some(Duration::from_millis(20)),
+            ))
+            .cancel_on_drop();
+
+        drop(guard);
+        scheduler.run().unwrap();
+        assert!(!ran.load(Ordering::SeqCst));
+        assert_eq!(scheduler.metrics().cancelled, 1);
+    }
+
+    #[test]
+    fn a_detached_guard_lets_its_task_run() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        let guard = scheduler
+            .schedule(Task::new(
+                move || flag.store(true, Ordering::SeqCst),
+                Some(Duration::from_millis(5)),
+            ))
+            .cancel_on_drop();
+
+        let handle = guard.detach();
+        scheduler.run().unwrap();
+        assert!(ran.load(Ordering::SeqCst));
+        assert!(!handle.cancel());
+    }
+
+    #[test]
+    fn guards_dropped_by_a_cancelled_task_do_not_deadlock() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        // The timeout is owned by the request, which is owned by another task.
+        let timeout = scheduler
+            .schedule(Task::new(
+                move || flag.store(true, Ordering::SeqCst),
+                Some(Duration::from_millis(5)),
+            ))
+            .cancel_on_drop();
+        let request = scheduler.schedule(Task::new(
+            move || drop(timeout),
+            Some(Duration::from_millis(50)),
+        ));
+
+        let canceller = scheduler.clone();
+        scheduler.schedule(Task::new(
+            move || {
+                canceller.cancel_many(&[request.id()]);
+            },
+            None,
+        ));
+        scheduler.run().unwrap();
+        assert!(!ran.load(Ordering::SeqCst));
+        assert!(scheduler.is_idle());
+    }
+
+    #[test]
+    fn cancel_sleeping_task_before_deadline() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicBool::new(false));
+
+        let flag = ran.clone();
+        let handle = scheduler.schedule(Task::new(
+            move || flag.store(true, Ordering::SeqCst),
+            Some(Duration::from_millis(50)),
+        ));
+        assert!(handle.cancel());
+        scheduler.run().unwrap();
+
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn cancel_ready_task() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicBool::new(false));
+
+        let flag = ran.clone();
+        let handle =
+            scheduler.schedule(Task::new(move || flag.store(true, Ordering::SeqCst), None));
+        assert!(handle.cancel());
+        assert!(!handle.cancel());
+        scheduler.run().unwrap();
+
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn cancel_after_execution_returns_false() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicBool::new(false));
+
+        let flag = ran.clone();
+        let handle =
+            scheduler.schedule(Task::new(move || flag.store(true, Ordering::SeqCst), None));
+        scheduler.run().unwrap();
+
+        assert!(ran.load(Ordering::SeqCst));
+        assert!(!handle.cancel());
+    }
+
+    #[test]
+    fn join_value_from_scheduler_thread() {
+        let scheduler = Scheduler::new();
+        let handle = scheduler.spawn(|| (1..=10).sum::<u32>());
+        assert!(!handle.is_finished());
+
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+
+        assert_eq!(handle.join(), 55);
+        runner.join().unwrap();
+    }
+
+    #[test]
+    fn is_finished_after_run() {
+        let scheduler = Scheduler::new();
+        let handle = scheduler.spawn(|| "done");
+        scheduler.run().unwrap();
+
+        assert!(handle.is_finished());
+        assert_eq!(handle.join(), "done");
+    }
+
+    #[test]
+    fn join_reports_how_the_task_ended() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let on_panic = reported.clone();
+        let scheduler = Scheduler::builder()
+            .on_panic(move |_, payload| {
+                on_panic
+                    .lock()
+                    .push(crate::scheduler::panic_message(payload.as_ref()).to_owned())
+            })
+            .build();
+        let ok = scheduler.spawn(|| 1);
+        let panicked = scheduler.spawn(|| -> i32 { panic!("boom {}", 7) });
+        let resumed = scheduler.spawn(|| -> i32 { std::panic::panic_any(42u8) });
+        let built = Task::builder()
+            .callback(|| -> i32 { panic!("built") })
+            .spawn_on(&scheduler);
+        let cancelled = scheduler.spawn(|| 1);
+        assert!(scheduler.cancel(cancelled.id()));
+        scheduler.run().unwrap();
+
+        assert_eq!(ok.try_join().unwrap(), 1);
+        let error = panicked.try_join().unwrap_err();
+        assert_eq!(error.kind(), JoinErrorKind::Panicked);
+        assert_eq!(error.panic_message(), Some("boom 7"));
+        assert!(error.to_string().ends_with("panicked: boom 7"));
+        // The loop still hears about the panics, by message.
+        assert_eq!(*reported.lock(), ["boom 7", "Box<dyn Any>", "built"]);
+        assert_eq!(built.try_join().unwrap_err().panic_message(), Some("built"));
+        // join() carries on with the original payload.
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| resumed.join())).unwrap_err();
+        assert_eq!(payload.downcast_ref::<u8>(), Some(&42));
+        let error = cancelled.try_join().unwrap_err();
+        assert_eq!(error.kind(), JoinErrorKind::Cancelled);
+        assert!(error.into_panic().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "was cancelled")]
+    fn join_cancelled_task_panics() {
+        let scheduler = Scheduler::new();
+        let handle = scheduler.spawn(|| 1);
+        assert!(scheduler.cancel(handle.id()));
+        handle.join();
+    }
+
+    #[test]
+    fn continuations_chain_on_the_loop() {
+        let scheduler = Scheduler::new();
+        let result = scheduler
+            .spawn(|| 20)
+            .then(&scheduler, |n| n.unwrap() + 1)
+            .then(&scheduler, |n| n.unwrap() * 2)
+            .then(&scheduler, |n| format!("{}", n.unwrap()));
+        let report = scheduler.run().unwrap();
+
+        assert_eq!(result.join(), "42");
+        assert_eq!(report.tasks_executed, 4);
+    }
+
+    #[test]
+    fn cancelling_the_first_task_resolves_the_chain() {
+        let scheduler = Scheduler::new();
+        let first = scheduler.spawn(|| 1);
+        let first_id = first.id();
+        let chained = first
+            .then(&scheduler, |n| n.map(|n| n + 1))
+            // The first error arrives wrapped in the first continuation's
+            // value.
+            .then(&scheduler, |n| n.and_then(|n| n).map(|n| n * 2));
+        assert!(scheduler.cancel(first_id));
+        scheduler.run().unwrap();
+
+        let error = chained.join().unwrap_err();
+        assert_eq!(error.id(), first_id);
+    }
+
+    #[test]
+    fn awaiting_a_handle_resolves_to_its_result() {
+        let scheduler = Scheduler::new();
+        let blocking = scheduler.spawn_blocking(|| {
+            thread::sleep(Duration::from_millis(20));
+            7
+        });
+        let cancelled = scheduler.spawn(|| 1);
+        assert!(scheduler.cancel(cancelled.id()));
+
+        // Awaited on the loop thread, where `join()` would refuse to wait.
+        let (value, error) = scheduler.block_on(async { (blocking.await, cancelled.await) });
+        assert_eq!(value.unwrap(), 7);
+        assert_eq!(error.unwrap_err().kind(), JoinErrorKind::Cancelled);
+    }
+
+    #[test]
+    fn join_on_loop_thread_does_not_deadlock() {
+        let scheduler = Scheduler::new();
+        let pending = Arc::new(Mutex::new(None));
+        let refused = Arc::new(AtomicBool::new(false));
+
+        let handle = pending.clone();
+        let flag = refused.clone();
+        scheduler.schedule(Task::new(
+            move || {
+                let handle: JoinHandle<i32> = handle.lock().take().unwrap();
+                let result = panic::catch_unwind(AssertUnwindSafe(|| handle.join()));
+                flag.store(result.is_err(), Ordering::SeqCst);
+            },
+            None,
+        ));
+        *pending.lock() = Some(scheduler.spawn(|| 1));
+        scheduler.run().unwrap();
+
+        assert!(refused.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn blocking_work_does_not_delay_timers() {
+        let scheduler = Scheduler::new();
+        let started = Instant::now();
+        let timer_at = Arc::new(Mutex::new(None));
+
+        let blocking = scheduler.spawn_blocking(|| {
+            thread::sleep(Duration::from_millis(200));
+            7
+        });
+        let at = timer_at.clone();
+        scheduler.schedule(Task::new(
+            move || *at.lock() = Some(started.elapsed()),
+            Some(Duration::from_millis(50)),
+        ));
+        let report = scheduler.run().unwrap();
+
+        let timer_at = timer_at.lock().unwrap();
+        assert!(timer_at < Duration::from_millis(150), "{:?}", timer_at);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(report.tasks_executed, 2);
+        assert!(blocking.is_finished());
+        assert_eq!(blocking.join(), 7);
+    }
+
+    #[test]
+    fn blocking_panic_is_reported_through_the_loop() {
+        let panicked = Arc::new(Mutex::new(None));
+        let seen = panicked.clone();
+        let scheduler = Scheduler::builder()
+            .on_panic(move |id, _payload| *seen.lock() = Some(id))
+            .build();
+
+        let handle = scheduler.spawn_blocking(|| -> i32 { panic!("boom") });
+        let report = scheduler.run().unwrap();
+
+        assert_eq!(report.panics, 1);
+        assert_eq!(*panicked.lock(), Some(handle.id()));
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| handle.join())).is_err());
+    }
+
+    #[test]
+    fn blocking_threads_bounds_the_side_pool() {
+        let scheduler = Scheduler::builder().blocking_threads(1).build();
+        let started = Instant::now();
+        let handles: Vec<_> = (0..3)
+            .map(|i| {
+                scheduler.spawn_blocking(move || {
+                    thread::sleep(Duration::from_millis(30));
+                    i
+                })
+            })
+            .collect();
+        scheduler.run().unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        let results: Vec<i32> = handles.into_iter().map(JoinHandle::join).collect();
+        assert_eq!(results, [0, 1, 2]);
+    }
+}
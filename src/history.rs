@@ -0,0 +1,165 @@
+use crate::TaskId;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many bytes of a name that isn't `'static` a slot keeps.
+const INLINE_NAME: usize = 32;
+
+/// What an [`EventRecord`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// A task was queued.
+    Scheduled,
+    /// A task's callback was about to be called.
+    Started,
+    /// A task's callback returned, after `duration`.
+    Completed { duration: Duration },
+    /// A task was dropped before it could run (again).
+    Cancelled,
+    /// A task's callback panicked.
+    Panicked,
+    /// The loop slept for `duration`, waiting for a timer.
+    Slept { duration: Duration },
+}
+
+/// One of the things the scheduler did last, from
+/// [`Scheduler::recent_events`](crate::Scheduler::recent_events).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRecord {
+    /// When it happened, on the scheduler's clock.
+    pub at: Instant,
+    pub kind: EventKind,
+    /// The task it happened to; `None` for [`EventKind::Slept`].
+    pub task: Option<TaskId>,
+    /// The task's name. A name that isn't `'static` is cut to its first
+    /// 32 bytes.
+    pub name: Option<Cow<'static, str>>,
+}
+
+/// A task name as a slot keeps it, without allocating.
+#[derive(Clone, Copy)]
+enum SlotName {
+    None,
+    Static(&'static str),
+    Inline { len: u8, bytes: [u8; INLINE_NAME] },
+}
+
+impl SlotName {
+    fn new(name: Option<&Cow<'static, str>>) -> Self {
+        match name {
+            None => Self::None,
+            Some(Cow::Borrowed(name)) => Self::Static(name),
+            Some(Cow::Owned(name)) => {
+                let mut len = name.len().min(INLINE_NAME);
+                while !name.is_char_boundary(len) {
+                    len -= 1;
+                }
+                let mut bytes = [0; INLINE_NAME];
+                bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+                Self::Inline {
+                    len: len as u8,
+                    bytes,
+                }
+            }
+        }
+    }
+
+    fn to_cow(self) -> Option<Cow<'static, str>> {
+        match self {
+            Self::None => None,
+            Self::Static(name) => Some(Cow::Borrowed(name)),
+            Self::Inline { len, bytes } => {
+                let name = std::str::from_utf8(&bytes[..usize::from(len)]).unwrap_or_default();
+                Some(Cow::Owned(name.to_owned()))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    at: Instant,
+    kind: EventKind,
+    task: Option<TaskId>,
+    name: SlotName,
+}
+
+/// The last few lifecycle events, overwritten oldest first. All the room
+/// it needs is set aside up front.
+pub(crate) struct EventRing {
+    slots: VecDeque<Slot>,
+    capacity: usize,
+}
+
+impl EventRing {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            slots: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        at: Instant,
+        kind: EventKind,
+        task: Option<TaskId>,
+        name: Option<&Cow<'static, str>>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.slots.len() == self.capacity {
+            self.slots.pop_front();
+        }
+        self.slots.push_back(Slot {
+            at,
+            kind,
+            task,
+            name: SlotName::new(name),
+        });
+    }
+
+    /// Oldest first.
+    pub(crate) fn records(&self) -> Vec<EventRecord> {
+        self.slots
+            .iter()
+            .map(|slot| EventRecord {
+                at: slot.at,
+                kind: slot.kind,
+                task: slot.task,
+                name: slot.name.to_cow(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventKind, EventRing};
+    use std::borrow::Cow;
+    use std::time::Instant;
+
+    #[test]
+    fn keeps_the_newest_events_and_cuts_long_names() {
+        let mut ring = EventRing::new(2);
+        let at = Instant::now();
+        let long = Cow::Owned("é".repeat(20));
+        ring.record(
+            at,
+            EventKind::Scheduled,
+            None,
+            Some(&Cow::Borrowed("first")),
+        );
+        ring.record(at, EventKind::Started, None, Some(&long));
+        ring.record(at, EventKind::Cancelled, None, None);
+
+        let records = ring.records();
+        let kinds: Vec<EventKind> = records.iter().map(|record| record.kind).collect();
+        assert_eq!(kinds, [EventKind::Started, EventKind::Cancelled]);
+        // Two bytes a character, cut on a character boundary.
+        assert_eq!(records[0].name.as_deref(), Some(&*"é".repeat(16)));
+        assert_eq!(records[1].name, None);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::TaskId;
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+/// Which phase of the loop a task runs in; see [the crate
+/// docs](crate#loop-phases).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// A task that waited in the sleeping queue for its delay.
+    Timers,
+    /// A task scheduled without a delay.
+    #[default]
+    Immediate,
+    /// A callback from [`Scheduler::on_close`](crate::Scheduler::on_close).
+    Close,
+    /// A callback from [`Scheduler::next_tick`](crate::Scheduler::next_tick),
+    /// run as soon as the callback before it returned.
+    NextTick,
+    /// A task from [`Scheduler::schedule_idle`](crate::Scheduler::schedule_idle),
+    /// run when there is nothing else to do.
+    Idle,
+}
+
+/// What the loop does after [`SchedulerHooks::on_idle`] returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum IdleAction {
+    /// What it would have done without the hook: wait for the next timer,
+    /// or, with no timers pending, return ([`Scheduler::run`]) or wait for
+    /// new work ([`Scheduler::run_forever`]).
+    ///
+    /// [`Scheduler::run`]: crate::Scheduler::run
+    /// [`Scheduler::run_forever`]: crate::Scheduler::run_forever
+    #[default]
+    Sleep,
+    /// Return from the run method straight away, leaving pending timers
+    /// where they are.
+    Return,
+    /// Look at the queues again without waiting, for instance because the
+    /// hook just scheduled something.
+    Continue,
+}
+
+/// What lifecycle hooks are told about a task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskMeta {
+    pub id: TaskId,
+    pub name: Option<Cow<'static, str>>,
+    /// When the task is, or was, due to run.
+    pub deadline: Option<Instant>,
+    /// The phase the task runs, or ran, in.
+    pub phase: Phase,
+}
+
+/// Observes tasks as they move through a [`Scheduler`](crate::Scheduler).
+///
+/// Install with [`SchedulerBuilder::hooks`](crate::SchedulerBuilder::hooks).
+/// Every method defaults to doing nothing. Hooks are called with no queue
+/// locks held, so they may schedule or cancel tasks themselves.
+pub trait SchedulerHooks: Send + Sync {
+    /// A task was queued. Interval tasks report each re-run they queue.
+    fn on_schedule(&self, _task: &TaskMeta) {}
+
+    /// A task's callback is about to run on the loop thread.
+    fn on_start(&self, _task: &TaskMeta) {}
+
+    /// A task's callback returned, or panicked, after running for `elapsed`
+    /// of wall-clock time.
+    fn on_complete(&self, _task: &TaskMeta, _elapsed: Duration) {}
+
+    /// The loop has nothing to run right now and is about to wait for
+    /// `next_deadline`, the earliest pending timer, or to return if there
+    /// is none. This is where to pump another event source on the loop
+    /// thread. Called by [`Scheduler::run`] and its variants, but not by
+    /// [`Scheduler::tick`] or [`Scheduler::run_pool`].
+    ///
+    /// [`Scheduler::run`]: crate::Scheduler::run
+    /// [`Scheduler::tick`]: crate::Scheduler::tick
+    /// [`Scheduler::run_pool`]: crate::Scheduler::run_pool
+    fn on_idle(&self, _next_deadline: Option<Instant>) -> IdleAction {
+        IdleAction::Sleep
+    }
+}
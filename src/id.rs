@@ -0,0 +1,76 @@
+use std::fmt;
+#[cfg(not(feature = "uuid-ids"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Identifies a task, for [`Scheduler::cancel`] and the like.
+///
+/// By default an id is a number from a counter shared by the whole process,
+/// so no two tasks ever get the same one, whichever scheduler they end up
+/// on. With the `uuid-ids` feature it is a random v4 `uuid::Uuid`
+/// instead, unique across processes too.
+///
+/// [`Scheduler::cancel`]: crate::Scheduler::cancel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(Repr);
+
+#[cfg(not(feature = "uuid-ids"))]
+type Repr = u64;
+#[cfg(feature = "uuid-ids")]
+type Repr = uuid::Uuid;
+
+#[cfg(not(feature = "uuid-ids"))]
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+impl TaskId {
+    /// An id that no other task has, for [`TaskBuilder::id`].
+    ///
+    /// [`TaskBuilder::id`]: crate::TaskBuilder::id
+    pub fn new() -> Self {
+        #[cfg(not(feature = "uuid-ids"))]
+        return Self(NEXT.fetch_add(1, Ordering::Relaxed));
+        #[cfg(feature = "uuid-ids")]
+        return Self(uuid::Uuid::new_v4());
+    }
+
+    /// The number behind the id.
+    #[cfg(not(feature = "uuid-ids"))]
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    /// The uuid behind the id.
+    #[cfg(feature = "uuid-ids")]
+    pub fn as_uuid(&self) -> uuid::Uuid {
+        self.0
+    }
+
+    /// An id made up from `index`, which tests can tell apart without
+    /// minting one.
+    #[cfg(test)]
+    pub(crate) fn from_index(index: u64) -> Self {
+        #[cfg(not(feature = "uuid-ids"))]
+        return Self(index);
+        #[cfg(feature = "uuid-ids")]
+        return Self(uuid::Uuid::from_u128(index.into()));
+    }
+}
+
+impl Default for TaskId {
+    /// A fresh id, as from [`TaskId::new`].
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "uuid-ids")]
+impl From<uuid::Uuid> for TaskId {
+    fn from(uuid: uuid::Uuid) -> Self {
+        Self(uuid)
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
@@ -0,0 +1,430 @@
+//! Readiness of file descriptors, waited for by the loop alongside its
+//! timers; see [`Scheduler::register_readable`].
+//!
+//! Built on `poll(2)`: each wait polls every registered descriptor plus the
+//! read end of a pipe that [`WakeSignal`](crate::WakeSignal) writes to, so
+//! that `schedule()` still cuts the wait short.
+
+use crate::sync::Mutex;
+use crate::{Scheduler, Task};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+type Callback = Box<dyn FnMut() + Send>;
+
+/// The registered descriptors of one scheduler.
+pub(crate) struct Reactor {
+    registrations: Mutex<Registrations>,
+    /// The pipe that cuts a poll short: written to by
+    /// [`Reactor::interrupt`], drained by [`Reactor::poll`].
+    reader: OwnedFd,
+    writer: OwnedFd,
+    /// Set while the loop is blocked in `poll`, so that only then does a
+    /// wakeup cost a write.
+    polling: AtomicBool,
+}
+
+#[derive(Default)]
+struct Registrations {
+    next_token: u64,
+    by_token: HashMap<u64, Registration>,
+}
+
+struct Registration {
+    fd: RawFd,
+    events: libc::c_short,
+    /// Taken out while the callback runs.
+    callback: Option<Callback>,
+    /// Set from the event that queued the callback until it has run. The
+    /// descriptor is left out of polls meanwhile, so one that stays ready
+    /// queues one run at a time.
+    queued: bool,
+}
+
+impl Reactor {
+    pub(crate) fn new() -> io::Result<Self> {
+        let mut ends = [0; 2];
+        // Safety: `ends` has room for the two descriptors.
+        if unsafe { libc::pipe(ends.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: `pipe` just opened both, and nothing else owns them.
+        let (reader, writer) =
+            unsafe { (OwnedFd::from_raw_fd(ends[0]), OwnedFd::from_raw_fd(ends[1])) };
+        for end in [&reader, &writer] {
+            let fd = end.as_raw_fd();
+            // Safety: plain flag updates on a descriptor we own.
+            unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                if flags < 0
+                    || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0
+                    || libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(Self {
+            registrations: Mutex::default(),
+            reader,
+            writer,
+            polling: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        fd: RawFd,
+        events: libc::c_short,
+        callback: Callback,
+    ) -> IoHandle {
+        let mut registrations = self.registrations.lock();
+        let token = registrations.next_token;
+        registrations.next_token += 1;
+        registrations.by_token.insert(
+            token,
+            Registration {
+                fd,
+                events,
+                callback: Some(callback),
+                queued: false,
+            },
+        );
+        drop(registrations);
+        // A poll in progress doesn't know about the new descriptor yet.
+        self.interrupt();
+        IoHandle {
+            token,
+            reactor: Arc::downgrade(self),
+        }
+    }
+
+    fn deregister(&self, token: u64) -> bool {
+        let removed = self.registrations.lock().by_token.remove(&token);
+        let found = removed.is_some();
+        // Dropped outside the lock, since the callback may own anything.
+        drop(removed);
+        if found {
+            self.interrupt();
+        }
+        found
+    }
+
+    /// Whether any descriptor is registered, which keeps
+    /// [`Scheduler::run`] going.
+    pub(crate) fn has_registrations(&self) -> bool {
+        !self.registrations.lock().by_token.is_empty()
+    }
+
+    /// Ends a poll in progress. Called by [`WakeSignal`](crate::WakeSignal)
+    /// on every notification.
+    pub(crate) fn interrupt(&self) {
+        if self.polling.load(Ordering::SeqCst) {
+            // A full pipe already holds a wakeup, so a failed write loses
+            // nothing.
+            // Safety: writes one byte from a live buffer to a descriptor we
+            // own.
+            unsafe { libc::write(self.writer.as_raw_fd(), [1u8].as_ptr().cast(), 1) };
+        }
+    }
+
+    /// Waits for `timeout`, forever if `None`, or until a descriptor is
+    /// ready or `woken` (checked once polling has been announced) says a
+    /// notification came in. Queues a dispatch task on `scheduler` for each
+    /// ready descriptor; returns how many.
+    pub(crate) fn poll(
+        self: &Arc<Self>,
+        scheduler: &Scheduler,
+        timeout: Option<Duration>,
+        woken: impl FnOnce() -> bool,
+    ) -> usize {
+        let mut fds = vec![libc::pollfd {
+            fd: self.reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let mut tokens = Vec::new();
+        for (token, registration) in &self.registrations.lock().by_token {
+            if !registration.queued {
+                fds.push(libc::pollfd {
+                    fd: registration.fd,
+                    events: registration.events,
+                    revents: 0,
+                });
+                tokens.push(*token);
+            }
+        }
+
+        // Announced before checking for a notification, so that one landing
+        // in between writes to the pipe instead of going unseen.
+        self.polling.store(true, Ordering::SeqCst);
+        let timeout = match timeout {
+            _ if woken() => 0,
+            // Rounded up, since waking before the deadline would only mean
+            // polling again.
+            Some(timeout) => {
+                i32::try_from(timeout.as_nanos().div_ceil(1_000_000)).unwrap_or(i32::MAX)
+            }
+            None => -1,
+        };
+        // Safety: `fds` is a live array of `fds.len()` entries.
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        self.polling.store(false, Ordering::SeqCst);
+        // An interrupted or failed poll is just an early return; the loop
+        // looks at its queues and polls again.
+        if result <= 0 {
+            return 0;
+        }
+        if fds[0].revents != 0 {
+            let mut buf = [0u8; 64];
+            // Safety: reads into a live buffer of the given length.
+            while unsafe { libc::read(self.reader.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) }
+                > 0
+            {}
+        }
+
+        let mut registrations = self.registrations.lock();
+        let ready: Vec<u64> = tokens
+            .into_iter()
+            .zip(&fds[1..])
+            .filter(|(_, fd)| fd.revents != 0)
+            .filter_map(|(token, _)| {
+                // Deregistered during the poll, or queued by another one.
+                let registration = registrations.by_token.get_mut(&token)?;
+                (!registration.queued).then(|| {
+                    registration.queued = true;
+                    token
+                })
+            })
+            .collect();
+        drop(registrations);
+        for &token in &ready {
+            let dispatch = Dispatch {
+                reactor: self.clone(),
+                token,
+            };
+            scheduler.schedule(Task::new(move || dispatch.run(), None));
+        }
+        ready.len()
+    }
+}
+
+/// Queued for a ready descriptor: runs its callback, then lets the
+/// descriptor be polled again, as it does when dropped without running.
+struct Dispatch {
+    reactor: Arc<Reactor>,
+    token: u64,
+}
+
+impl Dispatch {
+    /// Runs the callback, unless the descriptor has been deregistered since
+    /// the event that queued it.
+    fn run(self) {
+        let callback = self
+            .reactor
+            .registrations
+            .lock()
+            .by_token
+            .get_mut(&self.token)
+            .and_then(|registration| registration.callback.take());
+        let Some(mut callback) = callback else {
+            return;
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(&mut callback));
+
+        let mut registrations = self.reactor.registrations.lock();
+        match registrations.by_token.get_mut(&self.token) {
+            Some(registration) if result.is_ok() => registration.callback = Some(callback),
+            // A callback that panicked is deregistered, so that it can't
+            // keep the loop alive without ever running again.
+            _ => {
+                registrations.by_token.remove(&self.token);
+                drop(registrations);
+                drop(callback);
+                if let Err(payload) = result {
+                    panic::resume_unwind(payload);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Dispatch {
+    fn drop(&mut self) {
+        let mut registrations = self.reactor.registrations.lock();
+        if let Some(registration) = registrations.by_token.get_mut(&self.token) {
+            registration.queued = false;
+        }
+    }
+}
+
+/// A descriptor registered with [`Scheduler::register_readable`] or
+/// [`Scheduler::register_writable`].
+///
+/// Dropping the handle leaves the descriptor registered; it stays that way
+/// until [`IoHandle::deregister`] is called or its callback panics.
+#[derive(Clone)]
+pub struct IoHandle {
+    token: u64,
+    reactor: Weak<Reactor>,
+}
+
+impl IoHandle {
+    /// Stops watching the descriptor and drops its callback. Returns
+    /// whether it was still registered.
+    ///
+    /// Once this returns, the callback is never called again, not even for
+    /// an event that was already queued. A run in progress on the loop
+    /// thread finishes first. Call this before closing the descriptor.
+    pub fn deregister(&self) -> bool {
+        self.reactor
+            .upgrade()
+            .is_some_and(|reactor| reactor.deregister(self.token))
+    }
+
+    /// Whether the descriptor is still registered.
+    pub fn is_registered(&self) -> bool {
+        self.reactor.upgrade().is_some_and(|reactor| {
+            reactor
+                .registrations
+                .lock()
+                .by_token
+                .contains_key(&self.token)
+        })
+    }
+}
+
+impl fmt::Debug for IoHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoHandle")
+            .field("token", &self.token)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sync::Mutex;
+    use crate::{IoHandle, Scheduler, Task};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn accepts_a_connection_while_a_timer_keeps_firing() {
+        let scheduler = Scheduler::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let start = Instant::now();
+        let ticks = Arc::new(Mutex::new(Vec::new()));
+        let tick = ticks.clone();
+        let timer = scheduler.schedule_interval(Duration::from_millis(10), move || {
+            tick.lock().push(start.elapsed())
+        });
+        let accepted = Arc::new(Mutex::new(None));
+        let handle = Arc::new(Mutex::new(None));
+        let (inner, got, own) = (scheduler.clone(), accepted.clone(), handle.clone());
+        let listening = listener.try_clone().unwrap();
+        let registered = scheduler
+            .register_readable(&listener, move || {
+                // Readable can be spurious; only a real connection counts.
+                if let Ok((mut stream, _)) = listening.accept() {
+                    let mut byte = [0];
+                    stream.set_nonblocking(false).unwrap();
+                    stream.read_exact(&mut byte).unwrap();
+                    *got.lock() = Some((byte[0], start.elapsed()));
+                    own.lock().take().map(|own: IoHandle| own.deregister());
+                    // Leave a few more ticks, then stop the timer too.
+                    let timer = timer.clone();
+                    inner.schedule(Task::new(
+                        move || {
+                            timer.cancel();
+                        },
+                        Some(Duration::from_millis(35)),
+                    ));
+                }
+            })
+            .unwrap();
+        *handle.lock() = Some(registered.clone());
+
+        let client = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(25));
+            TcpStream::connect(address)
+                .unwrap()
+                .write_all(&[7])
+                .unwrap();
+        });
+        let report = scheduler.run().unwrap();
+        client.join().unwrap();
+
+        let (byte, at) = accepted.lock().unwrap();
+        assert_eq!(byte, 7);
+        assert!(at >= Duration::from_millis(25), "{:?}", at);
+        assert!(!registered.is_registered());
+        // The timer kept firing before, during and after the wait for the
+        // connection, each time after its deadline and with no run missed.
+        let ticks = ticks.lock();
+        assert!(ticks.len() >= 5, "{:?}", ticks);
+        for (n, tick) in ticks.iter().enumerate() {
+            assert!(
+                *tick >= Duration::from_millis(10 * (n as u64 + 1)),
+                "{:?}",
+                ticks
+            );
+        }
+        assert!(ticks.iter().any(|tick| *tick < at) && ticks.iter().any(|tick| *tick > at));
+        assert_eq!(report.panics, 0);
+    }
+
+    #[test]
+    fn run_for_returns_on_time_while_waiting_for_io() {
+        let scheduler = Scheduler::new();
+        let (_writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        let handle = scheduler.register_readable(&reader, || {}).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(scheduler.run_for(Duration::from_millis(100)).unwrap(), 0);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(
+            elapsed < Duration::from_millis(500),
+            "overran: {:?}",
+            elapsed
+        );
+        handle.deregister();
+    }
+
+    #[test]
+    fn deregistering_drops_queued_events() {
+        let scheduler = Scheduler::new();
+        let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        writer.write_all(b"x").unwrap();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        let handle = scheduler
+            .register_readable(&reader, move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+
+        // Poll once so the event is queued, then deregister before it runs.
+        let reactor = scheduler.reactor().unwrap();
+        assert_eq!(reactor.poll(&scheduler, Some(Duration::ZERO), || false), 1);
+        assert_eq!(scheduler.ready_len(), 1);
+        assert!(handle.deregister());
+        assert!(!handle.deregister());
+        scheduler.run().unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+    }
+}
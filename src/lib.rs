@@ -0,0 +1,136 @@
+//! A small event loop built around two queues: tasks that are ready to run
+//! right away and tasks that are sleeping until their delay elapses.
+//!
+//! Tasks are scheduled onto a [`Scheduler`] and executed in order by
+//! [`Scheduler::run`], which returns once both queues have drained.
+//! Callbacks can keep the loop alive by scheduling further work:
+//!
+//! ```
+//! use revent_loop::Scheduler;
+//! use std::time::Duration;
+//!
+//! fn countdown(n: usize, scheduler: &Scheduler) {
+//!     if n > 0 {
+//!         println!("Down={}", n);
+//!         scheduler.schedule_with(
+//!             move |scheduler| countdown(n - 1, scheduler),
+//!             Some(Duration::from_millis(20)),
+//!         );
+//!     }
+//! }
+//!
+//! fn countup(n: usize, scheduler: &Scheduler) {
+//!     if n > 0 {
+//!         println!("Up={}", n);
+//!         scheduler.schedule_with(move |scheduler| countup(n - 1, scheduler), None);
+//!     }
+//! }
+//!
+//! let scheduler = Scheduler::new();
+//! scheduler.schedule_with(|scheduler| countdown(3, scheduler), None);
+//! scheduler.schedule_with(|scheduler| countup(3, scheduler), None);
+//!
+//! scheduler.run().unwrap();
+//! ```
+//!
+//! A callback that outlives a single run, such as a task that keeps
+//! rescheduling itself, should hold a [`SchedulerHandle`] rather than an
+//! `Arc<Scheduler>`, so that it doesn't keep the scheduler alive.
+//!
+//! # Loop phases
+//!
+//! Every ready task belongs to a [`Phase`], and among ready tasks of the
+//! same [`Priority`] the loop runs them phase by phase:
+//!
+//! 1. **Timers** and **Immediate**, together: tasks whose delay has run out
+//!    and tasks scheduled without one, in the order they became ready. An
+//!    immediate is ready from the moment it is scheduled, a timer from its
+//!    deadline, however late the loop notices; equal times go to whichever
+//!    was scheduled first.
+//! 2. **Close**: callbacks from [`Scheduler::on_close`] for tasks that were
+//!    cancelled.
+//!
+//! So a timer that falls due while older immediates are waiting runs after
+//! them, and never jumps the queue. A delay of
+//! [`Duration::ZERO`](std::time::Duration::ZERO) is no delay at all: the
+//! task is an immediate, queued in line with the others. Any longer delay,
+//! however short, makes the task a timer.
+//!
+//! Due timers are promoted between callbacks. Callbacks from
+//! [`Scheduler::next_tick`] sit outside the phases: they run as soon as the
+//! callback that queued them returns. A higher priority still wins over
+//! all of the above, and [`SchedulerPolicy::EarliestDeadlineFirst`] orders
+//! by deadline alone.
+
+mod blocking;
+mod builder;
+mod clock;
+mod cooperative;
+mod cron;
+mod debounce;
+mod deps;
+mod error;
+mod events;
+mod executor;
+mod handle;
+mod history;
+mod hooks;
+mod id;
+#[cfg(all(feature = "io", unix))]
+mod io;
+mod local;
+mod metrics;
+mod queue;
+mod random;
+mod rate;
+mod retry;
+mod runner;
+mod scheduler;
+mod scope;
+#[cfg(all(feature = "signals", unix))]
+mod signals;
+mod sleep;
+mod small;
+mod snapshot;
+mod source;
+mod status;
+mod sync;
+mod task;
+mod timers;
+mod wake;
+mod watchdog;
+mod wheel;
+
+pub use builder::SchedulerBuilder;
+#[cfg(any(test, feature = "test-util"))]
+pub use clock::MockClock;
+pub use clock::{Clock, SystemClock, VirtualClock};
+pub use cooperative::{AsyncTimer, RunAsync};
+pub use cron::CronZone;
+pub use debounce::{Debounced, Throttled};
+pub use deps::DependencyPolicy;
+pub use error::{
+    CronParseError, JoinError, JoinErrorKind, OverflowPolicy, RunError, ScheduleError,
+    SchedulerGone,
+};
+pub use events::SchedulerEvent;
+pub use handle::{JoinFuture, JoinHandle, SchedulerHandle, SequenceHandle, TaskGuard, TaskHandle};
+pub use history::{EventKind, EventRecord};
+pub use hooks::{IdleAction, Phase, SchedulerHooks, TaskMeta};
+pub use id::TaskId;
+#[cfg(all(feature = "io", unix))]
+pub use io::IoHandle;
+pub use local::{LocalScheduler, LocalTask};
+pub use metrics::Metrics;
+pub use queue::{SchedulerPolicy, ShrinkPolicy};
+pub use retry::RetryPolicy;
+pub use runner::RunnerHandle;
+pub use scheduler::{PanicPolicy, RunReport, Scheduler, TickResult};
+pub use scope::Scope;
+pub use sleep::Sleep;
+pub use snapshot::{ScheduleSnapshot, TaskSnapshot};
+pub use source::SourceHandle;
+pub use status::TaskState;
+pub use task::{CatchUp, IntervalMode, Priority, QueuedIn, Task, TaskBuilder, TaskInfo};
+pub use timers::TimerBackend;
+pub use wake::{ParkTimeout, SleepStrategy, SpinThenPark, StdSleep, WakeSignal};
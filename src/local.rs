@@ -0,0 +1,270 @@
+use crate::scheduler::panic_message;
+use crate::timers::{Keyed, TimerQueue};
+use crate::TaskId;
+use crate::{Clock, RunReport};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A unit of work for the [`LocalScheduler`]: like a
+/// [`Task`](crate::Task), but the callback does not have to be [`Send`].
+pub struct LocalTask {
+    id: TaskId,
+    callback: Box<dyn FnOnce()>,
+    expires: Option<Duration>,
+}
+
+impl Keyed for LocalTask {
+    fn key(&self) -> TaskId {
+        self.id
+    }
+}
+
+impl fmt::Debug for LocalTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalTask").field("id", &self.id).finish()
+    }
+}
+
+impl LocalTask {
+    /// Creates a task that runs `callback` once `expires` has elapsed, or
+    /// as soon as possible if `expires` is `None`.
+    pub fn new(callback: impl FnOnce() + 'static, expires: Option<Duration>) -> Self {
+        Self {
+            id: TaskId::new(),
+            callback: Box::new(callback),
+            expires,
+        }
+    }
+
+    /// The id this task was created with.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+}
+
+/// A loop for callbacks that never leave the thread they were created on,
+/// such as closures holding an [`Rc`] or a [`RefCell`].
+///
+/// It has the same ready and sleeping queues, timer ordering and
+/// [`RunReport`] as [`Scheduler`](crate::Scheduler), minus everything that
+/// involves other threads. Being `!Send`, it is handed out behind an
+/// [`Rc`] rather than an `Arc`.
+///
+/// ```
+/// use revent_loop::{LocalScheduler, LocalTask};
+/// use std::cell::Cell;
+/// use std::rc::Rc;
+///
+/// let scheduler = LocalScheduler::new();
+/// let count = Rc::new(Cell::new(0));
+///
+/// let counter = count.clone();
+/// scheduler.schedule(LocalTask::new(move || counter.set(counter.get() + 1), None));
+/// scheduler.run();
+///
+/// assert_eq!(count.get(), 1);
+/// ```
+pub struct LocalScheduler {
+    ready: RefCell<VecDeque<LocalTask>>,
+    sleeping: RefCell<TimerQueue<LocalTask>>,
+    next_seq: Cell<u64>,
+    clock: Option<Box<dyn Clock>>,
+}
+
+impl LocalScheduler {
+    /// Creates an empty scheduler on the system clock.
+    pub fn new() -> Rc<Self> {
+        Self::with_optional_clock(None)
+    }
+
+    /// Creates a scheduler that takes its time from `clock`.
+    pub fn with_clock(clock: impl Clock + 'static) -> Rc<Self> {
+        Self::with_optional_clock(Some(Box::new(clock)))
+    }
+
+    fn with_optional_clock(clock: Option<Box<dyn Clock>>) -> Rc<Self> {
+        Rc::new(Self {
+            ready: RefCell::default(),
+            sleeping: RefCell::default(),
+            next_seq: Cell::new(0),
+            clock,
+        })
+    }
+
+    /// The current time according to the scheduler's [`Clock`].
+    pub fn now(&self) -> Instant {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Queues `task` and returns its id. Callable from inside a running
+    /// callback.
+    pub fn schedule(&self, task: LocalTask) -> TaskId {
+        let id = task.id;
+        match task.expires {
+            None => self.ready.borrow_mut().push_back(task),
+            Some(expires) => {
+                let seq = self.next_seq.get();
+                self.next_seq.set(seq + 1);
+                let deadline = self.now() + expires;
+                self.sleeping.borrow_mut().push(deadline, seq, task);
+            }
+        }
+        id
+    }
+
+    /// Drops the pending task with the given id. Returns `false` if no such
+    /// task is waiting.
+    pub fn cancel(&self, id: TaskId) -> bool {
+        let mut ready = self.ready.borrow_mut();
+        if let Some(index) = ready.iter().position(|task| task.id == id) {
+            let task = ready.remove(index);
+            drop(ready);
+            // Dropped outside the borrow, since callbacks may own anything.
+            drop(task);
+            return true;
+        }
+        drop(ready);
+        let task = self.sleeping.borrow_mut().remove(id);
+        task.is_some()
+    }
+
+    /// Whether both queues are empty.
+    pub fn is_idle(&self) -> bool {
+        self.ready.borrow().is_empty() && self.sleeping.borrow().is_empty()
+    }
+
+    /// Executes tasks until both queues are empty and reports what ran.
+    ///
+    /// A callback that panics is reported on stderr and counted in
+    /// [`RunReport::panics`]; the loop carries on with the next task.
+    pub fn run(&self) -> RunReport {
+        let started = self.now();
+        let mut report = RunReport::default();
+        loop {
+            let due = self.sleeping.borrow_mut().pop_due(self.now());
+            report.timers_fired += due.len();
+            self.ready.borrow_mut().extend(due);
+
+            let next = self.ready.borrow_mut().pop_front();
+            if let Some(task) = next {
+                report.tasks_executed += 1;
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(task.callback)) {
+                    report.panics += 1;
+                    eprintln!(
+                        "revent_loop: task {} panicked: {}",
+                        task.id,
+                        panic_message(payload.as_ref())
+                    );
+                }
+                continue;
+            }
+
+            let next_deadline = self.sleeping.borrow().next_deadline();
+            let Some(deadline) = next_deadline else {
+                break;
+            };
+            let sleep_started = self.now();
+            let remaining = deadline.saturating_duration_since(sleep_started);
+            match &self.clock {
+                Some(clock) => clock.sleep(remaining),
+                None => thread::sleep(remaining),
+            }
+            report.time_sleeping += self.now().saturating_duration_since(sleep_started);
+        }
+        report.total_runtime = self.now().saturating_duration_since(started);
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::VirtualClock;
+
+    #[test]
+    fn runs_closures_that_capture_an_rc() {
+        let scheduler = LocalScheduler::new();
+        let count = Rc::new(Cell::new(0));
+        for _ in 0..3 {
+            let count = count.clone();
+            scheduler.schedule(LocalTask::new(move || count.set(count.get() + 1), None));
+        }
+
+        let report = scheduler.run();
+        assert_eq!(count.get(), 3);
+        assert_eq!(report.tasks_executed, 3);
+        assert!(scheduler.is_idle());
+    }
+
+    #[test]
+    fn timers_fire_in_deadline_order() {
+        let clock = VirtualClock::new();
+        let scheduler = LocalScheduler::with_clock(clock.clone());
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let start = clock.now();
+        for (label, ms) in [(3, 30), (1, 10), (2, 20), (0, 0)] {
+            let (order, clock) = (order.clone(), clock.clone());
+            let delay = (ms > 0).then(|| Duration::from_millis(ms));
+            scheduler.schedule(LocalTask::new(
+                move || order.borrow_mut().push((label, clock.now())),
+                delay,
+            ));
+        }
+
+        let report = scheduler.run();
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(
+            *order.borrow(),
+            [(0, at(0)), (1, at(10)), (2, at(20)), (3, at(30))]
+        );
+        assert_eq!(report.timers_fired, 3);
+        assert_eq!(report.time_sleeping, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn callbacks_can_schedule_and_cancel() {
+        let scheduler = LocalScheduler::new();
+        let value = Rc::new(Cell::new(0));
+        let doomed = {
+            let value = value.clone();
+            scheduler.schedule(LocalTask::new(
+                move || value.set(-1),
+                Some(Duration::from_millis(5)),
+            ))
+        };
+        let parent = {
+            let (scheduler, value) = (scheduler.clone(), value.clone());
+            move || {
+                assert!(scheduler.cancel(doomed));
+                let value = value.clone();
+                scheduler.schedule(LocalTask::new(move || value.set(value.get() + 10), None));
+            }
+        };
+        scheduler.schedule(LocalTask::new(parent, None));
+
+        assert_eq!(scheduler.run().tasks_executed, 2);
+        assert_eq!(value.get(), 10);
+        assert!(!scheduler.cancel(doomed));
+    }
+
+    #[test]
+    fn a_panicking_callback_does_not_stop_the_loop() {
+        let scheduler = LocalScheduler::new();
+        let ran = Rc::new(Cell::new(false));
+        scheduler.schedule(LocalTask::new(|| panic!("boom"), None));
+        let flag = ran.clone();
+        scheduler.schedule(LocalTask::new(move || flag.set(true), None));
+
+        let report = scheduler.run();
+        assert_eq!(report.panics, 1);
+        assert!(ran.get());
+    }
+}
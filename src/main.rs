@@ -1,5 +0,0 @@
-mod scheduler;
-
-fn main() {
-    println!("Hello, world!");
-}
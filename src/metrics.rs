@@ -0,0 +1,234 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// A point-in-time view of a scheduler's counters, from
+/// [`Scheduler::metrics`](crate::Scheduler::metrics).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Metrics {
+    /// Tasks waiting in the ready queue.
+    pub ready_len: usize,
+    /// Timers waiting in the sleeping queue.
+    pub sleeping_len: usize,
+    /// Tasks queued so far, counting every re-run of an interval.
+    pub scheduled: u64,
+    /// Callbacks run so far, including ones that panicked.
+    pub executed: u64,
+    /// Timers moved from the sleeping to the ready queue.
+    pub timers_fired: u64,
+    /// Callbacks that panicked.
+    pub panics: u64,
+    /// Callbacks that ran past their
+    /// [`TaskBuilder::exec_timeout`](crate::TaskBuilder::exec_timeout).
+    pub timed_out: u64,
+    /// Callbacks from
+    /// [`Scheduler::schedule_fallible`](crate::Scheduler::schedule_fallible)
+    /// that returned an error.
+    pub failed: u64,
+    /// Tasks dropped before they could run (again): successful
+    /// cancellations, as reported by [`Scheduler::cancel`] and
+    /// [`Scheduler::cancel_many`], plus everything [`Scheduler::clear`]
+    /// drops.
+    ///
+    /// [`Scheduler::cancel`]: crate::Scheduler::cancel
+    /// [`Scheduler::cancel_many`]: crate::Scheduler::cancel_many
+    /// [`Scheduler::clear`]: crate::Scheduler::clear
+    pub cancelled: u64,
+    /// The longest the ready queue has been.
+    pub max_ready_len: usize,
+    /// Time executed tasks spent in the ready queue between becoming due
+    /// and starting to run, summed over all of them.
+    pub total_wait: Duration,
+    /// Timers that have started running, each counted in the lateness
+    /// figures below.
+    pub timers_run: u64,
+    /// The least time a timer started running after its deadline. Zero
+    /// until a timer has run.
+    pub min_lateness: Duration,
+    /// How long after their deadlines timers started running, summed over
+    /// every timer counted in `timers_run`.
+    pub total_lateness: Duration,
+    /// The most time a timer started running after its deadline. Zero
+    /// until a timer has run.
+    pub max_lateness: Duration,
+    /// Tasks a [`Scheduler::run_pool`] worker took from another worker's
+    /// local queue.
+    ///
+    /// [`Scheduler::run_pool`]: crate::Scheduler::run_pool
+    pub steals: u64,
+    /// Times a ready task was held back by
+    /// [`SchedulerBuilder::rate_limit`].
+    ///
+    /// [`SchedulerBuilder::rate_limit`]: crate::SchedulerBuilder::rate_limit
+    pub throttled: u64,
+    /// Batches of ready tasks cut short by
+    /// [`SchedulerBuilder::max_batch_duration`].
+    ///
+    /// [`SchedulerBuilder::max_batch_duration`]: crate::SchedulerBuilder::max_batch_duration
+    pub batches_cut: u64,
+    /// Events that a [`Scheduler::subscribe`] receiver had no room for.
+    ///
+    /// [`Scheduler::subscribe`]: crate::Scheduler::subscribe
+    pub events_dropped: u64,
+}
+
+impl Metrics {
+    /// The mean of [`Metrics::total_wait`] over the executed tasks.
+    pub fn average_wait(&self) -> Duration {
+        mean(self.total_wait, self.executed)
+    }
+
+    /// The mean of [`Metrics::total_lateness`] over the timers run.
+    pub fn average_lateness(&self) -> Duration {
+        mean(self.total_lateness, self.timers_run)
+    }
+}
+
+fn mean(total: Duration, count: u64) -> Duration {
+    match u32::try_from(count) {
+        Ok(0) => Duration::ZERO,
+        Ok(count) => total / count,
+        Err(_) => Duration::from_nanos((total.as_nanos() / u128::from(count)) as u64),
+    }
+}
+
+/// The live counters behind [`Metrics`].
+///
+/// They are updated next to the queue operations they describe, but are
+/// plain atomics, so taking a snapshot never touches the queue locks.
+#[derive(Default)]
+pub(crate) struct Counters {
+    ready_len: AtomicUsize,
+    sleeping_len: AtomicUsize,
+    scheduled: AtomicU64,
+    executed: AtomicU64,
+    timers_fired: AtomicU64,
+    panics: AtomicU64,
+    timed_out: AtomicU64,
+    failed: AtomicU64,
+    cancelled: AtomicU64,
+    max_ready_len: AtomicUsize,
+    total_wait_nanos: AtomicU64,
+    timers_run: AtomicU64,
+    /// `u64::MAX` minus the least lateness in nanoseconds, so that the
+    /// default of zero means none has been recorded.
+    min_lateness_complement: AtomicU64,
+    total_lateness_nanos: AtomicU64,
+    max_lateness_nanos: AtomicU64,
+    steals: AtomicU64,
+    throttled: AtomicU64,
+    batches_cut: AtomicU64,
+    events_dropped: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn scheduled(&self, count: usize) {
+        self.scheduled.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cancelled(&self, count: usize) {
+        self.cancelled.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// A task left the ready queue to run after waiting for `waited`.
+    pub(crate) fn executed(&self, waited: Duration) {
+        self.executed.fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+        self.total_wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// A timer started running `lateness` after its deadline.
+    pub(crate) fn timer_run(&self, lateness: Duration) {
+        let nanos = u64::try_from(lateness.as_nanos()).unwrap_or(u64::MAX);
+        self.timers_run.fetch_add(1, Ordering::Relaxed);
+        self.min_lateness_complement
+            .fetch_max(u64::MAX - nanos, Ordering::Relaxed);
+        self.total_lateness_nanos
+            .fetch_add(nanos, Ordering::Relaxed);
+        self.max_lateness_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn timers_fired(&self, count: usize) {
+        self.timers_fired.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn panicked(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn timed_out(&self) {
+        self.timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stolen(&self) {
+        self.steals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn batch_cut(&self) {
+        self.batches_cut.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn events_dropped(&self, count: usize) {
+        self.events_dropped
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn ready_added(&self, count: usize) {
+        let len = self.ready_len.fetch_add(count, Ordering::Relaxed) + count;
+        self.max_ready_len.fetch_max(len, Ordering::Relaxed);
+    }
+
+    pub(crate) fn ready_removed(&self, count: usize) {
+        self.ready_len.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sleeping_added(&self, count: usize) {
+        self.sleeping_len.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sleeping_removed(&self, count: usize) {
+        self.sleeping_len.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    /// Tasks in either queue.
+    pub(crate) fn pending(&self) -> usize {
+        self.ready_len.load(Ordering::Relaxed) + self.sleeping_len.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn snapshot(&self) -> Metrics {
+        let timers_run = self.timers_run.load(Ordering::Relaxed);
+        Metrics {
+            ready_len: self.ready_len.load(Ordering::Relaxed),
+            sleeping_len: self.sleeping_len.load(Ordering::Relaxed),
+            scheduled: self.scheduled.load(Ordering::Relaxed),
+            executed: self.executed.load(Ordering::Relaxed),
+            timers_fired: self.timers_fired.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+            max_ready_len: self.max_ready_len.load(Ordering::Relaxed),
+            total_wait: Duration::from_nanos(self.total_wait_nanos.load(Ordering::Relaxed)),
+            timers_run,
+            min_lateness: match timers_run {
+                0 => Duration::ZERO,
+                _ => Duration::from_nanos(
+                    u64::MAX - self.min_lateness_complement.load(Ordering::Relaxed),
+                ),
+            },
+            total_lateness: Duration::from_nanos(self.total_lateness_nanos.load(Ordering::Relaxed)),
+            max_lateness: Duration::from_nanos(self.max_lateness_nanos.load(Ordering::Relaxed)),
+            steals: self.steals.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            batches_cut: self.batches_cut.load(Ordering::Relaxed),
+            events_dropped: self.events_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
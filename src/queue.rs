@@ -0,0 +1,429 @@
+use crate::TaskId;
+use crate::{Phase, Priority, Task};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The order in which a [`Scheduler`](crate::Scheduler) picks ready tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SchedulerPolicy {
+    /// Highest [`Priority`] first, then by [`Phase`](crate::Phase), then in
+    /// the order tasks became ready.
+    #[default]
+    Fifo,
+    /// Earliest deadline first. A delayed task's deadline is the instant its
+    /// delay runs out; tasks scheduled without a delay are due the moment
+    /// they are queued. Ties go to the higher priority, then to whichever
+    /// was queued first; the [`Phase`](crate::Phase) plays no part.
+    EarliestDeadlineFirst,
+}
+
+/// Whether a [`Scheduler`](crate::Scheduler) gives back queue memory after
+/// a burst; see [`SchedulerBuilder::shrink_policy`].
+///
+/// [`SchedulerBuilder::shrink_policy`]: crate::SchedulerBuilder::shrink_policy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ShrinkPolicy {
+    /// Queues keep whatever room they have grown to.
+    #[default]
+    Never,
+    /// Whenever the loop runs out of ready tasks, queues holding more than
+    /// twice the room they need are shrunk to fit what they hold, but never
+    /// below `keep`. The slack keeps an oscillating load from shrinking and
+    /// regrowing them over and over.
+    WhenIdle { keep: usize },
+}
+
+/// The ready queue, ordered according to a [`SchedulerPolicy`].
+pub(crate) struct ReadyQueue {
+    order: Order,
+    /// How long a task may sit in the queue before it is run ahead of
+    /// higher priorities. `None` disables aging.
+    starvation_threshold: Option<Duration>,
+}
+
+enum Order {
+    /// Two lanes per [`Priority`], drained in [`lane`] order. Each is kept
+    /// in [`ready_order`].
+    Fifo(Box<[VecDeque<Task>; 6]>),
+    EarliestDeadlineFirst {
+        heap: BinaryHeap<Reverse<DeadlineTask>>,
+        /// Handed out upwards by `push_back` and downwards by `push_front`,
+        /// so equal keys still keep their queue order.
+        next_back: i64,
+        next_front: i64,
+    },
+}
+
+/// A ready task in the EDF heap.
+pub(crate) struct DeadlineTask {
+    seq: i64,
+    task: Task,
+}
+
+impl DeadlineTask {
+    fn key(&self) -> (Option<Instant>, usize, i64) {
+        (self.task.deadline(), rank(self.task.priority()), self.seq)
+    }
+}
+
+impl PartialEq for DeadlineTask {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for DeadlineTask {}
+
+impl PartialOrd for DeadlineTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DeadlineTask {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Lower ranks run first.
+fn rank(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+/// Lower lanes run first: by priority, then expired timers and immediates
+/// together, in the order they became ready, then close callbacks.
+fn lane(task: &Task) -> usize {
+    let phase = match task.phase {
+        // Microtasks and idle tasks never reach the ready queue.
+        Phase::Timers | Phase::Immediate | Phase::NextTick | Phase::Idle => 0,
+        Phase::Close => 1,
+    };
+    rank(task.priority()) * 2 + phase
+}
+
+/// Sorts a lane by when its tasks became ready, which is a timer's deadline
+/// rather than when the loop noticed it had passed, then by scheduling
+/// order.
+fn ready_order(task: &Task) -> (Option<Instant>, u64) {
+    (task.deadline(), task.seq)
+}
+
+/// The [`lane`] of normal-priority timers and immediates.
+const NORMAL_LANES: std::ops::Range<usize> = 2..3;
+
+impl ReadyQueue {
+    pub(crate) fn new(policy: SchedulerPolicy, starvation_threshold: Option<Duration>) -> Self {
+        let order = match policy {
+            SchedulerPolicy::Fifo => Order::Fifo(Default::default()),
+            SchedulerPolicy::EarliestDeadlineFirst => Order::EarliestDeadlineFirst {
+                heap: BinaryHeap::new(),
+                next_back: 0,
+                next_front: -1,
+            },
+        };
+        Self {
+            order,
+            starvation_threshold,
+        }
+    }
+
+    pub(crate) fn push_back(&mut self, task: Task) {
+        match &mut self.order {
+            Order::Fifo(lanes) => {
+                let lane = &mut lanes[lane(&task)];
+                let key = ready_order(&task);
+                // Only a timer that fell due before the newest tasks were
+                // queued goes anywhere but the back.
+                match lane.back() {
+                    Some(last) if ready_order(last) > key => {
+                        let index = lane.partition_point(|queued| ready_order(queued) <= key);
+                        lane.insert(index, task);
+                    }
+                    _ => lane.push_back(task),
+                }
+            }
+            Order::EarliestDeadlineFirst {
+                heap, next_back, ..
+            } => {
+                heap.push(Reverse(DeadlineTask {
+                    seq: *next_back,
+                    task,
+                }));
+                *next_back += 1;
+            }
+        }
+    }
+
+    /// Puts `task` back ahead of everything else with the same ordering key.
+    pub(crate) fn push_front(&mut self, task: Task) {
+        match &mut self.order {
+            Order::Fifo(lanes) => lanes[lane(&task)].push_front(task),
+            Order::EarliestDeadlineFirst {
+                heap, next_front, ..
+            } => {
+                heap.push(Reverse(DeadlineTask {
+                    seq: *next_front,
+                    task,
+                }));
+                *next_front -= 1;
+            }
+        }
+    }
+
+    /// Takes the next task to run at `now`.
+    ///
+    /// With a starvation threshold, a task that became ready more than the
+    /// threshold ago is taken ahead of higher priorities, oldest first. The
+    /// task's own priority is left untouched. EDF needs no aging: a task's
+    /// deadline never moves, so waiting tasks reach the front on their own.
+    pub(crate) fn pop_front(&mut self, now: Instant) -> Option<Task> {
+        let starvation_threshold = self.starvation_threshold;
+        match &mut self.order {
+            Order::Fifo(lanes) => {
+                let starved = starvation_threshold
+                    .and_then(|threshold| now.checked_sub(threshold))
+                    .and_then(|cutoff| {
+                        // Lanes are FIFO, so each head is the longest waiting
+                        // task at that priority and phase.
+                        (0..lanes.len())
+                            .filter_map(|lane| Some((lanes[lane].front()?.deadline()?, lane)))
+                            .filter(|(ready_since, _)| *ready_since <= cutoff)
+                            .min()
+                    });
+                match starved {
+                    Some((_, lane)) => lanes[lane].pop_front(),
+                    None => lanes.iter_mut().find_map(VecDeque::pop_front),
+                }
+            }
+            Order::EarliestDeadlineFirst { heap, .. } => {
+                heap.pop().map(|Reverse(ready)| ready.task)
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.order {
+            Order::Fifo(lanes) => lanes.iter().map(VecDeque::len).sum(),
+            Order::EarliestDeadlineFirst { heap, .. } => heap.len(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Makes room for `additional` more tasks. Under FIFO that is in the
+    /// lane normal-priority timers and immediates go to; the rest grow as
+    /// needed.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        match &mut self.order {
+            Order::Fifo(lanes) => {
+                for lane in &mut lanes[NORMAL_LANES] {
+                    lane.reserve(additional);
+                }
+            }
+            Order::EarliestDeadlineFirst { heap, .. } => heap.reserve(additional),
+        }
+    }
+
+    /// Gives back room beyond what the queue holds or `keep`, whichever is
+    /// more.
+    pub(crate) fn shrink_to(&mut self, keep: usize) {
+        match &mut self.order {
+            Order::Fifo(lanes) => {
+                for (index, lane) in lanes.iter_mut().enumerate() {
+                    // Only the normal lanes get reserved room to keep.
+                    let keep = if NORMAL_LANES.contains(&index) {
+                        keep
+                    } else {
+                        0
+                    };
+                    lane.shrink_to(keep);
+                }
+            }
+            Order::EarliestDeadlineFirst { heap, .. } => heap.shrink_to(keep),
+        }
+    }
+
+    /// The room held, across all lanes under FIFO.
+    pub(crate) fn capacity(&self) -> usize {
+        match &self.order {
+            Order::Fifo(lanes) => lanes.iter().map(VecDeque::capacity).sum(),
+            Order::EarliestDeadlineFirst { heap, .. } => heap.capacity(),
+        }
+    }
+
+    /// Every queued task, in the order they would run if nothing else were
+    /// queued and no task were starving.
+    pub(crate) fn tasks(&self) -> Vec<&Task> {
+        match &self.order {
+            Order::Fifo(lanes) => lanes.iter().flatten().collect(),
+            Order::EarliestDeadlineFirst { heap, .. } => {
+                let mut ready: Vec<_> = heap.iter().map(|Reverse(ready)| ready).collect();
+                ready.sort();
+                ready.into_iter().map(|ready| &ready.task).collect()
+            }
+        }
+    }
+
+    /// The id of the task that was scheduled first.
+    pub(crate) fn oldest(&self) -> Option<(u64, TaskId)> {
+        self.tasks()
+            .into_iter()
+            .map(|task| (task.seq, task.id()))
+            .min()
+    }
+
+    /// Empties the queue, handing back everything that was in it.
+    pub(crate) fn take_all(&mut self) -> Vec<Task> {
+        match &mut self.order {
+            Order::Fifo(lanes) => lanes.iter_mut().flat_map(|lane| lane.drain(..)).collect(),
+            Order::EarliestDeadlineFirst { heap, .. } => {
+                heap.drain().map(|Reverse(ready)| ready.task).collect()
+            }
+        }
+    }
+
+    /// Takes out every task for which `pred` returns `true`, so the caller
+    /// can drop them once its locks are released.
+    pub(crate) fn extract(&mut self, mut pred: impl FnMut(&Task) -> bool) -> Vec<Task> {
+        let mut removed = Vec::new();
+        match &mut self.order {
+            Order::Fifo(lanes) => {
+                for lane in lanes.iter_mut() {
+                    let (gone, kept) = lane.drain(..).partition(|task| pred(task));
+                    *lane = kept;
+                    removed.extend(gone);
+                }
+            }
+            Order::EarliestDeadlineFirst { heap, .. } => {
+                let (gone, kept): (Vec<_>, Vec<_>) = std::mem::take(heap)
+                    .into_iter()
+                    .partition(|Reverse(ready)| pred(&ready.task));
+                *heap = BinaryHeap::from(kept);
+                removed.extend(gone.into_iter().map(|Reverse(ready)| ready.task));
+            }
+        }
+        removed
+    }
+
+    pub(crate) fn remove(&mut self, id: TaskId) -> Option<Task> {
+        match &mut self.order {
+            Order::Fifo(lanes) => lanes.iter_mut().find_map(|lane| {
+                let index = lane.iter().position(|task| task.id() == id)?;
+                lane.remove(index)
+            }),
+            Order::EarliestDeadlineFirst { heap, .. } => {
+                if !heap.iter().any(|Reverse(ready)| ready.task.id() == id) {
+                    return None;
+                }
+                let mut ready = std::mem::take(heap).into_vec();
+                let index = ready
+                    .iter()
+                    .position(|Reverse(ready)| ready.task.id() == id)
+                    .unwrap();
+                let Reverse(DeadlineTask { task, .. }) = ready.swap_remove(index);
+                *heap = BinaryHeap::from(ready);
+                Some(task)
+            }
+        }
+    }
+}
+
+impl Extend<Task> for ReadyQueue {
+    fn extend<I: IntoIterator<Item = Task>>(&mut self, tasks: I) {
+        for task in tasks {
+            self.push_back(task);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn task(priority: Priority) -> Task {
+        Task::new_with_priority(|| {}, None, priority)
+    }
+
+    fn drain(queue: &mut ReadyQueue) -> Vec<TaskId> {
+        let now = Instant::now();
+        std::iter::from_fn(|| queue.pop_front(now))
+            .map(|task| task.id())
+            .collect()
+    }
+
+    #[test]
+    fn pops_by_priority_then_fifo() {
+        let mut queue = ReadyQueue::new(SchedulerPolicy::Fifo, None);
+        let low = task(Priority::Low);
+        let normal = task(Priority::Normal);
+        let first_high = task(Priority::High);
+        let second_high = task(Priority::High);
+        let expected = [first_high.id(), second_high.id(), normal.id(), low.id()];
+
+        queue.extend([low, first_high, normal, second_high]);
+        assert_eq!(queue.len(), 4);
+        assert_eq!(drain(&mut queue), expected);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn edf_pops_by_deadline_and_keeps_ties_in_order() {
+        let mut queue = ReadyQueue::new(SchedulerPolicy::EarliestDeadlineFirst, None);
+        let now = Instant::now();
+        let with_deadline = |offset_ms| {
+            let mut task = task(Priority::Normal);
+            task.set_deadline(now + Duration::from_millis(offset_ms));
+            task
+        };
+        let late = with_deadline(30);
+        let first_tie = with_deadline(10);
+        let second_tie = with_deadline(10);
+        let front = with_deadline(10);
+        let early = with_deadline(0);
+        let expected = [
+            early.id(),
+            front.id(),
+            first_tie.id(),
+            second_tie.id(),
+            late.id(),
+        ];
+
+        queue.extend([late, first_tie, second_tie, early]);
+        queue.push_front(front);
+        assert_eq!(drain(&mut queue), expected);
+    }
+
+    #[test]
+    fn starved_task_overtakes_higher_priorities() {
+        let threshold = Duration::from_millis(10);
+        let mut queue = ReadyQueue::new(SchedulerPolicy::Fifo, Some(threshold));
+        let start = Instant::now();
+        let ready_at = |priority, offset_ms| {
+            let mut task = task(priority);
+            task.set_deadline(start + Duration::from_millis(offset_ms));
+            task
+        };
+        let low = ready_at(Priority::Low, 0);
+        let high = ready_at(Priority::High, 5);
+        let (low_id, high_id) = (low.id(), high.id());
+        queue.extend([low, high]);
+        queue.push_back(ready_at(Priority::High, 5));
+
+        // Nothing has waited long enough yet, so priority order applies.
+        assert_eq!(
+            queue.pop_front(start + threshold / 2).unwrap().id(),
+            high_id
+        );
+        let starved = queue.pop_front(start + threshold).unwrap();
+        assert_eq!(starved.id(), low_id);
+        assert_eq!(starved.priority(), Priority::Low);
+    }
+}
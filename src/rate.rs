@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+/// The token bucket behind [`SchedulerBuilder::rate_limit`].
+///
+/// It holds up to `n` tokens and gains one every `per / n`. Rather than
+/// counting tokens, it tracks when the bucket will next be full (the
+/// "theoretical arrival time" of GCRA), which needs no refill timer and no
+/// fractions.
+///
+/// [`SchedulerBuilder::rate_limit`]: crate::SchedulerBuilder::rate_limit
+pub(crate) struct RateLimiter {
+    /// How long one token takes to refill.
+    interval: Duration,
+    /// How far ahead of `now` the bucket may be drawn down: `n - 1` tokens.
+    burst: Duration,
+    /// When the bucket is next full again; `None` while it already is.
+    full_at: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(n: u32, per: Duration) -> Self {
+        let interval = per / n;
+        Self {
+            interval,
+            burst: interval * (n - 1),
+            full_at: None,
+        }
+    }
+
+    /// Takes a token, or returns when the next one will be available.
+    pub(crate) fn try_acquire(&mut self, now: Instant) -> Result<(), Instant> {
+        let full_at = self.full_at.map_or(now, |full_at| full_at.max(now));
+        let available_at = full_at.checked_sub(self.burst).unwrap_or(now);
+        if available_at > now {
+            return Err(available_at);
+        }
+        self.full_at = Some(full_at + self.interval);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_one_per_interval() {
+        let now = Instant::now();
+        let at = |ms| now + Duration::from_millis(ms);
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(1));
+
+        assert_eq!(limiter.try_acquire(at(0)), Ok(()));
+        assert_eq!(limiter.try_acquire(at(0)), Ok(()));
+        assert_eq!(limiter.try_acquire(at(0)), Err(at(500)));
+        assert_eq!(limiter.try_acquire(at(499)), Err(at(500)));
+        assert_eq!(limiter.try_acquire(at(500)), Ok(()));
+        assert_eq!(limiter.try_acquire(at(600)), Err(at(1000)));
+        // A long quiet spell refills the bucket, but no further than full.
+        assert_eq!(limiter.try_acquire(at(5000)), Ok(()));
+        assert_eq!(limiter.try_acquire(at(5000)), Ok(()));
+        assert_eq!(limiter.try_acquire(at(5000)), Err(at(5500)));
+    }
+}
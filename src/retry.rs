@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+/// How [`Scheduler::schedule_with_retry`] spaces out attempts.
+///
+/// The first retry waits `initial_delay`, and every one after that waits
+/// `multiplier` times longer than the last, up to `max_delay`. With a
+/// `jitter` of, say, `0.1`, each delay is then moved by up to 10% either
+/// way, so that many tasks failing together don't retry in lockstep.
+///
+/// [`Scheduler::schedule_with_retry`]: crate::Scheduler::schedule_with_retry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, counting the first. At least one is always made.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    /// The fraction, between `0.0` and `1.0`, by which a delay may be moved
+    /// in either direction.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    /// Five attempts, doubling from 100ms up to 30s, without jitter.
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+            jitter: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retry number `retry`, counting from zero. `random`
+    /// is drawn from `[0, 1)` and places the delay within the jitter.
+    pub(crate) fn delay(&self, retry: u32, random: f64) -> Duration {
+        // In nanoseconds, which keeps whole-millisecond delays exact.
+        let max = self.max_delay.as_nanos() as f64;
+        let base = self.initial_delay.as_nanos() as f64 * self.multiplier.powi(retry as i32);
+        let mut delay = base.min(max);
+        if self.jitter > 0.0 {
+            let spread = self.jitter.min(1.0) * delay;
+            delay += spread * (2.0 * random - 1.0);
+        }
+        Duration::from_nanos(delay.clamp(0.0, max).round() as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_the_cap_and_jitter_stays_in_bounds() {
+        let policy = RetryPolicy {
+            max_delay: Duration::from_millis(500),
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<_> = (0..5).map(|retry| policy.delay(retry, 0.5)).collect();
+        let ms = Duration::from_millis;
+        assert_eq!(delays, [ms(100), ms(200), ms(400), ms(500), ms(500)]);
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        assert_eq!(jittered.delay(1, 0.0), ms(100));
+        assert_eq!(jittered.delay(1, 0.5), ms(200));
+        assert_eq!(jittered.delay(1, 0.75), ms(250));
+    }
+}
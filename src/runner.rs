@@ -0,0 +1,153 @@
+use crate::Scheduler;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Controls a loop started with [`Scheduler::start`].
+///
+/// Dropping the handle without calling [`RunnerHandle::stop`] still asks
+/// the loop to shut down, but does not wait for the thread to exit.
+pub struct RunnerHandle {
+    scheduler: Arc<Scheduler>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RunnerHandle {
+    pub(crate) fn new(scheduler: Arc<Scheduler>, thread: JoinHandle<()>) -> Self {
+        Self {
+            scheduler,
+            thread: Some(thread),
+        }
+    }
+
+    /// Shuts the loop down gracefully and waits for the thread to exit.
+    ///
+    /// The running callback (if any) finishes; pending tasks stay queued
+    /// until [`Scheduler::reset`] lets another run pick them up.
+    pub fn stop(mut self) {
+        self.scheduler.shutdown();
+        self.wait();
+    }
+
+    /// Waits for the loop thread to exit without asking it to stop, e.g.
+    /// after a task has called [`Scheduler::shutdown`].
+    pub fn join(mut self) {
+        self.wait();
+    }
+
+    /// Whether the loop thread has exited.
+    pub fn is_finished(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_none_or(|thread| thread.is_finished())
+    }
+
+    fn wait(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if let Err(panic) = thread.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+impl Drop for RunnerHandle {
+    fn drop(&mut self) {
+        if self.thread.take().is_some() {
+            self.scheduler.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Scheduler, Task};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < Duration::from_secs(2), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn schedule_from_several_threads_then_stop() {
+        let scheduler = Scheduler::new();
+        let runner = scheduler.start();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let producers: Vec<_> = (0..3)
+            .map(|_| {
+                let scheduler = scheduler.clone();
+                let ran = ran.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        let ran = ran.clone();
+                        scheduler.schedule(Task::new(
+                            move || {
+                                ran.fetch_add(1, Ordering::SeqCst);
+                            },
+                            None,
+                        ));
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        wait_for(|| ran.load(Ordering::SeqCst) == 30);
+        assert!(!runner.is_finished());
+        runner.stop();
+    }
+
+    #[test]
+    fn loop_survives_drained_queues() {
+        let scheduler = Scheduler::new();
+        let runner = scheduler.start();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..2 {
+            let counter = ran.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                },
+                Some(Duration::from_millis(10)),
+            ));
+            let expected = ran.load(Ordering::SeqCst) + 1;
+            wait_for(|| ran.load(Ordering::SeqCst) == expected);
+            thread::sleep(Duration::from_millis(20));
+        }
+        runner.stop();
+    }
+
+    #[test]
+    fn runs_on_named_thread() {
+        let scheduler = Scheduler::new();
+        let runner = scheduler.start();
+        let handle = scheduler.spawn(|| thread::current().name().map(String::from));
+
+        assert_eq!(handle.join().as_deref(), Some("revent-loop"));
+        runner.stop();
+    }
+
+    #[test]
+    fn dropping_the_handle_stops_the_loop() {
+        let scheduler = Scheduler::new();
+        let runner = scheduler.start();
+        let thread = scheduler.spawn(thread::current);
+        let loop_thread = thread.join();
+        drop(runner);
+
+        // The loop thread holds a clone of the scheduler until it exits.
+        wait_for(|| Arc::strong_count(&scheduler) == 1);
+        drop(loop_thread);
+    }
+}
@@ -1,157 +1,7322 @@
-use std::collections::VecDeque;
-use std::ops::{Sub};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use std::{fmt, thread};
-use uuid::Uuid;
+use crate::blocking::BlockingPool;
+use crate::builder::Config;
+use crate::cooperative::{Progress, RunAsync, Turn};
+use crate::cron::CronSchedule;
+use crate::deps::Dependencies;
+use crate::events::Subscribers;
+use crate::executor::FutureTask;
+use crate::history::EventRing;
+use crate::metrics::Counters;
+use crate::queue::ReadyQueue;
+use crate::random::Rng;
+use crate::rate::RateLimiter;
+use crate::scope::FinishOnDrop;
+use crate::source::{Polled, PolledChannel, Source};
+use crate::status::TaskStatuses;
+use crate::sync::{Condvar, Mutex, MutexGuard};
+use crate::task::Callback;
+use crate::timers::TimerQueue;
+use crate::wake::{ParkTimeout, SleepStrategy, WakeSignal};
+use crate::watchdog::Watchdog;
+#[cfg(all(feature = "io", unix))]
+use crate::IoHandle;
+use crate::{
+    AsyncTimer, Clock, CronParseError, EventKind, EventRecord, JoinHandle, Metrics, RunnerHandle,
+    SchedulerBuilder, SchedulerEvent, SchedulerHandle, Scope, SequenceHandle, Sleep, SourceHandle,
+    Task, TaskHandle, TaskId, TaskMeta, Throttled,
+};
+use crate::{
+    CatchUp, Debounced, IdleAction, IntervalMode, OverflowPolicy, Phase, Priority, QueuedIn,
+    RetryPolicy, RunError, ScheduleError, ScheduleSnapshot, SchedulerPolicy, ShrinkPolicy,
+    TaskInfo, TaskSnapshot, TaskState,
+};
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::future::Future;
+#[cfg(all(feature = "io", unix))]
+use std::os::fd::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Weak};
+use std::task::Waker;
+use std::thread;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant, SystemTime};
 
-struct Task {
-    id: Uuid,
-    callback: Box<dyn FnOnce() + Send + 'static>,
-    expires: Option<Duration>,
+/// A task on its way from [`Scheduler::schedule`] to one of the queues.
+struct Injected {
+    task: Task,
+    /// Set for tasks bound for the sleeping queue.
+    deadline: Option<Instant>,
 }
 
-impl fmt::Debug for Task {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Task").field("id", &self.id).finish()
+/// What a call to [`Scheduler::tick`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickResult {
+    /// How many tasks ran during the tick.
+    pub executed: usize,
+    /// When the scheduler next has work: `Some(now)` if ready or idle tasks
+    /// are still queued, the earliest timer deadline otherwise, or `None` if
+    /// nothing is pending at all or the scheduler is paused.
+    pub next_deadline: Option<Instant>,
+}
+
+/// A summary of one call to [`Scheduler::run`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RunReport {
+    /// Callbacks that ran, including ones scheduled during the run.
+    pub tasks_executed: usize,
+    /// Timers that expired and moved to the ready queue.
+    pub timers_fired: usize,
+    /// How long `run()` took, on the scheduler's clock.
+    pub total_runtime: Duration,
+    /// The part of `total_runtime` spent waiting for timers.
+    pub time_sleeping: Duration,
+    /// Callbacks that panicked.
+    pub panics: usize,
+    /// The [`PanicPolicy`] that ended the run early because a callback
+    /// panicked, if one did.
+    pub stopped_on_panic: Option<PanicPolicy>,
+}
+
+impl RunReport {
+    /// The report, as an error if a [`PanicPolicy`] cut the run short.
+    pub(crate) fn into_result(self) -> Result<Self, RunError> {
+        match self.stopped_on_panic {
+            Some(_) => Err(RunError::StoppedOnPanic(self)),
+            None => Ok(self),
+        }
+    }
+}
+
+/// What the loop does once a callback panics; see
+/// [`SchedulerBuilder::panic_policy`](crate::SchedulerBuilder::panic_policy).
+///
+/// The panic is caught and reported to the `on_panic` hook whichever policy
+/// is in effect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum PanicPolicy {
+    /// Carries on with the next task.
+    #[default]
+    Continue,
+    /// Stops promoting timers, runs the tasks that are already ready, then
+    /// returns [`RunError::StoppedOnPanic`]. Timers stay queued for a later
+    /// run.
+    DrainAndStop,
+    /// Drops every pending task as soon as the callback returns, then
+    /// returns [`RunError::StoppedOnPanic`]. Unlike [`Scheduler::clear`],
+    /// this drops the tasks' [`Scheduler::on_close`] callbacks too, so
+    /// nothing is left to run.
+    AbortAll,
+}
+
+/// An interval task whose callback is currently executing.
+///
+/// Interval tasks are out of both queues while they run, so cancelling one
+/// mid-callback is recorded here and checked before it is re-enqueued.
+struct RunningInterval {
+    id: TaskId,
+    cancelled: bool,
+}
+
+/// A [`Scheduler::run_pool`] worker's own ready tasks: the ones its
+/// callbacks scheduled. The worker takes the newest first, others steal
+/// the oldest.
+type LocalQueue = Mutex<VecDeque<Task>>;
+
+thread_local! {
+    /// The local queue of the pool worker on this thread, with the address
+    /// of the scheduler it belongs to.
+    static WORKER: RefCell<Option<(usize, Arc<LocalQueue>)>> = const { RefCell::new(None) };
+
+    /// The scheduler whose callback this thread is executing, for
+    /// [`Scheduler::current`].
+    static CURRENT: RefCell<Option<Weak<Scheduler>>> = const { RefCell::new(None) };
+
+    /// The error a callback from [`Scheduler::schedule_fallible`] failed
+    /// with, until `execute` picks it up.
+    static FAILURE: RefCell<Option<Box<dyn Error + Send>>> = const { RefCell::new(None) };
+}
+
+/// Makes a scheduler [`Scheduler::current`] for the duration of a callback,
+/// then puts back whichever one was current before, so a callback that runs
+/// another scheduler's loop gets its own back afterwards.
+struct CurrentGuard(Option<Weak<Scheduler>>);
+
+impl CurrentGuard {
+    fn enter(scheduler: &Scheduler) -> Self {
+        Self(CURRENT.with(|current| current.replace(Some(scheduler.me()))))
+    }
+}
+
+impl Drop for CurrentGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+/// How often a pool worker looks at the shared queue before its own, so a
+/// callback that keeps rescheduling itself can't starve everything else.
+const SHARED_QUEUE_INTERVAL: usize = 61;
+
+/// How many ready tasks the loop takes between checks of
+/// [`SchedulerBuilder::max_batch_duration`].
+const BATCH_CHECK_INTERVAL: usize = 32;
+
+/// How many ready tasks [`Scheduler::run_async`] runs before yielding to
+/// the runtime.
+const ASYNC_BATCH: usize = 64;
+
+/// Shared state of the workers in one [`Scheduler::run_pool`] call.
+struct Pool {
+    /// One per worker, or none if the scheduler's settings rule them out.
+    locals: Vec<Arc<LocalQueue>>,
+    /// Workers executing a callback. Tasks are popped under this lock, so
+    /// holding it while the count is zero means no callback can be about to
+    /// schedule more work.
+    busy: Mutex<usize>,
+    done: AtomicBool,
+    /// Set while one worker waits for the next timer on the pool's behalf.
+    timer_claimed: AtomicBool,
+}
+
+/// Counts a worker as busy until dropped. A worker unwinding out of a hook
+/// stops the whole pool, since the others would otherwise wait for it.
+struct Busy<'a>(&'a Scheduler, &'a Pool);
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        *self.1.busy.lock() -= 1;
+        if thread::panicking() {
+            self.1.done.store(true, AtomicOrdering::SeqCst);
+            self.0.wake.notify();
+        }
+    }
+}
+
+/// Installs a pool worker's local queue on its thread, and on drop hands
+/// whatever is left in it back to the shared queue.
+struct LocalWorker<'a> {
+    scheduler: &'a Scheduler,
+    local: Arc<LocalQueue>,
+}
+
+impl<'a> LocalWorker<'a> {
+    fn enter(scheduler: &'a Scheduler, local: Arc<LocalQueue>) -> Self {
+        WORKER.with(|worker| *worker.borrow_mut() = Some((scheduler.addr(), local.clone())));
+        Self { scheduler, local }
+    }
+}
+
+impl Drop for LocalWorker<'_> {
+    fn drop(&mut self) {
+        WORKER.with(|worker| *worker.borrow_mut() = None);
+        let left = std::mem::take(&mut *self.local.lock());
+        if !left.is_empty() {
+            self.scheduler.ready_fns.lock().extend(left);
+        }
+    }
+}
+
+/// Travels inside a keyed task's callback and frees its key once the
+/// callback is dropped, unless a newer task has taken the key over since.
+struct KeyToken {
+    scheduler: Weak<Scheduler>,
+    key: String,
+    id: TaskId,
+}
+
+impl KeyToken {
+    /// Frees the key, returning whether it still belonged to this task.
+    fn release(&self) -> bool {
+        let Some(scheduler) = self.scheduler.upgrade() else {
+            return false;
+        };
+        let mut keyed = scheduler.keyed.lock();
+        if keyed.get(&self.key) != Some(&self.id) {
+            return false;
+        }
+        keyed.remove(&self.key);
+        true
+    }
+}
+
+impl Drop for KeyToken {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Lets another loop run once this one returns, even if it unwinds.
+struct Running<'a>(&'a Scheduler);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.running.store(false, AtomicOrdering::SeqCst);
+    }
+}
+
+/// Unregisters a loop thread, even if the loop unwinds.
+pub(crate) struct LoopThread<'a>(&'a Scheduler);
+
+impl Drop for LoopThread<'_> {
+    fn drop(&mut self) {
+        let current = thread::current().id();
+        let mut loop_threads = self.0.loop_threads.lock();
+        if let Some(index) = loop_threads.iter().position(|id| *id == current) {
+            loop_threads.swap_remove(index);
+        }
+    }
+}
+
+/// Runs [`Task`]s on the thread that calls [`Scheduler::run`].
+///
+/// The scheduler is always handed out behind an [`Arc`] so that callbacks
+/// can hold on to it and schedule follow-up work.
+///
+/// # Injection
+///
+/// Scheduling doesn't touch the queues the loop works from. New tasks go
+/// into a channel that the loop drains at the top of every iteration and
+/// between callbacks, so producer threads never wait on the loop. Each
+/// task's deadline and position are fixed when it is scheduled, which
+/// means:
+///
+/// - tasks scheduled from the same thread, including the loop thread from
+///   inside a callback, run in the order they were scheduled (within a
+///   priority, under [`crate::SchedulerPolicy::Fifo`]);
+/// - tasks from different threads are ordered by when their `schedule()`
+///   calls went through, with no guarantee for calls that overlap;
+/// - a task is visible to [`Scheduler::cancel`],
+///   [`Scheduler::pending_tasks`] and the other queue accessors as soon as
+///   `schedule()` returns, since they drain the channel first.
+///
+/// With a [`SchedulerBuilder::max_pending`] limit, tasks skip the channel
+/// and are queued directly, so that the limit is checked against the
+/// queues themselves.
+pub struct Scheduler {
+    ready_fns: Mutex<ReadyQueue>,
+    sleeping_fns: Mutex<TimerQueue<Task>>,
+    injector: Sender<Injected>,
+    /// The receiving end of `injector`. Locked before either queue.
+    injected: Mutex<Receiver<Injected>>,
+    next_seq: AtomicU64,
+    /// The largest [`TaskBuilder::slack`](crate::TaskBuilder::slack) ever
+    /// scheduled, in nanoseconds, which bounds how far ahead a wakeup looks
+    /// for timers to take along.
+    max_slack: AtomicU64,
+    /// One entry per worker in [`Scheduler::run_pool`], at most one otherwise.
+    running_intervals: Mutex<Vec<RunningInterval>>,
+    /// The local queues of every running pool worker. Locked after both
+    /// queues, and never together with a local queue's own lock being
+    /// held first.
+    locals: Mutex<Vec<Arc<LocalQueue>>>,
+    /// Tasks from [`Scheduler::schedule_idle`]. Locked after every other
+    /// queue.
+    idle_fns: Mutex<VecDeque<Task>>,
+    /// Callbacks from [`Scheduler::next_tick`], run before anything else.
+    microtasks: Mutex<VecDeque<Task>>,
+    /// Callbacks from [`Scheduler::on_close`], by the id of the task they
+    /// wait on. Never held while taking another lock.
+    closers: Mutex<HashMap<TaskId, Vec<Task>>>,
+    /// Tasks from [`Scheduler::schedule_after`] waiting for their
+    /// prerequisite. Never held while taking another lock.
+    dependencies: Mutex<Dependencies>,
+    /// The pending task for each key given to [`Scheduler::schedule_keyed`].
+    keyed: Mutex<HashMap<String, TaskId>>,
+    /// The threads currently inside [`Scheduler::run`] or
+    /// [`Scheduler::run_pool`].
+    loop_threads: Mutex<Vec<ThreadId>>,
+    /// Set while a loop runs, so that a second one can't start.
+    running: AtomicBool,
+    /// Signalled by `schedule()` and `shutdown()` so a waiting loop
+    /// re-evaluates its queues.
+    wake: WakeSignal,
+    shutdown: AtomicBool,
+    paused: AtomicBool,
+    /// Set once a callback has panicked under a [`PanicPolicy`] other than
+    /// `Continue`, until the next run starts.
+    panic_stop: AtomicBool,
+    /// Serialises admission when a pending limit is set.
+    capacity: Mutex<()>,
+    /// Signalled when a task leaves the queues, for [`OverflowPolicy::Block`].
+    space: Condvar,
+    counters: Counters,
+    blocking: BlockingPool,
+    /// [`Scheduler::spawn_blocking`] jobs that have not queued their result
+    /// yet.
+    blocking_in_flight: AtomicUsize,
+    /// Channels bridged by [`Scheduler::on_receive`] or
+    /// [`Scheduler::poll_channel`] that are still open.
+    open_sources: AtomicUsize,
+    /// The channels from [`Scheduler::poll_channel`], drained by the loop
+    /// on every turn.
+    polled_channels: Mutex<Vec<Box<dyn PolledChannel>>>,
+    /// What [`Scheduler::status`] knows beyond the queues. Never held while
+    /// taking another lock.
+    statuses: Mutex<TaskStatuses>,
+    /// The receivers from [`Scheduler::subscribe`].
+    subscribers: Subscribers,
+    /// What [`Scheduler::recent_events`] returns. Locked after any other
+    /// lock.
+    recent: Mutex<EventRing>,
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    rng: Mutex<Rng>,
+    /// When the scheduler was created, which
+    /// [`SchedulerBuilder::timer_granularity`] counts from.
+    epoch: Instant,
+    /// Set by [`SchedulerBuilder::slow_task_threshold`].
+    watchdog: Option<Watchdog>,
+    config: Config,
+    me: Weak<Scheduler>,
+}
+
+impl Scheduler {
+    /// Creates an empty scheduler with the default settings.
+    pub fn new() -> Arc<Self> {
+        Self::builder().build()
+    }
+
+    /// Starts configuring a scheduler.
+    pub fn builder() -> SchedulerBuilder {
+        SchedulerBuilder::default()
+    }
+
+    /// Creates a scheduler that takes its time from `clock`. Shorthand for
+    /// `Scheduler::builder().clock(clock).build()`.
+    pub fn with_clock(clock: impl Clock + 'static) -> Arc<Self> {
+        Self::builder().clock(clock).build()
+    }
+
+    /// Creates a scheduler with room for `ready` tasks and `sleeping` timers
+    /// set aside up front. Shorthand for
+    /// `Scheduler::builder().capacity(ready, sleeping).build()`.
+    pub fn with_capacity(ready: usize, sleeping: usize) -> Arc<Self> {
+        Self::builder().capacity(ready, sleeping).build()
+    }
+
+    pub(crate) fn with_config(config: Config) -> Arc<Self> {
+        let (injector, injected) = mpsc::channel();
+        let epoch = config
+            .clock
+            .as_ref()
+            .map_or_else(Instant::now, |clock| clock.now());
+        let (ready, sleeping) = config.capacity;
+        let mut ready_fns = ReadyQueue::new(config.policy, config.starvation_threshold);
+        ready_fns.reserve(ready);
+        let mut sleeping_fns = TimerQueue::new(config.timer_backend, epoch);
+        sleeping_fns.reserve(sleeping);
+        Arc::new_cyclic(|me| Self {
+            ready_fns: Mutex::new(ready_fns),
+            sleeping_fns: Mutex::new(sleeping_fns),
+            injector,
+            injected: Mutex::new(injected),
+            next_seq: AtomicU64::new(0),
+            max_slack: AtomicU64::new(0),
+            running_intervals: Mutex::new(Vec::new()),
+            locals: Mutex::new(Vec::new()),
+            idle_fns: Mutex::default(),
+            microtasks: Mutex::default(),
+            closers: Mutex::default(),
+            dependencies: Mutex::default(),
+            keyed: Mutex::default(),
+            loop_threads: Mutex::new(Vec::new()),
+            running: AtomicBool::new(false),
+            wake: WakeSignal::default(),
+            shutdown: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            panic_stop: AtomicBool::new(false),
+            capacity: Mutex::new(()),
+            space: Condvar::new(),
+            counters: Counters::default(),
+            blocking: BlockingPool::new(config.blocking_threads),
+            blocking_in_flight: AtomicUsize::new(0),
+            open_sources: AtomicUsize::new(0),
+            polled_channels: Mutex::default(),
+            statuses: Mutex::new(TaskStatuses::new(config.status_history)),
+            subscribers: Subscribers::default(),
+            recent: Mutex::new(EventRing::new(config.event_history)),
+            rate_limiter: config
+                .rate_limit
+                .map(|(n, per)| Mutex::new(RateLimiter::new(n, per))),
+            rng: Mutex::new(config.seed.map_or_else(Rng::from_entropy, Rng::seeded)),
+            epoch,
+            watchdog: config
+                .slow_task
+                .as_ref()
+                .map(|(threshold, handler)| Watchdog::new(*threshold, handler.clone())),
+            config,
+            me: me.clone(),
+        })
+    }
+
+    /// Queues `task` for execution. Safe to call from any thread, including
+    /// from inside a running callback.
+    ///
+    /// If the loop is waiting for a later timer, it wakes up and
+    /// re-evaluates, so a task that is due sooner is never held up by the
+    /// wait. The returned handle can be used to cancel the task while it is
+    /// still waiting to run.
+    ///
+    /// A delay of [`Duration::ZERO`] is the same as none: the task joins the
+    /// ready queue behind the tasks already there; see [the crate
+    /// docs](crate#loop-phases).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the scheduler was built with
+    /// [`SchedulerBuilder::max_pending`], is full, and has no
+    /// [`OverflowPolicy`]. With [`OverflowPolicy::Block`], panics if the
+    /// wait would block the loop thread itself. Use
+    /// [`Scheduler::try_schedule`] to get the task back instead.
+    pub fn schedule(&self, task: Task) -> TaskHandle {
+        let deadline = self.deadline_after(task.expires);
+        self.enqueue(task, deadline)
+    }
+
+    /// When a task with delay `expires` falls due, or `None` if it is ready
+    /// straight away, as with no delay or a zero one.
+    fn deadline_after(&self, expires: Option<Duration>) -> Option<Instant> {
+        // The delay counts from now, not from whenever the loop gets around
+        // to looking at the sleeping queue.
+        let expires = expires.filter(|expires| !expires.is_zero())?;
+        Some(self.now() + expires)
+    }
+
+    /// Queues `f` like [`Scheduler::schedule`] with [`Task::new`], but hands
+    /// it the scheduler running it, so follow-up work can be scheduled
+    /// without cloning an `Arc` into every closure.
+    pub fn schedule_with(
+        &self,
+        f: impl FnOnce(&Scheduler) + Send + 'static,
+        expires: Option<Duration>,
+    ) -> TaskHandle {
+        self.schedule(Task::with_scheduler(f, expires))
+    }
+
+    /// Queues `f` like [`Scheduler::schedule`] with [`Task::new`], and hands
+    /// the error it returns, if any, to
+    /// [`SchedulerBuilder::on_error`] together with the task's
+    /// [`TaskMeta`].
+    ///
+    /// ```
+    /// use revent_loop::Scheduler;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let failed = Arc::new(Mutex::new(Vec::new()));
+    /// let reported = failed.clone();
+    /// let scheduler = Scheduler::builder()
+    ///     .on_error(move |meta, error| reported.lock().unwrap().push((meta.id, error.to_string())))
+    ///     .build();
+    /// let id = scheduler
+    ///     .schedule_fallible(|| "x".parse::<u8>().map(drop), None)
+    ///     .id();
+    /// scheduler.schedule_fallible(|| "7".parse::<u8>().map(drop), None);
+    ///
+    /// scheduler.run().unwrap();
+    /// let failed = failed.lock().unwrap();
+    /// assert_eq!(*failed, [(id, "invalid digit found in string".to_string())]);
+    /// assert_eq!(scheduler.metrics().failed, 1);
+    /// ```
+    pub fn schedule_fallible<E>(
+        &self,
+        f: impl FnOnce() -> Result<(), E> + Send + 'static,
+        expires: Option<Duration>,
+    ) -> TaskHandle
+    where
+        E: Error + Send + 'static,
+    {
+        self.schedule(Task::new(
+            move || {
+                if let Err(error) = f() {
+                    FAILURE.with(|failure| *failure.borrow_mut() = Some(Box::new(error)));
+                }
+            },
+            expires,
+        ))
+    }
+
+    /// Like [`Scheduler::schedule`], but returns the task inside
+    /// [`ScheduleError::QueueFull`] when the [`SchedulerBuilder::max_pending`]
+    /// limit has been reached, or inside [`ScheduleError::ShutDown`] after
+    /// [`Scheduler::shutdown`].
+    pub fn try_schedule(&self, task: Task) -> Result<TaskHandle, ScheduleError> {
+        if self.is_shutdown() {
+            return Err(ScheduleError::ShutDown(task));
+        }
+        let deadline = self.deadline_after(task.expires);
+        self.admit(task, deadline, OverflowPolicy::RejectNew)
+    }
+
+    /// Queues a batch of tasks, taking each queue's lock once rather than
+    /// once per task, and returns their handles in input order.
+    ///
+    /// Tasks keep the order they had in the batch relative to each other,
+    /// exactly as if scheduled one at a time. With a
+    /// [`SchedulerBuilder::max_pending`] limit the batch is admitted task by
+    /// task through [`Scheduler::schedule`] instead.
+    pub fn schedule_all(&self, tasks: impl IntoIterator<Item = Task>) -> Vec<TaskHandle> {
+        let tasks = tasks.into_iter();
+        if self.config.max_pending.is_some() {
+            return tasks.map(|task| self.schedule(task)).collect();
+        }
+
+        let now = self.now();
+        let mut handles = Vec::with_capacity(tasks.size_hint().0);
+        let mut scheduled = Vec::new();
+        let mut ready = Vec::new();
+        let mut sleeping = Vec::new();
+        for mut task in tasks {
+            handles.push(TaskHandle::new(task.id, self.me.clone()));
+            self.record(EventKind::Scheduled, Some(task.id), task.name.as_ref());
+            #[cfg(feature = "tracing")]
+            tracing::trace!(
+                id = %task.id,
+                name = task.name.as_deref(),
+                delay = ?task.expires,
+                "task scheduled"
+            );
+            let deadline = match task.expires.filter(|expires| !expires.is_zero()) {
+                Some(expires) => Some(now + expires),
+                None => {
+                    // The whole batch is due from when it was handed over.
+                    task.deadline.get_or_insert(now);
+                    None
+                }
+            };
+            let deadline = self.prepare(&mut task, deadline);
+            if self.observed() {
+                scheduled.push(task.meta());
+            }
+            match deadline {
+                None => ready.push(task),
+                Some(deadline) => sleeping.push((deadline, task.seq, task)),
+            }
+        }
+
+        let (ready_count, sleeping_count) = (ready.len(), sleeping.len());
+        self.counters.ready_added(ready_count);
+        self.counters.sleeping_added(sleeping_count);
+        // Holding the channel keeps the batch behind anything scheduled
+        // before it.
+        let injected = self.drain_and_hold_injector();
+        self.insert(ready, sleeping);
+        drop(injected);
+        self.counters.scheduled(ready_count + sleeping_count);
+        self.wake.notify();
+        for task in scheduled {
+            self.report_scheduled(Some(task));
+        }
+        handles
+    }
+
+    /// Queues a task without checking the pending limit.
+    pub(crate) fn schedule_unbounded(&self, task: Task) -> TaskHandle {
+        let handle = TaskHandle::new(task.id, self.me.clone());
+        let deadline = self.deadline_after(task.expires);
+        let scheduled = self.push(task, deadline);
+        self.report_scheduled(scheduled);
+        handle
+    }
+
+    /// Runs `f` at the absolute instant `at` (on the scheduler's clock).
+    ///
+    /// If `at` has already passed, the task goes straight to the ready queue.
+    pub fn schedule_at(&self, at: Instant, f: impl FnOnce() + Send + 'static) -> TaskHandle {
+        let mut task = Task::new(f, None);
+        if at > self.now() {
+            return self.enqueue(task, Some(at));
+        }
+        // Under EDF an overdue task keeps its original deadline.
+        task.set_deadline(at);
+        self.enqueue(task, None)
+    }
+
+    /// Queues `task` once the task with id `prerequisite` has finished
+    /// running, whether it returned or panicked. If it has already finished,
+    /// `task` is queued straight away; its delay, if any, counts from then.
+    ///
+    /// Until then `task` is in neither queue, so it doesn't count towards
+    /// [`Scheduler::pending_count`] or keep [`Scheduler::run`] going on its
+    /// own. An id the scheduler doesn't know, for example one that hasn't
+    /// been scheduled yet, is waited for. Only the most recently finished
+    /// tasks are remembered, so very old prerequisites are waited for too.
+    /// If the prerequisite is cancelled, [`SchedulerBuilder::dependency_policy`]
+    /// decides what becomes of `task`.
+    pub fn schedule_after(&self, prerequisite: TaskId, task: Task) -> TaskHandle {
+        let handle = TaskHandle::new(task.id, self.me());
+        let ready = self.dependencies.lock().park(prerequisite, task);
+        if let Some(task) = ready {
+            self.schedule(task);
+        }
+        handle
+    }
+
+    /// Runs `tasks` one after another, in order: each is queued only once
+    /// the one before it has returned (or panicked), and its delay counts
+    /// from then.
+    ///
+    /// Built on [`Scheduler::schedule_after`], so the tasks waiting their
+    /// turn aren't counted as pending.
+    pub fn schedule_sequence(&self, tasks: Vec<Task>) -> SequenceHandle {
+        let ids: Vec<TaskId> = tasks.iter().map(|task| task.id).collect();
+        let mut previous = None;
+        for task in tasks {
+            let id = task.id;
+            match previous {
+                None => self.schedule(task),
+                Some(previous) => self.schedule_after(previous, task),
+            };
+            previous = Some(id);
+        }
+        SequenceHandle::new(ids, self.me())
+    }
+
+    /// Cleans up after `ids`, which are never going to run: queues their
+    /// [`Scheduler::on_close`] callbacks and applies the
+    /// [`DependencyPolicy`](crate::DependencyPolicy) to whatever waited for
+    /// them.
+    fn tasks_cancelled(&self, ids: &[TaskId]) {
+        if ids.is_empty() {
+            return;
+        }
+        let policy = self.config.dependency_policy;
+        let mut dependencies = self.dependencies.lock();
+        let (mut released, mut dropped) = (Vec::new(), Vec::new());
+        for id in ids {
+            let (release, drop) = dependencies.cancelled(*id, policy);
+            released.extend(release);
+            dropped.extend(drop);
+        }
+        drop(dependencies);
+        self.counters.cancelled(dropped.len());
+        let dropped_ids: Vec<TaskId> = dropped.iter().map(|task| task.id).collect();
+        self.report_cancelled(&dropped);
+        drop(dropped);
+        self.statuses
+            .lock()
+            .cancelled(ids.iter().chain(&dropped_ids).copied());
+        self.queue_closers(ids.iter().chain(&dropped_ids));
+        for task in released {
+            self.schedule(task);
+        }
+    }
+
+    /// Runs `f` in the close phase once the task with id `task` is
+    /// cancelled, by any of the cancelling methods, a
+    /// [`DependencyPolicy`](crate::DependencyPolicy) or an
+    /// [`OverflowPolicy::DropOldest`] eviction. If the task runs instead,
+    /// `f` is dropped.
+    ///
+    /// Close callbacks also run when [`Scheduler::run`] and its variants
+    /// return because of [`Scheduler::shutdown`], for every task that is
+    /// still pending then (the tasks themselves stay queued). See [the
+    /// crate docs](crate#loop-phases) for where the close phase falls.
+    pub fn on_close(&self, task: TaskId, f: impl FnOnce() + Send + 'static) {
+        let mut closer = Task::new(f, None);
+        closer.phase = Phase::Close;
+        self.closers.lock().entry(task).or_default().push(closer);
+    }
+
+    /// Queues the close callbacks registered for `ids`.
+    fn queue_closers<'a>(&self, ids: impl IntoIterator<Item = &'a TaskId>) {
+        let mut closers = self.closers.lock();
+        if closers.is_empty() {
+            return;
+        }
+        let due: Vec<Task> = ids
+            .into_iter()
+            .filter_map(|id| closers.remove(id))
+            .flatten()
+            .collect();
+        drop(closers);
+        for closer in due {
+            self.schedule(closer);
+        }
+    }
+
+    /// Forgets the close callbacks registered for `ids` without running
+    /// them.
+    fn drop_closers<'a>(&self, ids: impl IntoIterator<Item = &'a TaskId>) {
+        let mut closers = self.closers.lock();
+        let dropped: Vec<Vec<Task>> = ids
+            .into_iter()
+            .filter_map(|id| closers.remove(id))
+            .collect();
+        drop(closers);
+        // Dropped outside the lock, since callbacks may own anything.
+        drop(dropped);
+    }
+
+    /// The close phase of a loop that is shutting down: runs the close
+    /// callbacks that are queued, then those of every task still pending.
+    /// Returns how many ran.
+    fn run_close_phase(&self) -> usize {
+        self.drain_injector();
+        let queued = self
+            .ready_fns
+            .lock()
+            .extract(|task| task.phase == Phase::Close);
+        self.counters.ready_removed(queued.len());
+        self.notify_space();
+        let registered = std::mem::take(&mut *self.closers.lock());
+        let mut executed = 0;
+        for closer in queued.into_iter().chain(registered.into_values().flatten()) {
+            self.execute(closer);
+            executed += 1 + self.run_microtasks();
+        }
+        executed
+    }
+
+    /// Runs `f` after `delay`, replacing whatever task is still pending
+    /// under the same `key`. Only the last task scheduled under a key runs,
+    /// which debounces work by identity, such as saving one document.
+    ///
+    /// A key is freed once its task has run or been cancelled.
+    pub fn schedule_keyed(
+        &self,
+        key: impl Into<String>,
+        delay: Duration,
+        f: impl FnOnce() + Send + 'static,
+    ) -> TaskHandle {
+        let key = key.into();
+        let id = TaskId::new();
+        let token = KeyToken {
+            scheduler: self.me(),
+            key: key.clone(),
+            id,
+        };
+        let mut task = Task::new(
+            move || {
+                // A task that was already running, or had not reached the
+                // queues yet, when it was replaced is skipped here instead.
+                if token.release() {
+                    f()
+                }
+            },
+            Some(delay),
+        );
+        task.id = id;
+
+        let mut keyed = self.keyed.lock();
+        let replaced = keyed
+            .insert(key, id)
+            .and_then(|previous| self.remove(previous));
+        drop(keyed);
+        if replaced.is_some() {
+            self.counters.cancelled(1);
+            self.notify_space();
+        }
+        // Dropped outside the lock, since its token takes it too.
+        drop(replaced);
+        self.schedule(task)
+    }
+
+    /// Wraps `f` so that it only runs once [`Debounced::call`] has not been
+    /// called for `delay`.
+    pub fn debounced(&self, delay: Duration, f: impl Fn() + Send + Sync + 'static) -> Debounced {
+        Debounced::new(self.me(), delay, f)
+    }
+
+    /// Wraps `f` so that [`Throttled::call`] runs it at most once per
+    /// `period`.
+    pub fn throttled(&self, period: Duration, f: impl Fn() + Send + Sync + 'static) -> Throttled {
+        Throttled::new(self.me(), period, f)
+    }
+
+    /// Puts `task` in the sleeping queue until `deadline`, or at the back of
+    /// the ready queue if there is none, then wakes the loop.
+    ///
+    /// Ready tasks without a deadline yet are stamped with the current time,
+    /// which is what [`crate::SchedulerPolicy::EarliestDeadlineFirst`] orders
+    /// by.
+    fn enqueue(&self, task: Task, deadline: Option<Instant>) -> TaskHandle {
+        let id = task.id;
+        match self.config.overflow {
+            Some(overflow) => match self.admit(task, deadline, overflow) {
+                Ok(handle) => handle,
+                // RejectNew: the task is dropped here.
+                Err(_) => TaskHandle::new(id, self.me.clone()),
+            },
+            None => match self.admit(task, deadline, OverflowPolicy::RejectNew) {
+                Ok(handle) => handle,
+                Err(err) => panic!("Scheduler::schedule: {}", err),
+            },
+        }
+    }
+
+    /// [`Scheduler::enqueue`], applying `overflow` if the pending limit has
+    /// been reached. Only [`OverflowPolicy::RejectNew`] returns an error.
+    fn admit(
+        &self,
+        task: Task,
+        deadline: Option<Instant>,
+        overflow: OverflowPolicy,
+    ) -> Result<TaskHandle, ScheduleError> {
+        let handle = TaskHandle::new(task.id, self.me.clone());
+        let Some(limit) = self.config.max_pending else {
+            let scheduled = self.push(task, deadline);
+            self.report_scheduled(scheduled);
+            return Ok(handle);
+        };
+
+        // Removals only ever make room, so holding this lock across the
+        // check and the push is enough to stay under the limit.
+        let mut capacity = self.capacity.lock();
+        let mut evicted = Vec::new();
+        while self.counters.pending() >= limit {
+            match overflow {
+                OverflowPolicy::DropOldest => match self.pop_oldest() {
+                    Some(oldest) => evicted.push(oldest),
+                    // A limit of zero: there is nothing to make room with.
+                    None => return Err(ScheduleError::QueueFull(task)),
+                },
+                OverflowPolicy::RejectNew => return Err(ScheduleError::QueueFull(task)),
+                OverflowPolicy::Block => {
+                    if self.is_loop_thread() {
+                        drop(capacity);
+                        panic!(
+                            "Scheduler::schedule would block the loop thread waiting for \
+                             space in a full queue"
+                        );
+                    }
+                    capacity = self.space.wait(capacity);
+                }
+            }
+        }
+        let scheduled = self.push(task, deadline);
+        drop(capacity);
+        let evicted_ids: Vec<TaskId> = evicted.iter().map(|task| task.id).collect();
+        self.report_cancelled(&evicted);
+        // Dropped outside the lock, since callbacks may own anything.
+        drop(evicted);
+        self.tasks_cancelled(&evicted_ids);
+        self.report_scheduled(scheduled);
+        Ok(handle)
+    }
+
+    /// Takes out the pending task that was scheduled first, for
+    /// [`OverflowPolicy::DropOldest`].
+    fn pop_oldest(&self) -> Option<Task> {
+        loop {
+            let ready = self.ready_fns.lock().oldest();
+            let sleeping = self
+                .sleeping_fns
+                .lock()
+                .iter()
+                .map(|task| (task.seq, task.id))
+                .min();
+            let (_, id) = match (ready, sleeping) {
+                (Some(ready), Some(sleeping)) => ready.min(sleeping),
+                (ready, sleeping) => ready.or(sleeping)?,
+            };
+            // The loop may have started it in the meantime; look again.
+            if let Some(task) = self.remove(id) {
+                return Some(task);
+            }
+        }
+    }
+
+    /// Wakes producers waiting in [`OverflowPolicy::Block`] after a task
+    /// has left the queues.
+    fn notify_space(&self) {
+        if self.config.overflow == Some(OverflowPolicy::Block) {
+            // Taking the lock orders this after any producer's check, so the
+            // wakeup can't slip in between its check and its wait.
+            drop(self.capacity.lock());
+            self.space.notify_all();
+        }
+    }
+
+    /// Does the queueing for [`Scheduler::enqueue`] and returns what the
+    /// `on_schedule` hook, if installed, should be told once the caller has
+    /// released any locks of its own.
+    fn push(&self, mut task: Task, deadline: Option<Instant>) -> Option<TaskMeta> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            id = %task.id,
+            name = task.name.as_deref(),
+            delay = ?deadline.map(|deadline| deadline.saturating_duration_since(self.now())),
+            "task scheduled"
+        );
+        let deadline = self.prepare(&mut task, deadline);
+        match deadline {
+            None => self.counters.ready_added(1),
+            Some(_) => self.counters.sleeping_added(1),
+        }
+        let scheduled = self.observed().then(|| task.meta());
+        self.record(EventKind::Scheduled, Some(task.id), task.name.as_ref());
+        if self.config.max_pending.is_some() {
+            let (ready, sleeping) = match deadline {
+                None => (vec![task], Vec::new()),
+                Some(deadline) => (Vec::new(), vec![(deadline, task.seq, task)]),
+            };
+            self.insert(ready, sleeping);
+        } else if let Some(task) = self.push_local(task, deadline.is_some()) {
+            // The receiver lives as long as `self`, so this can't fail.
+            let _ = self.injector.send(Injected { task, deadline });
+        }
+        self.counters.scheduled(1);
+        self.wake.notify();
+        scheduled
+    }
+
+    /// Readies `task` for a queue, the same way whichever method queues it:
+    /// numbers it, rounds `deadline`, sets the phase to match and makes
+    /// room for a timer's slack. Without a deadline the task counts as due
+    /// from now. Returns the rounded deadline.
+    fn prepare(&self, task: &mut Task, deadline: Option<Instant>) -> Option<Instant> {
+        task.seq = self.next_seq.fetch_add(1, AtomicOrdering::Relaxed);
+        let slack = task.slack();
+        if deadline.is_some() && !slack.is_zero() {
+            let nanos = u64::try_from(slack.as_nanos()).unwrap_or(u64::MAX);
+            self.max_slack.fetch_max(nanos, AtomicOrdering::Relaxed);
+        }
+        let deadline = deadline.map(|deadline| self.round_deadline(deadline));
+        match deadline {
+            None => {
+                task.deadline.get_or_insert_with(|| self.now());
+                if task.phase != Phase::Close {
+                    task.phase = Phase::Immediate;
+                }
+            }
+            Some(deadline) => {
+                task.deadline = Some(deadline);
+                task.phase = Phase::Timers;
+            }
+        }
+        deadline
+    }
+
+    /// Puts a ready task scheduled from inside a pool worker's callback on
+    /// that worker's local queue, or hands it back if it doesn't belong
+    /// there. Prioritised tasks always go through the shared queue.
+    fn push_local(&self, task: Task, sleeping: bool) -> Option<Task> {
+        if sleeping || task.priority != Priority::Normal {
+            return Some(task);
+        }
+        WORKER.with(|worker| match &*worker.borrow() {
+            Some((scheduler, local)) if *scheduler == self.addr() => {
+                local.lock().push_back(task);
+                None
+            }
+            _ => Some(task),
+        })
+    }
+
+    /// Identifies this scheduler to [`WORKER`].
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Runs `f` on every pool worker's local queue.
+    fn for_each_local(&self, mut f: impl FnMut(&mut VecDeque<Task>)) {
+        for local in self.locals.lock().iter() {
+            f(&mut local.lock());
+        }
+    }
+
+    /// Moves everything sent through the injection channel into the queues.
+    fn drain_injector(&self) {
+        drop(self.drain_and_hold_injector());
+    }
+
+    /// [`Scheduler::drain_injector`], returning the locked receiver so that
+    /// the caller can keep other drains from overtaking work of its own.
+    fn drain_and_hold_injector(&self) -> MutexGuard<'_, Receiver<Injected>> {
+        let injected = self.injected.lock();
+        let mut ready = Vec::new();
+        let mut sleeping = Vec::new();
+        for Injected { task, deadline } in injected.try_iter() {
+            match deadline {
+                None => ready.push(task),
+                Some(deadline) => sleeping.push((deadline, task.seq, task)),
+            }
+        }
+        self.insert(ready, sleeping);
+        injected
+    }
+
+    /// Adds tasks that [`Scheduler::push`] has already prepared (and
+    /// counted) to the queues, taking each lock at most once.
+    fn insert(&self, ready: Vec<Task>, sleeping: Vec<(Instant, u64, Task)>) {
+        if !ready.is_empty() {
+            self.ready_fns.lock().extend(ready);
+        }
+        if !sleeping.is_empty() {
+            self.sleeping_fns.lock().extend(sleeping);
+        }
+    }
+
+    fn report_scheduled(&self, scheduled: Option<TaskMeta>) {
+        let Some(task) = scheduled else {
+            return;
+        };
+        if let Some(hooks) = &self.config.hooks {
+            hooks.on_schedule(&task);
+        }
+        self.emit(|at| SchedulerEvent::Scheduled { task, at });
+    }
+
+    /// Whether hooks or subscribers want to hear about scheduled tasks.
+    fn observed(&self) -> bool {
+        self.config.hooks.is_some() || self.subscribers.is_active()
+    }
+
+    /// Sends the event `event` builds, if anyone is subscribed. Called with
+    /// no queue locks held.
+    fn emit(&self, event: impl FnOnce(Instant) -> SchedulerEvent) {
+        if self.subscribers.is_active() {
+            let dropped = self.subscribers.send(event(self.now()));
+            self.counters.events_dropped(dropped);
+        }
+    }
+
+    /// Records each of `tasks` as cancelled, and sends a
+    /// [`SchedulerEvent::Cancelled`] for it.
+    fn report_cancelled<'a>(&self, tasks: impl IntoIterator<Item = &'a Task> + Clone) {
+        let at = self.now();
+        let mut recent = self.recent.lock();
+        for task in tasks.clone() {
+            recent.record(at, EventKind::Cancelled, Some(task.id), task.name.as_ref());
+        }
+        drop(recent);
+        if self.subscribers.is_active() {
+            for task in tasks {
+                self.emit(|at| SchedulerEvent::Cancelled {
+                    task: task.meta(),
+                    at,
+                });
+            }
+        }
+    }
+
+    /// Notes `kind` in the ring behind [`Scheduler::recent_events`].
+    fn record(&self, kind: EventKind, task: Option<TaskId>, name: Option<&Cow<'static, str>>) {
+        let at = self.now();
+        self.recent.lock().record(at, kind, task, name);
+    }
+
+    /// Notes that the loop slept for `slept`, waiting for a timer.
+    pub(crate) fn record_sleep(&self, slept: Duration) {
+        if !slept.is_zero() {
+            self.record(EventKind::Slept { duration: slept }, None, None);
+        }
+    }
+
+    /// Runs `f` every `period`, starting one period from now, until the
+    /// returned handle (or [`Scheduler::cancel`]) cancels it.
+    ///
+    /// The next run is scheduled when the previous one returns, so a slow
+    /// callback pushes later runs back rather than piling them up.
+    pub fn schedule_interval(
+        &self,
+        period: Duration,
+        f: impl FnMut() + Send + 'static,
+    ) -> TaskHandle {
+        self.schedule(Task::interval(f, period))
+    }
+
+    /// Like [`Scheduler::schedule_interval`], but with the choice of
+    /// [`IntervalMode`].
+    ///
+    /// With [`IntervalMode::FixedRate`], runs stay due on a grid of whole
+    /// periods from now: a callback that takes 30ms of a 100ms period still
+    /// runs every 100ms, where [`IntervalMode::FixedDelay`] would run it
+    /// every 130ms. A run that is late doesn't push the later ones back.
+    pub fn schedule_interval_with_mode(
+        &self,
+        period: Duration,
+        mode: IntervalMode,
+        mut f: impl FnMut() + Send + 'static,
+    ) -> TaskHandle {
+        let catch_up = match mode {
+            IntervalMode::FixedDelay => return self.schedule_interval(period, f),
+            IntervalMode::FixedRate(catch_up) => catch_up,
+        };
+        let scheduler = self.me();
+        let mut due = self.now() + period;
+        let run = move || {
+            f();
+            let now = scheduler.upgrade()?.now();
+            due += period;
+            if catch_up == CatchUp::Skip && due < now {
+                let behind = (now - due).as_nanos().div_ceil(period.as_nanos());
+                due += period * u32::try_from(behind).unwrap_or(u32::MAX);
+            }
+            Some(due.saturating_duration_since(now))
+        };
+        self.schedule(Task::repeat(run, Some(period)))
+    }
+
+    /// Runs `f` after `initial_delay`, then again after every delay it
+    /// returns, until it returns `None` or the returned handle cancels it.
+    ///
+    /// Like [`Scheduler::schedule_interval`], but the callback picks each
+    /// wait itself, for example to poll faster while there is work about.
+    pub fn schedule_repeating(
+        &self,
+        initial_delay: Duration,
+        f: impl FnMut() -> Option<Duration> + Send + 'static,
+    ) -> TaskHandle {
+        self.schedule(Task::repeat(f, Some(initial_delay)))
+    }
+
+    /// Runs `f` every time the wall clock matches the five-field cron
+    /// expression `expr` (minute, hour, day of month, month, day of week),
+    /// until cancelled.
+    ///
+    /// Times are read from the scheduler's [`Clock::wall_time`] and matched
+    /// in the [`SchedulerBuilder::cron_zone`] time zone. Each firing works
+    /// out the next matching minute and sleeps until then, so a wall clock
+    /// that jumps is picked up from the following run on.
+    ///
+    /// ```
+    /// use revent_loop::Scheduler;
+    ///
+    /// let scheduler = Scheduler::new();
+    /// let nightly = scheduler.schedule_cron("30 2 * * mon-fri", || println!("backing up"));
+    /// assert!(nightly.unwrap().cancel());
+    /// assert!(scheduler.schedule_cron("every day at noon", || {}).is_err());
+    /// ```
+    pub fn schedule_cron(
+        &self,
+        expr: &str,
+        mut f: impl FnMut() + Send + 'static,
+    ) -> Result<TaskHandle, CronParseError> {
+        let schedule: CronSchedule = expr.parse()?;
+        let zone = self.config.cron_zone;
+        let scheduler = self.me();
+        let mut due = schedule.next_after(self.wall_time(), zone);
+        let first = due.map(|due| self.until_wall_time(due));
+        let fire = move || {
+            f();
+            let scheduler = scheduler.upgrade()?;
+            // Never before the time just run for, in case the wall clock is
+            // running behind the loop's.
+            let after = scheduler.wall_time().max(due?);
+            due = schedule.next_after(after, zone);
+            Some(scheduler.until_wall_time(due?))
+        };
+        Ok(match first {
+            Some(first) => self.schedule(Task::repeat(fire, Some(first))),
+            // A date that no longer comes up, such as 29 February once leap
+            // years are out of range; nothing to schedule.
+            None => TaskHandle::new(TaskId::new(), self.me()),
+        })
+    }
+
+    /// Runs `f` on the loop, and again after each backoff of `policy` for as
+    /// long as it returns `Err`, up to [`RetryPolicy::max_attempts`].
+    ///
+    /// Retries keep the task's id, so the returned handle cancels whichever
+    /// attempt is pending, or, from inside a running attempt, all later ones.
+    pub fn schedule_with_retry<E: 'static>(
+        &self,
+        policy: RetryPolicy,
+        f: impl FnMut() -> Result<(), E> + Send + 'static,
+    ) -> TaskHandle {
+        self.schedule_with_retry_or_else(policy, f, drop)
+    }
+
+    /// Like [`Scheduler::schedule_with_retry`], but hands the last error to
+    /// `on_exhausted` if every attempt failed.
+    pub fn schedule_with_retry_or_else<E: 'static>(
+        &self,
+        policy: RetryPolicy,
+        mut f: impl FnMut() -> Result<(), E> + Send + 'static,
+        on_exhausted: impl FnOnce(E) + Send + 'static,
+    ) -> TaskHandle {
+        let mut on_exhausted = Some(on_exhausted);
+        let mut retries = 0;
+        let scheduler = self.me();
+        let attempt = move || {
+            let error = f().err()?;
+            if retries + 1 >= policy.max_attempts {
+                if let Some(on_exhausted) = on_exhausted.take() {
+                    on_exhausted(error);
+                }
+                return None;
+            }
+            let random = scheduler.upgrade()?.random_fraction();
+            let delay = policy.delay(retries, random);
+            retries += 1;
+            Some(delay)
+        };
+        self.schedule(Task::repeat(attempt, None))
+    }
+
+    /// Like [`Scheduler::schedule_interval`], but every delay, the first one
+    /// included, is moved by a random amount of up to `jitter` either way.
+    ///
+    /// Spreading the runs out keeps many intervals with the same period
+    /// from firing all at once. The randomness can be made reproducible with
+    /// [`SchedulerBuilder::seed`].
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `jitter` is shorter than `period`, which would allow a
+    /// delay of zero.
+    pub fn schedule_interval_jittered(
+        &self,
+        period: Duration,
+        jitter: Duration,
+        mut f: impl FnMut() + Send + 'static,
+    ) -> TaskHandle {
+        assert!(
+            jitter < period,
+            "interval jitter must be shorter than the period"
+        );
+        let jittered = move |random: f64| {
+            // Both ends of `period ± jitter` are included.
+            let offset = jitter.mul_f64(random * 2.0);
+            (period - jitter) + offset.min(jitter * 2)
+        };
+        let scheduler = self.me();
+        let first = jittered(self.random_fraction());
+        let run = move || {
+            f();
+            Some(jittered(scheduler.upgrade()?.random_fraction()))
+        };
+        self.schedule(Task::repeat(run, Some(first)))
+    }
+
+    /// Runs `f` on the loop and hands its return value to the returned
+    /// [`JoinHandle`].
+    ///
+    /// If `f` panics, the handle gets the payload, as a
+    /// [`JoinErrorKind::Panicked`](crate::JoinErrorKind::Panicked) error.
+    /// The loop still reports the panic, but with a copy of its message
+    /// for the payload.
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> JoinHandle<T> {
+        let task_id = TaskId::new();
+        let (handle, completer) = JoinHandle::new(task_id, self.me.clone());
+        let mut task = Task::new(move || completer.run(f), None);
+        task.id = task_id;
+        self.schedule(task);
+        handle
+    }
+
+    /// Runs `f` on a side thread, off the loop, and hands its return value to
+    /// the returned [`JoinHandle`].
+    ///
+    /// Use this for work that would otherwise block the loop, such as
+    /// blocking I/O or a long computation: timers and other tasks keep
+    /// running while it does. Threads are started as needed, up to
+    /// [`SchedulerBuilder::blocking_threads`], and exit again after a while
+    /// without work.
+    ///
+    /// The result is delivered by a task pushed onto the ready queue when
+    /// `f` returns, so [`JoinHandle::is_finished`] turns `true` only once the
+    /// loop has run it. [`Scheduler::run`] keeps going while blocking work
+    /// is in flight. If `f` panics, the panic is reported through the loop
+    /// and handed to the handle, as for [`Scheduler::spawn`].
+    pub fn spawn_blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> JoinHandle<T> {
+        let task_id = TaskId::new();
+        let (handle, completer) = JoinHandle::new(task_id, self.me.clone());
+        let scheduler = self.me.clone();
+        self.blocking_in_flight.fetch_add(1, AtomicOrdering::SeqCst);
+        self.blocking.spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let Some(scheduler) = scheduler.upgrade() else {
+                return;
+            };
+            let mut task = Task::new(
+                move || match result {
+                    Ok(value) => completer.complete(value),
+                    Err(payload) => completer.panic(payload),
+                },
+                None,
+            );
+            task.id = task_id;
+            scheduler.schedule_unbounded(task);
+            // Only now may the loop conclude it has nothing left to wait for.
+            scheduler
+                .blocking_in_flight
+                .fetch_sub(1, AtomicOrdering::SeqCst);
+            scheduler.wake.notify();
+        });
+        handle
+    }
+
+    /// Calls `f` on the loop thread for every message that arrives on `rx`,
+    /// in the order they were sent.
+    ///
+    /// A thread of its own waits on the channel and queues each message as
+    /// an ordinary ready task, which cuts short the loop's wait for its
+    /// next timer. [`Scheduler::run`] keeps going until every sender has
+    /// been dropped, at which point the source deregisters itself and runs
+    /// [`SourceHandle::on_closed`], if set, after the last message.
+    ///
+    /// Messages go through [`Scheduler::schedule`], so with a bounded queue
+    /// and [`OverflowPolicy::Block`] a busy loop holds the channel back.
+    pub fn on_receive<T: Send + 'static>(
+        &self,
+        rx: Receiver<T>,
+        f: impl FnMut(T) + Send + 'static,
+    ) -> SourceHandle {
+        let source = Source::new(self.me.clone());
+        self.open_sources.fetch_add(1, AtomicOrdering::SeqCst);
+        source.forward(rx, f);
+        SourceHandle::new(source)
+    }
+
+    /// Like [`Scheduler::on_receive`], but without a thread of its own: the
+    /// loop drains `rx` with `try_recv` on every turn.
+    ///
+    /// The catch is latency. Nothing wakes the loop when a message arrives,
+    /// so while the channel is open the loop never waits longer than the
+    /// [`SchedulerBuilder::channel_poll_interval`] (10ms by default), even
+    /// with its next timer far off, and a message can sit on the channel
+    /// for up to that long. [`Scheduler::on_receive`] picks messages up
+    /// straight away, at the cost of a thread per channel.
+    ///
+    /// Once every sender has been dropped, the bridge deregisters itself
+    /// after queueing the remaining messages, then
+    /// [`SourceHandle::on_closed`]. Only the loop and pool workers poll, so
+    /// messages wait while no loop is running, and with a custom [`Clock`]
+    /// a wait for a timer isn't cut short to poll.
+    pub fn poll_channel<T: Send + 'static>(
+        &self,
+        rx: Receiver<T>,
+        f: impl FnMut(T) + Send + 'static,
+    ) -> SourceHandle {
+        let source = Source::new(self.me.clone());
+        self.open_sources.fetch_add(1, AtomicOrdering::SeqCst);
+        self.polled_channels
+            .lock()
+            .push(Box::new(Polled::new(rx, f, source.clone())));
+        // The loop may be in a wait that doesn't know to end in time.
+        self.wake.notify();
+        SourceHandle::new(source)
+    }
+
+    /// Queues the messages waiting on every channel from
+    /// [`Scheduler::poll_channel`], and drops the ones that have closed.
+    fn poll_channels(&self) {
+        // Taken out, so that no lock is held while queueing or dropping.
+        let mut channels = std::mem::take(&mut *self.polled_channels.lock());
+        if channels.is_empty() {
+            return;
+        }
+        channels.retain_mut(|channel| channel.drain(self));
+        let mut polled_channels = self.polled_channels.lock();
+        // Behind any registered meanwhile.
+        channels.append(&mut polled_channels);
+        *polled_channels = channels;
+    }
+
+    /// The longest the loop may wait before it next drains the channels
+    /// from [`Scheduler::poll_channel`], if there are any.
+    fn channel_poll_timeout(&self) -> Option<Duration> {
+        (!self.polled_channels.lock().is_empty()).then_some(self.config.channel_poll_interval)
+    }
+
+    /// Called once a bridged channel closes or is deregistered.
+    pub(crate) fn release_source(&self) {
+        self.open_sources.fetch_sub(1, AtomicOrdering::SeqCst);
+        // The loop may be waiting for nothing but this source.
+        self.wake.notify();
+    }
+
+    /// Calls `f` on the loop thread whenever `source` is readable, until the
+    /// returned handle deregisters it.
+    ///
+    /// Each readiness event queues one run of `f` as an ordinary ready
+    /// task, and the descriptor isn't polled again until that run is over,
+    /// so readiness is level-triggered: `f` should read a non-blocking
+    /// `source` until `WouldBlock`, and cope with a spurious wakeup.
+    ///
+    /// While anything is registered, [`Scheduler::run`] waits in `poll(2)`,
+    /// for I/O and the next timer at once, and doesn't return when its
+    /// queues drain. The scheduler doesn't own `source`: deregister it
+    /// before closing the descriptor. A callback that panics is
+    /// deregistered.
+    ///
+    /// Only the single-threaded loop waits on I/O: [`Scheduler::run_pool`]
+    /// runs callbacks that are already queued but returns once it drains,
+    /// and with a custom [`Clock`] descriptors are polled between sleeps
+    /// without waiting.
+    ///
+    /// Fails only if the first registration can't create the pipe that
+    /// wakes a poll in progress.
+    #[cfg(all(feature = "io", unix))]
+    pub fn register_readable(
+        &self,
+        source: &impl AsRawFd,
+        f: impl FnMut() + Send + 'static,
+    ) -> std::io::Result<IoHandle> {
+        Ok(self
+            .wake
+            .reactor_or_init()?
+            .register(source.as_raw_fd(), libc::POLLIN, Box::new(f)))
+    }
+
+    /// Like [`Scheduler::register_readable`], but calls `f` whenever
+    /// `source` is writable.
+    #[cfg(all(feature = "io", unix))]
+    pub fn register_writable(
+        &self,
+        source: &impl AsRawFd,
+        f: impl FnMut() + Send + 'static,
+    ) -> std::io::Result<IoHandle> {
+        Ok(self
+            .wake
+            .reactor_or_init()?
+            .register(source.as_raw_fd(), libc::POLLOUT, Box::new(f)))
+    }
+
+    /// The poller behind [`Scheduler::register_readable`], once anything
+    /// has been registered.
+    #[cfg(all(test, feature = "io", unix))]
+    pub(crate) fn reactor(&self) -> Option<&Arc<crate::io::Reactor>> {
+        self.wake.reactor()
+    }
+
+    /// Whether there is nothing left to run: both queues are empty, no
+    /// [`Scheduler::spawn_blocking`] job is about to queue its result and
+    /// no channel from [`Scheduler::on_receive`] is still open.
+    fn is_drained(&self) -> bool {
+        self.is_idle()
+            && self.idle_fns.lock().is_empty()
+            && self.microtasks.lock().is_empty()
+            && self.blocking_in_flight.load(AtomicOrdering::SeqCst) == 0
+            && self.open_sources.load(AtomicOrdering::SeqCst) == 0
+    }
+
+    /// Runs `f` as soon as the callback that is running now returns, ahead
+    /// of every ready task and before any timer is promoted.
+    ///
+    /// Called from outside a callback, `f` runs before anything else the
+    /// next time the loop looks at its queues. Microtasks queued by a
+    /// microtask run in the same batch, so a microtask that keeps queueing
+    /// more holds up the whole loop. They are not counted as pending, and
+    /// aren't subject to [`SchedulerBuilder::rate_limit`].
+    pub fn next_tick(&self, f: impl FnOnce() + Send + 'static) {
+        let mut task = Task::new(f, None);
+        task.phase = Phase::NextTick;
+        self.microtasks.lock().push_back(task);
+        self.wake.notify();
+    }
+
+    /// Ends the running callback's turn, queueing `cont` to carry on with
+    /// its work once everything that is already ready has had a go.
+    ///
+    /// `cont` goes to the back of the ready queue, behind every task that
+    /// became ready in the meantime and behind any timer that falls due
+    /// before it runs: yielded work never jumps ahead of work that was
+    /// waiting. That holds under [`Scheduler::run_pool`] too, where `cont`
+    /// skips the worker's local queue. Otherwise this is
+    /// `schedule(Task::new(cont, None))`.
+    ///
+    /// ```
+    /// use revent_loop::Scheduler;
+    ///
+    /// fn process(chunk: usize, scheduler: &Scheduler) {
+    ///     // ... one chunk of a long job ...
+    ///     if chunk < 10 {
+    ///         let next = scheduler.handle();
+    ///         scheduler.yield_now(move || {
+    ///             if let Ok(scheduler) = next.upgrade() {
+    ///                 process(chunk + 1, &scheduler)
+    ///             }
+    ///         });
+    ///     }
+    /// }
+    ///
+    /// let scheduler = Scheduler::new();
+    /// scheduler.schedule_with(|scheduler| process(0, scheduler), None);
+    /// scheduler.run().unwrap();
+    /// ```
+    pub fn yield_now(&self, cont: impl FnOnce() + Send + 'static) -> TaskHandle {
+        let local = WORKER.with(|worker| worker.borrow_mut().take());
+        let handle = self.schedule(Task::new(cont, None));
+        WORKER.with(|worker| *worker.borrow_mut() = local);
+        handle
+    }
+
+    /// Queues `f` to run only when there is nothing else to do: no ready
+    /// task is waiting and no timer is due, so the loop would otherwise
+    /// sleep or return. One idle task runs at a time, oldest first, and the
+    /// loop looks for other work again before the next.
+    ///
+    /// For housekeeping that must never hold up real work. Idle tasks keep
+    /// [`Scheduler::run`] going until they have run, but aren't counted by
+    /// [`Scheduler::pending_count`], [`Scheduler::is_idle`] or a
+    /// [`SchedulerBuilder::max_pending`] limit; [`Scheduler::idle_len`]
+    /// counts them instead.
+    pub fn schedule_idle(&self, f: impl FnOnce() + Send + 'static) -> TaskHandle {
+        let mut task = Task::new(f, None);
+        task.phase = Phase::Idle;
+        let handle = TaskHandle::new(task.id, self.me());
+        let scheduled = self.observed().then(|| task.meta());
+        self.record(EventKind::Scheduled, Some(task.id), task.name.as_ref());
+        self.idle_fns.lock().push_back(task);
+        self.counters.scheduled(1);
+        self.wake.notify();
+        self.report_scheduled(scheduled);
+        handle
+    }
+
+    /// Runs the oldest idle task, if there is one. Returns how many tasks
+    /// ran, counting microtasks.
+    fn run_idle(&self) -> usize {
+        let Some(task) = self.idle_fns.lock().pop_front() else {
+            return 0;
+        };
+        self.execute(task);
+        1 + self.run_microtasks()
+    }
+
+    /// Whether no timer was due as of `next_deadline`, the earliest one
+    /// still pending after the last promotion.
+    fn no_timer_due(&self, next_deadline: Option<Instant>) -> bool {
+        next_deadline.is_none_or(|deadline| deadline > self.now())
+    }
+
+    /// Runs queued microtasks, including any they queue, until there are
+    /// none left. Returns how many ran.
+    fn run_microtasks(&self) -> usize {
+        let mut executed = 0;
+        loop {
+            let Some(task) = self.microtasks.lock().pop_front() else {
+                return executed;
+            };
+            self.execute(task);
+            executed += 1;
+        }
+    }
+
+    /// Spawns `future` onto the loop.
+    ///
+    /// The future is polled inside [`Scheduler::run`]. While it is pending it
+    /// stays out of the queues, and its waker puts it back on the ready queue.
+    pub fn spawn_future(&self, future: impl Future<Output = ()> + Send + 'static) {
+        FutureTask::new(future, self.me.clone()).enqueue();
+    }
+
+    /// Returns a future that completes after `duration`, without blocking
+    /// the loop thread the way `thread::sleep` inside a callback would.
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        Sleep::new(self, duration)
+    }
+
+    /// Runs the loop on the calling thread until `future` completes and
+    /// returns its output.
+    ///
+    /// Other tasks keep running in the meantime. If the queues drain while
+    /// `future` is still waiting on something outside the loop, the thread
+    /// waits until new work is scheduled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from inside a running callback, or while another
+    /// thread is running the loop.
+    pub fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        assert!(
+            !self.is_loop_thread(),
+            "Scheduler::block_on called from inside the loop"
+        );
+        let output = Arc::new(Mutex::new(None));
+        let slot = output.clone();
+        self.spawn_future(async move {
+            let value = future.await;
+            *slot.lock() = Some(value);
+        });
+
+        loop {
+            if let Err(error) = self.run() {
+                panic!("Scheduler::block_on: {}", error);
+            }
+            if let Some(value) = output.lock().take() {
+                return value;
+            }
+            assert!(
+                !self.is_shutdown(),
+                "scheduler shut down before the future passed to block_on completed"
+            );
+            self.wake.wait();
+        }
+    }
+
+    /// Runs `f` with a [`Scope`] whose tasks may borrow anything that
+    /// outlives the call, then runs the loop on the calling thread until it
+    /// is idle, and returns what `f` returned.
+    ///
+    /// Modeled on [`std::thread::scope`]: by the time `scope` returns, every
+    /// scoped task has either finished or been dropped. Scoped tasks still
+    /// queued after the loop stops (because of [`Scheduler::shutdown`], say)
+    /// are cancelled, and ones running on another loop thread are waited
+    /// for. The same happens, without running the loop, if `f` panics.
+    /// Other tasks on the scheduler run alongside as usual.
+    ///
+    /// ```
+    /// use revent_loop::Scheduler;
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// let scheduler = Scheduler::new();
+    /// let data = vec![1, 2, 3, 4];
+    /// let total = AtomicU64::new(0);
+    /// scheduler.scope(|s| {
+    ///     for value in &data {
+    ///         let total = &total;
+    ///         s.schedule(move || { total.fetch_add(*value, Ordering::SeqCst); }, None);
+    ///     }
+    /// });
+    /// assert_eq!(total.into_inner(), 10);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from inside a running callback, or if another
+    /// thread is running the loop by the time `f` returns. Scoped tasks
+    /// are dropped as above either way.
+    pub fn scope<'env, T>(
+        &self,
+        f: impl for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    ) -> T {
+        assert!(
+            !self.is_loop_thread(),
+            "Scheduler::scope called from inside the loop"
+        );
+        let scope = Scope::new(self);
+        let finish = FinishOnDrop(&scope);
+        let result = f(&scope);
+        if let Err(error) = self.run() {
+            panic!("Scheduler::scope: {}", error);
+        }
+        drop(finish);
+        result
+    }
+
+    /// Cancels the pending task with the given id, dropping its callback.
+    ///
+    /// Returns `false` if no such task is waiting, either because the id is
+    /// unknown or because the task already started running. The exception is
+    /// an interval task cancelled from inside its own run: that run finishes,
+    /// no further runs happen and `true` is returned. Safe to call from any
+    /// thread while [`Scheduler::run`] is executing.
+    pub fn cancel(&self, id: TaskId) -> bool {
+        let mut parked = self.dependencies.lock().unpark(id);
+        if parked.is_none() {
+            let mut idle_fns_guard = self.idle_fns.lock();
+            parked = idle_fns_guard
+                .iter()
+                .position(|task| task.id == id)
+                .and_then(|index| idle_fns_guard.remove(index));
+        }
+        if parked.is_some() {
+            self.report_cancelled(&parked);
+            drop(parked);
+            self.counters.cancelled(1);
+            self.tasks_cancelled(&[id]);
+            return true;
+        }
+        let mut running = self.running_intervals.lock();
+        if let Some(interval) = running.iter_mut().find(|interval| interval.id == id) {
+            let cancelled = !interval.cancelled;
+            interval.cancelled = true;
+            drop(running);
+            self.counters.cancelled(usize::from(cancelled));
+            return cancelled;
+        }
+        let task = self.remove(id);
+        drop(running);
+        let removed = task.is_some();
+        self.report_cancelled(&task);
+        // Dropped outside the lock, since callbacks may own anything.
+        drop(task);
+        self.counters.cancelled(usize::from(removed));
+        if removed {
+            self.notify_space();
+            self.tasks_cancelled(&[id]);
+        }
+        removed
+    }
+
+    /// Moves a pending task's deadline to `new_delay` from now, keeping its
+    /// callback and id. A task that was already ready goes back to sleeping.
+    ///
+    /// Returns `false` if no such task is waiting, either because the id is
+    /// unknown or because the task already started running. Meant for
+    /// timeouts that get pushed out on every bit of activity, like idle
+    /// timers and debounced saves.
+    pub fn reschedule(&self, id: TaskId, new_delay: Duration) -> bool {
+        self.drain_injector();
+        // Both queues stay locked throughout, so the task is never missing
+        // from them while it moves and the loop can't mistake that for idle.
+        let mut ready_fns_guard = self.ready_fns.lock();
+        let mut sleeping_fns_guard = self.sleeping_fns.lock();
+        let mut task = sleeping_fns_guard.remove(id);
+        if task.is_none() {
+            task = ready_fns_guard.remove(id);
+            if task.is_none() {
+                self.for_each_local(|local| {
+                    if let Some(index) = local.iter().position(|task| task.id == id) {
+                        task = local.remove(index);
+                    }
+                });
+            }
+            if task.is_some() {
+                self.counters.ready_removed(1);
+                self.counters.sleeping_added(1);
+            }
+        }
+        let Some(mut task) = task else {
+            return false;
+        };
+
+        let deadline = self.round_deadline(self.now() + new_delay);
+        task.deadline = Some(deadline);
+        task.phase = Phase::Timers;
+        task.seq = self.next_seq.fetch_add(1, AtomicOrdering::Relaxed);
+        sleeping_fns_guard.push(deadline, task.seq, task);
+        drop(sleeping_fns_guard);
+        drop(ready_fns_guard);
+        // The loop may be waiting on the old deadline.
+        self.wake.notify();
+        true
+    }
+
+    /// Cancels every pending task whose id is in `ids` and returns how many
+    /// were removed. Each queue is locked only once for the whole batch.
+    pub fn cancel_many(&self, ids: &[TaskId]) -> usize {
+        let ids: HashSet<TaskId> = ids.iter().copied().collect();
+
+        self.drain_injector();
+        let mut running = self.running_intervals.lock();
+        let mut cancelled = 0;
+        for interval in running.iter_mut() {
+            if ids.contains(&interval.id) && !interval.cancelled {
+                interval.cancelled = true;
+                cancelled += 1;
+            }
+        }
+
+        let mut ready_fns_guard = self.ready_fns.lock();
+        let mut removed = ready_fns_guard.extract(|task| ids.contains(&task.id));
+        drop(ready_fns_guard);
+        self.for_each_local(|local| {
+            let (gone, kept) = local.drain(..).partition(|task| ids.contains(&task.id));
+            *local = kept;
+            removed.extend(gone);
+        });
+        let removed_ready = removed.len();
+        self.counters.ready_removed(removed_ready);
+
+        let mut sleeping_fns_guard = self.sleeping_fns.lock();
+        let sleeping = sleeping_fns_guard.extract(|task| ids.contains(&task.id));
+        let removed_sleeping = sleeping.len();
+        self.counters.sleeping_removed(removed_sleeping);
+        drop(sleeping_fns_guard);
+        drop(running);
+        let mut gone: Vec<TaskId> = removed
+            .iter()
+            .chain(&sleeping)
+            .map(|task| task.id)
+            .collect();
+        self.report_cancelled(removed.iter().chain(&sleeping));
+        // Dropped outside the locks, since callbacks may own anything.
+        drop((removed, sleeping));
+        if removed_sleeping > 0 {
+            self.wake.notify();
+        }
+        let mut dependencies = self.dependencies.lock();
+        let parked: Vec<Task> = ids
+            .iter()
+            .filter_map(|id| dependencies.unpark(*id))
+            .collect();
+        drop(dependencies);
+        let mut idle_fns_guard = self.idle_fns.lock();
+        let (idle, kept) = idle_fns_guard
+            .drain(..)
+            .partition(|task| ids.contains(&task.id));
+        *idle_fns_guard = kept;
+        drop(idle_fns_guard);
+        let parked: Vec<Task> = parked.into_iter().chain::<VecDeque<Task>>(idle).collect();
+        gone.extend(parked.iter().map(|task| task.id));
+        cancelled += parked.len();
+        self.report_cancelled(&parked);
+        drop(parked);
+
+        cancelled += removed_ready + removed_sleeping;
+        self.counters.cancelled(cancelled);
+        if removed_ready + removed_sleeping > 0 {
+            self.notify_space();
+        }
+        self.tasks_cancelled(&gone);
+
+        cancelled
+    }
+
+    /// Cancels every pending task for which `pred` returns `true` and
+    /// returns how many were removed.
+    ///
+    /// `pred` sees the same snapshot as [`Scheduler::pending_tasks`] and is
+    /// called with no locks held. A task that starts running between the
+    /// snapshot and the removal is left alone.
+    pub fn cancel_where(&self, pred: impl Fn(&TaskInfo) -> bool) -> usize {
+        let ids: Vec<TaskId> = self
+            .pending_tasks()
+            .into_iter()
+            .filter(|info| pred(info))
+            .map(|info| info.id)
+            .collect();
+        if ids.is_empty() {
+            return 0;
+        }
+        self.cancel_many(&ids)
+    }
+
+    /// Drops every pending task and returns how many ready and sleeping
+    /// tasks were removed. Their [`Scheduler::on_close`] callbacks are
+    /// queued in their place.
+    ///
+    /// A callback that is running right now finishes, but if it belongs to
+    /// an interval, the interval is not run again. The dropped callbacks are
+    /// released after the queue locks, so their destructors may call back
+    /// into the scheduler.
+    pub fn clear(&self) -> (usize, usize) {
+        self.drop_pending(true)
+    }
+
+    /// Does the work of [`Scheduler::clear`]. The dropped tasks' close
+    /// callbacks are queued if `close` is set, and dropped with them if not.
+    fn drop_pending(&self, close: bool) -> (usize, usize) {
+        self.drain_injector();
+        let mut running = self.running_intervals.lock();
+        for interval in running.iter_mut() {
+            interval.cancelled = true;
+        }
+
+        let mut ready_fns_guard = self.ready_fns.lock();
+        let mut ready = ready_fns_guard.take_all();
+        drop(ready_fns_guard);
+        self.for_each_local(|local| ready.extend(local.drain(..)));
+        self.counters.ready_removed(ready.len());
+
+        let mut sleeping_fns_guard = self.sleeping_fns.lock();
+        let sleeping = sleeping_fns_guard.take_all();
+        self.counters.sleeping_removed(sleeping.len());
+        drop(sleeping_fns_guard);
+        drop(running);
+        let parked = self.dependencies.lock().take_all();
+        let microtasks = std::mem::take(&mut *self.microtasks.lock());
+        let idle = std::mem::take(&mut *self.idle_fns.lock());
+        let ids: Vec<TaskId> = ready
+            .iter()
+            .chain(&sleeping)
+            .chain(&parked)
+            .chain(&idle)
+            .map(|task| task.id)
+            .collect();
+        self.counters.cancelled(ids.len());
+        self.report_cancelled(ready.iter().chain(&sleeping).chain(&parked).chain(&idle));
+        drop((parked, microtasks, idle));
+        self.statuses.lock().cancelled(ids.iter().copied());
+        self.wake.notify();
+        self.notify_space();
+        match close {
+            true => self.queue_closers(&ids),
+            false => self.drop_closers(&ids),
+        }
+
+        (ready.len(), sleeping.len())
+    }
+
+    /// Takes the task with the given id out of whichever queue holds it.
+    ///
+    /// Both queues are locked for the whole lookup, so a timer that is
+    /// moving to the ready queue is found in one or the other.
+    pub(crate) fn remove(&self, id: TaskId) -> Option<Task> {
+        self.drain_injector();
+        let mut ready_fns_guard = self.ready_fns.lock();
+        let mut sleeping_fns_guard = self.sleeping_fns.lock();
+        if let Some(task) = ready_fns_guard.remove(id) {
+            self.counters.ready_removed(1);
+            return Some(task);
+        }
+        if let Some(task) = sleeping_fns_guard.remove(id) {
+            self.counters.sleeping_removed(1);
+            drop(sleeping_fns_guard);
+            drop(ready_fns_guard);
+            // The loop may be waiting on this timer's deadline.
+            self.wake.notify();
+            return Some(task);
+        }
+        let mut local_task = None;
+        self.for_each_local(|local| {
+            if let Some(index) = local.iter().position(|task| task.id == id) {
+                local_task = local.remove(index);
+            }
+        });
+        if local_task.is_some() {
+            self.counters.ready_removed(1);
+        }
+        local_task
+    }
+
+    /// Runs the task's callback, catching any panic so that one bad task
+    /// can't take the loop (and everything queued behind it) down with it.
+    fn execute(&self, mut task: Task) {
+        let id = task.id;
+        self.statuses.lock().started(id);
+        let now = self.now();
+        // Taken before the callback is, for the events after it.
+        let observed = self.subscribers.is_active().then(|| task.meta());
+        if let Some(task) = &observed {
+            self.emit(|at| SchedulerEvent::Started {
+                task: task.clone(),
+                at,
+            });
+        }
+        let waited = now.saturating_duration_since(task.deadline.unwrap_or(now));
+        self.counters.executed(waited);
+        if task.phase == Phase::Timers {
+            self.counters.timer_run(waited);
+            if let Some((threshold, hook)) = &self.config.deadline_miss {
+                if waited > *threshold {
+                    hook(task.meta(), waited);
+                }
+            }
+        }
+        let name = task.name.clone();
+        self.record(EventKind::Started, Some(id), name.as_ref());
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("task", id = %id, name = name.as_deref()).entered();
+        #[cfg(feature = "tracing")]
+        tracing::trace!("task started");
+        let meta = self.config.hooks.as_ref().map(|hooks| {
+            let meta = task.meta();
+            hooks.on_start(&meta);
+            (meta, Instant::now())
+        });
+        let on_finish = task
+            .execution
+            .as_mut()
+            .and_then(|execution| execution.on_finish.take());
+        let mut rescheduled = None;
+        let mut repeats = false;
+        let mut finished_as = TaskState::Completed;
+        // Timed from here, after the hooks, so only the callback counts.
+        let timeout = task
+            .execution
+            .as_ref()
+            .and_then(|execution| execution.timeout)
+            .map(|timeout| (timeout, task.meta(), self.now()));
+        let current = CurrentGuard::enter(self);
+        let watched = self
+            .watchdog
+            .as_ref()
+            .map(|watchdog| watchdog.enter(id, name.clone()));
+        let mut failure = None;
+        let result = match task.callback {
+            Callback::Once(callback) => {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| callback.call(self)));
+                failure = FAILURE
+                    .with(|failure| failure.borrow_mut().take())
+                    .map(|error| {
+                        let meta = TaskMeta {
+                            id,
+                            name: name.clone(),
+                            deadline: task.deadline,
+                            phase: task.phase,
+                        };
+                        (meta, error)
+                    });
+                result
+            }
+            Callback::Repeat(ref mut callback) => {
+                self.running_intervals.lock().push(RunningInterval {
+                    id,
+                    cancelled: false,
+                });
+                let result = panic::catch_unwind(AssertUnwindSafe(&mut *callback));
+                // Re-enqueue under the lock so a concurrent cancel either sees
+                // the task running or finds it back in the sleeping queue. An
+                // interval that panicked is not run again, since its state
+                // may be half-updated.
+                let mut running = self.running_intervals.lock();
+                let index = running
+                    .iter()
+                    .position(|interval| interval.id == id)
+                    .unwrap();
+                let cancelled = running.swap_remove(index).cancelled;
+                if cancelled {
+                    finished_as = TaskState::Cancelled;
+                }
+                if let (false, Ok(Some(delay))) = (cancelled, &result) {
+                    let deadline = self.now() + *delay;
+                    rescheduled = self.push(task, Some(deadline));
+                    repeats = true;
+                }
+                drop(running);
+                result.map(drop)
+            }
+        };
+        drop(watched);
+        drop(current);
+        let timed_out = timeout.and_then(|(timeout, meta, started)| {
+            let overran = self.now().saturating_duration_since(started) > timeout;
+            overran.then_some(meta)
+        });
+        if let (Ok(()), Some(on_finish)) = (&result, on_finish) {
+            on_finish(timed_out.is_some());
+        }
+        if let Some(meta) = timed_out {
+            self.counters.timed_out();
+            (self.config.on_timeout)(meta);
+        }
+        if let Some((meta, error)) = failure {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(error = %error, "task failed");
+            self.counters.failed();
+            (self.config.on_error)(meta, error);
+        }
+        if let (Some(hooks), Some((meta, started))) = (&self.config.hooks, meta) {
+            hooks.on_complete(&meta, started.elapsed());
+        }
+        let finished = match &result {
+            Ok(()) => EventKind::Completed {
+                duration: self.now().saturating_duration_since(now),
+            },
+            Err(_) => EventKind::Panicked,
+        };
+        self.record(finished, Some(id), name.as_ref());
+        if finished_as == TaskState::Cancelled {
+            self.record(EventKind::Cancelled, Some(id), name.as_ref());
+        }
+        if let Some(task) = observed {
+            let cancelled = (finished_as == TaskState::Cancelled).then(|| task.clone());
+            self.emit(|at| match result {
+                Ok(()) => SchedulerEvent::Completed {
+                    task,
+                    at,
+                    duration: at.saturating_duration_since(now),
+                },
+                Err(_) => SchedulerEvent::Panicked { task, at },
+            });
+            if let Some(task) = cancelled {
+                self.emit(|at| SchedulerEvent::Cancelled { task, at });
+            }
+        }
+        self.report_scheduled(rescheduled);
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(()) => tracing::trace!("task finished"),
+            Err(payload) => {
+                tracing::error!(panic = panic_message(payload.as_ref()), "task panicked")
+            }
+        }
+        // Released before the panic is reported: a panic still counts as
+        // having finished. A repeating task finishes with its last run.
+        self.statuses
+            .lock()
+            .stopped(id, (!repeats).then_some(finished_as));
+        if !repeats {
+            let closers = self.closers.lock().remove(&id);
+            drop(closers);
+            let released = self.dependencies.lock().finished(id);
+            for task in released {
+                self.schedule(task);
+            }
+        }
+        if let Err(payload) = result {
+            self.counters.panicked();
+            (self.config.on_panic)(id, name.as_deref(), payload);
+            if self.config.panic_policy != PanicPolicy::Continue {
+                self.panic_stop.store(true, AtomicOrdering::SeqCst);
+                self.wake.notify();
+            }
+        }
+    }
+
+    /// Rounds a timer's deadline up to the next multiple of
+    /// [`SchedulerBuilder::timer_granularity`].
+    fn round_deadline(&self, deadline: Instant) -> Instant {
+        let granularity = self.config.timer_granularity.as_nanos();
+        if granularity == 0 {
+            return deadline;
+        }
+        let since = deadline.saturating_duration_since(self.epoch).as_nanos();
+        let rounded = since.div_ceil(granularity) * granularity;
+        self.epoch + Duration::from_nanos(u64::try_from(rounded).unwrap_or(u64::MAX))
+    }
+
+    /// Whether the calling thread is one currently inside
+    /// [`Scheduler::run`] or [`Scheduler::run_pool`].
+    pub(crate) fn is_loop_thread(&self) -> bool {
+        self.loop_threads.lock().contains(&thread::current().id())
+    }
+
+    /// Takes the flag that lets only one loop run at a time, until the
+    /// guard drops.
+    fn claim_loop(&self) -> Result<Running<'_>, RunError> {
+        self.running
+            .compare_exchange(false, true, AtomicOrdering::SeqCst, AtomicOrdering::SeqCst)
+            .map(|_| Running(self))
+            .map_err(|_| RunError::AlreadyRunning)
+    }
+
+    /// Marks the calling thread as a loop thread until the guard drops.
+    pub(crate) fn enter_loop(&self) -> LoopThread<'_> {
+        self.loop_threads.lock().push(thread::current().id());
+        LoopThread(self)
+    }
+
+    /// Asks [`Scheduler::run`] to return as soon as the current callback
+    /// finishes, even if it is waiting for a timer.
+    ///
+    /// Callable from any thread or from inside a task. Pending tasks stay in
+    /// their queues; until [`Scheduler::reset`] is called, `run()` returns
+    /// immediately without executing them.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, AtomicOrdering::SeqCst);
+        self.wake.notify();
+    }
+
+    /// Shuts the scheduler down when the process gets SIGINT (Ctrl-C) or
+    /// SIGTERM.
+    ///
+    /// The first signal calls [`Scheduler::shutdown`], which ends `run()`,
+    /// [`Scheduler::run_forever`] or a loop started with
+    /// [`Scheduler::start`] once the running callback returns, even from a
+    /// wait for a timer; pending tasks stay queued. Every later signal also
+    /// [`clear`](Scheduler::clear)s the queues, for when a graceful stop
+    /// takes too long. Signals count for the whole process, however
+    /// many schedulers are watching.
+    ///
+    /// The process's own handlers for both signals are replaced the first
+    /// time this is called; calling it again, for this scheduler or
+    /// another, only adds to what is shut down. Fails if the handlers can't
+    /// be installed.
+    #[cfg(all(feature = "signals", unix))]
+    pub fn shutdown_on_ctrl_c(&self) -> std::io::Result<()> {
+        crate::signals::watch(self.me.clone())
+    }
+
+    /// Clears a previous [`Scheduler::shutdown`] so that `run()` executes
+    /// tasks again.
+    pub fn reset(&self) {
+        self.shutdown.store(false, AtomicOrdering::SeqCst);
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(AtomicOrdering::SeqCst)
+    }
+
+    /// The policy that is ending the current run because a callback
+    /// panicked, if one is.
+    fn stopping_on_panic(&self) -> Option<PanicPolicy> {
+        self.panic_stop
+            .load(AtomicOrdering::SeqCst)
+            .then_some(self.config.panic_policy)
+    }
+
+    /// Stops dispatching tasks, without dropping any, until
+    /// [`Scheduler::resume`] is called.
+    ///
+    /// The callback that is running finishes, then the loop neither runs
+    /// ready tasks nor promotes timers. Time keeps passing, so timers that
+    /// fall due in the meantime fire straight after resuming. `schedule()`
+    /// keeps accepting work, and [`Scheduler::tick`] runs nothing while
+    /// paused. Callable from any thread or from inside a task.
+    pub fn pause(&self) {
+        self.paused.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Undoes [`Scheduler::pause`] and wakes the loop.
+    pub fn resume(&self) {
+        self.paused.store(false, AtomicOrdering::SeqCst);
+        self.wake.notify();
+    }
+
+    /// Whether [`Scheduler::pause`] is in effect.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Executes tasks until both the ready and the sleeping queue are empty,
+    /// or until [`Scheduler::shutdown`] is called, and reports what ran.
+    ///
+    /// While the scheduler is paused with work still queued, `run()` waits
+    /// for [`Scheduler::resume`] rather than returning. With the `io`
+    /// feature, a descriptor still registered for readiness keeps it
+    /// running too.
+    ///
+    /// Only one loop runs at a time: if another thread is running it, or
+    /// `run()` is called from one of the loop's own callbacks, it returns
+    /// [`RunError::AlreadyRunning`] straight away. A run that a
+    /// [`PanicPolicy`] ends early returns [`RunError::StoppedOnPanic`].
+    pub fn run(&self) -> Result<RunReport, RunError> {
+        self.run_loop(None, false)
+    }
+
+    /// Like [`Scheduler::run`], but gives up once `budget` has elapsed.
+    /// Returns how many tasks ran, or the same errors as `run()`.
+    pub fn run_for(&self, budget: Duration) -> Result<usize, RunError> {
+        self.run_until(self.now() + budget)
+    }
+
+    /// Like [`Scheduler::run`], but returns once `deadline` (on the
+    /// scheduler's clock) is reached, even
+    /// if there is more ready work. A wait for a timer that falls after
+    /// `deadline` returns straight away instead of sleeping past it. Returns
+    /// how many tasks ran, or the same errors as `run()`.
+    pub fn run_until(&self, deadline: Instant) -> Result<usize, RunError> {
+        self.run_loop(Some(deadline), false)
+            .map(|report| report.tasks_executed)
+    }
+
+    /// Runs the loop on the calling thread until [`Scheduler::shutdown`] is
+    /// called.
+    ///
+    /// Unlike [`Scheduler::run`], this does not return when the queues
+    /// drain. The loop blocks on a condition variable instead, and
+    /// `schedule()` from any thread wakes it immediately. Fails the same
+    /// way as `run()`.
+    pub fn run_forever(&self) -> Result<(), RunError> {
+        self.run_loop(None, true).map(drop)
+    }
+
+    /// Like [`Scheduler::run`], but executes ready tasks on `workers`
+    /// threads at once: the calling thread plus `workers - 1` scoped ones,
+    /// all of which have exited by the time this returns.
+    ///
+    /// Idle workers park until `schedule()` wakes them. Whichever worker is
+    /// idle promotes due timers, and one of them at a time waits for the
+    /// next deadline, so a simulated [`Clock`] still advances once per
+    /// deadline. [`Scheduler::shutdown`] stops every worker as soon as its
+    /// current callback finishes.
+    ///
+    /// Ordering guarantees are weaker than with a single loop thread. Tasks
+    /// still leave the ready queue in the usual order, but up to `workers`
+    /// of them run at the same time and may finish in any order, and a task
+    /// scheduled from inside a callback can start before that callback
+    /// returns. Runs of one interval never overlap, since the next run is
+    /// only queued once the previous one has returned.
+    ///
+    /// With the default [`SchedulerPolicy::Fifo`], no starvation threshold
+    /// and no pending limit, each worker also keeps a local queue. Ready
+    /// [`Priority::Normal`] tasks scheduled from one of its callbacks go
+    /// there, and the worker runs the newest of them first. An idle worker
+    /// takes from the shared queue, then steals the oldest task from
+    /// another worker ([`Metrics::steals`]). Every so often a worker checks
+    /// the shared queue before its own, so that no task waits forever.
+    /// Whatever is left in a local queue when the pool stops goes back to
+    /// the shared queue in scheduling order.
+    ///
+    /// Like `run()`, it returns [`RunError::AlreadyRunning`] if the loop is
+    /// running already.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn run_pool(&self, workers: usize) -> Result<RunReport, RunError> {
+        assert!(workers > 0, "Scheduler::run_pool needs at least one worker");
+        let _running = self.claim_loop()?;
+        let started = self.now();
+        let before = self.counters.snapshot();
+        self.panic_stop.store(false, AtomicOrdering::SeqCst);
+        // Local queues would bypass the priority lanes, deadline ordering,
+        // aging and the pending limit.
+        let use_locals = self.config.policy == SchedulerPolicy::Fifo
+            && self.config.starvation_threshold.is_none()
+            && self.config.max_pending.is_none();
+        let pool = Pool {
+            locals: match use_locals {
+                true => (0..workers).map(|_| Arc::default()).collect(),
+                false => Vec::new(),
+            },
+            busy: Mutex::new(0),
+            done: AtomicBool::new(false),
+            timer_claimed: AtomicBool::new(false),
+        };
+        self.locals.lock().extend(pool.locals.iter().cloned());
+
+        let (executed, time_sleeping) = thread::scope(|scope| {
+            let pool = &pool;
+            let helpers: Vec<_> = (1..workers)
+                .map(|index| scope.spawn(move || self.pool_worker(pool, index)))
+                .collect();
+            let (mut executed, mut time_sleeping) = self.pool_worker(pool, 0);
+            for helper in helpers {
+                let (ran, slept) = helper
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic));
+                executed += ran;
+                time_sleeping += slept;
+            }
+            (executed, time_sleeping)
+        });
+        self.locals
+            .lock()
+            .retain(|local| !pool.locals.iter().any(|own| Arc::ptr_eq(local, own)));
+        if self.stopping_on_panic() == Some(PanicPolicy::AbortAll) {
+            self.drop_pending(false);
+        }
+
+        let after = self.counters.snapshot();
+        RunReport {
+            tasks_executed: executed,
+            timers_fired: (after.timers_fired - before.timers_fired) as usize,
+            total_runtime: self.now().saturating_duration_since(started),
+            time_sleeping,
+            panics: (after.panics - before.panics) as usize,
+            stopped_on_panic: self.stopping_on_panic(),
+        }
+        .into_result()
+    }
+
+    /// One worker of [`Scheduler::run_pool`]. Returns how many tasks it ran
+    /// and how long it spent waiting for timers.
+    fn pool_worker(&self, pool: &Pool, index: usize) -> (usize, Duration) {
+        let _loop_thread = self.enter_loop();
+        let _local = pool
+            .locals
+            .get(index)
+            .map(|local| LocalWorker::enter(self, local.clone()));
+        let mut executed = 0;
+        let mut time_sleeping = Duration::ZERO;
+        loop {
+            // Read before looking at the queues, so that anything scheduled
+            // from here on cuts the wait below short.
+            let seen = self.wake.generation();
+            let stopping = self.stopping_on_panic();
+            if self.is_shutdown()
+                || pool.done.load(AtomicOrdering::SeqCst)
+                || stopping == Some(PanicPolicy::AbortAll)
+            {
+                break;
+            }
+            if self.is_paused() {
+                if !self.finish_pool_if_idle(pool) {
+                    self.wake.wait_past(seen, None);
+                }
+                continue;
+            }
+
+            executed += self.run_microtasks();
+            let next_deadline = match stopping {
+                None => {
+                    self.poll_channels();
+                    self.promote_expired()
+                }
+                Some(_) => None,
+            };
+            let shared_first = executed % SHARED_QUEUE_INTERVAL == SHARED_QUEUE_INTERVAL - 1;
+            if let Some((task, busy)) = self.pop_pool_task(pool, index, shared_first) {
+                if !self.is_idle() {
+                    // Hand the rest, and the timers, to any idle worker.
+                    self.wake.notify();
+                }
+                if let Some(task) = self.take_rate_token(task) {
+                    self.execute(task);
+                    executed += 1 + self.run_microtasks();
+                }
+                drop(busy);
+                continue;
+            }
+            if stopping.is_some() {
+                // Draining, and nothing left to take.
+                break;
+            }
+
+            let poll_timeout = self.channel_poll_timeout();
+            match next_deadline {
+                Some(deadline) if !pool.timer_claimed.swap(true, AtomicOrdering::SeqCst) => {
+                    let sleep_started = self.now();
+                    let remaining = deadline.saturating_duration_since(sleep_started);
+                    match &self.config.clock {
+                        Some(clock) => clock.sleep(remaining),
+                        None => self.wake.wait_past(
+                            seen,
+                            Some(poll_timeout.map_or(remaining, |timeout| remaining.min(timeout))),
+                        ),
+                    }
+                    let slept = self.now().saturating_duration_since(sleep_started);
+                    self.record_sleep(slept);
+                    time_sleeping += slept;
+                    pool.timer_claimed.store(false, AtomicOrdering::SeqCst);
+                }
+                // Another worker is waiting for the timer and will hand on
+                // whatever it can't run itself.
+                Some(_) => self.wake.wait_past(seen, None),
+                None => {
+                    if !self.finish_pool_if_idle(pool) {
+                        self.wake.wait_past(seen, poll_timeout);
+                    }
+                }
+            }
+        }
+        (executed, time_sleeping)
+    }
+
+    /// Takes worker `index`'s next task: its own newest, then the shared
+    /// queue's first (in the other order if `shared_first`), then the
+    /// oldest from another worker.
+    fn pop_pool_task<'a>(
+        &'a self,
+        pool: &'a Pool,
+        index: usize,
+        shared_first: bool,
+    ) -> Option<(Task, Busy<'a>)> {
+        let mut busy = pool.busy.lock();
+        let own = || {
+            let task = pool.locals.get(index)?.lock().pop_back()?;
+            self.counters.ready_removed(1);
+            Some(task)
+        };
+        let task = match shared_first {
+            true => self.pop_ready().or_else(own),
+            false => own().or_else(|| self.pop_ready()),
+        };
+        // Timers were promoted just before, so with nothing ready or left
+        // to steal, the worker would otherwise wait.
+        let task = task
+            .or_else(|| self.steal(pool, index))
+            .or_else(|| self.idle_fns.lock().pop_front())?;
+        *busy += 1;
+        Some((task, Busy(self, pool)))
+    }
+
+    /// Takes the oldest task from the first other worker that has one.
+    fn steal(&self, pool: &Pool, index: usize) -> Option<Task> {
+        let workers = pool.locals.len();
+        (1..workers).find_map(|offset| {
+            let victim = &pool.locals[(index + offset) % workers];
+            let task = victim.lock().pop_front()?;
+            self.counters.ready_removed(1);
+            self.counters.stolen();
+            Some(task)
+        })
+    }
+
+    /// Ends the pool if no worker is running a callback and nothing is
+    /// queued. Returns whether it did.
+    fn finish_pool_if_idle(&self, pool: &Pool) -> bool {
+        let busy = pool.busy.lock();
+        if *busy > 0 || !self.is_drained() {
+            return false;
+        }
+        pool.done.store(true, AtomicOrdering::SeqCst);
+        drop(busy);
+        self.wake.notify();
+        true
+    }
+
+    /// Drives the loop from an async runtime rather than blocking a thread
+    /// on it, for example with `tokio::spawn(scheduler.run_async())`.
+    ///
+    /// The future resolves like [`Scheduler::run`], with the same report,
+    /// once the queues drain or the scheduler is shut down. Each poll runs
+    /// a batch of ready tasks and then yields, so that a busy loop doesn't
+    /// starve the runtime's other tasks. When the loop would wait,
+    /// `schedule()` wakes the future, and a helper thread of its own wakes
+    /// it at the next timer deadline; nothing depends on the runtime's
+    /// timer.
+    ///
+    /// [`SchedulerBuilder::async_timer`] replaces the helper thread, for
+    /// runtimes that offer a timer of their own; [`AsyncTimer`] shows how to
+    /// use tokio's. The loop doesn't wait on
+    /// [`Scheduler::register_readable`] descriptors this way, and with a
+    /// custom [`Clock`] but no such timer the future sleeps through
+    /// [`Clock::sleep`], on whichever thread polls it. Resolves to
+    /// [`RunError::AlreadyRunning`] if the loop was already running when
+    /// the future was first polled.
+    pub fn run_async(self: &Arc<Self>) -> RunAsync {
+        RunAsync::new(self.clone())
+    }
+
+    /// Claims the loop for [`Scheduler::run_async`] until
+    /// [`Scheduler::release_loop`].
+    pub(crate) fn start_async_run(&self) -> Result<Progress, RunError> {
+        std::mem::forget(self.claim_loop()?);
+        self.panic_stop.store(false, AtomicOrdering::SeqCst);
+        Ok(Progress {
+            started: self.now(),
+            before: self.counters.snapshot(),
+            executed: 0,
+            time_sleeping: Duration::ZERO,
+        })
+    }
+
+    /// The [`SchedulerBuilder::async_timer`], if one was set.
+    pub(crate) fn async_timer(&self) -> Option<&Arc<dyn AsyncTimer>> {
+        self.config.async_timer.as_ref()
+    }
+
+    /// Lets another loop run after [`Scheduler::start_async_run`].
+    pub(crate) fn release_loop(&self) {
+        drop(Running(self));
+    }
+
+    /// One poll's worth of [`Scheduler::run_async`]: runs up to a batch of
+    /// ready tasks, or says how long to wait for more, as an iteration of
+    /// [`Scheduler::run`] would. Before a wait, `waker` is left for the
+    /// next notification.
+    pub(crate) fn run_turn(&self, progress: &mut Progress, waker: &Waker) -> Turn {
+        // Read before looking at the queues, so that anything scheduled
+        // from here on wakes the future.
+        let seen = self.wake.generation();
+        let aborting = || self.stopping_on_panic() == Some(PanicPolicy::AbortAll);
+        let should_yield = || self.is_shutdown() || aborting() || self.is_paused();
+        let finish = |progress: &mut Progress| {
+            Turn::Done(self.finish_run(
+                &should_yield,
+                progress.started,
+                progress.before,
+                progress.executed,
+                progress.time_sleeping,
+            ))
+        };
+        if self.is_shutdown() || aborting() || self.stopping_on_panic().is_some() {
+            return finish(progress);
+        }
+        if self.is_paused() {
+            return match self.is_drained() {
+                true => finish(progress),
+                false => Turn::Wait(None),
+            };
+        }
+
+        progress.executed += self.run_microtasks();
+        self.poll_channels();
+        let next_deadline = self.promote_expired();
+        let taken = std::cell::Cell::new(0);
+        let ran = self.run_active(&|| {
+            taken.set(taken.get() + 1);
+            should_yield() || taken.get() > ASYNC_BATCH
+        });
+        if ran > 0 {
+            progress.executed += ran;
+            return Turn::Yield;
+        }
+        if !should_yield() && self.no_timer_due(next_deadline) {
+            let ran = self.run_idle();
+            if ran > 0 {
+                progress.executed += ran;
+                return Turn::Yield;
+            }
+        }
+        if let (Some(hooks), false) = (&self.config.hooks, should_yield()) {
+            match hooks.on_idle(next_deadline) {
+                IdleAction::Sleep => {}
+                IdleAction::Return => return finish(progress),
+                IdleAction::Continue => return Turn::Yield,
+            }
+        }
+
+        self.shrink_when_idle();
+        if next_deadline.is_none() && self.is_drained() {
+            return finish(progress);
+        }
+        let sleeps = self.config.async_timer.is_none();
+        if let (Some(clock), Some(deadline), true) = (&self.config.clock, next_deadline, sleeps) {
+            let sleep_started = self.now();
+            clock.sleep(deadline.saturating_duration_since(sleep_started));
+            let slept = self.now().saturating_duration_since(sleep_started);
+            self.record_sleep(slept);
+            progress.time_sleeping += slept;
+            return Turn::Yield;
+        }
+        if !self.wake.wake_past(seen, waker) {
+            // Something came in while we looked.
+            return Turn::Yield;
+        }
+        let poll_by = self
+            .channel_poll_timeout()
+            .map(|timeout| self.now() + timeout);
+        Turn::Wait(match (next_deadline, poll_by) {
+            (Some(deadline), Some(poll_by)) => Some(deadline.min(poll_by)),
+            (deadline, poll_by) => deadline.or(poll_by),
+        })
+    }
+
+    /// Starts [`Scheduler::run_forever`] on a dedicated background thread.
+    ///
+    /// The loop keeps going until the returned handle stops it, or a
+    /// [`PanicPolicy`] does. If the loop is already running elsewhere, the
+    /// thread panics with [`RunError::AlreadyRunning`], which
+    /// [`RunnerHandle::stop`] and [`RunnerHandle::join`] pass on.
+    pub fn start(self: &Arc<Self>) -> RunnerHandle {
+        let scheduler = self.clone();
+        let thread = thread::Builder::new()
+            .name("revent-loop".into())
+            .spawn(move || {
+                if let Err(error @ RunError::AlreadyRunning) = scheduler.run_forever() {
+                    panic!("{}", error);
+                }
+            })
+            .expect("failed to spawn the scheduler thread");
+        RunnerHandle::new(self.clone(), thread)
+    }
+
+    /// Runs one non-blocking iteration of the loop, for embedding the
+    /// scheduler in a loop you drive yourself.
+    ///
+    /// Due timers are promoted, then the tasks that were ready at that point
+    /// run; anything they schedule waits for the next tick, except
+    /// [`Scheduler::next_tick`] callbacks, which run first thing. A tick
+    /// that finds nothing else to do runs one [`Scheduler::schedule_idle`]
+    /// task instead. Unlike [`Scheduler::run`], `tick()` never sleeps.
+    pub fn tick(&self) -> TickResult {
+        if self.is_paused() {
+            return TickResult {
+                executed: 0,
+                next_deadline: None,
+            };
+        }
+        let mut executed = self.run_microtasks();
+        self.promote_expired();
+        let ready = self.ready_len();
+
+        let mut taken = 0;
+        while taken < ready && !self.is_shutdown() && !self.is_paused() {
+            let Some(task) = self.pop_ready() else {
+                break;
+            };
+            taken += 1;
+            if let Some(task) = self.take_rate_token(task) {
+                self.execute(task);
+                executed += 1 + self.run_microtasks();
+            }
+        }
+
+        if executed == 0 && !self.is_shutdown() && !self.is_paused() {
+            let next_deadline = self.promote_expired();
+            if self.ready_len() == 0 && self.no_timer_due(next_deadline) {
+                executed += self.run_idle();
+            }
+        }
+
+        let next_deadline = if self.ready_len() > 0 || !self.idle_fns.lock().is_empty() {
+            Some(self.now())
+        } else {
+            self.next_deadline()
+        };
+        TickResult {
+            executed,
+            next_deadline,
+        }
+    }
+
+    /// Takes out every task that is due, without running it, for callers
+    /// that run callbacks themselves with [`Scheduler::run_task`], for
+    /// example on a thread a framework insists on.
+    ///
+    /// Timers whose deadline has passed are promoted first, then the ready
+    /// queue is emptied in the order the loop would run it; later timers
+    /// stay in place. Like `run()`, this claims the loop for as long as it
+    /// takes, so it fails with [`RunError::AlreadyRunning`] while a loop is
+    /// running.
+    pub fn poll_due(&self) -> Result<Vec<Task>, RunError> {
+        let _running = self.claim_loop()?;
+        self.promote_expired();
+        let mut due = Vec::with_capacity(self.ready_len());
+        while let Some(task) = self.pop_ready() {
+            // Held back by the rate limit, it sleeps again and isn't due.
+            due.extend(self.take_rate_token(task));
+        }
+        Ok(due)
+    }
+
+    /// Runs a task taken out with [`Scheduler::poll_due`] on the calling
+    /// thread, just as the loop would have.
+    ///
+    /// Panics are caught and reported through the hooks, an interval is
+    /// queued again for its next run, and tasks that wait on this one are
+    /// released. A task that is dropped instead never counts as finished.
+    pub fn run_task(&self, task: Task) {
+        self.execute(task);
+    }
+
+    /// Moves every timer that is already due into the ready queue in one
+    /// sweep and returns the deadline of the earliest timer still pending.
+    /// Newly scheduled tasks are taken in first.
+    fn promote_expired(&self) -> Option<Instant> {
+        self.drain_injector();
+        let now = self.now();
+        // The ready queue is locked first and kept until the due timers are
+        // in it, so an idle check never sees them in neither queue.
+        let mut ready_tasks = self.ready_fns.lock();
+        let mut sleeping_tasks = self.sleeping_fns.lock();
+
+        // Overdue timers move straight to the ready queue instead of
+        // underflowing a wait.
+        let mut due = sleeping_tasks.pop_due(now);
+        // Timers with slack may join a wakeup that is happening anyway.
+        let max_slack = Duration::from_nanos(self.max_slack.load(AtomicOrdering::Relaxed));
+        if !due.is_empty() && !max_slack.is_zero() {
+            due.extend(sleeping_tasks.pop_early(now + max_slack, |deadline, task| {
+                deadline <= now + task.slack()
+            }));
+        }
+        #[cfg(feature = "tracing")]
+        for task in &due {
+            tracing::trace!(id = %task.id, name = task.name.as_deref(), "timer expired");
+        }
+        let next_deadline = sleeping_tasks.next_deadline();
+        self.counters.sleeping_removed(due.len());
+        self.counters.timers_fired(due.len());
+        drop(sleeping_tasks);
+
+        if !due.is_empty() {
+            let fired: Vec<TaskMeta> = match self.subscribers.is_active() {
+                true => due.iter().map(Task::meta).collect(),
+                false => Vec::new(),
+            };
+            self.counters.ready_added(due.len());
+            ready_tasks.extend(due);
+            drop(ready_tasks);
+            for task in fired {
+                self.emit(|at| SchedulerEvent::TimerFired { task, at });
+            }
+        }
+        next_deadline
+    }
+
+    /// How many tasks are waiting in the ready queue, including the local
+    /// queues of any [`Scheduler::run_pool`] workers.
+    pub fn ready_len(&self) -> usize {
+        self.drain_injector();
+        let mut len = self.ready_fns.lock().len();
+        self.for_each_local(|local| len += local.len());
+        len
+    }
+
+    /// How many timers are waiting in the sleeping queue.
+    pub fn sleeping_len(&self) -> usize {
+        self.drain_injector();
+        self.sleeping_fns.lock().len()
+    }
+
+    /// How many tasks the ready queue has room for without growing. Under
+    /// [`SchedulerPolicy::Fifo`] that is the total over its lanes, which
+    /// each grow on their own; see [`Scheduler::reserve_ready`].
+    pub fn ready_capacity(&self) -> usize {
+        self.drain_injector();
+        self.ready_fns.lock().capacity()
+    }
+
+    /// How many timers the sleeping queue holds before it has to grow.
+    pub fn sleeping_capacity(&self) -> usize {
+        self.drain_injector();
+        self.sleeping_fns.lock().capacity()
+    }
+
+    /// Makes room for at least `additional` more ready tasks on top of
+    /// those already queued.
+    ///
+    /// Under [`SchedulerPolicy::Fifo`] the ready queue keeps a lane per
+    /// priority and phase; the room goes to normal-priority timers and to
+    /// normal-priority immediates, each. Other lanes grow as needed.
+    pub fn reserve_ready(&self, additional: usize) {
+        self.ready_fns.lock().reserve(additional);
+    }
+
+    /// Makes room for at least `additional` more timers on top of those
+    /// already sleeping. With a
+    /// [`TimerBackend::Wheel`](crate::TimerBackend::Wheel) this is room in
+    /// the wheel's index; its slots grow as needed.
+    pub fn reserve_sleeping(&self, additional: usize) {
+        self.sleeping_fns.lock().reserve(additional);
+    }
+
+    /// Gives back the room the ready and sleeping queues have grown to
+    /// beyond what they hold, keeping the `keep` of a
+    /// [`ShrinkPolicy::WhenIdle`] if there is one.
+    pub fn shrink_to_fit(&self) {
+        let keep = match self.config.shrink {
+            ShrinkPolicy::Never => 0,
+            ShrinkPolicy::WhenIdle { keep } => keep,
+        };
+        self.ready_fns.lock().shrink_to(keep);
+        self.sleeping_fns.lock().shrink_to(keep);
+    }
+
+    /// [`ShrinkPolicy::WhenIdle`], for the loop to call once it has run out
+    /// of ready tasks.
+    fn shrink_when_idle(&self) {
+        let ShrinkPolicy::WhenIdle { keep } = self.config.shrink else {
+            return;
+        };
+        let oversized = |len: usize, capacity: usize| capacity > 2 * len.max(keep);
+        let mut ready = self.ready_fns.lock();
+        if oversized(ready.len(), ready.capacity()) {
+            ready.shrink_to(keep);
+        }
+        drop(ready);
+        let mut sleeping = self.sleeping_fns.lock();
+        if oversized(sleeping.len(), sleeping.capacity()) {
+            sleeping.shrink_to(keep);
+        }
+    }
+
+    /// The number of tasks waiting in either queue. A callback that is
+    /// running right now is not counted.
+    pub fn pending_count(&self) -> usize {
+        self.ready_len() + self.sleeping_len()
+    }
+
+    /// How many tasks from [`Scheduler::schedule_idle`] are waiting.
+    pub fn idle_len(&self) -> usize {
+        self.idle_fns.lock().len()
+    }
+
+    /// Whether both queues are empty. Tasks from [`Scheduler::schedule_idle`]
+    /// don't count.
+    pub fn is_idle(&self) -> bool {
+        self.drain_injector();
+        let mut idle = self.ready_fns.lock().is_empty() && self.sleeping_fns.lock().is_empty();
+        self.for_each_local(|local| idle &= local.is_empty());
+        idle
+    }
+
+    /// Describes every task waiting in either queue, ready tasks first in
+    /// the order they would run, then timers by deadline. Callbacks stay
+    /// where they are. Under [`Scheduler::run_pool`], tasks on the workers'
+    /// local queues follow the shared ones, oldest first. Idle tasks come
+    /// last.
+    ///
+    /// Both queues are locked together (in the same order as everywhere
+    /// else), so the snapshot never shows a timer in the middle of moving
+    /// to the ready queue.
+    pub fn pending_tasks(&self) -> Vec<TaskInfo> {
+        self.drain_injector();
+        let ready_fns_guard = self.ready_fns.lock();
+        let sleeping_fns_guard = self.sleeping_fns.lock();
+        let mut local = Vec::new();
+        self.for_each_local(|queue| {
+            local.extend(queue.iter().map(|task| task.info(QueuedIn::Ready)))
+        });
+
+        let ready = ready_fns_guard
+            .tasks()
+            .into_iter()
+            .map(|task| task.info(QueuedIn::Ready))
+            .chain(local);
+        let sleeping = sleeping_fns_guard
+            .sorted()
+            .into_iter()
+            .map(|task| task.info(QueuedIn::Sleeping));
+        let mut pending: Vec<TaskInfo> = ready.chain(sleeping).collect();
+        let idle_fns_guard = self.idle_fns.lock();
+        pending.extend(idle_fns_guard.iter().map(|task| task.info(QueuedIn::Idle)));
+        pending
+    }
+
+    /// Every pending task, with its priority and time left until it is due,
+    /// and the scheduler's [`metrics`](Scheduler::metrics), for shipping to
+    /// a monitoring system; see [`ScheduleSnapshot::to_json`].
+    ///
+    /// All the queues stay locked while it is taken, so the tasks and the
+    /// counters describe the same moment.
+    pub fn snapshot(&self) -> ScheduleSnapshot {
+        self.drain_injector();
+        let now = self.now();
+        let describe = |task: &Task, queue: QueuedIn| TaskSnapshot {
+            id: task.id,
+            name: task.name.clone(),
+            priority: task.priority,
+            queue,
+            remaining: task.deadline.map_or(Duration::ZERO, |deadline| {
+                deadline.saturating_duration_since(now)
+            }),
+        };
+        let ready_fns_guard = self.ready_fns.lock();
+        let sleeping_fns_guard = self.sleeping_fns.lock();
+        let mut tasks: Vec<TaskSnapshot> = ready_fns_guard
+            .tasks()
+            .into_iter()
+            .map(|task| describe(task, QueuedIn::Ready))
+            .collect();
+        self.for_each_local(|local| {
+            tasks.extend(local.iter().map(|task| describe(task, QueuedIn::Ready)))
+        });
+        tasks.extend(
+            sleeping_fns_guard
+                .sorted()
+                .into_iter()
+                .map(|task| describe(task, QueuedIn::Sleeping)),
+        );
+        let idle_fns_guard = self.idle_fns.lock();
+        tasks.extend(
+            idle_fns_guard
+                .iter()
+                .map(|task| describe(task, QueuedIn::Idle)),
+        );
+        let metrics = self.metrics();
+        drop(idle_fns_guard);
+        drop(sleeping_fns_guard);
+        drop(ready_fns_guard);
+        ScheduleSnapshot { tasks, metrics }
+    }
+
+    /// Where the task with the given id is in its life: queued, running, or
+    /// finished as one of the last [`SchedulerBuilder::status_history`]
+    /// tasks to do so. Anything else is [`TaskState::Unknown`].
+    ///
+    /// A task is taken off its queue a moment before it is marked as
+    /// running, so a status read right then can come back `Unknown`.
+    ///
+    /// ```
+    /// use revent_loop::{Scheduler, TaskState};
+    ///
+    /// let scheduler = Scheduler::new();
+    /// let id = scheduler.spawn(|| {}).id();
+    /// assert_eq!(scheduler.status(id), TaskState::Ready);
+    /// scheduler.run().unwrap();
+    /// assert_eq!(scheduler.status(id), TaskState::Completed);
+    /// ```
+    pub fn status(&self, id: TaskId) -> TaskState {
+        self.drain_injector();
+        let ready_fns_guard = self.ready_fns.lock();
+        let sleeping_fns_guard = self.sleeping_fns.lock();
+        if let Some(task) = sleeping_fns_guard.iter().find(|task| task.id == id) {
+            // A timer that is due counts as ready before the loop gets round
+            // to moving it.
+            return match task.deadline {
+                Some(deadline) if deadline > self.now() => TaskState::Sleeping { deadline },
+                _ => TaskState::Ready,
+            };
+        }
+        let mut ready = ready_fns_guard.tasks().iter().any(|task| task.id == id);
+        self.for_each_local(|local| ready |= local.iter().any(|task| task.id == id));
+        ready |= self.idle_fns.lock().iter().any(|task| task.id == id);
+        drop(sleeping_fns_guard);
+        drop(ready_fns_guard);
+        ready |= self.microtasks.lock().iter().any(|task| task.id == id);
+        if ready {
+            return TaskState::Ready;
+        }
+        if self.dependencies.lock().is_waiting(id) {
+            return TaskState::Waiting;
+        }
+        self.statuses.lock().get(id).unwrap_or(TaskState::Unknown)
+    }
+
+    /// The deadline of the earliest pending timer, or `None` if no timers
+    /// are waiting.
+    ///
+    /// Only the sleeping queue is considered: with ready tasks queued the
+    /// scheduler has work right now regardless, which
+    /// [`Scheduler::ready_len`] tells you. [`TickResult::next_deadline`]
+    /// combines the two.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.drain_injector();
+        self.sleeping_fns.lock().next_deadline()
+    }
+
+    /// A snapshot of the scheduler's counters. Reading them takes none of
+    /// the queue locks, so this is cheap to call from any thread, even
+    /// while the loop is busy.
+    pub fn metrics(&self) -> Metrics {
+        self.counters.snapshot()
+    }
+
+    /// A channel of [`SchedulerEvent`]s for every task from now on, for
+    /// watching the scheduler from another thread rather than from inside
+    /// it as [`SchedulerHooks`](crate::SchedulerHooks) do.
+    ///
+    /// Each receiver holds up to [`SchedulerBuilder::event_buffer`] events.
+    /// The loop never waits for a subscriber: events that don't fit are
+    /// dropped and counted in [`Metrics::events_dropped`]. Dropping the
+    /// receiver unsubscribes it.
+    ///
+    /// ```
+    /// use revent_loop::{Scheduler, SchedulerEvent};
+    ///
+    /// let scheduler = Scheduler::new();
+    /// let events = scheduler.subscribe();
+    /// scheduler.spawn(|| {});
+    /// scheduler.run().unwrap();
+    /// let kinds: Vec<_> = events
+    ///     .try_iter()
+    ///     .map(|event| match event {
+    ///         SchedulerEvent::Scheduled { .. } => "scheduled",
+    ///         SchedulerEvent::Started { .. } => "started",
+    ///         SchedulerEvent::Completed { .. } => "completed",
+    ///         _ => "other",
+    ///     })
+    ///     .collect();
+    /// assert_eq!(kinds, ["scheduled", "started", "completed"]);
+    /// ```
+    pub fn subscribe(&self) -> Receiver<SchedulerEvent> {
+        self.subscribers.subscribe(self.config.event_buffer)
+    }
+
+    /// The last [`SchedulerBuilder::event_history`] things the scheduler
+    /// did, oldest first. Always recorded, for finding out afterwards what
+    /// led up to a problem.
+    pub fn recent_events(&self) -> Vec<EventRecord> {
+        self.recent.lock().records()
+    }
+
+    /// A readable report of the scheduler's counters, its pending tasks and
+    /// its [`recent_events`](Scheduler::recent_events), for logging when
+    /// something has gone wrong. The format may change between versions.
+    pub fn dump_state(&self) -> String {
+        use std::fmt::Write;
+
+        fn task(id: TaskId, name: Option<&str>) -> String {
+            match name {
+                Some(name) => format!("task {} ({})", id, name),
+                None => format!("task {}", id),
+            }
+        }
+
+        let now = self.now();
+        let metrics = self.metrics();
+        let pending = self.pending_tasks();
+        let recent = self.recent_events();
+        let mut dump = String::new();
+        // Writing to a `String` can't fail.
+        let _ = writeln!(
+            dump,
+            "scheduler: {} scheduled, {} executed, {} cancelled, {} panicked",
+            metrics.scheduled, metrics.executed, metrics.cancelled, metrics.panics
+        );
+        let _ = writeln!(dump, "pending tasks: {}", pending.len());
+        for info in &pending {
+            let _ = write!(dump, "  {}: ", task(info.id, info.name.as_deref()));
+            let _ = match (info.state, info.deadline) {
+                (QueuedIn::Sleeping, Some(deadline)) => writeln!(
+                    dump,
+                    "sleeping, due in {:?}",
+                    deadline.saturating_duration_since(now)
+                ),
+                (QueuedIn::Sleeping, None) => writeln!(dump, "sleeping"),
+                (QueuedIn::Ready, _) => writeln!(dump, "ready"),
+                (QueuedIn::Idle, _) => writeln!(dump, "idle"),
+            };
+        }
+        let _ = writeln!(dump, "recent events: {}", recent.len());
+        for record in &recent {
+            let _ = write!(
+                dump,
+                "  {:?} ago: ",
+                now.saturating_duration_since(record.at)
+            );
+            let subject = record
+                .task
+                .map(|id| task(id, record.name.as_deref()))
+                .unwrap_or_default();
+            let _ = match record.kind {
+                EventKind::Scheduled => writeln!(dump, "{} scheduled", subject),
+                EventKind::Started => writeln!(dump, "{} started", subject),
+                EventKind::Completed { duration } => {
+                    writeln!(dump, "{} completed in {:?}", subject, duration)
+                }
+                EventKind::Cancelled => writeln!(dump, "{} cancelled", subject),
+                EventKind::Panicked => writeln!(dump, "{} panicked", subject),
+                EventKind::Slept { duration } => writeln!(dump, "slept for {:?}", duration),
+            };
+        }
+        dump
+    }
+
+    /// The scheduler whose callback is running on this thread, or `None`
+    /// outside of one.
+    ///
+    /// Lets code deep inside a callback schedule follow-up work without a
+    /// handle being passed down to it:
+    ///
+    /// ```
+    /// use revent_loop::{Scheduler, Task};
+    ///
+    /// fn log_later(line: &'static str) {
+    ///     let scheduler = Scheduler::current().expect("called outside the loop");
+    ///     scheduler.schedule(Task::new(move || println!("{}", line), None));
+    /// }
+    ///
+    /// let scheduler = Scheduler::new();
+    /// scheduler.schedule(Task::new(|| log_later("hello"), None));
+    /// scheduler.run().unwrap();
+    /// assert!(Scheduler::current().is_none());
+    /// ```
+    pub fn current() -> Option<Arc<Scheduler>> {
+        CURRENT.with(|current| current.borrow().as_ref().and_then(Weak::upgrade))
+    }
+
+    /// A reference to this scheduler that does not keep it alive.
+    pub fn handle(&self) -> SchedulerHandle {
+        SchedulerHandle::new(self.me())
+    }
+
+    pub(crate) fn me(&self) -> Weak<Scheduler> {
+        self.me.clone()
+    }
+
+    /// A number in `[0, 1)` from the scheduler's generator.
+    fn random_fraction(&self) -> f64 {
+        self.rng.lock().fraction()
+    }
+
+    /// The calendar time according to the scheduler's [`Clock`].
+    fn wall_time(&self) -> SystemTime {
+        match &self.config.clock {
+            Some(clock) => clock.wall_time(),
+            None => SystemTime::now(),
+        }
+    }
+
+    /// How long until the wall clock reads `at`.
+    fn until_wall_time(&self, at: SystemTime) -> Duration {
+        at.duration_since(self.wall_time()).unwrap_or_default()
+    }
+
+    /// The current time according to the scheduler's [`Clock`].
+    pub fn now(&self) -> Instant {
+        match &self.config.clock {
+            Some(clock) => clock.now(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Blocks until `deadline`. With the system clock the wait ends early
+    /// if `schedule()` or `shutdown()` signal the loop; custom clocks sleep
+    /// through it.
+    fn wait_until(&self, deadline: Instant) {
+        let remaining = deadline.saturating_duration_since(self.now());
+        #[cfg(feature = "tracing")]
+        tracing::debug!("sleeping {:?} until next deadline", remaining);
+        match &self.config.clock {
+            Some(clock) => {
+                // Virtual time can't pass inside `poll`, so I/O is only
+                // picked up between sleeps.
+                self.wait_for_io(Some(Duration::ZERO));
+                clock.sleep(remaining);
+            }
+            None if self.wait_for_io(Some(remaining)) => {}
+            None => match &self.config.sleep {
+                Some(strategy) => strategy.wait_until(deadline, &self.wake),
+                None => ParkTimeout.wait_until(deadline, &self.wake),
+            },
+        }
+    }
+
+    /// Waits for a notification, or for `timeout` if there are channels to
+    /// poll.
+    fn wait_for_work(&self, poll_timeout: Option<Duration>) {
+        match poll_timeout {
+            Some(timeout) => {
+                self.wake.wait_timeout(timeout);
+            }
+            None => self.wake.wait(),
+        }
+    }
+
+    /// Whether a descriptor is registered for readiness, which keeps
+    /// [`Scheduler::run`] waiting even with both queues empty.
+    fn has_io(&self) -> bool {
+        #[cfg(all(feature = "io", unix))]
+        return self
+            .wake
+            .reactor()
+            .is_some_and(|reactor| reactor.has_registrations());
+        #[cfg(not(all(feature = "io", unix)))]
+        return false;
+    }
+
+    /// Waits in `poll(2)` for `timeout`, forever if `None`, or until a
+    /// registered descriptor is ready or the loop is notified. Returns
+    /// `false` without waiting if nothing is registered.
+    fn wait_for_io(&self, timeout: Option<Duration>) -> bool {
+        #[cfg(all(feature = "io", unix))]
+        if let Some(reactor) = self
+            .wake
+            .reactor()
+            .filter(|reactor| reactor.has_registrations())
+        {
+            reactor.poll(self, timeout, || self.wake.take_notification());
+            return true;
+        }
+        let _ = timeout;
+        false
+    }
+
+    /// Executes ready tasks until the queue is empty, `should_stop` says
+    /// otherwise or the batch runs out of
+    /// [`SchedulerBuilder::max_batch_duration`], promoting due timers in
+    /// between. Returns how many ran.
+    fn run_active(&self, should_stop: &dyn Fn() -> bool) -> usize {
+        let started = self.now();
+        let mut executed = 0;
+        let mut taken = 0;
+        while let Some(task) = self.pop_ready() {
+            taken += 1;
+            if let Some(budget) = self.config.max_batch_duration {
+                // Only after some progress, since running nothing means the
+                // queue is empty to the caller.
+                if taken % BATCH_CHECK_INTERVAL == 0
+                    && executed > 0
+                    && self.now().saturating_duration_since(started) >= budget
+                {
+                    self.ready_fns.lock().push_front(task);
+                    self.counters.ready_added(1);
+                    self.counters.batch_cut();
+                    return executed;
+                }
+            }
+            if should_stop() {
+                // Put it back; stopping leaves pending work in place.
+                let mut ready_fns_guard = self.ready_fns.lock();
+                ready_fns_guard.push_front(task);
+                self.counters.ready_added(1);
+                drop(ready_fns_guard);
+                return executed;
+            }
+            if let Some(task) = self.take_rate_token(task) {
+                self.execute(task);
+                executed += 1 + self.run_microtasks();
+            }
+            // Check the timers between callbacks so a busy ready queue
+            // can't hold back tasks whose deadline has passed.
+            if self.stopping_on_panic().is_none() {
+                self.promote_expired();
+            }
+        }
+        executed
+    }
+
+    /// Hands `task` back if the rate limit lets it run now. Otherwise puts
+    /// it to sleep until the limit allows another task, keeping its `seq`
+    /// so that held-back tasks stay in order.
+    fn take_rate_token(&self, mut task: Task) -> Option<Task> {
+        let Some(limiter) = &self.rate_limiter else {
+            return Some(task);
+        };
+        let now = self.now();
+        let Err(next_slot) = limiter.lock().try_acquire(now) else {
+            return Some(task);
+        };
+        self.counters.throttled();
+        task.deadline = Some(next_slot);
+        let mut sleeping_fns_guard = self.sleeping_fns.lock();
+        sleeping_fns_guard.push(next_slot, task.seq, task);
+        self.counters.sleeping_added(1);
+        drop(sleeping_fns_guard);
+        // A pool worker may be waiting on a later timer.
+        self.wake.notify();
+        None
+    }
+
+    fn pop_ready(&self) -> Option<Task> {
+        let mut ready_fns_guard = self.ready_fns.lock();
+        let task = ready_fns_guard.pop_front(self.now())?;
+        self.counters.ready_removed(1);
+        drop(ready_fns_guard);
+        self.notify_space();
+        Some(task)
+    }
+
+    fn run_loop(&self, stop_at: Option<Instant>, keep_alive: bool) -> Result<RunReport, RunError> {
+        let _running = self.claim_loop()?;
+        let _loop_thread = self.enter_loop();
+        let started = self.now();
+        let before = self.counters.snapshot();
+        self.panic_stop.store(false, AtomicOrdering::SeqCst);
+        let mut time_sleeping = Duration::ZERO;
+        let out_of_time = || stop_at.is_some_and(|stop_at| self.now() >= stop_at);
+        let aborting = || self.stopping_on_panic() == Some(PanicPolicy::AbortAll);
+        let should_stop = || self.is_shutdown() || out_of_time() || aborting();
+        let should_yield = || should_stop() || self.is_paused();
+        // Caps a wait without a timer at what is left of a `run_for()` or
+        // `run_until()` budget.
+        let within_budget = |timeout: Option<Duration>| match stop_at {
+            Some(stop_at) => {
+                let remaining = stop_at.saturating_duration_since(self.now());
+                Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)))
+            }
+            None => timeout,
+        };
+
+        let mut executed = 0;
+        while !should_stop() && self.stopping_on_panic().is_none() {
+            if self.is_paused() {
+                if !keep_alive && self.is_drained() && !self.has_io() {
+                    break;
+                }
+                // Only `resume()` (or new work, or shutdown) ends this wait;
+                // the flag is checked again either way.
+                match stop_at {
+                    Some(stop_at) => {
+                        self.wake
+                            .wait_timeout(stop_at.saturating_duration_since(self.now()));
+                    }
+                    None => self.wake.wait(),
+                }
+                continue;
+            }
+
+            executed += self.run_microtasks();
+            self.poll_channels();
+            let next_deadline = self.promote_expired();
+            let ran = self.run_active(&should_yield);
+            if ran > 0 {
+                executed += ran;
+                continue;
+            }
+            if !should_yield() && self.no_timer_due(next_deadline) {
+                let ran = self.run_idle();
+                if ran > 0 {
+                    executed += ran;
+                    continue;
+                }
+            }
+            if let (Some(hooks), false) = (&self.config.hooks, should_yield()) {
+                match hooks.on_idle(next_deadline) {
+                    IdleAction::Sleep => {}
+                    IdleAction::Return => break,
+                    IdleAction::Continue => continue,
+                }
+            }
+
+            self.shrink_when_idle();
+            // The ready queue is empty: wait for the next timer or for new
+            // work, whichever comes first. Both `schedule()` and `shutdown()`
+            // cut the wait short.
+            let poll_timeout = self.channel_poll_timeout();
+            match next_deadline {
+                Some(deadline) => {
+                    if stop_at.is_some_and(|stop_at| deadline > stop_at) {
+                        break;
+                    }
+                    let sleep_started = self.now();
+                    let wake_at = match (poll_timeout, &self.config.clock) {
+                        // Back in time to look at the polled channels. Time
+                        // on a custom clock needn't pass in step with them.
+                        (Some(timeout), None) => deadline.min(sleep_started + timeout),
+                        _ => deadline,
+                    };
+                    self.wait_until(wake_at);
+                    let slept = self.now().saturating_duration_since(sleep_started);
+                    self.record_sleep(slept);
+                    time_sleeping += slept;
+                }
+                None if self.wait_for_io(within_budget(poll_timeout)) => {}
+                None if keep_alive => self.wait_for_work(poll_timeout),
+                None => {
+                    // Something may have been scheduled since we looked.
+                    if self.is_drained() || out_of_time() {
+                        break;
+                    }
+                    if self.blocking_in_flight.load(AtomicOrdering::SeqCst) > 0
+                        || self.open_sources.load(AtomicOrdering::SeqCst) > 0
+                    {
+                        // Nothing to do until a blocking job reports back or
+                        // a message arrives, or the budget runs out.
+                        self.wait_for_work(within_budget(poll_timeout));
+                        if out_of_time() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.finish_run(&should_yield, started, before, executed, time_sleeping)
+    }
+
+    /// Wraps up a run that has left its loop, draining or clearing the
+    /// queues as the [`PanicPolicy`] and [`Scheduler::shutdown`] ask, and
+    /// reports on it.
+    fn finish_run(
+        &self,
+        should_yield: &dyn Fn() -> bool,
+        started: Instant,
+        before: Metrics,
+        mut executed: usize,
+        time_sleeping: Duration,
+    ) -> Result<RunReport, RunError> {
+        match self.stopping_on_panic() {
+            Some(PanicPolicy::DrainAndStop) if !should_yield() => {
+                // The panic may have come from a microtask or an idle task,
+                // with ready ones still waiting.
+                executed += self.run_microtasks();
+                executed += self.run_active(should_yield);
+            }
+            Some(PanicPolicy::AbortAll) => {
+                self.drop_pending(false);
+            }
+            _ => {}
+        }
+        if self.is_shutdown() {
+            executed += self.run_close_phase();
+        }
+
+        let after = self.counters.snapshot();
+        RunReport {
+            tasks_executed: executed,
+            timers_fired: (after.timers_fired - before.timers_fired) as usize,
+            total_runtime: self.now().saturating_duration_since(started),
+            time_sleeping,
+            panics: (after.panics - before.panics) as usize,
+            stopped_on_panic: self.stopping_on_panic(),
+        }
+        .into_result()
+    }
+}
+
+/// Schedules every task through [`Scheduler::schedule_all`].
+impl Extend<Task> for &Scheduler {
+    fn extend<I: IntoIterator<Item = Task>>(&mut self, tasks: I) {
+        self.schedule_all(tasks);
+    }
+}
+
+/// Extracts the message from a panic payload, if it carries one.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DependencyPolicy, Priority};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    fn countdown(n: usize, scheduler: Arc<Scheduler>) {
+        if n > 0 {
+            thread::sleep(Duration::from_secs(1));
+            let scheduler_clone = scheduler.clone();
+            scheduler.schedule(Task::new(
+                move || countdown(n - 1, scheduler_clone.clone()),
+                Some(Duration::from_secs(2)),
+            ));
+        }
+    }
+
+    fn countup(n: usize, scheduler: Arc<Scheduler>) {
+        if n > 0 {
+            thread::sleep(Duration::from_secs(2));
+            let scheduler_clone = scheduler.clone();
+            scheduler.schedule(Task::new(
+                move || countup(n - 1, scheduler_clone.clone()),
+                None,
+            ));
+        }
+    }
+
+    #[test]
+    fn test() {
+        let scheduler = Scheduler::new();
+
+        {
+            let scheduler_clone = scheduler.clone();
+            scheduler.schedule(Task::new(move || countdown(5, scheduler_clone), None));
+        }
+
+        {
+            let scheduler_clone = scheduler.clone();
+            scheduler.schedule(Task::new(move || countup(3, scheduler_clone), None));
+        }
+
+        let report = scheduler.run().unwrap();
+        // countdown(5..=0) and countup(3..=0), each step a separate task.
+        assert_eq!(report.tasks_executed, 10);
+        // Every countdown step after the first waits on a timer.
+        assert_eq!(report.timers_fired, 5);
+        assert_eq!(report.panics, 0);
+        // 10s of countdown and countup callbacks interleave, then the last
+        // two countdown steps each wait out 2s on their own.
+        assert!(report.total_runtime >= Duration::from_secs(15));
+        assert!(report.time_sleeping >= Duration::from_secs(3));
+        assert!(report.time_sleeping <= Duration::from_secs(5));
+        assert!(report.time_sleeping < report.total_runtime);
+    }
+
+    #[test]
+    fn schedule_with_hands_callbacks_the_running_scheduler() {
+        fn countdown(n: u64, steps: Arc<Mutex<Vec<u64>>>, scheduler: &Scheduler) {
+            steps.lock().push(n);
+            if n > 0 {
+                scheduler.schedule_with(
+                    move |scheduler| countdown(n - 1, steps, scheduler),
+                    Some(Duration::from_secs(1)),
+                );
+            }
+        }
+
+        let clock = crate::VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let steps = Arc::new(Mutex::new(Vec::new()));
+        let recorded = steps.clone();
+        scheduler.schedule_with(move |scheduler| countdown(3, recorded, scheduler), None);
+
+        let report = scheduler.run().unwrap();
+        assert_eq!(*steps.lock(), [3, 2, 1, 0]);
+        assert_eq!(report.tasks_executed, 4);
+        assert_eq!(report.total_runtime, Duration::from_secs(3));
+    }
+
+    fn count_later(count: Arc<AtomicUsize>) {
+        let scheduler = Scheduler::current().unwrap();
+        scheduler.schedule(Task::new(
+            move || {
+                count.fetch_add(1, AtomicOrdering::SeqCst);
+            },
+            Some(Duration::from_millis(1)),
+        ));
+    }
+
+    #[test]
+    fn current_finds_the_running_scheduler_from_a_free_function() {
+        let scheduler = Scheduler::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        scheduler.schedule(Task::new(move || count_later(counter), None));
+
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 2);
+        assert_eq!(count.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[test]
+    fn current_is_none_outside_run() {
+        assert!(Scheduler::current().is_none());
+        let scheduler = Scheduler::builder().on_panic(|_, _| {}).build();
+        scheduler.schedule(Task::new(|| panic!("boom"), None));
+        scheduler.run().unwrap();
+        assert!(Scheduler::current().is_none());
+    }
+
+    #[test]
+    fn current_is_restored_after_a_nested_run() {
+        let outer = Scheduler::new();
+        let same = Arc::new(Mutex::new(Vec::new()));
+        let (recorded, expected) = (same.clone(), outer.clone());
+        outer.schedule(Task::new(
+            move || {
+                let inner = Scheduler::new();
+                let (recorded_inner, expected_inner) = (recorded.clone(), inner.clone());
+                inner.schedule(Task::new(
+                    move || {
+                        let current = Scheduler::current().unwrap();
+                        recorded_inner
+                            .lock()
+                            .push(Arc::ptr_eq(&current, &expected_inner));
+                    },
+                    None,
+                ));
+                inner.run().unwrap();
+                let current = Scheduler::current().unwrap();
+                recorded.lock().push(Arc::ptr_eq(&current, &expected));
+            },
+            None,
+        ));
+
+        outer.run().unwrap();
+        assert_eq!(*same.lock(), [true, true]);
+    }
+
+    #[test]
+    fn reschedule_pushes_a_sleeping_task_out() {
+        let clock = crate::VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let start = clock.now();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let (recorded, now) = (fired.clone(), clock.clone());
+        let timeout = scheduler
+            .schedule(Task::new(
+                move || recorded.lock().push(now.now()),
+                Some(Duration::from_millis(100)),
+            ))
+            .id();
+        let activity = scheduler.clone();
+        scheduler.schedule(Task::new(
+            move || assert!(activity.reschedule(timeout, Duration::from_millis(300))),
+            Some(Duration::from_millis(50)),
+        ));
+
+        let report = scheduler.run().unwrap();
+        assert_eq!(*fired.lock(), [start + Duration::from_millis(350)]);
+        assert_eq!(report.tasks_executed, 2);
+        assert!(!scheduler.reschedule(timeout, Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn reschedule_moves_a_ready_task_back_to_sleeping() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let handle = scheduler.schedule(Task::new(|| {}, None));
+        assert!(scheduler.reschedule(handle.id(), Duration::from_secs(5)));
+        assert_eq!((scheduler.ready_len(), scheduler.sleeping_len()), (0, 1));
+        assert_eq!(
+            scheduler.next_deadline(),
+            Some(crate::Clock::now(&clock) + Duration::from_secs(5))
+        );
+
+        assert_eq!(scheduler.tick().executed, 0);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(scheduler.tick().executed, 1);
+    }
+
+    #[test]
+    fn schedule_keyed_runs_only_the_last_task_for_a_key() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for attempt in 0..3 {
+            let ran = ran.clone();
+            scheduler.schedule_keyed("save:42", Duration::from_millis(100), move || {
+                ran.lock().push(attempt)
+            });
+            thread::sleep(Duration::from_millis(3));
+        }
+        let other = ran.clone();
+        scheduler.schedule_keyed("save:7", Duration::from_millis(100), move || {
+            other.lock().push(7)
+        });
+
+        let report = scheduler.run().unwrap();
+        assert_eq!(*ran.lock(), [2, 7]);
+        assert_eq!(report.tasks_executed, 2);
+        assert_eq!(scheduler.metrics().cancelled, 2);
+        assert!(scheduler.keyed.lock().is_empty());
+    }
+
+    #[test]
+    fn cancelling_a_keyed_task_frees_its_key() {
+        let scheduler = Scheduler::new();
+        let first = scheduler.schedule_keyed("idle", Duration::from_secs(60), || {});
+        assert!(first.cancel());
+        assert!(scheduler.keyed.lock().is_empty());
+
+        // Scheduling under the key again starts from scratch.
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        scheduler.schedule_keyed("idle", Duration::from_millis(1), move || {
+            flag.store(true, AtomicOrdering::SeqCst)
+        });
+        scheduler.run().unwrap();
+        assert!(ran.load(AtomicOrdering::SeqCst));
+        assert!(scheduler.keyed.lock().is_empty());
+    }
+
+    #[test]
+    fn dependent_tasks_run_after_their_prerequisites() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let task = |name: &'static str| {
+            let order = order.clone();
+            Task::new(move || order.lock().push(name), None)
+        };
+        let (a, b, c) = (task("a"), task("b"), task("c"));
+        let (a_id, b_id) = (a.id, b.id);
+
+        // C is scheduled first, before B even exists.
+        scheduler.schedule_after(b_id, c);
+        scheduler.schedule_after(a_id, b);
+        assert_eq!(scheduler.pending_count(), 0);
+        scheduler.schedule(a);
+
+        scheduler.run().unwrap();
+        assert_eq!(*order.lock(), ["a", "b", "c"]);
+
+        // A prerequisite that already ran releases at once.
+        scheduler.schedule_after(a_id, task("d"));
+        assert_eq!(scheduler.pending_count(), 1);
+    }
+
+    #[test]
+    fn cancelling_a_prerequisite_applies_the_dependency_policy() {
+        let chain = |policy| {
+            let scheduler = Scheduler::builder().dependency_policy(policy).build();
+            let ran = Arc::new(AtomicUsize::new(0));
+            let counted = |ran: &Arc<AtomicUsize>| {
+                let ran = ran.clone();
+                Task::new(
+                    move || {
+                        ran.fetch_add(1, AtomicOrdering::SeqCst);
+                    },
+                    None,
+                )
+            };
+            let a = scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(60))));
+            let b = scheduler.schedule_after(a.id(), counted(&ran));
+            scheduler.schedule_after(b.id(), counted(&ran));
+            assert!(a.cancel());
+            scheduler.run().unwrap();
+            (
+                ran.load(AtomicOrdering::SeqCst),
+                scheduler.metrics().cancelled,
+            )
+        };
+        assert_eq!(chain(DependencyPolicy::CancelDependents), (0, 3));
+        assert_eq!(chain(DependencyPolicy::Release), (2, 1));
+    }
+
+    #[test]
+    fn sequences_run_in_order_with_delays_after_the_previous_task() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let task = |name: &'static str, delay: u64| {
+            let order = order.clone();
+            Task::new(
+                move || order.lock().push(name),
+                Some(Duration::from_millis(delay)),
+            )
+        };
+        scheduler.schedule_sequence(vec![task("a", 0), task("b", 50), task("c", 0)]);
+
+        scheduler.tick();
+        assert_eq!(*order.lock(), ["a"]);
+        clock.advance(Duration::from_millis(49));
+        scheduler.tick();
+        assert_eq!(*order.lock(), ["a"]);
+        clock.advance(Duration::from_millis(1));
+        scheduler.tick();
+        scheduler.tick();
+        assert_eq!(*order.lock(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn cancelling_a_sequence_drops_the_rest() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicUsize::new(0));
+        let task = || {
+            let ran = ran.clone();
+            Task::new(
+                move || {
+                    ran.fetch_add(1, AtomicOrdering::SeqCst);
+                },
+                None,
+            )
+        };
+        let sequence = scheduler.schedule_sequence(vec![task(), task(), task()]);
+        scheduler.tick();
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 1);
+        assert_eq!(sequence.cancel(), 2);
+
+        scheduler.run().unwrap();
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 1);
+        assert_eq!(sequence.cancel(), 0);
+    }
+
+    #[test]
+    fn microtasks_run_before_ready_tasks_and_timers() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let log = |name: &'static str| {
+            let order = order.clone();
+            move || order.lock().push(name)
+        };
+
+        scheduler.schedule(Task::new(log("timer"), Some(Duration::from_millis(5))));
+        let (first, nested) = (log("microtask"), log("nested microtask"));
+        let inner = scheduler.clone();
+        let outer = scheduler.clone();
+        let first_log = log("first");
+        let busy = clock.clone();
+        scheduler.schedule(Task::new(
+            move || {
+                first_log();
+                outer.next_tick(move || {
+                    first();
+                    inner.next_tick(nested);
+                });
+                // The timer expires while the first task is running.
+                busy.advance(Duration::from_millis(10));
+            },
+            None,
+        ));
+        scheduler.schedule(Task::new(log("second"), None));
+
+        scheduler.run().unwrap();
+        assert_eq!(
+            *order.lock(),
+            ["first", "microtask", "nested microtask", "second", "timer"]
+        );
+    }
+
+    #[test]
+    fn yielded_work_goes_behind_ready_tasks_and_due_timers() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        fn step(i: usize, scheduler: Arc<Scheduler>, order: Arc<Mutex<Vec<String>>>) {
+            order.lock().push(format!("y{}", i));
+            if i < 5 {
+                let next = scheduler.clone();
+                scheduler.yield_now(move || step(i + 1, next, order));
+            }
+        }
+        let (first, yielding) = (scheduler.clone(), order.clone());
+        scheduler.schedule(Task::new(move || step(0, first, yielding), None));
+        for name in ["a", "b", "c"] {
+            let order = order.clone();
+            let ticking = clock.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    order.lock().push(name.to_owned());
+                    // The timer falls due while `c` runs.
+                    if name == "c" {
+                        ticking.advance(Duration::from_millis(1));
+                    }
+                },
+                None,
+            ));
+        }
+        let timer = order.clone();
+        scheduler.schedule(Task::new(
+            move || timer.lock().push("timer".to_owned()),
+            Some(Duration::from_millis(1)),
+        ));
+
+        scheduler.run().unwrap();
+        assert_eq!(
+            *order.lock(),
+            ["y0", "a", "b", "c", "y1", "timer", "y2", "y3", "y4", "y5"]
+        );
+    }
+
+    #[test]
+    fn idle_tasks_wait_for_the_ready_stream_to_finish() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let idle = order.clone();
+        let handle = scheduler.schedule_idle(move || idle.lock().push(usize::MAX));
+        // Each task queues the next, so the ready queue never empties.
+        fn stream(n: usize, scheduler: &Scheduler, order: Arc<Mutex<Vec<usize>>>) {
+            order.lock().push(n);
+            if n < 5 {
+                scheduler.schedule_with(move |scheduler| stream(n + 1, scheduler, order), None);
+            }
+        }
+        let first = order.clone();
+        scheduler.schedule_with(move |scheduler| stream(0, scheduler, first), None);
+        assert_eq!(scheduler.idle_len(), 1);
+        assert_eq!(
+            scheduler.pending_tasks().last().unwrap().state,
+            QueuedIn::Idle
+        );
+        assert_eq!(scheduler.pending_tasks().last().unwrap().id, handle.id());
+
+        scheduler.run().unwrap();
+        assert_eq!(*order.lock(), [0, 1, 2, 3, 4, 5, usize::MAX]);
+        assert_eq!(scheduler.idle_len(), 0);
+    }
+
+    #[test]
+    fn idle_tasks_run_on_their_own_and_can_be_cancelled() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicUsize::new(0));
+        let count = || {
+            let ran = ran.clone();
+            move || {
+                ran.fetch_add(1, AtomicOrdering::SeqCst);
+            }
+        };
+        scheduler.schedule_idle(count());
+        let cancelled = scheduler.schedule_idle(count());
+        scheduler.schedule_idle(count());
+        assert!(cancelled.cancel());
+        assert!(scheduler.is_idle());
+
+        let report = scheduler.run().unwrap();
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 2);
+        assert_eq!(report.tasks_executed, 2);
+        assert_eq!(scheduler.metrics().cancelled, 1);
+    }
+
+    #[test]
+    fn batch_budget_lets_timers_through_a_long_backlog() {
+        let fired_at = |budget: Option<Duration>| {
+            let clock = crate::MockClock::new();
+            let start = clock.now();
+            let mut builder = Scheduler::builder().clock(clock.clone());
+            if let Some(budget) = budget {
+                builder = builder.max_batch_duration(budget);
+            }
+            let scheduler = builder.build();
+            let fired = Arc::new(Mutex::new(None));
+
+            let at = fired.clone();
+            let timer_clock = clock.clone();
+            // High, since a timer of the same priority waits its turn
+            // behind the older backlog.
+            scheduler.schedule(Task::new_with_priority(
+                move || *at.lock() = Some(timer_clock.now() - start),
+                Some(Duration::from_millis(5)),
+                Priority::High,
+            ));
+            for _ in 0..10_000 {
+                let busy = clock.clone();
+                scheduler.schedule(Task::new(
+                    move || busy.advance(Duration::from_micros(1)),
+                    None,
+                ));
+            }
+            scheduler.run().unwrap();
+            let fired = fired.lock().unwrap();
+            (fired, scheduler.metrics().batches_cut)
+        };
+
+        let (fired, cut) = fired_at(Some(Duration::from_millis(1)));
+        assert!(fired < Duration::from_millis(6), "{:?}", fired);
+        assert!(cut >= 9, "{}", cut);
+        assert_eq!(fired_at(None).1, 0);
+    }
+
+    #[test]
+    fn watchdog_reports_slow_callbacks_while_they_run() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let done = Arc::new(AtomicBool::new(false));
+        let (recorded, finished) = (reports.clone(), done.clone());
+        let scheduler = Scheduler::builder()
+            .slow_task_threshold(Duration::from_millis(50), move |id, name, elapsed| {
+                let still_running = !finished.load(AtomicOrdering::SeqCst);
+                let name = name.map(str::to_owned);
+                recorded.lock().push((id, name, elapsed, still_running));
+            })
+            .build();
+
+        let finished = done.clone();
+        let slow = scheduler.schedule(Task::new_named(
+            "slow",
+            move || {
+                thread::sleep(Duration::from_millis(200));
+                finished.store(true, AtomicOrdering::SeqCst);
+            },
+            None,
+        ));
+        scheduler.schedule(Task::new(|| thread::sleep(Duration::from_millis(10)), None));
+        scheduler.run().unwrap();
+
+        let reports = reports.lock();
+        assert_eq!(reports.len(), 1);
+        let (id, name, elapsed, still_running) = &reports[0];
+        assert_eq!(*id, slow.id());
+        assert_eq!(name.as_deref(), Some("slow"));
+        assert!(*elapsed >= Duration::from_millis(50), "{:?}", elapsed);
+        assert!(still_running);
+    }
+
+    #[test]
+    fn exec_timeout_marks_overrunning_callbacks() {
+        let clock = crate::MockClock::new();
+        let timeouts = Arc::new(Mutex::new(Vec::new()));
+        let reported = timeouts.clone();
+        let scheduler = Scheduler::builder()
+            .clock(clock.clone())
+            .on_timeout(move |meta| reported.lock().push(meta.id))
+            .build();
+
+        let busy = clock.clone();
+        let slow = Task::builder()
+            .callback(move || {
+                busy.advance(Duration::from_millis(100));
+                "done"
+            })
+            .exec_timeout(Duration::from_millis(20))
+            .spawn_on(&scheduler);
+        let busy = clock.clone();
+        let quick = Task::builder()
+            .callback(move || {
+                busy.advance(Duration::from_millis(10));
+                "done"
+            })
+            .exec_timeout(Duration::from_millis(20))
+            .spawn_on(&scheduler);
+        let slow_id = slow.id();
+        scheduler.run().unwrap();
+
+        assert_eq!(*timeouts.lock(), [slow_id]);
+        let error = slow.try_join().unwrap_err();
+        assert_eq!(error.kind(), crate::JoinErrorKind::TimedOut);
+        assert_eq!(error.id(), slow_id);
+        assert_eq!(quick.try_join().unwrap(), "done");
+        assert_eq!(scheduler.metrics().timed_out, 1);
+    }
+
+    #[test]
+    fn blocked_timers_report_their_lateness() {
+        let clock = crate::MockClock::new();
+        let misses = Arc::new(Mutex::new(Vec::new()));
+        let reported = misses.clone();
+        let scheduler = Scheduler::builder()
+            .clock(clock.clone())
+            .on_deadline_miss(Duration::from_millis(100), move |meta, late| {
+                reported.lock().push((meta.id, late))
+            })
+            .build();
+
+        let timer = scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(50))));
+        let busy = clock.clone();
+        scheduler.schedule(Task::new(
+            move || busy.advance(Duration::from_millis(300)),
+            None,
+        ));
+        scheduler.run().unwrap();
+
+        let late = Duration::from_millis(250);
+        assert_eq!(*misses.lock(), [(timer.id(), late)]);
+        let metrics = scheduler.metrics();
+        assert_eq!(metrics.timers_run, 1);
+        assert_eq!((metrics.min_lateness, metrics.max_lateness), (late, late));
+        assert_eq!(metrics.average_lateness(), late);
+    }
+
+    #[test]
+    fn batched_timers_report_their_lateness() {
+        let clock = crate::MockClock::new();
+        let misses = Arc::new(Mutex::new(Vec::new()));
+        let reported = misses.clone();
+        let scheduler = Scheduler::builder()
+            .clock(clock.clone())
+            .on_deadline_miss(Duration::from_millis(100), move |meta, late| {
+                reported.lock().push((meta.id, meta.phase, late))
+            })
+            .build();
+
+        let busy = clock.clone();
+        let handles = scheduler.schedule_all([
+            Task::new(|| {}, Some(Duration::from_millis(50))),
+            Task::new(move || busy.advance(Duration::from_millis(300)), None),
+        ]);
+        scheduler.run().unwrap();
+
+        let late = Duration::from_millis(250);
+        assert_eq!(*misses.lock(), [(handles[0].id(), Phase::Timers, late)]);
+        assert_eq!(scheduler.metrics().timers_run, 1);
+    }
+
+    #[test]
+    fn slack_lets_a_timer_share_an_earlier_wakeup() {
+        let fired_at = |slack: Duration| {
+            let clock = crate::VirtualClock::new();
+            let start = clock.now();
+            let scheduler = Scheduler::with_clock(clock.clone());
+            let fired = Arc::new(Mutex::new(Vec::new()));
+            for (delay, slack) in [(100, Duration::ZERO), (103, slack)] {
+                let (fired, clock) = (fired.clone(), clock.clone());
+                scheduler.schedule(
+                    Task::builder()
+                        .callback(move || fired.lock().push(clock.now() - start))
+                        .delay(Duration::from_millis(delay))
+                        .slack(slack)
+                        .build(),
+                );
+            }
+            scheduler.run().unwrap();
+            let fired = fired.lock().clone();
+            fired
+        };
+
+        let ms = Duration::from_millis;
+        assert_eq!(fired_at(ms(5)), [ms(100), ms(100)]);
+        assert_eq!(fired_at(Duration::ZERO), [ms(100), ms(103)]);
+        // Too little slack to reach back to the first wakeup.
+        assert_eq!(fired_at(ms(2)), [ms(100), ms(103)]);
+    }
+
+    #[test]
+    fn batched_timers_share_an_earlier_wakeup_too() {
+        let clock = crate::VirtualClock::new();
+        let start = clock.now();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let tasks =
+            [(100, Duration::ZERO), (103, Duration::from_millis(5))].map(|(delay, slack)| {
+                let (fired, clock) = (fired.clone(), clock.clone());
+                Task::builder()
+                    .callback(move || fired.lock().push(clock.now() - start))
+                    .delay(Duration::from_millis(delay))
+                    .slack(slack)
+                    .build()
+            });
+        scheduler.schedule_all(tasks);
+        scheduler.run().unwrap();
+
+        let ms = Duration::from_millis;
+        assert_eq!(*fired.lock(), [ms(100), ms(100)]);
+    }
+
+    #[test]
+    fn timer_granularity_rounds_deadlines_up() {
+        let clock = crate::VirtualClock::new();
+        let start = clock.now();
+        let scheduler = Scheduler::builder()
+            .clock(clock.clone())
+            .timer_granularity(Duration::from_millis(10))
+            .build();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for delay in [9, 1, 4] {
+            let (fired, clock) = (fired.clone(), clock.clone());
+            scheduler.schedule(Task::new(
+                move || fired.lock().push((delay, clock.now() - start)),
+                Some(Duration::from_millis(delay)),
+            ));
+        }
+
+        let ten = Duration::from_millis(10);
+        let deadlines: Vec<_> = scheduler
+            .pending_tasks()
+            .iter()
+            .map(|task| task.deadline.unwrap() - start)
+            .collect();
+        assert_eq!(deadlines, [ten; 3]);
+        let report = scheduler.run().unwrap();
+        assert_eq!(*fired.lock(), [(9, ten), (1, ten), (4, ten)]);
+        assert_eq!(report.time_sleeping, ten);
+    }
+
+    #[test]
+    fn timer_wheel_fires_what_the_heap_fires() {
+        const TIMERS: usize = 100_000;
+        let tick = Duration::from_millis(1);
+        let fire = |backend: crate::TimerBackend| {
+            let clock = crate::VirtualClock::new();
+            let start = clock.now();
+            let scheduler = Scheduler::builder()
+                .clock(clock.clone())
+                .timer_backend(backend)
+                .build();
+            let fired = Arc::new(Mutex::new(Vec::with_capacity(TIMERS)));
+            let mut rng = Rng::seeded(7);
+            let mut ids = Vec::with_capacity(TIMERS);
+            for _ in 0..TIMERS {
+                // Up to a day, to reach the upper levels of the wheel.
+                let delay = Duration::from_micros(rng.next_u64() % 86_400_000_000);
+                let (fired, clock) = (fired.clone(), clock.clone());
+                let id = TaskId::new();
+                let mut task = Task::new(
+                    move || fired.lock().push((id, clock.now() - start)),
+                    Some(delay),
+                );
+                task.id = id;
+                scheduler.schedule(task);
+                ids.push((id, delay));
+            }
+            let cancelled: Vec<TaskId> = ids.iter().step_by(2).map(|(id, _)| *id).collect();
+            match backend {
+                crate::TimerBackend::Heap => {
+                    assert_eq!(scheduler.cancel_many(&cancelled), TIMERS / 2)
+                }
+                crate::TimerBackend::Wheel { .. } => {
+                    assert!(cancelled.iter().all(|id| scheduler.cancel(*id)))
+                }
+            }
+            scheduler.run().unwrap();
+            let fired = fired.lock();
+            assert_eq!(fired.len(), TIMERS / 2);
+            let mut fired: HashMap<TaskId, Duration> = fired.iter().copied().collect();
+            assert_eq!(fired.len(), TIMERS / 2, "a timer fired twice");
+            let expected: HashMap<TaskId, Duration> = ids.into_iter().skip(1).step_by(2).collect();
+            for (id, delay) in &expected {
+                let at = fired.remove(id).expect("a timer never fired");
+                assert!(
+                    at >= *delay && at < *delay + tick,
+                    "{:?} fired at {:?}",
+                    delay,
+                    at
+                );
+            }
+            assert_eq!(scheduler.metrics().timers_fired, (TIMERS / 2) as u64);
+        };
+
+        fire(crate::TimerBackend::Heap);
+        fire(crate::TimerBackend::Wheel { tick, slots: 64 });
+    }
+
+    #[test]
+    fn high_resolution_waits_overshoot_less() {
+        // Timing-sensitive; shared CI machines are too noisy for it.
+        if std::env::var_os("CI").is_some() {
+            return;
+        }
+        fn chain(handle: SchedulerHandle, left: usize) {
+            if left > 0 {
+                let next = handle.clone();
+                let _ = handle.schedule(Task::new(
+                    move || chain(next, left - 1),
+                    Some(Duration::from_millis(5)),
+                ));
+            }
+        }
+        let overshoot = |high_resolution: bool| {
+            let scheduler = Scheduler::builder()
+                .high_resolution(high_resolution)
+                .build();
+            chain(scheduler.handle(), 20);
+            scheduler.run().unwrap();
+            scheduler.metrics().average_lateness()
+        };
+
+        let precise = overshoot(true);
+        let default = overshoot(false);
+        assert!(precise < default, "{:?} vs {:?}", precise, default);
+    }
+
+    #[test]
+    fn sleep_strategy_is_asked_to_wait_for_each_timer() {
+        struct Recording(Arc<Mutex<Vec<Instant>>>);
+        impl SleepStrategy for Recording {
+            fn wait_until(&self, deadline: Instant, wakeup: &WakeSignal) {
+                self.0.lock().push(deadline);
+                ParkTimeout.wait_until(deadline, wakeup);
+            }
+        }
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = Scheduler::builder()
+            .sleep_strategy(Recording(recorded.clone()))
+            .build();
+        for delay in [20, 5] {
+            scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(delay))));
+        }
+        let mut deadlines: Vec<_> = scheduler
+            .pending_tasks()
+            .into_iter()
+            .map(|info| info.deadline.unwrap())
+            .collect();
+        deadlines.sort();
+
+        scheduler.run().unwrap();
+        let mut waits = recorded.lock().clone();
+        // A wait can end a little early; the loop then asks again.
+        waits.dedup();
+        assert_eq!(waits, deadlines);
+    }
+
+    #[test]
+    fn rate_limit_spaces_out_ready_tasks() {
+        let clock = crate::VirtualClock::new();
+        let scheduler = Scheduler::builder()
+            .clock(clock.clone())
+            .rate_limit(2, Duration::from_secs(1))
+            .build();
+        let start = clock.now();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..10 {
+            let (ran, clock) = (ran.clone(), clock.clone());
+            scheduler.schedule(Task::new(
+                move || ran.lock().push((i, clock.now() - start)),
+                None,
+            ));
+        }
+
+        let report = scheduler.run().unwrap();
+        let expected: Vec<_> = (0..10u64)
+            .map(|i| (i, Duration::from_millis(500 * i.saturating_sub(1))))
+            .collect();
+        assert_eq!(*ran.lock(), expected);
+        assert_eq!(report.tasks_executed, 10);
+        assert!(scheduler.metrics().throttled >= 8);
+    }
+
+    fn failing_times(
+        failures: usize,
+        clock: &crate::VirtualClock,
+    ) -> (
+        Arc<Mutex<Vec<Duration>>>,
+        impl FnMut() -> Result<(), usize> + Send + 'static,
+    ) {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let (recorded, clock) = (attempts.clone(), clock.clone());
+        let start = clock.now();
+        let f = move || {
+            let mut attempts = recorded.lock();
+            attempts.push(clock.now() - start);
+            match attempts.len() {
+                n if n <= failures => Err(n),
+                _ => Ok(()),
+            }
+        };
+        (attempts, f)
+    }
+
+    #[test]
+    fn retries_back_off_until_the_callback_succeeds() {
+        let clock = crate::VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let (attempts, f) = failing_times(3, &clock);
+        scheduler.schedule_with_retry_or_else(RetryPolicy::default(), f, |_| {
+            panic!("should have succeeded")
+        });
+
+        let report = scheduler.run().unwrap();
+        let ms = Duration::from_millis;
+        assert_eq!(*attempts.lock(), [ms(0), ms(100), ms(300), ms(700)]);
+        assert_eq!(report.panics, 0);
+    }
+
+    #[test]
+    fn retries_stop_after_max_attempts_with_the_last_error() {
+        let clock = crate::VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let (attempts, f) = failing_times(usize::MAX, &clock);
+        let exhausted = Arc::new(Mutex::new(Vec::new()));
+        let errors = exhausted.clone();
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..RetryPolicy::default()
+        };
+        scheduler.schedule_with_retry_or_else(policy, f, move |error| errors.lock().push(error));
+
+        scheduler.run().unwrap();
+        assert_eq!(attempts.lock().len(), 3);
+        assert_eq!(*exhausted.lock(), [3]);
+    }
+
+    #[test]
+    fn cancelling_a_retry_stops_later_attempts() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let handle = scheduler.schedule_with_retry(RetryPolicy::default(), move || {
+            counted.fetch_add(1, AtomicOrdering::SeqCst);
+            Err(())
+        });
+
+        assert_eq!(scheduler.tick().executed, 1);
+        assert!(handle.cancel());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(scheduler.tick().executed, 0);
+        assert_eq!(attempts.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[test]
+    fn cron_fires_on_matching_simulated_minutes() {
+        // 2023-11-14 22:13:20 UTC.
+        let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = crate::VirtualClock::starting_at(wall);
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let (recorded, now) = (fired.clone(), clock.clone());
+        scheduler
+            .schedule_cron("*/5 * * * *", move || {
+                let since_epoch = crate::Clock::wall_time(&now)
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap();
+                recorded.lock().push(since_epoch.as_secs() % 3600 / 60);
+            })
+            .unwrap();
+
+        scheduler.run_for(Duration::from_secs(16 * 60)).unwrap();
+        assert_eq!(*fired.lock(), [15, 20, 25]);
+        assert_eq!(scheduler.sleeping_len(), 1);
+    }
+
+    fn jittered_deadlines(seed: u64) -> Vec<Duration> {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::builder().clock(clock.clone()).seed(seed).build();
+        let start = crate::Clock::now(&clock);
+        for _ in 0..100 {
+            scheduler.schedule_interval_jittered(
+                Duration::from_secs(1),
+                Duration::from_millis(200),
+                || {},
+            );
+        }
+        let mut deadlines: Vec<_> = scheduler
+            .pending_tasks()
+            .into_iter()
+            .map(|info| info.deadline.unwrap() - start)
+            .collect();
+        deadlines.sort();
+        deadlines
+    }
+
+    #[test]
+    fn jittered_intervals_spread_out_deterministically_per_seed() {
+        let deadlines = jittered_deadlines(42);
+        let mut distinct = deadlines.clone();
+        distinct.dedup();
+        assert!(
+            distinct.len() > 90,
+            "only {} distinct deadlines",
+            distinct.len()
+        );
+        let (first, last) = (deadlines[0], deadlines[99]);
+        assert!(first >= Duration::from_millis(800));
+        assert!(last <= Duration::from_millis(1200));
+        assert!(last - first > Duration::from_millis(300));
+
+        assert_eq!(jittered_deadlines(42), deadlines);
+        assert_ne!(jittered_deadlines(43), deadlines);
+    }
+
+    fn record_fire(at: &Arc<Mutex<Option<Instant>>>) -> impl FnOnce() + Send + 'static {
+        let at = at.clone();
+        move || *at.lock() = Some(Instant::now())
+    }
+
+    #[test]
+    fn delay_counts_from_schedule_time() {
+        let scheduler = Scheduler::new();
+        let fired = Arc::new(Mutex::new(None));
+
+        let scheduled = Instant::now();
+        scheduler.schedule(Task::new(record_fire(&fired), Some(Duration::from_secs(3))));
+        thread::sleep(Duration::from_secs(1));
+        scheduler.run().unwrap();
+
+        let elapsed = fired.lock().unwrap() - scheduled;
+        assert!(
+            elapsed >= Duration::from_secs(3),
+            "fired early: {:?}",
+            elapsed
+        );
+        assert!(
+            elapsed < Duration::from_millis(3500),
+            "fired late: {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn delay_is_measured_against_requested_duration() {
+        let scheduler = Scheduler::new();
+        let fired = Arc::new(Mutex::new(None));
+
+        let scheduled = Instant::now();
+        scheduler.schedule(Task::new(record_fire(&fired), Some(Duration::from_secs(2))));
+        scheduler.run().unwrap();
+
+        let elapsed = fired.lock().unwrap() - scheduled;
+        assert!(
+            elapsed >= Duration::from_secs(2),
+            "fired early: {:?}",
+            elapsed
+        );
+        assert!(
+            elapsed < Duration::from_millis(2500),
+            "fired late: {:?}",
+            elapsed
+        );
+    }
+
+    fn assert_waits_for(delay: Duration) {
+        let scheduler = Scheduler::new();
+        let fired = Arc::new(Mutex::new(None));
+
+        let scheduled = Instant::now();
+        scheduler.schedule(Task::new(record_fire(&fired), Some(delay)));
+        scheduler.run().unwrap();
+
+        let elapsed = fired.lock().unwrap() - scheduled;
+        assert!(
+            elapsed >= delay,
+            "{:?} delay fired after {:?}",
+            delay,
+            elapsed
+        );
+        assert!(
+            elapsed < delay + Duration::from_millis(200),
+            "{:?} delay fired after {:?}",
+            delay,
+            elapsed
+        );
+    }
+
+    #[test]
+    fn sub_second_delays_wait() {
+        assert_waits_for(Duration::from_millis(10));
+        assert_waits_for(Duration::from_millis(250));
+        assert_waits_for(Duration::from_millis(999));
+    }
+
+    #[test]
+    fn zero_delay_runs_promptly() {
+        assert_waits_for(Duration::ZERO);
+    }
+
+    #[test]
+    fn overdue_task_runs_without_panicking() {
+        let scheduler = Scheduler::new();
+        let fired = Arc::new(Mutex::new(None));
+
+        scheduler.schedule(Task::new(
+            record_fire(&fired),
+            Some(Duration::from_millis(10)),
+        ));
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        scheduler.run().unwrap();
+
+        let fired_at = fired.lock().expect("overdue task never ran");
+        assert!(fired_at - started < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn sleeping_tasks_fire_in_deadline_order() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for delay in [300, 100, 200] {
+            let order = order.clone();
+            scheduler.schedule(Task::new(
+                move || order.lock().push(delay),
+                Some(Duration::from_millis(delay)),
+            ));
+        }
+        scheduler.run().unwrap();
+
+        assert_eq!(*order.lock(), vec![100, 200, 300]);
+    }
+
+    #[test]
+    fn equal_deadlines_keep_insertion_order() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // Build the tasks up front so they all share a single deadline.
+        let delay = Duration::from_millis(50);
+        let deadline = Instant::now() + delay;
+        for i in 0..10 {
+            let order = order.clone();
+            let mut task = Task::new(move || order.lock().push(i), Some(delay));
+            task.deadline = Some(deadline);
+            let seq = scheduler.next_seq.fetch_add(1, AtomicOrdering::Relaxed);
+            scheduler.sleeping_fns.lock().push(deadline, seq, task);
+        }
+        scheduler.run().unwrap();
+
+        assert_eq!(*order.lock(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zero_delays_queue_in_line_with_immediates() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..6 {
+            let order = order.clone();
+            let delay = (i % 2 == 1).then_some(Duration::ZERO);
+            scheduler.schedule(Task::new(move || order.lock().push(i), delay));
+        }
+        assert_eq!((scheduler.ready_len(), scheduler.sleeping_len()), (6, 0));
+        scheduler.run().unwrap();
+        assert_eq!(*order.lock(), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn expired_timers_wait_behind_older_ready_tasks() {
+        let order_with = |timer_priority| {
+            let clock = crate::MockClock::new();
+            let scheduler = Scheduler::with_clock(clock.clone());
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let log = |name: &'static str| {
+                let order = order.clone();
+                move || order.lock().push(name)
+            };
+            let (first, busy) = (log("first"), clock.clone());
+            scheduler.schedule(Task::new(
+                move || {
+                    first();
+                    // Both timers fall due while this runs.
+                    busy.advance(Duration::from_millis(5));
+                },
+                None,
+            ));
+            scheduler.schedule(Task::new_with_priority(
+                log("timer"),
+                Some(Duration::from_nanos(1)),
+                timer_priority,
+            ));
+            scheduler.schedule(Task::new(log("second"), None));
+            scheduler.schedule(Task::new(log("third"), None));
+            scheduler.run().unwrap();
+            let order = order.lock().clone();
+            order
+        };
+
+        assert_eq!(
+            order_with(Priority::Normal),
+            ["first", "second", "third", "timer"]
+        );
+        assert_eq!(
+            order_with(Priority::High),
+            ["first", "timer", "second", "third"]
+        );
+    }
+
+    #[test]
+    fn same_delay_batches_run_in_scheduling_order() {
+        let backends = [
+            crate::TimerBackend::Heap,
+            crate::TimerBackend::Wheel {
+                tick: Duration::from_millis(1),
+                slots: 64,
+            },
+        ];
+        let policies = [
+            SchedulerPolicy::Fifo,
+            SchedulerPolicy::EarliestDeadlineFirst,
+        ];
+        for (backend, policy) in backends.into_iter().flat_map(|b| policies.map(|p| (b, p))) {
+            let scheduler = Scheduler::builder()
+                .clock(crate::VirtualClock::new())
+                .timer_backend(backend)
+                .policy(policy)
+                .build();
+            for round in 0..5 {
+                let order = Arc::new(Mutex::new(Vec::new()));
+                for i in 0..100 {
+                    let order = order.clone();
+                    scheduler.schedule(Task::new(
+                        move || order.lock().push(i),
+                        Some(Duration::from_millis(10)),
+                    ));
+                }
+                scheduler.run().unwrap();
+                let order = order.lock();
+                assert!(
+                    order.len() == 100 && order.windows(2).all(|pair| pair[0] < pair[1]),
+                    "{:?} {:?} round {}: {:?}",
+                    backend,
+                    policy,
+                    round,
+                    order
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn overdue_timers_expire_in_one_sweep() {
+        let scheduler = Scheduler::new();
+        let count = Arc::new(Mutex::new(0));
+
+        for delay in [10, 20, 30, 40, 50] {
+            let count = count.clone();
+            scheduler.schedule(Task::new(
+                move || *count.lock() += 1,
+                Some(Duration::from_millis(delay)),
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        scheduler.run().unwrap();
+
+        assert_eq!(*count.lock(), 5);
+        assert!(started.elapsed() < Duration::from_millis(30));
+    }
+
+    #[test]
+    fn pending_timers_wait_for_the_earliest_deadline_only() {
+        let scheduler = Scheduler::new();
+        let count = Arc::new(Mutex::new(0));
+
+        for delay in [10, 20, 30, 40, 50] {
+            let count = count.clone();
+            scheduler.schedule(Task::new(
+                move || *count.lock() += 1,
+                Some(Duration::from_millis(delay)),
+            ));
+        }
+
+        let started = Instant::now();
+        scheduler.run().unwrap();
+
+        assert_eq!(*count.lock(), 5);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_millis(150), "took {:?}", elapsed);
+    }
+
+    fn spin(remaining: usize, scheduler: Arc<Scheduler>) {
+        if remaining > 0 {
+            thread::sleep(Duration::from_millis(1));
+            let next = scheduler.clone();
+            scheduler.schedule(Task::new(move || spin(remaining - 1, next), None));
+        }
+    }
+
+    #[test]
+    fn timers_fire_while_ready_queue_is_busy() {
+        let scheduler = Scheduler::new();
+        let fired = Arc::new(Mutex::new(None));
+
+        let scheduled = Instant::now();
+        scheduler.schedule(Task::new(
+            record_fire(&fired),
+            Some(Duration::from_millis(200)),
+        ));
+        let spinner = scheduler.clone();
+        scheduler.schedule(Task::new(move || spin(1000, spinner), None));
+        scheduler.run().unwrap();
+
+        let elapsed = fired.lock().unwrap() - scheduled;
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(
+            elapsed < Duration::from_millis(300),
+            "timer starved for {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn cancel_by_id() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        let mut ids = Vec::new();
+        for (i, delay) in [None, Some(Duration::from_millis(20))]
+            .into_iter()
+            .enumerate()
+        {
+            let ran = ran.clone();
+            ids.push(
+                scheduler
+                    .schedule(Task::new(move || ran.lock().push(i), delay))
+                    .id(),
+            );
+        }
+        assert!(scheduler.cancel(ids[0]));
+        assert!(scheduler.cancel(ids[1]));
+        assert!(!scheduler.cancel(ids[1]));
+        assert!(!scheduler.cancel(TaskId::new()));
+        scheduler.run().unwrap();
+
+        assert!(ran.lock().is_empty());
+    }
+
+    #[cfg(not(feature = "uuid-ids"))]
+    #[test]
+    fn counter_ids_are_cheap_and_unique() {
+        const TASKS: usize = 100_000;
+        let scheduler = Scheduler::new();
+        let started = Instant::now();
+        let ids: Vec<_> = (0..TASKS)
+            .map(|_| scheduler.schedule(Task::new(|| {}, None)).id())
+            .collect();
+        let elapsed = started.elapsed();
+        // Other tests mint ids concurrently, so they only have to increase.
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        // Only catches something pathological, like a syscall per id.
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+        assert_eq!(scheduler.run().unwrap().tasks_executed, TASKS);
+    }
+
+    #[test]
+    fn cancel_many_removes_from_both_queues() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        let mut ids = Vec::new();
+        for i in 0..6 {
+            let ran = ran.clone();
+            let delay = (i % 2 == 1).then(|| Duration::from_millis(10));
+            ids.push(
+                scheduler
+                    .schedule(Task::new(move || ran.lock().push(i), delay))
+                    .id(),
+            );
+        }
+        assert_eq!(
+            scheduler.cancel_many(&[ids[0], ids[1], ids[2], TaskId::new()]),
+            3
+        );
+        scheduler.run().unwrap();
+
+        assert_eq!(*ran.lock(), vec![4, 3, 5]);
+    }
+
+    #[test]
+    fn cancel_from_another_thread_while_running() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(Mutex::new(false));
+
+        let flag = ran.clone();
+        let id = scheduler
+            .schedule(Task::new(
+                move || *flag.lock() = true,
+                Some(Duration::from_millis(200)),
+            ))
+            .id();
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(scheduler.cancel(id));
+        runner.join().unwrap();
+
+        assert!(!*ran.lock());
+    }
+
+    #[test]
+    fn interval_runs_once_per_period_until_cancelled() {
+        let scheduler = Scheduler::new();
+        let runs = Arc::new(Mutex::new(0));
+
+        let counter = runs.clone();
+        let handle =
+            scheduler.schedule_interval(Duration::from_millis(50), move || *counter.lock() += 1);
+        scheduler.schedule(Task::new(
+            move || assert!(handle.cancel()),
+            Some(Duration::from_millis(275)),
+        ));
+        scheduler.run().unwrap();
+
+        assert_eq!(*runs.lock(), 5);
+    }
+
+    #[test]
+    fn repeating_callbacks_pick_their_own_delays() {
+        let clock = crate::MockClock::new();
+        let start = clock.now();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let runs = Arc::new(Mutex::new(Vec::new()));
+
+        let at = runs.clone();
+        let ticking = clock.clone();
+        scheduler.schedule_repeating(Duration::from_millis(5), move || {
+            let mut runs = at.lock();
+            runs.push(ticking.now() - start);
+            (runs.len() < 3).then(|| Duration::from_millis(10))
+        });
+        for _ in 0..40 {
+            clock.advance(Duration::from_millis(1));
+            scheduler.tick();
+        }
+
+        let ms = Duration::from_millis;
+        assert_eq!(*runs.lock(), [ms(5), ms(15), ms(25)]);
+        assert!(scheduler.is_idle());
+    }
+
+    #[test]
+    fn repeating_callbacks_can_be_cancelled_between_runs() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let handle = scheduler.schedule_repeating(Duration::ZERO, move || {
+            counter.fetch_add(1, AtomicOrdering::SeqCst);
+            Some(Duration::from_millis(10))
+        });
+        scheduler.tick();
+        assert!(handle.cancel());
+        clock.advance(Duration::from_millis(10));
+        scheduler.tick();
+
+        assert_eq!(runs.load(AtomicOrdering::SeqCst), 1);
+        assert!(scheduler.is_idle());
+    }
+
+    /// When each of the first `runs` runs of a 100ms interval starts, in ms
+    /// from scheduling, if every run takes `busy` ms.
+    fn interval_starts(mode: IntervalMode, busy: u64, runs: usize) -> Vec<u64> {
+        let clock = crate::MockClock::new();
+        let start = clock.now();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let starts = Arc::new(Mutex::new(Vec::new()));
+
+        let at = starts.clone();
+        let working = clock.clone();
+        scheduler.schedule_interval_with_mode(Duration::from_millis(100), mode, move || {
+            at.lock().push((working.now() - start).as_millis() as u64);
+            working.advance(Duration::from_millis(busy));
+        });
+        while starts.lock().len() < runs {
+            // Time only moves on once nothing more is due.
+            if scheduler.tick().executed == 0 {
+                clock.advance(Duration::from_millis(1));
+            }
+        }
+        let starts = starts.lock().clone();
+        starts
+    }
+
+    #[test]
+    fn fixed_rate_intervals_do_not_drift() {
+        let fixed_delay = interval_starts(IntervalMode::FixedDelay, 30, 4);
+        assert_eq!(fixed_delay, [100, 230, 360, 490]);
+        let fixed_rate = interval_starts(IntervalMode::FixedRate(CatchUp::Skip), 30, 4);
+        assert_eq!(fixed_rate, [100, 200, 300, 400]);
+    }
+
+    #[test]
+    fn fixed_rate_intervals_burst_or_skip_after_falling_behind() {
+        // Every run takes 250ms, two and a half periods.
+        let burst = interval_starts(IntervalMode::FixedRate(CatchUp::Burst), 250, 4);
+        assert_eq!(burst, [100, 350, 600, 850]);
+        let skip = interval_starts(IntervalMode::FixedRate(CatchUp::Skip), 250, 4);
+        assert_eq!(skip, [100, 400, 700, 1000]);
+    }
+
+    #[test]
+    fn interval_cancelled_mid_wait_never_runs() {
+        let scheduler = Scheduler::new();
+        let runs = Arc::new(Mutex::new(0));
+
+        let counter = runs.clone();
+        let handle =
+            scheduler.schedule_interval(Duration::from_millis(100), move || *counter.lock() += 1);
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+        thread::sleep(Duration::from_millis(150));
+        assert!(handle.cancel());
+        runner.join().unwrap();
+
+        assert_eq!(*runs.lock(), 1);
+    }
+
+    #[test]
+    fn interval_cancelled_from_its_own_callback() {
+        let scheduler = Scheduler::new();
+        let runs = Arc::new(Mutex::new(0));
+        let handle: Arc<Mutex<Option<TaskHandle>>> = Arc::new(Mutex::new(None));
+
+        let counter = runs.clone();
+        let own_handle = handle.clone();
+        *handle.lock() = Some(
+            scheduler.schedule_interval(Duration::from_millis(10), move || {
+                let mut runs = counter.lock();
+                *runs += 1;
+                if *runs == 3 {
+                    assert!(own_handle.lock().as_ref().unwrap().cancel());
+                }
+            }),
+        );
+        scheduler.run().unwrap();
+
+        assert_eq!(*runs.lock(), 3);
+    }
+
+    fn forever(iteration: usize, scheduler: Arc<Scheduler>, iterations: Arc<Mutex<usize>>) {
+        *iterations.lock() = iteration;
+        if iteration == 3 {
+            scheduler.shutdown();
+        }
+        let next = scheduler.clone();
+        scheduler.schedule(Task::new(
+            move || forever(iteration + 1, next, iterations),
+            Some(Duration::from_millis(5)),
+        ));
+    }
+
+    #[test]
+    fn shutdown_from_task_stops_infinite_chain() {
+        let scheduler = Scheduler::new();
+        let iterations = Arc::new(Mutex::new(0));
+
+        let chain = scheduler.clone();
+        let seen = iterations.clone();
+        scheduler.schedule(Task::new(move || forever(1, chain, seen), None));
+        scheduler.run().unwrap();
+
+        assert_eq!(*iterations.lock(), 3);
+        assert_eq!(scheduler.sleeping_len(), 1);
+    }
+
+    #[test]
+    fn shutdown_interrupts_a_sleeping_loop() {
+        let scheduler = Scheduler::new();
+        scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(10))));
+
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        let stopped = Instant::now();
+        scheduler.shutdown();
+        runner.join().unwrap();
+
+        assert!(stopped.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn run_after_reset_executes_pending_tasks() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(Mutex::new(false));
+
+        let flag = ran.clone();
+        scheduler.schedule(Task::new(move || *flag.lock() = true, None));
+        scheduler.shutdown();
+        scheduler.run().unwrap();
+        assert!(!*ran.lock());
+
+        scheduler.reset();
+        scheduler.run().unwrap();
+        assert!(*ran.lock());
+    }
+
+    #[test]
+    fn run_for_returns_within_budget_under_endless_work() {
+        let scheduler = Scheduler::new();
+        let spinner = scheduler.clone();
+        scheduler.schedule(Task::new(move || spin(usize::MAX, spinner), None));
+
+        let started = Instant::now();
+        let executed = scheduler.run_for(Duration::from_millis(100)).unwrap();
+
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(
+            elapsed < Duration::from_millis(150),
+            "overran: {:?}",
+            elapsed
+        );
+        assert!(executed > 0);
+        assert_eq!(scheduler.ready_len(), 1);
+    }
+
+    #[test]
+    fn run_until_does_not_sleep_past_deadline() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(Mutex::new(0));
+
+        for delay in [Duration::from_millis(20), Duration::from_secs(10)] {
+            let ran = ran.clone();
+            scheduler.schedule(Task::new(move || *ran.lock() += 1, Some(delay)));
+        }
+
+        let started = Instant::now();
+        let executed = scheduler
+            .run_until(started + Duration::from_millis(100))
+            .unwrap();
+
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(executed, 1);
+        assert_eq!(*ran.lock(), 1);
+    }
+
+    #[test]
+    fn run_for_returns_on_time_while_blocking_work_is_outstanding() {
+        let scheduler = Scheduler::new();
+        let job = scheduler.spawn_blocking(|| thread::sleep(Duration::from_secs(2)));
+
+        let started = Instant::now();
+        scheduler.run_for(Duration::from_millis(100)).unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(
+            elapsed < Duration::from_millis(500),
+            "overran: {:?}",
+            elapsed
+        );
+
+        // The job's result is still delivered by a later run.
+        scheduler.run().unwrap();
+        assert!(job.is_finished());
+    }
+
+    #[test]
+    fn run_for_returns_early_when_queues_drain() {
+        let scheduler = Scheduler::new();
+        for _ in 0..3 {
+            scheduler.schedule(Task::new(|| {}, None));
+        }
+
+        let started = Instant::now();
+        assert_eq!(scheduler.run_for(Duration::from_secs(5)).unwrap(), 3);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn tick_runs_only_currently_ready_tasks() {
+        let scheduler = Scheduler::new();
+        let follow_up = scheduler.clone();
+        scheduler.schedule(Task::new(
+            move || {
+                follow_up.schedule(Task::new(|| {}, None));
+            },
+            None,
+        ));
+        scheduler.schedule(Task::new(|| {}, None));
+
+        let first = scheduler.tick();
+        assert_eq!(first.executed, 2);
+        assert!(first.next_deadline.is_some());
+
+        let second = scheduler.tick();
+        assert_eq!(second.executed, 1);
+        assert_eq!(second.next_deadline, None);
+    }
+
+    #[test]
+    fn poll_due_hands_out_only_due_tasks_in_order() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let task = |name: &'static str, delay: u64| {
+            let ran = ran.clone();
+            let delay = (delay > 0).then(|| Duration::from_millis(delay));
+            Task::new(move || ran.lock().push(name), delay)
+        };
+        let ids: Vec<_> = [("d", 20), ("a", 0), ("c", 10), ("e", 30), ("b", 0)]
+            .into_iter()
+            .map(|(name, delay)| (name, scheduler.schedule(task(name, delay)).id()))
+            .collect();
+        let named = |tasks: &[Task]| -> Vec<&str> {
+            tasks
+                .iter()
+                .map(|task| ids.iter().find(|(_, id)| *id == task.id()).unwrap().0)
+                .collect()
+        };
+
+        let first = scheduler.poll_due().unwrap();
+        assert_eq!(named(&first), ["a", "b"]);
+        clock.advance(Duration::from_millis(20));
+        let second = scheduler.poll_due().unwrap();
+        assert_eq!(named(&second), ["c", "d"]);
+        // Nothing ran on its own, and the later timer stayed put.
+        assert!(ran.lock().is_empty());
+        assert_eq!((scheduler.ready_len(), scheduler.sleeping_len()), (0, 1));
+
+        for task in first.into_iter().chain(second) {
+            scheduler.run_task(task);
+        }
+        assert_eq!(*ran.lock(), ["a", "b", "c", "d"]);
+        assert!(scheduler.poll_due().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tick_fires_delayed_tasks_at_wall_clock_time() {
+        let scheduler = Scheduler::new();
+        let fired = Arc::new(Mutex::new(None));
+
+        let scheduled = Instant::now();
+        scheduler.schedule(Task::new(
+            record_fire(&fired),
+            Some(Duration::from_millis(50)),
+        ));
+
+        let result = scheduler.tick();
+        assert_eq!(result.executed, 0);
+        assert!(result.next_deadline.unwrap() >= scheduled + Duration::from_millis(50));
+
+        while fired.lock().is_none() {
+            let started = Instant::now();
+            scheduler.tick();
+            assert!(started.elapsed() < Duration::from_millis(5), "tick blocked");
+            thread::sleep(Duration::from_millis(2));
+        }
+        let elapsed = fired.lock().unwrap() - scheduled;
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(
+            elapsed < Duration::from_millis(80),
+            "fired after {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn run_forever_wakes_for_new_work() {
+        let scheduler = Scheduler::new();
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run_forever())
+        };
+        thread::sleep(Duration::from_millis(100));
+
+        let fired = Arc::new(Mutex::new(None));
+        let scheduled = Instant::now();
+        scheduler.schedule(Task::new(record_fire(&fired), None));
+        while fired.lock().is_none() {
+            assert!(scheduled.elapsed() < Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let latency = fired.lock().unwrap() - scheduled;
+        assert!(
+            latency < Duration::from_millis(10),
+            "woke after {:?}",
+            latency
+        );
+
+        scheduler.shutdown();
+        runner.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn earlier_deadline_interrupts_a_long_wait() {
+        let scheduler = Scheduler::new();
+        let long = scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(10))));
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+        thread::sleep(Duration::from_millis(20));
+
+        let fired = Arc::new(Mutex::new(None));
+        let scheduled = Instant::now();
+        scheduler.schedule(Task::new(
+            record_fire(&fired),
+            Some(Duration::from_millis(50)),
+        ));
+        while fired.lock().is_none() {
+            assert!(
+                scheduled.elapsed() < Duration::from_secs(1),
+                "timer held up"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        let elapsed = fired.lock().unwrap() - scheduled;
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(
+            elapsed < Duration::from_millis(80),
+            "fired after {:?}",
+            elapsed
+        );
+
+        assert!(long.cancel());
+        runner.join().unwrap();
+    }
+
+    #[test]
+    fn immediate_task_interrupts_a_long_wait() {
+        let scheduler = Scheduler::new();
+        let long = scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(10))));
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+        thread::sleep(Duration::from_millis(20));
+
+        let cancel = long.clone();
+        let handle = scheduler.spawn(move || cancel.cancel());
+        assert!(handle.join());
+        runner.join().unwrap();
+    }
+
+    #[test]
+    fn panicking_task_does_not_stop_the_loop() {
+        let panics = Arc::new(Mutex::new(Vec::new()));
+        let seen = panics.clone();
+        let scheduler = Scheduler::builder()
+            .on_panic(move |id, payload| {
+                seen.lock()
+                    .push((id, panic_message(payload.as_ref()).to_string()));
+            })
+            .build();
+        let ran = Arc::new(Mutex::new(false));
+
+        let bad = scheduler.schedule(Task::new(|| panic!("boom"), None));
+        let flag = ran.clone();
+        scheduler.schedule(Task::new(move || *flag.lock() = true, None));
+        scheduler.run().unwrap();
+
+        assert!(*ran.lock());
+        assert_eq!(*panics.lock(), vec![(bad.id(), "boom".to_string())]);
+    }
+
+    #[test]
+    fn panicking_interval_is_not_rescheduled() {
+        let scheduler = Scheduler::builder().on_panic(|_, _| {}).build();
+        let runs = Arc::new(Mutex::new(0));
+
+        let counter = runs.clone();
+        scheduler.schedule_interval(Duration::from_millis(5), move || {
+            *counter.lock() += 1;
+            panic!("interval");
+        });
+        scheduler.run().unwrap();
+
+        assert_eq!(*runs.lock(), 1);
+    }
+
+    #[test]
+    fn schedule_at_future_and_past_instants() {
+        let scheduler = Scheduler::new();
+        let future = Arc::new(Mutex::new(None));
+        let past = Arc::new(Mutex::new(None));
+
+        let now = Instant::now();
+        scheduler.schedule_at(now + Duration::from_millis(200), record_fire(&future));
+        scheduler.schedule_at(now - Duration::from_secs(1), record_fire(&past));
+        assert_eq!(scheduler.ready_len(), 1);
+
+        let started = Instant::now();
+        scheduler.run().unwrap();
+
+        let past = past.lock().unwrap();
+        assert!(past - started < Duration::from_millis(20));
+        let future = future.lock().unwrap();
+        assert!(future >= now + Duration::from_millis(200));
+        assert!(future < now + Duration::from_millis(300));
+    }
+
+    #[test]
+    fn schedule_at_follows_the_scheduler_clock() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let fired = Arc::new(Mutex::new(None));
+
+        scheduler.schedule_at(
+            crate::Clock::now(&clock) + Duration::from_secs(60),
+            record_fire(&fired),
+        );
+        assert_eq!(scheduler.tick().executed, 0);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(scheduler.tick().executed, 1);
+    }
+
+    #[test]
+    fn high_priority_runs_before_queued_low_tasks() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..10 {
+            let order = order.clone();
+            scheduler.schedule(Task::new_with_priority(
+                move || order.lock().push(i),
+                None,
+                Priority::Low,
+            ));
+        }
+        let high = order.clone();
+        scheduler.schedule(Task::new_with_priority(
+            move || high.lock().push(100),
+            None,
+            Priority::High,
+        ));
+
+        scheduler.run().unwrap();
+        let order = order.lock();
+        assert_eq!(order[0], 100);
+        assert_eq!(order[1..], (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn expired_timer_keeps_its_priority() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let normal = order.clone();
+        scheduler.schedule(Task::new(move || normal.lock().push("normal"), None));
+        let high = order.clone();
+        scheduler.schedule(Task::new_with_priority(
+            move || high.lock().push("high"),
+            Some(Duration::from_secs(1)),
+            Priority::High,
+        ));
+
+        clock.advance(Duration::from_secs(1));
+        scheduler.tick();
+        assert_eq!(*order.lock(), ["high", "normal"]);
+    }
+
+    fn edf_scheduler() -> Arc<Scheduler> {
+        Scheduler::builder()
+            .policy(crate::SchedulerPolicy::EarliestDeadlineFirst)
+            .build()
+    }
+
+    #[test]
+    fn edf_runs_nearer_deadline_ahead_of_earlier_tasks() {
+        let scheduler = edf_scheduler();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..3 {
+            let order = order.clone();
+            scheduler.schedule(Task::new(move || order.lock().push(i), None));
+        }
+        let overdue = order.clone();
+        scheduler.schedule_at(Instant::now() - Duration::from_secs(1), move || {
+            overdue.lock().push(100)
+        });
+
+        scheduler.run().unwrap();
+        assert_eq!(*order.lock(), vec![100, 0, 1, 2]);
+    }
+
+    #[test]
+    fn edf_runs_expired_timer_before_newer_ready_tasks() {
+        fn order_under(policy: crate::SchedulerPolicy) -> Vec<&'static str> {
+            let clock = crate::MockClock::new();
+            let scheduler = Scheduler::builder()
+                .clock(clock.clone())
+                .policy(policy)
+                .build();
+            let order = Arc::new(Mutex::new(Vec::new()));
+
+            let timer = order.clone();
+            scheduler.schedule(Task::new(
+                move || timer.lock().push("timer"),
+                Some(Duration::from_millis(10)),
+            ));
+            let busy = order.clone();
+            let inner = scheduler.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    // Run long enough for the timer to fall due, then queue
+                    // more work behind it.
+                    clock.advance(Duration::from_millis(50));
+                    for name in ["b", "c"] {
+                        let order = busy.clone();
+                        inner.schedule(Task::new(move || order.lock().push(name), None));
+                    }
+                },
+                None,
+            ));
+
+            scheduler.run().unwrap();
+            let order = order.lock().clone();
+            order
+        }
+
+        // Under FIFO too, since due timers run before immediates.
+        assert_eq!(
+            order_under(crate::SchedulerPolicy::Fifo),
+            ["timer", "b", "c"]
+        );
+        assert_eq!(
+            order_under(crate::SchedulerPolicy::EarliestDeadlineFirst),
+            ["timer", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn aging_lets_low_task_through_a_flood_of_high_tasks() {
+        fn flood(scheduler: Arc<Scheduler>, clock: crate::MockClock, runs: Arc<AtomicUsize>) {
+            clock.advance(Duration::from_millis(1));
+            // Give up eventually so the test fails instead of hanging.
+            if runs.fetch_add(1, AtomicOrdering::SeqCst) < 1000 {
+                let next = scheduler.clone();
+                scheduler.schedule(Task::new_with_priority(
+                    move || flood(next, clock, runs),
+                    None,
+                    Priority::High,
+                ));
+            }
+        }
+
+        let threshold = Duration::from_millis(20);
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::builder()
+            .clock(clock.clone())
+            .starvation_threshold(threshold)
+            .build();
+        let runs = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let (next, clock, runs) = (scheduler.clone(), clock.clone(), runs.clone());
+            scheduler.schedule(Task::new_with_priority(
+                move || flood(next, clock, runs),
+                None,
+                Priority::High,
+            ));
+        }
+
+        let started = crate::Clock::now(&clock);
+        let ran_at = Arc::new(Mutex::new(None));
+        let (low_ran_at, low_clock, stop) = (ran_at.clone(), clock.clone(), scheduler.clone());
+        scheduler.schedule(Task::new_with_priority(
+            move || {
+                *low_ran_at.lock() = Some(crate::Clock::now(&low_clock));
+                stop.shutdown();
+            },
+            None,
+            Priority::Low,
+        ));
+
+        scheduler.run().unwrap();
+        let waited = ran_at.lock().expect("low task starved") - started;
+        assert!(waited >= threshold);
+        assert!(waited <= threshold + Duration::from_millis(1));
+    }
+
+    #[test]
+    fn named_task_debug_shows_name() {
+        let task = Task::new_named("flush-cache", || {}, None);
+        assert_eq!(task.name(), Some("flush-cache"));
+        assert!(format!("{:?}", task).contains("flush-cache"));
+
+        let unnamed = Task::new(|| {}, None);
+        assert_eq!(unnamed.name(), None);
+        assert_eq!(
+            format!("{:?}", unnamed),
+            format!("Task {{ id: {:?} }}", unnamed.id())
+        );
+    }
+
+    #[test]
+    fn panic_report_carries_task_name() {
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let seen = names.clone();
+        let scheduler = Scheduler::with_config(Config {
+            on_panic: Arc::new(move |_, name, _| {
+                seen.lock().push(name.map(str::to_string));
+            }),
+            ..Config::default()
+        });
+
+        scheduler.schedule(Task::new_named("exploder", || panic!("boom"), None));
+        scheduler.schedule(Task::new(|| panic!("boom"), None));
+        scheduler.run().unwrap();
+
+        assert_eq!(*names.lock(), vec![Some("exploder".to_string()), None]);
+    }
+
+    #[derive(Default)]
+    struct CountingHooks {
+        scheduled: AtomicUsize,
+        started: AtomicUsize,
+        completed: AtomicUsize,
+        /// Set once the scheduler exists, so the hooks can check its locks.
+        scheduler: std::sync::OnceLock<Weak<Scheduler>>,
+        locked_during_hook: AtomicBool,
+    }
+
+    impl CountingHooks {
+        fn check_unlocked(&self) {
+            if let Some(scheduler) = self.scheduler.get().and_then(Weak::upgrade) {
+                if scheduler.ready_fns.try_lock().is_none()
+                    || scheduler.sleeping_fns.try_lock().is_none()
+                    || scheduler.running_intervals.try_lock().is_none()
+                {
+                    self.locked_during_hook.store(true, AtomicOrdering::SeqCst);
+                }
+            }
+        }
+    }
+
+    impl crate::SchedulerHooks for Arc<CountingHooks> {
+        fn on_schedule(&self, _task: &TaskMeta) {
+            self.check_unlocked();
+            self.scheduled.fetch_add(1, AtomicOrdering::SeqCst);
+        }
+
+        fn on_start(&self, _task: &TaskMeta) {
+            self.check_unlocked();
+            self.started.fetch_add(1, AtomicOrdering::SeqCst);
+        }
+
+        fn on_complete(&self, _task: &TaskMeta, _elapsed: Duration) {
+            self.check_unlocked();
+            self.completed.fetch_add(1, AtomicOrdering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn hooks_count_scheduled_and_executed_tasks() {
+        let hooks = Arc::new(CountingHooks::default());
+        let scheduler = Scheduler::builder().hooks(hooks.clone()).build();
+        hooks.scheduler.set(scheduler.me()).unwrap();
+
+        for _ in 0..3 {
+            let inner = scheduler.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    inner.schedule(Task::new(|| {}, Some(Duration::from_millis(5))));
+                },
+                None,
+            ));
+        }
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let handle = Arc::new(Mutex::new(None::<TaskHandle>));
+        let stop = handle.clone();
+        *handle.lock() = Some(
+            scheduler.schedule_interval(Duration::from_millis(5), move || {
+                if counter.fetch_add(1, AtomicOrdering::SeqCst) == 1 {
+                    stop.lock().as_ref().unwrap().cancel();
+                }
+            }),
+        );
+        scheduler.run().unwrap();
+
+        // 3 outer + 3 inner tasks, plus the interval's first run and the one
+        // re-run queued before it was cancelled.
+        assert_eq!(runs.load(AtomicOrdering::SeqCst), 2);
+        assert_eq!(hooks.scheduled.load(AtomicOrdering::SeqCst), 8);
+        assert_eq!(hooks.started.load(AtomicOrdering::SeqCst), 8);
+        assert_eq!(hooks.completed.load(AtomicOrdering::SeqCst), 8);
+        assert!(!hooks.locked_during_hook.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn on_idle_can_inject_work_and_return_early() {
+        #[derive(Default)]
+        struct Pump {
+            scheduler: std::sync::OnceLock<Weak<Scheduler>>,
+            calls: Mutex<Vec<Option<Instant>>>,
+            injected_ran: Arc<AtomicBool>,
+        }
+        impl crate::SchedulerHooks for Arc<Pump> {
+            fn on_idle(&self, next_deadline: Option<Instant>) -> IdleAction {
+                let mut calls = self.calls.lock();
+                calls.push(next_deadline);
+                if calls.len() > 1 {
+                    return IdleAction::Return;
+                }
+                let scheduler = self.scheduler.get().unwrap().upgrade().unwrap();
+                let ran = self.injected_ran.clone();
+                scheduler.schedule(Task::new(
+                    move || ran.store(true, AtomicOrdering::SeqCst),
+                    None,
+                ));
+                IdleAction::Continue
+            }
+        }
+
+        let pump = Arc::new(Pump::default());
+        let scheduler = Scheduler::builder().hooks(pump.clone()).build();
+        pump.scheduler.set(scheduler.me()).unwrap();
+        let timer = scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(60))));
+        let deadline = scheduler.pending_tasks()[0].deadline;
+
+        let started = Instant::now();
+        let report = scheduler.run().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(report.tasks_executed, 1);
+        assert!(pump.injected_ran.load(AtomicOrdering::SeqCst));
+        assert_eq!(*pump.calls.lock(), [deadline, deadline]);
+        // The timer is left for a later run.
+        assert!(timer.cancel());
+    }
+
+    #[test]
+    fn on_complete_reports_callback_duration() {
+        struct Elapsed(Arc<Mutex<Vec<(TaskMeta, Duration)>>>);
+        impl crate::SchedulerHooks for Elapsed {
+            fn on_complete(&self, task: &TaskMeta, elapsed: Duration) {
+                self.0.lock().push((task.clone(), elapsed));
+            }
+        }
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = Scheduler::builder().hooks(Elapsed(reports.clone())).build();
+        scheduler.schedule(Task::new_named(
+            "slow",
+            || thread::sleep(Duration::from_millis(20)),
+            None,
+        ));
+        scheduler.run().unwrap();
+
+        let reports = reports.lock();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].0.name.as_deref(), Some("slow"));
+        assert!(reports[0].1 >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn phases_order_an_immediate_a_zero_delay_and_a_next_tick() {
+        struct Phases(Arc<Mutex<Vec<(String, Phase)>>>);
+        impl crate::SchedulerHooks for Phases {
+            fn on_start(&self, task: &TaskMeta) {
+                let name = task.name.as_deref().unwrap_or("next tick");
+                self.0.lock().push((name.to_owned(), task.phase));
+            }
+        }
+
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = Scheduler::builder().hooks(Phases(started.clone())).build();
+        let inner = scheduler.clone();
+        scheduler.schedule(Task::new_named(
+            "outer",
+            move || {
+                inner.schedule(Task::new_named("immediate", || {}, None));
+                inner.schedule(Task::new_named("zero delay", || {}, Some(Duration::ZERO)));
+                inner.next_tick(|| {});
+            },
+            None,
+        ));
+        scheduler.run().unwrap();
+
+        let started = started.lock();
+        assert_eq!(
+            *started,
+            [
+                ("outer".to_owned(), Phase::Immediate),
+                ("next tick".to_owned(), Phase::NextTick),
+                ("immediate".to_owned(), Phase::Immediate),
+                ("zero delay".to_owned(), Phase::Immediate),
+            ]
+        );
+    }
+
+    #[test]
+    fn phases_order_a_batch_the_same_way() {
+        struct Phases(Arc<Mutex<Vec<(String, Phase)>>>);
+        impl crate::SchedulerHooks for Phases {
+            fn on_start(&self, task: &TaskMeta) {
+                let name = task.name.as_deref().unwrap_or("next tick");
+                self.0.lock().push((name.to_owned(), task.phase));
+            }
+        }
+
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = Scheduler::builder().hooks(Phases(started.clone())).build();
+        let inner = scheduler.clone();
+        scheduler.schedule(Task::new_named(
+            "outer",
+            move || {
+                inner.schedule_all([
+                    Task::new_named("timer", || {}, Some(Duration::from_millis(1))),
+                    Task::new_named("immediate", || {}, None),
+                    Task::new_named("zero delay", || {}, Some(Duration::ZERO)),
+                ]);
+                inner.next_tick(|| {});
+            },
+            None,
+        ));
+        scheduler.run().unwrap();
+
+        let started = started.lock();
+        assert_eq!(
+            *started,
+            [
+                ("outer".to_owned(), Phase::Immediate),
+                ("next tick".to_owned(), Phase::NextTick),
+                ("immediate".to_owned(), Phase::Immediate),
+                ("zero delay".to_owned(), Phase::Immediate),
+                ("timer".to_owned(), Phase::Timers),
+            ]
+        );
+    }
+
+    #[test]
+    fn close_callbacks_run_after_cancellation_and_at_shutdown() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let log = |name: &'static str| {
+            let order = order.clone();
+            move || order.lock().push(name)
+        };
+
+        let cancelled =
+            scheduler.schedule(Task::new(log("cancelled"), Some(Duration::from_secs(60))));
+        scheduler.on_close(cancelled.id(), log("closed on cancel"));
+        let finished = scheduler.schedule(Task::new(log("finished"), None));
+        scheduler.on_close(finished.id(), log("never"));
+        let pending = scheduler.schedule(Task::new(log("pending"), Some(Duration::from_secs(60))));
+        scheduler.on_close(pending.id(), log("closed on shutdown"));
+
+        let inner = scheduler.clone();
+        let after = log("after cancel");
+        scheduler.schedule(Task::new(
+            move || {
+                assert!(cancelled.cancel());
+                inner.schedule(Task::new(after, None));
+            },
+            Some(Duration::from_millis(5)),
+        ));
+        let stopper = scheduler.clone();
+        scheduler.schedule(Task::new(
+            move || stopper.shutdown(),
+            Some(Duration::from_millis(20)),
+        ));
+        scheduler.run().unwrap();
+
+        assert_eq!(
+            *order.lock(),
+            [
+                "finished",
+                "after cancel",
+                "closed on cancel",
+                "closed on shutdown"
+            ]
+        );
+        // The task itself stays queued.
+        assert_eq!(scheduler.sleeping_len(), 1);
+    }
+
+    #[cfg(feature = "tracing")]
+    mod tracing_events {
+        use super::*;
+        use std::fmt::{self, Write};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Formats every field as `name=value`, keeping the message apart.
+        #[derive(Default)]
+        struct Fields {
+            message: String,
+            rest: String,
+        }
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    write!(self.message, "{:?}", value).unwrap();
+                } else {
+                    write!(self.rest, " {}={:?}", field.name(), value).unwrap();
+                }
+            }
+
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if field.name() == "message" {
+                    self.message.push_str(value);
+                } else {
+                    write!(self.rest, " {}={}", field.name(), value).unwrap();
+                }
+            }
+        }
+
+        /// An event's message and the span it was emitted in, if any.
+        type Recorded = (Option<String>, String);
+
+        /// Records spans as `name fields` and events as `(span, message)`.
+        #[derive(Clone, Default)]
+        struct Collector {
+            spans: Arc<Mutex<Vec<String>>>,
+            stack: Arc<Mutex<Vec<u64>>>,
+            events: Arc<Mutex<Vec<Recorded>>>,
+        }
+
+        impl Subscriber for Collector {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields::default();
+                span.record(&mut fields);
+                let mut spans = self.spans.lock();
+                spans.push(format!("{}{}", span.metadata().name(), fields.rest));
+                Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                let span = self
+                    .stack
+                    .lock()
+                    .last()
+                    .map(|id| self.spans.lock()[*id as usize - 1].clone());
+                self.events.lock().push((span, fields.message));
+            }
+
+            fn enter(&self, span: &Id) {
+                self.stack.lock().push(span.into_u64());
+            }
+
+            fn exit(&self, _span: &Id) {
+                self.stack.lock().pop();
+            }
+        }
+
+        #[test]
+        fn spans_and_events_for_immediate_and_delayed_tasks() {
+            let collector = Collector::default();
+            let scheduler = Scheduler::new();
+            let now = Task::new_named("now", || {}, None);
+            let later = Task::new(|| {}, Some(Duration::from_millis(5)));
+            let later_id = later.id();
+
+            tracing::subscriber::with_default(collector.clone(), || {
+                scheduler.schedule(now);
+                scheduler.schedule(later);
+                scheduler.run().unwrap();
+            });
+
+            let now_span = collector
+                .spans
+                .lock()
+                .iter()
+                .find(|span| span.ends_with("name=now"))
+                .cloned()
+                .expect("no span for the named task");
+            let later_span = format!("task id={}", later_id);
+            assert_eq!(
+                *collector.spans.lock(),
+                [now_span.clone(), later_span.clone()]
+            );
+
+            let events = collector.events.lock();
+            let outside = |message: &str| events.iter().any(|e| e == &(None, message.to_string()));
+            let inside = |span: &String, message: &str| {
+                events
+                    .iter()
+                    .any(|e| e == &(Some(span.clone()), message.to_string()))
+            };
+            let scheduled = events
+                .iter()
+                .filter(|(_, message)| message == "task scheduled")
+                .count();
+            assert_eq!(scheduled, 2);
+            assert!(outside("timer expired"));
+            assert!(events.iter().any(|(span, message)| span.is_none()
+                && message.starts_with("sleeping ")
+                && message.ends_with(" until next deadline")));
+            for span in [&now_span, &later_span] {
+                assert!(inside(span, "task started"));
+                assert!(inside(span, "task finished"));
+            }
+        }
+    }
+
+    #[test]
+    fn metrics_track_queues_and_counts() {
+        let scheduler = Scheduler::new();
+        for _ in 0..3 {
+            scheduler.schedule(Task::new(|| {}, None));
+        }
+        for delay in [10, 20] {
+            scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(delay))));
+        }
+        let cancelled = scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(30))));
+        assert!(cancelled.cancel());
+
+        let queued = scheduler.metrics();
+        assert_eq!(queued.ready_len, 3);
+        assert_eq!(queued.sleeping_len, 2);
+        assert_eq!(queued.scheduled, 6);
+        assert_eq!(queued.cancelled, 1);
+        assert_eq!(queued.max_ready_len, 3);
+
+        // Hold up the loop so the timers are overdue by the time they run.
+        scheduler.schedule(Task::new(|| thread::sleep(Duration::from_millis(40)), None));
+        scheduler.run().unwrap();
+
+        let done = scheduler.metrics();
+        assert_eq!(done.ready_len, 0);
+        assert_eq!(done.sleeping_len, 0);
+        assert_eq!(done.scheduled, 7);
+        assert_eq!(done.executed, done.scheduled - done.cancelled);
+        assert!(done.total_wait >= Duration::from_millis(20));
+        assert!(done.average_wait() > Duration::ZERO);
+        assert_eq!(done.average_wait(), done.total_wait / 6);
+    }
+
+    #[test]
+    fn introspection_counts_before_and_after_run() {
+        let scheduler = Scheduler::new();
+        assert!(scheduler.is_idle());
+        for _ in 0..3 {
+            scheduler.schedule(Task::new(|| {}, None));
+        }
+        for delay in [5, 10] {
+            scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(delay))));
+        }
+
+        assert_eq!(scheduler.ready_len(), 3);
+        assert_eq!(scheduler.sleeping_len(), 2);
+        assert_eq!(scheduler.pending_count(), 5);
+        assert!(!scheduler.is_idle());
+
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.ready_len(), 0);
+        assert_eq!(scheduler.sleeping_len(), 0);
+        assert_eq!(scheduler.pending_count(), 0);
+        assert!(scheduler.is_idle());
+    }
+
+    #[test]
+    fn introspection_while_running_on_another_thread() {
+        let scheduler = Scheduler::new();
+        scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(50))));
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(scheduler.sleeping_len(), 1);
+        while !scheduler.is_idle() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        runner.join().unwrap();
+        assert_eq!(scheduler.metrics().executed, 1);
+    }
+
+    #[test]
+    fn next_deadline_is_the_shortest_delay() {
+        let scheduler = Scheduler::new();
+        assert_eq!(scheduler.next_deadline(), None);
+
+        let scheduled = Instant::now();
+        for delay in [300, 100, 200] {
+            scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(delay))));
+        }
+        // Ready tasks don't count towards it.
+        scheduler.schedule(Task::new(|| {}, None));
+
+        let next = scheduler.next_deadline().unwrap();
+        assert!(next >= scheduled + Duration::from_millis(100));
+        assert!(next < scheduled + Duration::from_millis(120));
+    }
+
+    #[test]
+    fn status_follows_a_timer_through_its_life() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let id = TaskId::new();
+        let seen = Arc::new(Mutex::new(None));
+        let record = seen.clone();
+        let mut task = Task::new(
+            move || *record.lock() = Some(Scheduler::current().unwrap().status(id)),
+            Some(Duration::from_millis(50)),
+        );
+        task.id = id;
+        scheduler.schedule(task);
+
+        let deadline = clock.now() + Duration::from_millis(50);
+        assert_eq!(scheduler.status(id), TaskState::Sleeping { deadline });
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(scheduler.status(id), TaskState::Ready);
+        scheduler.run().unwrap();
+        assert_eq!(*seen.lock(), Some(TaskState::Running));
+        assert_eq!(scheduler.status(id), TaskState::Completed);
+    }
+
+    #[test]
+    fn status_remembers_cancelled_tasks_until_they_age_out() {
+        let scheduler = Scheduler::builder().status_history(2).build();
+        let cancelled = scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(60))));
+        assert!(scheduler.cancel(cancelled.id()));
+        assert_eq!(scheduler.status(cancelled.id()), TaskState::Cancelled);
+
+        // An interval cancelled from inside its own run.
+        let own_id = Arc::new(Mutex::new(None));
+        let cancel = own_id.clone();
+        let interval = scheduler.schedule_interval(Duration::from_millis(1), move || {
+            let id = cancel.lock().unwrap();
+            Scheduler::current().unwrap().cancel(id);
+        });
+        *own_id.lock() = Some(interval.id());
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.status(interval.id()), TaskState::Cancelled);
+        assert_eq!(scheduler.status(cancelled.id()), TaskState::Cancelled);
+
+        // A third finished task pushes the oldest out.
+        let ran = scheduler.spawn(|| {}).id();
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.status(ran), TaskState::Completed);
+        assert_eq!(scheduler.status(cancelled.id()), TaskState::Unknown);
+        assert_eq!(scheduler.status(TaskId::new()), TaskState::Unknown);
+    }
+
+    #[test]
+    fn dump_state_tells_what_became_of_each_task() {
+        // Time only passes by sleeping on this clock, so "soon" can't be
+        // due before the loop waits for it.
+        let scheduler = Scheduler::builder()
+            .clock(crate::VirtualClock::new())
+            .on_panic(|_, _| {})
+            .build();
+        scheduler.schedule(Task::new_named("flush", || {}, None));
+        scheduler.schedule(Task::new_named("boom", || panic!("boom"), None));
+        let stale = scheduler.schedule(Task::new_named(
+            "stale",
+            || {},
+            Some(Duration::from_secs(60)),
+        ));
+        scheduler.schedule(Task::new_named(
+            "soon",
+            || {},
+            Some(Duration::from_millis(5)),
+        ));
+        stale.cancel();
+        scheduler.run().unwrap();
+        scheduler.schedule(Task::new_named(
+            "later",
+            || {},
+            Some(Duration::from_secs(60)),
+        ));
+
+        let dump = scheduler.dump_state();
+        for line in [
+            "(flush) completed in",
+            "(boom) panicked",
+            "(stale) cancelled",
+            "(soon) completed in",
+            "slept for",
+            "(later) scheduled",
+            "(later): sleeping, due in",
+        ] {
+            assert!(dump.contains(line), "{:?} missing from:\n{}", line, dump);
+        }
+        let kinds: Vec<EventKind> = scheduler
+            .recent_events()
+            .iter()
+            .filter(|record| record.name.as_deref() == Some("flush"))
+            .map(|record| record.kind)
+            .collect();
+        assert!(matches!(
+            kinds[..],
+            [
+                EventKind::Scheduled,
+                EventKind::Started,
+                EventKind::Completed { .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn recent_events_keep_only_the_newest() {
+        let scheduler = Scheduler::builder().event_history(3).build();
+        for _ in 0..5 {
+            scheduler.spawn(|| {});
+        }
+        let last = scheduler.spawn(|| {}).id();
+        scheduler.run().unwrap();
+
+        let recent = scheduler.recent_events();
+        assert_eq!(recent.len(), 3);
+        assert_eq!(recent[2].task, Some(last));
+        assert!(matches!(recent[2].kind, EventKind::Completed { .. }));
+        assert!(Scheduler::builder()
+            .event_history(0)
+            .build()
+            .recent_events()
+            .is_empty());
+    }
+
+    #[test]
+    fn snapshot_lists_pending_tasks_with_time_left() {
+        let clock = crate::MockClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let late = scheduler.schedule(Task::new_named(
+            "late",
+            || {},
+            Some(Duration::from_millis(200)),
+        ));
+        let soon = scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(100))));
+        let flush = scheduler.schedule(
+            Task::builder()
+                .name("say \"flush\"")
+                .priority(Priority::High)
+                .callback(|| {})
+                .build(),
+        );
+        clock.advance(Duration::from_millis(30));
+
+        let snapshot = scheduler.snapshot();
+        let summary: Vec<_> = snapshot
+            .tasks
+            .iter()
+            .map(|task| (task.id, task.queue, task.remaining))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (flush.id(), QueuedIn::Ready, Duration::ZERO),
+                (soon.id(), QueuedIn::Sleeping, Duration::from_millis(70)),
+                (late.id(), QueuedIn::Sleeping, Duration::from_millis(170)),
+            ]
+        );
+        assert!(snapshot
+            .tasks
+            .windows(2)
+            .all(|pair| pair[0].remaining <= pair[1].remaining));
+        assert_eq!(snapshot.tasks[0].priority, Priority::High);
+        assert_eq!(snapshot.metrics.scheduled, 3);
+        assert_eq!(snapshot.metrics.sleeping_len, 2);
+
+        let json = snapshot.to_json();
+        let tasks = format!(
+            concat!(
+                r#"{{"tasks":[{{"id":"{}","name":"say \"flush\"","priority":"High","#,
+                r#""queue":"Ready","remaining_nanos":0}},{{"id":"{}","name":null,"#,
+                r#""priority":"Normal","queue":"Sleeping","remaining_nanos":70000000}},"#,
+                r#"{{"id":"{}","name":"late","priority":"Normal","queue":"Sleeping","#,
+                r#""remaining_nanos":170000000}}],"metrics":{{"ready_len":1,"sleeping_len":2,"#,
+                r#""scheduled":3,"#
+            ),
+            flush.id(),
+            soon.id(),
+            late.id()
+        );
+        assert!(json.starts_with(&tasks), "{}", json);
+        assert!(json.ends_with(r#""events_dropped":0}}"#), "{}", json);
+    }
+
+    #[test]
+    fn pending_tasks_describe_both_queues() {
+        let scheduler = Scheduler::new();
+        let flush = scheduler.schedule(Task::new_named("flush", || {}, None));
+        let anonymous = scheduler.schedule(Task::new(|| {}, None));
+        let scheduled = Instant::now();
+        let late = scheduler.schedule(Task::new_named(
+            "late",
+            || {},
+            Some(Duration::from_millis(200)),
+        ));
+        let soon = scheduler.schedule(Task::new_named(
+            "soon",
+            || {},
+            Some(Duration::from_millis(100)),
+        ));
+
+        let pending = scheduler.pending_tasks();
+        let summary: Vec<_> = pending
+            .iter()
+            .map(|info| (info.id, info.name.as_deref(), info.state))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (flush.id(), Some("flush"), QueuedIn::Ready),
+                (anonymous.id(), None, QueuedIn::Ready),
+                (soon.id(), Some("soon"), QueuedIn::Sleeping),
+                (late.id(), Some("late"), QueuedIn::Sleeping),
+            ]
+        );
+        let soon_deadline = pending[2].deadline.unwrap();
+        assert!(soon_deadline >= scheduled + Duration::from_millis(100));
+        assert!(soon_deadline < pending[3].deadline.unwrap());
+        assert!(pending[0].deadline.unwrap() <= scheduled);
+
+        // Listing doesn't consume anything.
+        assert_eq!(scheduler.pending_count(), 4);
+        assert!(soon.cancel());
+        assert_eq!(scheduler.pending_tasks().len(), 3);
+    }
+
+    #[test]
+    fn cancel_where_matches_name_prefix() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for (name, delay) in [("a:1", None), ("a:2", Some(5)), ("b:1", Some(5))] {
+            let ran = ran.clone();
+            scheduler.schedule(Task::new_named(
+                name,
+                move || ran.lock().push(name),
+                delay.map(Duration::from_millis),
+            ));
+        }
+
+        let removed = scheduler.cancel_where(|info| {
+            info.name
+                .as_deref()
+                .is_some_and(|name| name.starts_with("a:"))
+        });
+        assert_eq!(removed, 2);
+        scheduler.run().unwrap();
+        assert_eq!(*ran.lock(), ["b:1"]);
+    }
+
+    #[test]
+    fn cancel_where_predicate_may_use_the_scheduler() {
+        let scheduler = Scheduler::new();
+        scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(60))));
+        scheduler.schedule(Task::new(|| {}, None));
+
+        // Would deadlock if the queues were still locked.
+        let removed = scheduler.cancel_where(|info| {
+            scheduler.pending_count() == 2 && info.state == QueuedIn::Sleeping
+        });
+        assert_eq!(removed, 1);
+        assert_eq!(scheduler.pending_count(), 1);
+    }
+
+    #[test]
+    fn cancelled_and_panicking_callbacks_release_their_captures() {
+        let scheduler = Scheduler::builder().on_panic(|_, _| {}).build();
+        let captured = Arc::new(());
+        // One fits inline, the other is too big and gets boxed.
+        let small = |captured: Arc<()>, fail: bool| {
+            Task::new(
+                move || {
+                    let _captured = captured;
+                    assert!(!fail);
+                },
+                None,
+            )
+        };
+        let big = |captured: Arc<()>, fail: bool| {
+            let padding = [1u64; 16];
+            Task::new(
+                move || {
+                    let _captured = captured;
+                    assert!(!fail && padding.iter().sum::<u64>() == 16);
+                },
+                None,
+            )
+        };
+        let cancelled = [
+            scheduler.schedule(small(captured.clone(), false)).id(),
+            scheduler.schedule(big(captured.clone(), false)).id(),
+        ];
+        scheduler.schedule(small(captured.clone(), true));
+        scheduler.schedule(big(captured.clone(), true));
+        assert_eq!(Arc::strong_count(&captured), 5);
+
+        assert_eq!(scheduler.cancel_many(&cancelled), 2);
+        assert_eq!(Arc::strong_count(&captured), 3);
+        let report = scheduler.run().unwrap();
+        assert_eq!(report.panics, 2);
+        assert_eq!(Arc::strong_count(&captured), 1);
     }
-}
 
-impl Task {
-    fn new(callback: impl FnOnce() + Send + 'static, expires: Option<Duration>) -> Self {
-        Self {
-            id: Uuid::new_v4(),
-            callback: Box::new(callback),
-            expires,
+    #[test]
+    fn preallocated_queues_do_not_grow() {
+        const TASKS: usize = 1000;
+        let wheel = crate::TimerBackend::Wheel {
+            tick: Duration::from_millis(1),
+            slots: 64,
+        };
+        for builder in [
+            Scheduler::builder(),
+            Scheduler::builder().policy(SchedulerPolicy::EarliestDeadlineFirst),
+            Scheduler::builder().timer_backend(wheel),
+        ] {
+            let scheduler = builder.capacity(TASKS, TASKS).build();
+            let (ready, sleeping) = (scheduler.ready_capacity(), scheduler.sleeping_capacity());
+            assert!(ready >= TASKS && sleeping >= TASKS);
+            for i in 0..TASKS as u64 {
+                scheduler.schedule(Task::new(|| {}, None));
+                scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(i + 1))));
+            }
+            assert_eq!(scheduler.ready_capacity(), ready);
+            assert_eq!(scheduler.sleeping_capacity(), sleeping);
+
+            // And again at runtime, on top of what is queued.
+            scheduler.reserve_ready(TASKS);
+            scheduler.reserve_sleeping(TASKS);
+            let (ready, sleeping) = (scheduler.ready_capacity(), scheduler.sleeping_capacity());
+            for i in 0..TASKS as u64 {
+                scheduler.schedule(Task::new(|| {}, None));
+                scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(i + 1))));
+            }
+            assert_eq!(scheduler.ready_capacity(), ready);
+            assert_eq!(scheduler.sleeping_capacity(), sleeping);
         }
+        let scheduler = Scheduler::with_capacity(10, 20);
+        assert!(scheduler.ready_capacity() >= 10 && scheduler.sleeping_capacity() >= 20);
     }
-}
 
-struct Scheduler {
-    ready_fns: Mutex<VecDeque<Task>>,
-    sleeping_fns: Mutex<VecDeque<Task>>,
-}
+    #[test]
+    fn queues_shrink_back_after_a_burst() {
+        const TASKS: usize = 100_000;
+        let burst = |scheduler: &Scheduler| {
+            for _ in 0..TASKS {
+                scheduler.schedule(Task::new(|| {}, None));
+            }
+            for i in 0..TASKS as u64 {
+                scheduler.schedule(Task::new(|| {}, Some(Duration::from_nanos(i + 1))));
+            }
+            assert!(scheduler.ready_capacity() >= TASKS);
+            assert!(scheduler.sleeping_capacity() >= TASKS);
+            scheduler.run().unwrap();
+        };
 
-impl Scheduler {
-    fn new() -> Arc<Self> {
-        Arc::new(Self {
-            ready_fns: Mutex::new(VecDeque::new()),
-            sleeping_fns: Mutex::new(VecDeque::new()),
+        let scheduler = Scheduler::builder()
+            .shrink_policy(ShrinkPolicy::WhenIdle { keep: 100 })
+            .build();
+        burst(&scheduler);
+        assert!(
+            scheduler.ready_capacity() < 1000,
+            "{}",
+            scheduler.ready_capacity()
+        );
+        assert!(scheduler.sleeping_capacity() < 1000);
+        assert!(scheduler.ready_capacity() >= 100 && scheduler.sleeping_capacity() >= 100);
+
+        let scheduler = Scheduler::new();
+        burst(&scheduler);
+        assert!(scheduler.ready_capacity() >= TASKS);
+        scheduler.shrink_to_fit();
+        assert_eq!(
+            (scheduler.ready_capacity(), scheduler.sleeping_capacity()),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn a_panic_while_holding_the_queues_does_not_brick_the_scheduler() {
+        let scheduler = Scheduler::new();
+        scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(1))));
+        let poisoner = scheduler.clone();
+        let result = thread::spawn(move || {
+            let _ready = poisoner.ready_fns.lock();
+            let _sleeping = poisoner.sleeping_fns.lock();
+            panic!("while holding the queues");
         })
+        .join();
+        assert!(result.is_err());
+        assert!(scheduler.ready_fns.is_poisoned());
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        for delay in [None, Some(Duration::from_millis(2))] {
+            let ran = ran.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    ran.fetch_add(1, AtomicOrdering::SeqCst);
+                },
+                delay,
+            ));
+        }
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 3);
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 2);
     }
 
-    fn schedule(&self, mut task: Task) {
-        match task.expires {
-            None => {
-                let mut ready_fns_guard = self.ready_fns.lock().unwrap();
-                ready_fns_guard.push_back(task);
-                drop(ready_fns_guard);
-            }
-            Some(expires) =>{
-                let mut sleeping_fns_guard = self.sleeping_fns.lock().unwrap();
-                task.expires = Some(expires);
+    #[test]
+    fn failing_callbacks_are_reported_to_on_error() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let on_error = reported.clone();
+        let scheduler = Scheduler::builder()
+            .on_error(move |meta, error| on_error.lock().push((meta.id, error.to_string())))
+            .build();
+        let fail = |message: &'static str| move || Err(std::io::Error::other(message));
+        let first = scheduler.schedule_fallible(fail("first"), None).id();
+        scheduler.schedule_fallible(|| Ok::<(), std::io::Error>(()), None);
+        let second = scheduler
+            .schedule_fallible(fail("second"), Some(Duration::from_millis(1)))
+            .id();
 
-                // @todo: sort before pushing
-                sleeping_fns_guard.push_back(task);
-                drop(sleeping_fns_guard);
-            }
+        let report = scheduler.run().unwrap();
+        assert_eq!(report.tasks_executed, 3);
+        assert_eq!(
+            *reported.lock(),
+            [(first, "first".to_string()), (second, "second".to_string())]
+        );
+        assert_eq!(scheduler.metrics().failed, 2);
+    }
+
+    #[test]
+    fn clear_drops_everything_pending() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicUsize::new(0));
+        // Dropping the callbacks releases what they captured.
+        let captured = Arc::new(());
+        for delay in [None, None, None, Some(5), Some(10)] {
+            let (ran, captured) = (ran.clone(), captured.clone());
+            scheduler.schedule(Task::new(
+                move || {
+                    let _captured = captured;
+                    ran.fetch_add(1, AtomicOrdering::SeqCst);
+                },
+                delay.map(Duration::from_millis),
+            ));
         }
+        assert_eq!(Arc::strong_count(&captured), 6);
+
+        assert_eq!(scheduler.clear(), (3, 2));
+        assert_eq!(Arc::strong_count(&captured), 1);
+        assert!(scheduler.is_idle());
+        assert_eq!(scheduler.metrics().cancelled, 5);
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 0);
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 0);
     }
 
-    fn run(&self) {
-        let is_empty = |task: &str| {
-            if task == "ready" {
-                let ready_guard = self.ready_fns.lock().unwrap();
-                let empty = ready_guard.is_empty();
-                drop(ready_guard);
-                empty
-            } else {
-                let sleep_guard = self.sleeping_fns.lock().unwrap();
-                let empty = sleep_guard.is_empty();
-                drop(sleep_guard);
-                empty
-            }
+    #[test]
+    fn clear_from_inside_a_callback_stops_the_rest() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let counter = ran.clone();
+        let clearing = scheduler.clone();
+        scheduler.schedule_interval(Duration::from_millis(1), move || {
+            counter.fetch_add(1, AtomicOrdering::SeqCst);
+            assert_eq!(clearing.clear(), (0, 1));
+        });
+        scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(20))));
+        thread::sleep(Duration::from_millis(2));
+
+        // The in-flight interval finishes its run but is not re-queued.
+        let report = scheduler.run().unwrap();
+        assert_eq!(report.tasks_executed, 1);
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[test]
+    fn paused_scheduler_holds_timers_until_resumed() {
+        let scheduler = Scheduler::new();
+        scheduler.pause();
+        let fired = Arc::new(Mutex::new(None));
+        scheduler.schedule(Task::new(
+            record_fire(&fired),
+            Some(Duration::from_millis(10)),
+        ));
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(fired.lock().is_none());
+        assert_eq!(scheduler.sleeping_len(), 1);
+
+        let resumed = Instant::now();
+        scheduler.resume();
+        runner.join().unwrap();
+        let fired = fired.lock().unwrap();
+        assert!(fired - resumed < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn pause_from_inside_a_task() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicUsize::new(0));
+        let pausing = scheduler.clone();
+        scheduler.schedule(Task::new(move || pausing.pause(), None));
+        for _ in 0..3 {
+            let ran = ran.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    ran.fetch_add(1, AtomicOrdering::SeqCst);
+                },
+                None,
+            ));
+        }
+
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 0);
+        assert!(scheduler.is_paused());
+        assert_eq!(scheduler.tick().executed, 0);
+
+        scheduler.resume();
+        runner.join().unwrap();
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 3);
+    }
+
+    fn bounded(limit: usize) -> Arc<Scheduler> {
+        Scheduler::builder().max_pending(limit).build()
+    }
+
+    #[test]
+    fn try_schedule_rejects_past_the_limit() {
+        let scheduler = bounded(2);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let task = |name: &'static str, delay| {
+            let ran = ran.clone();
+            Task::new_named(name, move || ran.lock().push(name), delay)
+        };
+
+        scheduler.try_schedule(task("first", None)).unwrap();
+        scheduler
+            .try_schedule(task("second", Some(Duration::from_millis(5))))
+            .unwrap();
+        let rejected = match scheduler.try_schedule(task("third", None)) {
+            Err(ScheduleError::QueueFull(task)) => task,
+            Err(error) => panic!("{}", error),
+            Ok(_) => panic!("third task was accepted"),
+        };
+        assert_eq!(rejected.name(), Some("third"));
+        assert_eq!(scheduler.pending_count(), 2);
+
+        scheduler.run().unwrap();
+        // The rejected task still owns its callback.
+        scheduler.try_schedule(rejected).unwrap();
+        scheduler.run().unwrap();
+        assert_eq!(*ran.lock(), ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn try_schedule_rejects_after_shutdown() {
+        let scheduler = Scheduler::new();
+        scheduler.shutdown();
+        let rejected = match scheduler.try_schedule(Task::new_named("late", || {}, None)) {
+            Err(ScheduleError::ShutDown(task)) => task,
+            Err(error) => panic!("{}", error),
+            Ok(_) => panic!("task was accepted after shutdown"),
         };
+        assert_eq!(rejected.name(), Some("late"));
+        assert_eq!(scheduler.pending_count(), 0);
+
+        scheduler.reset();
+        scheduler.try_schedule(rejected).unwrap();
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "scheduler queue is full")]
+    fn schedule_panics_when_full() {
+        let scheduler = bounded(1);
+        scheduler.schedule(Task::new(|| {}, None));
+        scheduler.schedule(Task::new(|| {}, None));
+    }
 
-        let run_sleeping = || {
-            let mut sleeping_tasks = self.sleeping_fns.lock().unwrap();
-            if let Some(task) = sleeping_tasks.pop_front() {
-                if let Some(_) = task.expires {
-                    let now = Instant::now();
-                    let delta = task.expires.unwrap().sub(now.elapsed());
-                    if delta.as_secs() > 0 {
-                        thread::sleep(delta);
+    #[test]
+    fn woken_futures_are_exempt_from_the_limit() {
+        let scheduler = bounded(1);
+        let output = scheduler.block_on(async {
+            // Each yield re-queues the future's poll past the limit's reach.
+            for _ in 0..3 {
+                let mut yielded = false;
+                std::future::poll_fn(|cx| {
+                    if std::mem::replace(&mut yielded, true) {
+                        std::task::Poll::Ready(())
+                    } else {
+                        cx.waker().wake_by_ref();
+                        std::task::Poll::Pending
                     }
-                    let mut ready_tasks = self.ready_fns.lock().unwrap();
-                    ready_tasks.push_back(task);
-                    drop(ready_tasks);
-                }
+                })
+                .await;
             }
-            drop(sleeping_tasks);
+            7
+        });
+        assert_eq!(output, 7);
+    }
+
+    fn overflowing(limit: usize, policy: OverflowPolicy) -> Arc<Scheduler> {
+        Scheduler::builder()
+            .max_pending(limit)
+            .overflow_policy(policy)
+            .build()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_in_order() {
+        let scheduler = overflowing(3, OverflowPolicy::DropOldest);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        // The delayed one is the oldest, so it goes first despite being in
+        // the other queue.
+        let delayed = ran.clone();
+        scheduler.schedule(Task::new(
+            move || delayed.lock().push(0),
+            Some(Duration::from_millis(5)),
+        ));
+        for i in 1..6 {
+            let ran = ran.clone();
+            scheduler.schedule(Task::new(move || ran.lock().push(i), None));
+        }
+        assert_eq!(scheduler.pending_count(), 3);
+
+        scheduler.run().unwrap();
+        assert_eq!(*ran.lock(), [3, 4, 5]);
+    }
+
+    #[test]
+    fn reject_new_drops_the_incoming_task() {
+        let scheduler = overflowing(2, OverflowPolicy::RejectNew);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let mut handles = Vec::new();
+        for i in 0..4 {
+            let ran = ran.clone();
+            handles.push(scheduler.schedule(Task::new(move || ran.lock().push(i), None)));
+        }
+
+        // The rejected tasks' handles point at nothing.
+        assert!(!handles[3].cancel());
+        scheduler.run().unwrap();
+        assert_eq!(*ran.lock(), [0, 1]);
+    }
+
+    #[test]
+    fn block_waits_for_the_loop_to_make_room() {
+        let scheduler = overflowing(1, OverflowPolicy::Block);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run_forever())
         };
 
-        let run_active = || {
-            let mut ready_task = self.ready_fns.lock().unwrap();
-            while let Some(task) = ready_task.pop_front() {
-                drop(ready_task);
-                (task.callback)();
-                ready_task = self.ready_fns.lock().unwrap();
-            }
+        let producer = {
+            let (scheduler, ran) = (scheduler.clone(), ran.clone());
+            thread::spawn(move || {
+                for i in 0..20 {
+                    let ran = ran.clone();
+                    scheduler.schedule(Task::new(
+                        move || {
+                            thread::sleep(Duration::from_millis(1));
+                            ran.lock().push(i);
+                        },
+                        None,
+                    ));
+                }
+            })
         };
+        producer.join().unwrap();
+        while ran.lock().len() < 20 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        scheduler.shutdown();
+        runner.join().unwrap().unwrap();
+
+        assert_eq!(*ran.lock(), (0..20).collect::<Vec<_>>());
+        assert_eq!(scheduler.metrics().max_ready_len, 1);
+    }
+
+    #[test]
+    fn block_on_the_loop_thread_panics_instead_of_deadlocking() {
+        let panics = Arc::new(Mutex::new(Vec::new()));
+        let seen = panics.clone();
+        let scheduler = Scheduler::builder()
+            .max_pending(1)
+            .overflow_policy(OverflowPolicy::Block)
+            .on_panic(move |_, payload| {
+                seen.lock()
+                    .push(panic_message(payload.as_ref()).to_string());
+            })
+            .build();
+
+        let inner = scheduler.clone();
+        scheduler.schedule(Task::new(
+            move || {
+                inner.schedule(Task::new(|| {}, Some(Duration::from_millis(1))));
+                // The queue is full now.
+                inner.schedule(Task::new(|| {}, None));
+            },
+            None,
+        ));
+        scheduler.run().unwrap();
+
+        let panics = panics.lock();
+        assert_eq!(panics.len(), 1);
+        assert!(panics[0].contains("would block the loop thread"));
+    }
+
+    #[test]
+    fn schedule_all_returns_handles_in_input_order() {
+        let scheduler = Scheduler::new();
+        let tasks: Vec<Task> = (0..6)
+            .map(|i| Task::new(|| {}, (i % 2 == 1).then(|| Duration::from_millis(i))))
+            .collect();
+        let ids: Vec<TaskId> = tasks.iter().map(Task::id).collect();
+
+        let handles = scheduler.schedule_all(tasks);
+        assert_eq!(handles.iter().map(TaskHandle::id).collect::<Vec<_>>(), ids);
+        assert_eq!(scheduler.ready_len(), 3);
+        assert_eq!(scheduler.sleeping_len(), 3);
+        assert_eq!(scheduler.metrics().scheduled, 6);
+    }
+
+    #[test]
+    fn schedule_all_with_10k_mixed_tasks() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let tasks = (0..10_000u64).map(|i| {
+            let order = order.clone();
+            // Every third task is delayed, with deadlines out of input order.
+            let delay = (i % 3 == 0).then(|| Duration::from_micros((i * 7919) % 20_000));
+            Task::new(move || order.lock().push((i, delay)), delay)
+        });
+        scheduler.schedule_all(tasks);
+        let report = scheduler.run().unwrap();
+
+        assert_eq!(report.tasks_executed, 10_000);
+        let order = order.lock();
+        let immediate: Vec<u64> = order
+            .iter()
+            .filter(|(_, delay)| delay.is_none())
+            .map(|(i, _)| *i)
+            .collect();
+        assert_eq!(
+            immediate,
+            (0..10_000).filter(|i| i % 3 != 0).collect::<Vec<_>>()
+        );
+        let delays: Vec<Duration> = order.iter().filter_map(|(_, delay)| *delay).collect();
+        assert_eq!(delays.len(), 3334);
+        assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn extend_schedules_the_batch() {
+        let scheduler = Scheduler::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        let tasks = (0..5).map(|_| {
+            let count = count.clone();
+            Task::new(
+                move || {
+                    count.fetch_add(1, AtomicOrdering::SeqCst);
+                },
+                None,
+            )
+        });
+        (&*scheduler).extend(tasks);
+        scheduler.run().unwrap();
+        assert_eq!(count.load(AtomicOrdering::SeqCst), 5);
+    }
+
+    #[test]
+    fn concurrent_producers_lose_no_tasks() {
+        const PRODUCERS: usize = 8;
+        const PER_PRODUCER: usize = 5_000;
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(Mutex::new(vec![Vec::new(); PRODUCERS]));
+        let runner = scheduler.start();
+
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|producer| {
+                let (scheduler, ran) = (scheduler.clone(), ran.clone());
+                thread::spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        let ran = ran.clone();
+                        // A few timers mixed in, so both queues are fed.
+                        let delay = (i % 100 == 0).then(|| Duration::from_micros(50));
+                        scheduler.schedule(Task::new(move || ran.lock()[producer].push(i), delay));
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        while scheduler.metrics().executed < (PRODUCERS * PER_PRODUCER) as u64 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        runner.stop();
+
+        let ran = ran.lock();
+        for runs in ran.iter() {
+            assert_eq!(runs.len(), PER_PRODUCER);
+            // Each producer's immediate tasks run in the order it sent them.
+            let immediate: Vec<usize> = runs.iter().copied().filter(|i| i % 100 != 0).collect();
+            assert!(immediate.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        assert!(scheduler.is_idle());
+        let metrics = scheduler.metrics();
+        assert_eq!(metrics.scheduled, (PRODUCERS * PER_PRODUCER) as u64);
+        assert_eq!((metrics.ready_len, metrics.sleeping_len), (0, 0));
+    }
+
+    #[test]
+    fn injected_tasks_are_visible_before_the_loop_drains_them() {
+        let scheduler = Scheduler::new();
+        let ready = scheduler.schedule(Task::new(|| {}, None));
+        scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(60))));
+
+        assert_eq!(scheduler.pending_count(), 2);
+        assert_eq!(scheduler.pending_tasks()[0].id, ready.id());
+        assert!(ready.cancel());
+        assert_eq!(scheduler.pending_count(), 1);
+    }
+
+    #[test]
+    fn loop_thread_and_external_tasks_keep_their_schedule_order() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (sent_tx, sent_rx) = std::sync::mpsc::channel::<()>();
 
-        while !is_empty("ready") || !is_empty("sleep") {
-            if is_empty("ready") {
-                run_sleeping();
+        let first = {
+            let (scheduler, order) = (scheduler.clone(), order.clone());
+            move || {
+                let log = order.clone();
+                scheduler.schedule(Task::new(move || log.lock().push("loop 1"), None));
+                started_tx.send(()).unwrap();
+                sent_rx.recv().unwrap();
+                let log = order.clone();
+                scheduler.schedule(Task::new(move || log.lock().push("loop 2"), None));
             }
-            run_active();
+        };
+        scheduler.schedule(Task::new(first, None));
+        let runner = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run().unwrap())
+        };
+
+        started_rx.recv().unwrap();
+        let log = order.clone();
+        scheduler.schedule(Task::new(move || log.lock().push("external"), None));
+        sent_tx.send(()).unwrap();
+        runner.join().unwrap();
+
+        assert_eq!(*order.lock(), ["loop 1", "external", "loop 2"]);
+    }
+
+    fn sleepy_tasks(scheduler: &Scheduler, count: usize) {
+        for _ in 0..count {
+            scheduler.schedule(Task::new(|| thread::sleep(Duration::from_millis(10)), None));
         }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::thread;
-    use std::time::Duration;
+    #[test]
+    fn run_pool_runs_callbacks_concurrently() {
+        let scheduler = Scheduler::new();
+        sleepy_tasks(&scheduler, 100);
+        let started = Instant::now();
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 100);
+        let single = started.elapsed();
 
-    fn countdown(n: usize, scheduler: Arc<Scheduler>) {
-        if n > 0 {
-            println!("Down={}", n);
-            thread::sleep(Duration::from_secs(1));
-            let scheduler_clone = scheduler.clone();
+        sleepy_tasks(&scheduler, 100);
+        let started = Instant::now();
+        let report = scheduler.run_pool(4).unwrap();
+        let pooled = started.elapsed();
+
+        assert_eq!(report.tasks_executed, 100);
+        assert!(pooled >= Duration::from_millis(250), "{:?}", pooled);
+        assert!(pooled < single / 2, "{:?} vs {:?}", pooled, single);
+    }
+
+    #[test]
+    fn run_pool_returns_once_chained_work_and_timers_drain() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for i in 0..20u64 {
+            let (next, ran) = (scheduler.clone(), ran.clone());
+            let delay = (i % 4 == 0).then(|| Duration::from_millis(i + 1));
             scheduler.schedule(Task::new(
-                move || countdown(n - 1, scheduler_clone.clone()),
-                Some(Duration::from_secs(2)),
+                move || {
+                    ran.fetch_add(1, AtomicOrdering::SeqCst);
+                    let ran = ran.clone();
+                    next.schedule(Task::new(
+                        move || {
+                            ran.fetch_add(1, AtomicOrdering::SeqCst);
+                        },
+                        Some(Duration::from_millis(2)),
+                    ));
+                },
+                delay,
             ));
         }
+
+        let report = scheduler.run_pool(3).unwrap();
+        assert_eq!(ran.load(AtomicOrdering::SeqCst), 40);
+        assert_eq!(report.tasks_executed, 40);
+        assert_eq!(report.timers_fired, 25);
+        assert!(scheduler.is_idle());
+        assert!(scheduler.loop_threads.lock().is_empty());
     }
 
-    fn countup(n: usize, scheduler: Arc<Scheduler>) {
-        if n > 0 {
-            println!("Up={}", n);
-            thread::sleep(Duration::from_secs(2));
-            let scheduler_clone = scheduler.clone();
+    #[test]
+    fn run_pool_advances_a_virtual_clock_once_per_deadline() {
+        let clock = crate::VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for secs in [3, 1, 2] {
+            let (fired, clock) = (fired.clone(), clock.clone());
             scheduler.schedule(Task::new(
-                move || countup(n - 1, scheduler_clone.clone()),
+                move || fired.lock().push((secs, clock.now())),
+                Some(Duration::from_secs(secs)),
+            ));
+        }
+        let start = clock.now();
+
+        let report = scheduler.run_pool(4).unwrap();
+        assert_eq!(report.total_runtime, Duration::from_secs(3));
+        assert_eq!(report.time_sleeping, Duration::from_secs(3));
+        let mut fired = fired.lock().clone();
+        fired.sort();
+        for (secs, at) in fired {
+            assert_eq!(at, start + Duration::from_secs(secs));
+        }
+    }
+
+    #[test]
+    fn shutdown_stops_every_pool_worker() {
+        fn spin(scheduler: Arc<Scheduler>) {
+            let next = scheduler.clone();
+            scheduler.schedule(Task::new(move || spin(next), None));
+        }
+        let scheduler = Scheduler::new();
+        for _ in 0..4 {
+            spin(scheduler.clone());
+        }
+        let pool = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || scheduler.run_pool(4).unwrap())
+        };
+        thread::sleep(Duration::from_millis(20));
+        scheduler.shutdown();
+        let report = pool.join().unwrap();
+
+        assert!(report.tasks_executed > 0);
+        assert!(scheduler.loop_threads.lock().is_empty());
+    }
+
+    #[test]
+    fn run_pool_cancels_intervals_from_their_own_run() {
+        let scheduler = Scheduler::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let handle = Arc::new(Mutex::new(None::<TaskHandle>));
+        let interval = {
+            let (runs, handle) = (runs.clone(), handle.clone());
+            scheduler.schedule_interval(Duration::from_millis(1), move || {
+                if runs.fetch_add(1, AtomicOrdering::SeqCst) == 4 {
+                    handle.lock().take().unwrap().cancel();
+                }
+            })
+        };
+        *handle.lock() = Some(interval);
+        sleepy_tasks(&scheduler, 4);
+
+        scheduler.run_pool(2).unwrap();
+        assert_eq!(runs.load(AtomicOrdering::SeqCst), 5);
+        assert!(scheduler.running_intervals.lock().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one worker")]
+    fn run_pool_needs_a_worker() {
+        Scheduler::new().run_pool(0).unwrap();
+    }
+
+    #[test]
+    fn pool_runs_every_task_exactly_once_under_cross_worker_spawning() {
+        fn spawn_tree(scheduler: Arc<Scheduler>, runs: Arc<Vec<AtomicUsize>>, node: usize) {
+            let next = scheduler.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    runs[node].fetch_add(1, AtomicOrdering::SeqCst);
+                    thread::sleep(Duration::from_micros(20));
+                    for child in [2 * node + 1, 2 * node + 2] {
+                        if child < runs.len() {
+                            spawn_tree(next.clone(), runs.clone(), child);
+                        }
+                    }
+                },
                 None,
-            ))
+            ));
         }
+
+        let scheduler = Scheduler::new();
+        let runs: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..8_191).map(|_| AtomicUsize::new(0)).collect());
+        spawn_tree(scheduler.clone(), runs.clone(), 0);
+
+        let report = scheduler.run_pool(4).unwrap();
+        assert_eq!(report.tasks_executed, runs.len());
+        assert!(runs
+            .iter()
+            .all(|runs| runs.load(AtomicOrdering::SeqCst) == 1));
+        let metrics = scheduler.metrics();
+        assert!(metrics.steals > 0);
+        assert_eq!(metrics.ready_len, 0);
+        assert!(scheduler.locals.lock().is_empty());
     }
 
     #[test]
-    fn test() {
+    fn pool_worker_runs_its_own_tasks_newest_first() {
         let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let parent = {
+            let (scheduler, order) = (scheduler.clone(), order.clone());
+            move || {
+                for i in 0..3 {
+                    let order = order.clone();
+                    scheduler.schedule(Task::new(move || order.lock().push(i), None));
+                }
+                assert_eq!(scheduler.ready_len(), 3);
+                assert_eq!(scheduler.pending_tasks().len(), 3);
+            }
+        };
+        scheduler.schedule(Task::new(parent, None));
 
-        {
-            let scheduler_clone = scheduler.clone();
-            scheduler.schedule(Task::new(move || countdown(5, scheduler_clone), None));
+        scheduler.run_pool(1).unwrap();
+        assert_eq!(*order.lock(), [2, 1, 0]);
+    }
+
+    #[test]
+    fn local_tasks_can_be_cancelled() {
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let parent = {
+            let (scheduler, ran) = (scheduler.clone(), ran.clone());
+            move || {
+                let handle = scheduler.schedule(Task::new(
+                    move || ran.store(true, AtomicOrdering::SeqCst),
+                    None,
+                ));
+                assert!(handle.cancel());
+                assert!(scheduler.is_idle());
+            }
+        };
+        scheduler.schedule(Task::new(parent, None));
+
+        assert_eq!(scheduler.run_pool(2).unwrap().tasks_executed, 1);
+        assert!(!ran.load(AtomicOrdering::SeqCst));
+        assert_eq!(scheduler.metrics().cancelled, 1);
+    }
+
+    #[test]
+    fn local_tasks_go_back_to_the_shared_queue_on_shutdown() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let parent = {
+            let (scheduler, order) = (scheduler.clone(), order.clone());
+            move || {
+                scheduler.shutdown();
+                for i in 0..5 {
+                    let order = order.clone();
+                    scheduler.schedule(Task::new(move || order.lock().push(i), None));
+                }
+            }
+        };
+        scheduler.schedule(Task::new(parent, None));
+
+        scheduler.run_pool(1).unwrap();
+        assert_eq!(scheduler.ready_len(), 5);
+        scheduler.reset();
+        scheduler.run().unwrap();
+        assert_eq!(*order.lock(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn a_rescheduling_callback_does_not_starve_the_shared_queue() {
+        fn spin(scheduler: Arc<Scheduler>, spins: Arc<AtomicUsize>) {
+            let next = scheduler.clone();
+            scheduler.schedule(Task::new(
+                move || {
+                    if spins.fetch_add(1, AtomicOrdering::SeqCst) < 1_000 {
+                        spin(next, spins);
+                    }
+                },
+                None,
+            ));
         }
+        let scheduler = Scheduler::new();
+        let spins = Arc::new(AtomicUsize::new(0));
+        let spun_before_other = Arc::new(AtomicUsize::new(0));
+        spin(scheduler.clone(), spins.clone());
+        let other = {
+            let (spins, spun_before_other) = (spins.clone(), spun_before_other.clone());
+            move || {
+                spun_before_other.store(spins.load(AtomicOrdering::SeqCst), AtomicOrdering::SeqCst)
+            }
+        };
+        scheduler.schedule(Task::new(other, None));
 
-        {
-            let scheduler_clone = scheduler.clone();
-            scheduler.schedule(Task::new(move || countup(3, scheduler_clone), None));
+        scheduler.run_pool(1).unwrap();
+        let spun = spun_before_other.load(AtomicOrdering::SeqCst);
+        assert!(spun > 0 && spun <= SHARED_QUEUE_INTERVAL, "{}", spun);
+    }
+
+    #[test]
+    fn panic_policy_decides_what_runs_after_a_panic() {
+        use crate::PanicPolicy::{AbortAll, Continue, DrainAndStop};
+        for (policy, expected_ran, expected_left) in [
+            (Continue, vec!["ready", "timer"], 0),
+            (DrainAndStop, vec!["ready"], 1),
+            (AbortAll, vec![], 0),
+        ] {
+            let scheduler = Scheduler::builder()
+                .clock(crate::VirtualClock::new())
+                .on_panic(|_, _| {})
+                .panic_policy(policy)
+                .build();
+            let ran = Arc::new(Mutex::new(Vec::new()));
+            let record = |name: &'static str| {
+                let ran = ran.clone();
+                move || ran.lock().push(name)
+            };
+            scheduler.schedule(Task::new(|| panic!("inconsistent"), None));
+            scheduler.schedule(Task::new(record("ready"), None));
+            scheduler.schedule(Task::new(record("timer"), Some(Duration::from_secs(1))));
+
+            let result = scheduler.run();
+            assert_eq!(result.is_err(), policy != Continue, "{:?}", policy);
+            let report = match result {
+                Ok(report) | Err(RunError::StoppedOnPanic(report)) => report,
+                Err(error) => panic!("{}", error),
+            };
+            assert_eq!(report.panics, 1, "{:?}", policy);
+            assert_eq!(*ran.lock(), expected_ran, "{:?}", policy);
+            assert_eq!(scheduler.pending_count(), expected_left, "{:?}", policy);
+            let stopped = (policy != Continue).then_some(policy);
+            assert_eq!(report.stopped_on_panic, stopped);
         }
+    }
+
+    #[test]
+    fn abort_all_leaves_no_close_callbacks_behind() {
+        let scheduler = Scheduler::builder()
+            .on_panic(|_, _| {})
+            .panic_policy(PanicPolicy::AbortAll)
+            .build();
+        let closed = Arc::new(AtomicBool::new(false));
+        scheduler.schedule(Task::new(|| panic!("inconsistent"), None));
+        let pending = scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(60))));
+        let flag = closed.clone();
+        scheduler.on_close(pending.id(), move || {
+            flag.store(true, AtomicOrdering::SeqCst)
+        });
+
+        let error = scheduler.run().unwrap_err();
+        assert!(matches!(error, RunError::StoppedOnPanic(report) if report.panics == 1));
+        assert_eq!(scheduler.pending_count(), 0);
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 0);
+        assert!(!closed.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn run_from_inside_the_loop_is_refused() {
+        let scheduler = Scheduler::builder()
+            .on_panic(|_, payload| panic::resume_unwind(payload))
+            .build();
+        let nested = Arc::new(Mutex::new(Vec::new()));
+        let (inner, results) = (scheduler.clone(), nested.clone());
+        scheduler.schedule(Task::new(
+            move || {
+                let soon = Duration::from_millis(10);
+                let mut results = results.lock();
+                results.push(inner.run().map(drop));
+                results.push(inner.run_for(soon).map(drop));
+                results.push(inner.run_until(Instant::now() + soon).map(drop));
+                results.push(inner.run_forever());
+            },
+            None,
+        ));
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 1);
+        assert_eq!(*nested.lock(), [Err(RunError::AlreadyRunning); 4]);
+
+        // A loop that unwinds lets the next one start all the same.
+        scheduler.schedule(Task::new(|| panic!("out of the loop"), None));
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| scheduler.run())).is_err());
+        scheduler.schedule(Task::new(|| {}, None));
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 1);
+    }
+
+    #[test]
+    fn only_one_of_two_racing_runs_gets_the_loop() {
+        let scheduler = Scheduler::new();
+        let lost = Arc::new(AtomicBool::new(false));
+        let waiting = lost.clone();
+        // Keeps the winner busy until the other thread has been turned away.
+        scheduler.schedule(Task::new(
+            move || {
+                while !waiting.load(AtomicOrdering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            },
+            None,
+        ));
+        let runs: Vec<_> = (0..10)
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .inspect(|runs| {
+                let runs = runs.clone();
+                scheduler.schedule(Task::new(
+                    move || {
+                        runs.fetch_add(1, AtomicOrdering::SeqCst);
+                    },
+                    None,
+                ));
+            })
+            .collect();
+
+        let start = Arc::new(std::sync::Barrier::new(2));
+        let racers: Vec<_> = (0..2)
+            .map(|_| {
+                let (scheduler, start, lost) = (scheduler.clone(), start.clone(), lost.clone());
+                thread::spawn(move || {
+                    start.wait();
+                    let result = scheduler.run();
+                    if result.is_err() {
+                        lost.store(true, AtomicOrdering::SeqCst);
+                    }
+                    result
+                })
+            })
+            .collect();
+        let results: Vec<_> = racers
+            .into_iter()
+            .map(|racer| racer.join().unwrap())
+            .collect();
 
-        scheduler.run();
+        let won: Vec<_> = results.iter().filter_map(|result| result.ok()).collect();
+        assert_eq!(won.len(), 1, "{:?}", results);
+        assert_eq!(won[0].tasks_executed, 11);
+        assert!(results.contains(&Err(RunError::AlreadyRunning)));
+        assert!(runs
+            .iter()
+            .all(|runs| runs.load(AtomicOrdering::SeqCst) == 1));
     }
 }
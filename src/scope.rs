@@ -0,0 +1,249 @@
+use crate::sync::{Condvar, Mutex};
+use crate::TaskId;
+use crate::{Scheduler, Task, TaskHandle};
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Schedules tasks that may borrow from outside the scope, from
+/// [`Scheduler::scope`].
+///
+/// Tasks can't borrow anything created inside the scope's closure, since
+/// that is gone before they run:
+///
+/// ```compile_fail
+/// use revent_loop::Scheduler;
+///
+/// let scheduler = Scheduler::new();
+/// scheduler.scope(|s| {
+///     let inner = 1;
+///     s.schedule(|| println!("{}", inner), None);
+/// });
+/// ```
+pub struct Scope<'scope, 'env: 'scope> {
+    scheduler: &'scope Scheduler,
+    state: Arc<ScopeState>,
+    /// Invariant over both lifetimes, as in [`std::thread::Scope`].
+    _scope: PhantomData<&'scope mut &'scope ()>,
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+#[derive(Default)]
+struct ScopeState {
+    live: Mutex<Live>,
+    /// Signalled whenever a task's callback is released.
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct Live {
+    /// Tasks whose callback has neither finished nor been dropped.
+    ids: HashSet<TaskId>,
+    /// Set once the scope has started winding down; later tasks are
+    /// dropped instead of scheduled.
+    closed: bool,
+}
+
+/// Travels inside a scoped task's callback and marks it released when the
+/// callback is dropped, whether it ran, was cancelled or panicked.
+struct Token {
+    state: Arc<ScopeState>,
+    id: TaskId,
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        self.state.live.lock().ids.remove(&self.id);
+        self.state.released.notify_all();
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub(crate) fn new(scheduler: &'scope Scheduler) -> Self {
+        Self {
+            scheduler,
+            state: Arc::default(),
+            _scope: PhantomData,
+            _env: PhantomData,
+        }
+    }
+
+    /// Queues `f` on the scheduler, like [`Scheduler::schedule`] with
+    /// [`Task::new`], except that `f` only has to live as long as the scope.
+    ///
+    /// Once the scope is winding down (after its closure has returned and
+    /// the loop has drained, or while unwinding), `f` is dropped without
+    /// being scheduled and the handle refers to nothing.
+    pub fn schedule(
+        &'scope self,
+        f: impl FnOnce() + Send + 'scope,
+        expires: Option<Duration>,
+    ) -> TaskHandle {
+        let id = TaskId::new();
+        let mut live = self.state.live.lock();
+        if live.closed {
+            return TaskHandle::new(id, self.scheduler.me());
+        }
+        live.ids.insert(id);
+        drop(live);
+
+        let token = Token {
+            state: self.state.clone(),
+            id,
+        };
+        let callback: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let _token = token;
+            f()
+        });
+        // SAFETY: `Scheduler::scope` does not return, even when unwinding,
+        // until every token has been dropped, so the callback is never run
+        // or dropped after 'scope ends. Only the lifetime changes.
+        let callback: Box<dyn FnOnce() + Send + 'static> = unsafe { std::mem::transmute(callback) };
+        let mut task = Task::new(callback, expires);
+        task.id = id;
+        self.scheduler.schedule(task)
+    }
+
+    /// Stops accepting tasks, cancels the ones still queued and waits for
+    /// any that are running on another thread.
+    pub(crate) fn finish(&self) {
+        let mut live = self.state.live.lock();
+        live.closed = true;
+        let pending: Vec<TaskId> = live.ids.iter().copied().collect();
+        drop(live);
+        self.scheduler.cancel_many(&pending);
+
+        let live = self.state.live.lock();
+        drop(
+            self.state
+                .released
+                .wait_while(live, |live| !live.ids.is_empty()),
+        );
+    }
+}
+
+/// Calls [`Scope::finish`] on the way out of [`Scheduler::scope`], including
+/// when the scope's closure panics.
+pub(crate) struct FinishOnDrop<'a, 'scope, 'env>(pub(crate) &'a Scope<'scope, 'env>);
+
+impl Drop for FinishOnDrop<'_, '_, '_> {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Scheduler;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn sums_chunks_of_a_local_vec() {
+        let scheduler = Scheduler::new();
+        let data: Vec<u64> = (1..=1_000).collect();
+        let total = AtomicU64::new(0);
+
+        let chunks = scheduler.scope(|s| {
+            for chunk in data.chunks(100) {
+                let total = &total;
+                s.schedule(
+                    move || {
+                        total.fetch_add(chunk.iter().sum(), Ordering::SeqCst);
+                    },
+                    None,
+                );
+            }
+            data.len() / 100
+        });
+
+        assert_eq!(chunks, 10);
+        assert_eq!(total.load(Ordering::SeqCst), 500_500);
+    }
+
+    #[test]
+    fn scoped_tasks_can_schedule_more_scoped_tasks() {
+        let scheduler = Scheduler::new();
+        let ran = AtomicUsize::new(0);
+        scheduler.scope(|s| {
+            let ran = &ran;
+            s.schedule(
+                move || {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                    s.schedule(
+                        move || {
+                            ran.fetch_add(1, Ordering::SeqCst);
+                        },
+                        Some(Duration::from_millis(5)),
+                    );
+                },
+                None,
+            );
+        });
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn a_panicking_task_does_not_escape_the_scope() {
+        let scheduler = Scheduler::builder().on_panic(|_, _| {}).build();
+        let ran = AtomicBool::new(false);
+        scheduler.scope(|s| {
+            s.schedule(|| panic!("boom"), None);
+            s.schedule(|| ran.store(true, Ordering::SeqCst), None);
+        });
+        assert!(ran.load(Ordering::SeqCst));
+        assert_eq!(scheduler.metrics().panics, 1);
+    }
+
+    #[test]
+    fn a_panicking_scope_drops_its_tasks_before_unwinding() {
+        struct SetOnDrop<'a>(&'a AtomicBool);
+        impl Drop for SetOnDrop<'_> {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let scheduler = Scheduler::new();
+        let (ran, dropped) = (AtomicBool::new(false), AtomicBool::new(false));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            scheduler.scope(|s| {
+                let guard = SetOnDrop(&dropped);
+                let ran = &ran;
+                s.schedule(
+                    move || {
+                        let _guard = guard;
+                        ran.store(true, Ordering::SeqCst);
+                    },
+                    None,
+                );
+                panic!("scope closure failed");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert!(!ran.load(Ordering::SeqCst));
+        assert!(dropped.load(Ordering::SeqCst));
+        assert!(scheduler.is_idle());
+    }
+
+    #[test]
+    fn tasks_left_behind_by_a_shutdown_are_dropped() {
+        let scheduler = Scheduler::new();
+        let ran = AtomicBool::new(false);
+        scheduler.scope(|s| {
+            let next = scheduler.clone();
+            s.schedule(move || next.shutdown(), None);
+            s.schedule(
+                || ran.store(true, Ordering::SeqCst),
+                Some(Duration::from_millis(10)),
+            );
+        });
+
+        assert!(!ran.load(Ordering::SeqCst));
+        assert!(scheduler.is_idle());
+        scheduler.reset();
+    }
+}
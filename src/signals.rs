@@ -0,0 +1,154 @@
+//! SIGINT and SIGTERM as shutdown requests; see
+//! [`Scheduler::shutdown_on_ctrl_c`].
+//!
+//! The signal handler itself only writes a byte to a pipe, which is all it
+//! can safely do. A thread reading the other end makes the actual calls
+//! into the watched schedulers.
+
+use crate::sync::Mutex;
+use crate::Scheduler;
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::thread;
+
+/// The schedulers that a signal shuts down.
+static WATCHED: Mutex<Vec<Weak<Scheduler>>> = Mutex::new(Vec::new());
+
+/// The write end of the pipe, for the handler.
+static WRITER: AtomicI32 = AtomicI32::new(-1);
+
+/// Whether the handler and its thread were set up, or the OS error that
+/// stopped them.
+static INSTALLED: OnceLock<Result<(), i32>> = OnceLock::new();
+
+/// Makes SIGINT and SIGTERM shut `scheduler` down, from then on.
+pub(crate) fn watch(scheduler: Weak<Scheduler>) -> io::Result<()> {
+    INSTALLED
+        .get_or_init(|| install().map_err(|error| error.raw_os_error().unwrap_or(0)))
+        .map_err(io::Error::from_raw_os_error)?;
+    let mut watched = WATCHED.lock();
+    watched.retain(|watched| watched.strong_count() > 0);
+    if !watched.iter().any(|watched| watched.ptr_eq(&scheduler)) {
+        watched.push(scheduler);
+    }
+    Ok(())
+}
+
+fn install() -> io::Result<()> {
+    let mut ends = [0; 2];
+    // Safety: `ends` has room for the two descriptors.
+    if unsafe { libc::pipe(ends.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let [reader, writer] = ends;
+    // Safety: plain flag updates on descriptors `pipe` just opened. The
+    // write end doesn't block, so that the handler never can.
+    unsafe {
+        for fd in ends {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        let flags = libc::fcntl(writer, libc::F_GETFL);
+        libc::fcntl(writer, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+    WRITER.store(writer, Ordering::SeqCst);
+    thread::Builder::new()
+        .name("revent-loop-signals".into())
+        .spawn(move || listen(reader))?;
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // Safety: `action` is fully initialised before it is passed on, and
+        // the handler only makes async-signal-safe calls.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    // A full pipe already holds enough signals to act on, so a failed write
+    // loses nothing.
+    // Safety: writes one byte from a live buffer; `write` is
+    // async-signal-safe.
+    unsafe { libc::write(WRITER.load(Ordering::SeqCst), [1u8].as_ptr().cast(), 1) };
+}
+
+/// Shuts the watched schedulers down on the first signal, and drops their
+/// pending work too on every one after that.
+fn listen(reader: RawFd) {
+    let mut received = 0usize;
+    let mut buf = [0u8; 16];
+    loop {
+        // Safety: reads into a live buffer of the given length.
+        let read = unsafe { libc::read(reader, buf.as_mut_ptr().cast(), buf.len()) };
+        if read <= 0 {
+            if read < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+        received += read as usize;
+        let watched: Vec<Arc<Scheduler>> =
+            WATCHED.lock().iter().filter_map(Weak::upgrade).collect();
+        for scheduler in watched {
+            if received > 1 {
+                scheduler.clear();
+            }
+            scheduler.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Scheduler, Task};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn raise(signal: libc::c_int) {
+        // Safety: sends a signal that a handler is installed for.
+        assert_eq!(unsafe { libc::kill(libc::getpid(), signal) }, 0);
+    }
+
+    // Signals reach the whole process, so both steps share one test.
+    #[test]
+    fn a_second_signal_drops_pending_work() {
+        let scheduler = Scheduler::new();
+        scheduler.shutdown_on_ctrl_c().unwrap();
+        scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(60))));
+
+        // The first one stops a background runner gracefully.
+        let runner = scheduler.start();
+        thread::sleep(Duration::from_millis(20));
+        let start = Instant::now();
+        raise(libc::SIGINT);
+        while !runner.is_finished() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "runner kept going"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        runner.join();
+        assert_eq!(scheduler.pending_count(), 1);
+
+        // The next one also drops the timer that is still queued.
+        scheduler.reset();
+        thread::spawn(|| {
+            thread::sleep(Duration::from_millis(20));
+            raise(libc::SIGTERM);
+        });
+        let start = Instant::now();
+        scheduler.run_forever().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(scheduler.pending_count(), 0);
+    }
+}
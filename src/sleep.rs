@@ -0,0 +1,134 @@
+use crate::sync::Mutex;
+use crate::{Scheduler, Task, TaskHandle};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct SleepState {
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+/// A future that completes once its deadline has passed.
+///
+/// Created by [`Scheduler::sleep`]. The timer lives in the scheduler's
+/// sleeping queue, so awaiting it never blocks the loop thread. Dropping a
+/// `Sleep` before it fires removes the timer again.
+#[must_use = "futures do nothing unless awaited"]
+pub struct Sleep {
+    deadline: Instant,
+    scheduler: Weak<Scheduler>,
+    state: Arc<Mutex<SleepState>>,
+    timer: TaskHandle,
+}
+
+impl Sleep {
+    pub(crate) fn new(scheduler: &Scheduler, duration: Duration) -> Self {
+        let deadline = scheduler.now() + duration;
+        let state = Arc::new(Mutex::new(SleepState::default()));
+
+        let shared = state.clone();
+        let timer = scheduler.schedule(Task::new(
+            move || {
+                let mut state = shared.lock();
+                state.fired = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            },
+            Some(duration),
+        ));
+
+        Self {
+            deadline,
+            scheduler: scheduler.me(),
+            state,
+            timer,
+        }
+    }
+
+    /// The instant at which this sleep completes.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock();
+        let expired = self
+            .scheduler
+            .upgrade()
+            .is_some_and(|scheduler| scheduler.now() >= self.deadline);
+        if state.fired || expired {
+            return Poll::Ready(());
+        }
+        // Registered under the same lock the timer fires under, so a poll
+        // that races the timer either sees `fired` or leaves a waker for it.
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if !self.state.lock().fired {
+            self.timer.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sync::Mutex;
+    use crate::Scheduler;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn concurrent_sleeps_complete_in_deadline_order() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for millis in [100, 50] {
+            let sleep = scheduler.sleep(Duration::from_millis(millis));
+            let order = order.clone();
+            scheduler.spawn_future(async move {
+                sleep.await;
+                order.lock().push(millis);
+            });
+        }
+        let started = Instant::now();
+        scheduler.run().unwrap();
+
+        assert_eq!(*order.lock(), vec![50, 100]);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn sleep_inside_block_on() {
+        let scheduler = Scheduler::new();
+        let sleep = scheduler.sleep(Duration::from_millis(30));
+
+        let started = Instant::now();
+        scheduler.block_on(sleep);
+
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn dropped_sleep_removes_its_timer() {
+        let scheduler = Scheduler::new();
+        drop(scheduler.sleep(Duration::from_secs(10)));
+
+        let started = Instant::now();
+        scheduler.run().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
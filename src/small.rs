@@ -0,0 +1,205 @@
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+
+/// Room for a closure capturing up to two words, such as an `Arc` and a
+/// counter. That keeps `Callback` at the size of the `Box<dyn FnOnce>` it
+/// replaced; a third word would push `Task`, and `ScheduleError` with it,
+/// past what clippy's `result_large_err` allows.
+type Storage = [usize; 2];
+
+/// A `Box<dyn FnOnce(&A) + Send>` that keeps small closures inline.
+///
+/// Closures that fit in [`Storage`], alignment included, are stored in
+/// place, which saves an allocation for every tiny task. Anything bigger is
+/// boxed first, and the one-word box stored in place instead.
+// `repr(C)` puts the vtable last, so that `Callback` can keep its tag in the
+// vtable's null niche with its other variant laid over the storage.
+#[repr(C)]
+pub(crate) struct SmallFn<A: ?Sized + 'static> {
+    storage: MaybeUninit<Storage>,
+    vtable: &'static VTable<A>,
+    /// `Send` like the closures it is built from, but not `Sync`, as those
+    /// needn't be.
+    _marker: PhantomData<Box<dyn Send>>,
+}
+
+struct VTable<A: ?Sized> {
+    /// Moves the closure out of the storage and calls it.
+    call: unsafe fn(*mut Storage, &A),
+    drop: unsafe fn(*mut Storage),
+}
+
+trait Inline<A: ?Sized>: Sized {
+    const VTABLE: VTable<A>;
+}
+
+impl<A: ?Sized, F: FnOnce(&A)> Inline<A> for F {
+    const VTABLE: VTable<A> = VTable {
+        call: call_in_place::<A, F>,
+        drop: drop_in_place::<F>,
+    };
+}
+
+unsafe fn call_in_place<A: ?Sized, F: FnOnce(&A)>(storage: *mut Storage, argument: &A) {
+    unsafe { storage.cast::<F>().read()(argument) }
+}
+
+unsafe fn drop_in_place<F>(storage: *mut Storage) {
+    unsafe { storage.cast::<F>().drop_in_place() }
+}
+
+impl<A: ?Sized + 'static> SmallFn<A> {
+    pub(crate) fn new<F: FnOnce(&A) + Send + 'static>(callback: F) -> Self {
+        if fits::<F>() {
+            Self::inline(callback)
+        } else {
+            Self::inline(Box::new(callback))
+        }
+    }
+
+    fn inline<F: FnOnce(&A) + Send + 'static>(callback: F) -> Self {
+        assert!(fits::<F>());
+        let mut storage = MaybeUninit::<Storage>::uninit();
+        // Safety: `F` fits in the storage, size and alignment both.
+        unsafe { storage.as_mut_ptr().cast::<F>().write(callback) };
+        Self {
+            storage,
+            vtable: &<F as Inline<A>>::VTABLE,
+            _marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn call(self, argument: &A) {
+        // The closure is moved out by `call`, so it mustn't be dropped here
+        // too, not even if it panics.
+        let mut this = ManuallyDrop::new(self);
+        // Safety: the storage holds the closure `vtable` was made for.
+        unsafe { (this.vtable.call)(this.storage.as_mut_ptr(), argument) }
+    }
+}
+
+impl<A: ?Sized + 'static> Drop for SmallFn<A> {
+    fn drop(&mut self) {
+        // Safety: as in `call`; a closure that was called never gets here.
+        unsafe { (self.vtable.drop)(self.storage.as_mut_ptr()) }
+    }
+}
+
+/// Whether an `F` is stored in place rather than boxed.
+fn fits<F>() -> bool {
+    mem::size_of::<F>() <= mem::size_of::<Storage>()
+        && mem::align_of::<F>() <= mem::align_of::<Storage>()
+}
+
+#[cfg(test)]
+mod test {
+    //! Sticks to plain memory and panics, so that it runs under Miri:
+    //! `cargo +nightly miri test small`.
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts how many times it has been dropped.
+    struct Guard(Arc<AtomicUsize>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn is_inline<F>(_: &F) -> bool {
+        fits::<F>()
+    }
+
+    /// A closure that adds its argument to `sum`, capturing a guard plus
+    /// `N` bytes, and the guard's drop count.
+    fn counted<const N: usize>(
+        sum: &Arc<AtomicUsize>,
+    ) -> (impl FnOnce(&usize) + Send + 'static, Arc<AtomicUsize>) {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let guard = Guard(drops.clone());
+        let sum = sum.clone();
+        let padding = [7u8; N];
+        let callback = move |n: &usize| {
+            let _guard = guard;
+            assert_eq!(padding, [7u8; N]);
+            sum.fetch_add(*n, Ordering::SeqCst);
+        };
+        (callback, drops)
+    }
+
+    #[test]
+    fn runs_inline_and_boxed_closures_once() {
+        let sum = Arc::new(AtomicUsize::new(0));
+        let (small, small_drops) = counted::<0>(&sum);
+        let (big, big_drops) = counted::<64>(&sum);
+        assert!(is_inline(&small));
+        assert!(!is_inline(&big));
+
+        SmallFn::new(small).call(&1);
+        SmallFn::new(big).call(&2);
+        assert_eq!(sum.load(Ordering::SeqCst), 3);
+        assert_eq!(small_drops.load(Ordering::SeqCst), 1);
+        assert_eq!(big_drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dropping_without_calling_drops_the_captures_once() {
+        let sum = Arc::new(AtomicUsize::new(0));
+        let (small, small_drops) = counted::<0>(&sum);
+        let (big, big_drops) = counted::<64>(&sum);
+        // Moved around first, as tasks are between queues.
+        let callbacks = vec![SmallFn::new(small), SmallFn::new(big)];
+        let moved: Vec<_> = callbacks.into_iter().rev().collect();
+        drop(moved);
+
+        assert_eq!(sum.load(Ordering::SeqCst), 0);
+        assert_eq!(small_drops.load(Ordering::SeqCst), 1);
+        assert_eq!(big_drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn a_panicking_closure_drops_its_captures_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let guard = Guard(drops.clone());
+        let small = move |_: &()| {
+            let _guard = guard;
+            panic!("inline");
+        };
+        let guard = Guard(drops.clone());
+        let padding = [0u64; 8];
+        let big = move |_: &()| {
+            let _guard = guard;
+            assert_eq!(padding, [0; 8]);
+            panic!("boxed");
+        };
+        assert!(is_inline(&small));
+        assert!(!is_inline(&big));
+
+        let small = SmallFn::new(small);
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| small.call(&()))).is_err());
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        let big = SmallFn::new(big);
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| big.call(&()))).is_err());
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn over_aligned_closures_are_boxed() {
+        #[repr(align(64))]
+        struct Aligned(usize);
+        let aligned = Aligned(3);
+        let sum = Arc::new(AtomicUsize::new(0));
+        let total = sum.clone();
+        let callback = move |n: &usize| {
+            // Moved whole; `aligned.0` alone would only capture the field.
+            let aligned = aligned;
+            total.fetch_add(aligned.0 + n, Ordering::SeqCst);
+        };
+        assert!(!is_inline(&callback));
+        SmallFn::new(callback).call(&1);
+        assert_eq!(sum.load(Ordering::SeqCst), 4);
+    }
+}
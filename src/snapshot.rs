@@ -0,0 +1,149 @@
+use crate::{Metrics, Priority, QueuedIn, TaskId};
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Everything pending in a scheduler at one moment, plus its counters, from
+/// [`Scheduler::snapshot`](crate::Scheduler::snapshot).
+///
+/// Plain data, so it can be kept, compared or shipped elsewhere;
+/// [`ScheduleSnapshot::to_json`] writes it out as JSON. The types don't
+/// implement `serde::Serialize`. For another format, every field is public
+/// to copy into a type that does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleSnapshot {
+    /// In the order of [`Scheduler::pending_tasks`]: ready tasks as they
+    /// would run, then timers by deadline, then idle tasks.
+    ///
+    /// [`Scheduler::pending_tasks`]: crate::Scheduler::pending_tasks
+    pub tasks: Vec<TaskSnapshot>,
+    pub metrics: Metrics,
+}
+
+/// One pending task in a [`ScheduleSnapshot`]. The callback is left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskSnapshot {
+    pub id: TaskId,
+    pub name: Option<Cow<'static, str>>,
+    pub priority: Priority,
+    pub queue: QueuedIn,
+    /// How long until the task is due; zero once it is.
+    pub remaining: Duration,
+}
+
+impl ScheduleSnapshot {
+    /// The snapshot as a JSON object, with `tasks` and `metrics` keys.
+    /// Durations are whole nanoseconds, under keys ending in `_nanos`; ids
+    /// are strings, since their format depends on the `uuid-ids` feature.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"tasks\":[");
+        for (index, task) in self.tasks.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"id\":");
+            write_string(&mut json, &task.id.to_string());
+            json.push_str(",\"name\":");
+            match &task.name {
+                Some(name) => write_string(&mut json, name),
+                None => json.push_str("null"),
+            }
+            // Writing to a `String` can't fail.
+            let _ = write!(
+                json,
+                ",\"priority\":\"{:?}\",\"queue\":\"{:?}\",\"remaining_nanos\":{}}}",
+                task.priority,
+                task.queue,
+                task.remaining.as_nanos()
+            );
+        }
+        json.push_str("],\"metrics\":");
+        write_metrics(&mut json, &self.metrics);
+        json.push('}');
+        json
+    }
+}
+
+fn write_metrics(json: &mut String, metrics: &Metrics) {
+    let Metrics {
+        ready_len,
+        sleeping_len,
+        scheduled,
+        executed,
+        timers_fired,
+        panics,
+        timed_out,
+        failed,
+        cancelled,
+        max_ready_len,
+        total_wait,
+        timers_run,
+        min_lateness,
+        total_lateness,
+        max_lateness,
+        steals,
+        throttled,
+        batches_cut,
+        events_dropped,
+    } = *metrics;
+    let counts = [
+        ("ready_len", ready_len as u128),
+        ("sleeping_len", sleeping_len as u128),
+        ("scheduled", scheduled.into()),
+        ("executed", executed.into()),
+        ("timers_fired", timers_fired.into()),
+        ("panics", panics.into()),
+        ("timed_out", timed_out.into()),
+        ("failed", failed.into()),
+        ("cancelled", cancelled.into()),
+        ("max_ready_len", max_ready_len as u128),
+        ("total_wait_nanos", total_wait.as_nanos()),
+        ("timers_run", timers_run.into()),
+        ("min_lateness_nanos", min_lateness.as_nanos()),
+        ("total_lateness_nanos", total_lateness.as_nanos()),
+        ("max_lateness_nanos", max_lateness.as_nanos()),
+        ("steals", steals.into()),
+        ("throttled", throttled.into()),
+        ("batches_cut", batches_cut.into()),
+        ("events_dropped", events_dropped.into()),
+    ];
+    json.push('{');
+    for (index, (key, value)) in counts.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "\"{}\":{}", key, value);
+    }
+    json.push('}');
+}
+
+/// Writes `value` as a JSON string literal.
+fn write_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", u32::from(c));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_string;
+
+    #[test]
+    fn escapes_names_for_json() {
+        let mut json = String::new();
+        write_string(&mut json, "say \"hi\"\\\n\u{1}é");
+        assert_eq!(json, r#""say \"hi\"\\\n\u0001é""#);
+    }
+}
@@ -0,0 +1,418 @@
+use crate::sync::Mutex;
+use crate::{Scheduler, Task};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::{Arc, Weak};
+use std::thread;
+
+type OnClosed = Box<dyn FnOnce() + Send>;
+
+/// One channel bridged by [`Scheduler::on_receive`] or
+/// [`Scheduler::poll_channel`].
+pub(crate) struct Source {
+    scheduler: Weak<Scheduler>,
+    /// Cleared once, by whichever comes first of the channel closing and
+    /// [`SourceHandle::deregister`]. Until then the source keeps
+    /// [`Scheduler::run`] going.
+    open: AtomicBool,
+    /// Set by [`SourceHandle::deregister`], and checked by every queued
+    /// message before it calls the callback.
+    deregistered: AtomicBool,
+    closing: Mutex<Closing>,
+}
+
+enum Closing {
+    Open(Option<OnClosed>),
+    /// The channel has closed, and `on_closed` has been queued if it was
+    /// set.
+    Closed,
+}
+
+impl Source {
+    pub(crate) fn new(scheduler: Weak<Scheduler>) -> Arc<Self> {
+        Arc::new(Self {
+            scheduler,
+            open: AtomicBool::new(true),
+            deregistered: AtomicBool::new(false),
+            closing: Mutex::new(Closing::Open(None)),
+        })
+    }
+
+    /// Starts the thread that turns each message on `rx` into a ready task
+    /// calling `f`.
+    pub(crate) fn forward<T: Send + 'static>(
+        self: &Arc<Self>,
+        rx: Receiver<T>,
+        f: impl FnMut(T) + Send + 'static,
+    ) {
+        let source = self.clone();
+        // Shared by the queued messages, which only ever run one at a time
+        // on the loop but may be spread across pool workers.
+        let f = Arc::new(Mutex::new(f));
+        thread::Builder::new()
+            .name("revent-loop-source".into())
+            .spawn(move || {
+                while let Ok(message) = rx.recv() {
+                    let Some(scheduler) = source.live_scheduler() else {
+                        return;
+                    };
+                    source.deliver(&scheduler, &f, message, true);
+                }
+                source.closed();
+            })
+            .expect("failed to spawn a source thread");
+    }
+
+    /// Queues `message` for `f`, to be dropped instead if the source is
+    /// deregistered before it runs.
+    fn deliver<T: Send + 'static, F: FnMut(T) + Send + 'static>(
+        self: &Arc<Self>,
+        scheduler: &Scheduler,
+        f: &Arc<Mutex<F>>,
+        message: T,
+        bounded: bool,
+    ) {
+        let (source, f) = (self.clone(), f.clone());
+        let task = Task::new(
+            move || {
+                if !source.deregistered.load(Ordering::SeqCst) {
+                    (f.lock())(message);
+                }
+            },
+            None,
+        );
+        match bounded {
+            true => drop(scheduler.schedule(task)),
+            false => drop(scheduler.schedule_unbounded(task)),
+        }
+    }
+
+    /// The scheduler, unless it is gone or the source was deregistered.
+    fn live_scheduler(&self) -> Option<Arc<Scheduler>> {
+        if self.deregistered.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.scheduler.upgrade()
+    }
+
+    /// The sender side hung up: queues `on_closed` behind the last message.
+    fn closed(&self) {
+        let on_closed = match std::mem::replace(&mut *self.closing.lock(), Closing::Closed) {
+            Closing::Open(on_closed) => on_closed,
+            Closing::Closed => None,
+        };
+        let Some(scheduler) = self.live_scheduler() else {
+            return;
+        };
+        if let Some(on_closed) = on_closed {
+            // Possibly from the loop thread, which mustn't block on a full
+            // queue.
+            scheduler.schedule_unbounded(Task::new(on_closed, None));
+        }
+        // Last, so that the loop doesn't conclude it has drained before the
+        // callback is queued.
+        if self.open.swap(false, Ordering::SeqCst) {
+            scheduler.release_source();
+        }
+    }
+}
+
+/// A channel from [`Scheduler::poll_channel`], drained by the loop itself.
+pub(crate) trait PolledChannel: Send {
+    /// Queues every message waiting on the channel. Returns whether the
+    /// source is still open.
+    fn drain(&mut self, scheduler: &Scheduler) -> bool;
+}
+
+pub(crate) struct Polled<T, F> {
+    rx: Receiver<T>,
+    f: Arc<Mutex<F>>,
+    source: Arc<Source>,
+}
+
+impl<T, F> Polled<T, F> {
+    pub(crate) fn new(rx: Receiver<T>, f: F, source: Arc<Source>) -> Self {
+        Self {
+            rx,
+            f: Arc::new(Mutex::new(f)),
+            source,
+        }
+    }
+}
+
+impl<T: Send + 'static, F: FnMut(T) + Send + 'static> PolledChannel for Polled<T, F> {
+    fn drain(&mut self, scheduler: &Scheduler) -> bool {
+        loop {
+            if self.source.deregistered.load(Ordering::SeqCst) {
+                return false;
+            }
+            match self.rx.try_recv() {
+                // Unbounded, since the loop thread mustn't block on its own
+                // queue.
+                Ok(message) => self.source.deliver(scheduler, &self.f, message, false),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => {
+                    self.source.closed();
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// A channel bridged into a scheduler by [`Scheduler::on_receive`] or
+/// [`Scheduler::poll_channel`].
+///
+/// Like [`TaskHandle`](crate::TaskHandle), the handle doesn't keep the
+/// scheduler alive, and dropping it leaves the source registered.
+#[derive(Clone)]
+pub struct SourceHandle {
+    source: Arc<Source>,
+}
+
+impl SourceHandle {
+    pub(crate) fn new(source: Arc<Source>) -> Self {
+        Self { source }
+    }
+
+    /// Queues `f` once every sender has been dropped and the messages sent
+    /// before that have been queued, unless the source is deregistered
+    /// first. If the channel has closed already, `f` is queued straight
+    /// away. Replaces any callback set before.
+    pub fn on_closed(&self, f: impl FnOnce() + Send + 'static) {
+        let mut closing = self.source.closing.lock();
+        match &mut *closing {
+            Closing::Open(on_closed) => *on_closed = Some(Box::new(f)),
+            Closing::Closed => {
+                drop(closing);
+                if let Some(scheduler) = self.source.live_scheduler() {
+                    scheduler.schedule(Task::new(f, None));
+                }
+            }
+        }
+    }
+
+    /// Stops delivering messages, including ones already queued, and lets
+    /// [`Scheduler::run`] return without waiting for the channel to close.
+    /// Returns whether the source was still registered.
+    ///
+    /// The `on_closed` callback is dropped without running. The receiver
+    /// is dropped the next time the loop looks at a channel from
+    /// [`Scheduler::poll_channel`], or, for [`Scheduler::on_receive`], once
+    /// its thread wakes up for the next message or for the channel closing.
+    pub fn deregister(&self) -> bool {
+        self.source.deregistered.store(true, Ordering::SeqCst);
+        let on_closed = match &mut *self.source.closing.lock() {
+            Closing::Open(on_closed) => on_closed.take(),
+            Closing::Closed => None,
+        };
+        drop(on_closed);
+        if !self.source.open.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        if let Some(scheduler) = self.source.scheduler.upgrade() {
+            scheduler.release_source();
+        }
+        true
+    }
+
+    /// Whether the source is still delivering messages: the channel hasn't
+    /// closed and [`SourceHandle::deregister`] hasn't been called.
+    pub fn is_registered(&self) -> bool {
+        self.source.open.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for SourceHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SourceHandle")
+            .field("registered", &self.is_registered())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sync::Mutex;
+    use crate::{Scheduler, SourceHandle, Task};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Tick,
+        Message(u32),
+        Closed,
+    }
+
+    type Bridge = fn(&Scheduler, mpsc::Receiver<u32>, Box<dyn FnMut(u32) + Send>) -> SourceHandle;
+
+    #[test]
+    fn messages_run_between_timer_ticks() {
+        check_messages_run_between_timer_ticks(|scheduler, rx, f| scheduler.on_receive(rx, f));
+    }
+
+    #[test]
+    fn polled_messages_run_between_timer_ticks() {
+        check_messages_run_between_timer_ticks(|scheduler, rx, f| scheduler.poll_channel(rx, f));
+    }
+
+    fn check_messages_run_between_timer_ticks(bridge: Bridge) {
+        let scheduler = Scheduler::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = mpsc::channel();
+
+        let ticked = events.clone();
+        let timer = scheduler.schedule_interval(Duration::from_millis(10), move || {
+            ticked.lock().push(Event::Tick)
+        });
+        let received = events.clone();
+        let source = bridge(
+            &scheduler,
+            rx,
+            Box::new(move |n| received.lock().push(Event::Message(n))),
+        );
+        let closed = events.clone();
+        source.on_closed(move || {
+            closed.lock().push(Event::Closed);
+            timer.cancel();
+        });
+        let sender = thread::spawn(move || {
+            for n in 0..5 {
+                thread::sleep(Duration::from_millis(25));
+                tx.send(n).unwrap();
+            }
+        });
+        let start = Instant::now();
+        let report = scheduler.run().unwrap();
+        sender.join().unwrap();
+
+        // `run()` waited for the channel to close rather than returning
+        // between messages.
+        assert!(start.elapsed() >= Duration::from_millis(125));
+        assert!(!source.is_registered());
+        let events = events.lock();
+        let messages: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Message(n) => Some(*n),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(messages, [0, 1, 2, 3, 4]);
+        assert_eq!(events.last(), Some(&Event::Closed));
+        // The timer kept firing in every gap between messages.
+        let ticks_between = events
+            .split(|event| matches!(event, Event::Message(_)))
+            .skip(1)
+            .take(4)
+            .filter(|gap| gap.contains(&Event::Tick))
+            .count();
+        assert_eq!(ticks_between, 4, "{:?}", events);
+        assert_eq!(report.panics, 0);
+    }
+
+    #[test]
+    fn run_for_returns_on_time_with_an_idle_source_open() {
+        let scheduler = Scheduler::new();
+        let (tx, rx) = mpsc::channel::<u32>();
+        let _source = scheduler.on_receive(rx, |_| {});
+
+        let started = Instant::now();
+        assert_eq!(scheduler.run_for(Duration::from_millis(100)).unwrap(), 0);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(
+            elapsed < Duration::from_millis(500),
+            "overran: {:?}",
+            elapsed
+        );
+        drop(tx);
+    }
+
+    #[test]
+    fn deregistering_drops_queued_messages() {
+        let scheduler = Scheduler::new();
+        let (tx, rx) = mpsc::channel();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        let source = scheduler.on_receive(rx, move |()| {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        let closed = runs.clone();
+        source.on_closed(move || {
+            closed.fetch_add(1, Ordering::SeqCst);
+        });
+        tx.send(()).unwrap();
+        while scheduler.ready_len() == 0 {
+            thread::yield_now();
+        }
+
+        assert!(source.deregister());
+        assert!(!source.deregister());
+        drop(tx);
+        scheduler.schedule(Task::new(|| {}, Some(Duration::from_millis(20))));
+        scheduler.run().unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn polled_bridge_stops_once_the_sender_is_dropped() {
+        let scheduler = Scheduler::new();
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let got = received.clone();
+        let source =
+            scheduler.poll_channel(rx, move |n: u32| got.lock().push((n, start.elapsed())));
+        // Far enough off that only the poll interval gets the messages in.
+        let distant = scheduler.schedule(Task::new(|| {}, Some(Duration::from_secs(10))));
+        let closed = Arc::new(AtomicUsize::new(0));
+        let counted = closed.clone();
+        source.on_closed(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            distant.cancel();
+        });
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            tx.send(1).unwrap();
+            tx.send(2).unwrap();
+        });
+        scheduler.run().unwrap();
+        sender.join().unwrap();
+
+        let received = received.lock();
+        assert_eq!(received.iter().map(|(n, _)| *n).collect::<Vec<_>>(), [1, 2]);
+        assert!(
+            received.iter().all(|(_, at)| *at < Duration::from_secs(1)),
+            "{:?}",
+            received
+        );
+        assert_eq!(closed.load(Ordering::SeqCst), 1);
+        assert!(!source.is_registered());
+        assert!(!source.deregister());
+        // Nothing is left to keep the loop waiting.
+        assert_eq!(scheduler.run().unwrap().tasks_executed, 0);
+    }
+
+    #[test]
+    fn deregistering_a_polled_channel_drops_its_messages() {
+        let scheduler = Scheduler::new();
+        let (tx, rx) = mpsc::channel();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        let source = scheduler.poll_channel(rx, move |()| {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        tx.send(()).unwrap();
+
+        assert!(source.deregister());
+        // The sender is still there, but nothing keeps `run()` waiting.
+        scheduler.run().unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+        drop(tx);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::TaskId;
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+/// Where a task is in its life, from [`Scheduler::status`].
+///
+/// [`Scheduler::status`]: crate::Scheduler::status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaskState {
+    /// Waiting for its delay to run out.
+    Sleeping { deadline: Instant },
+    /// Due, and waiting for the loop to get to it. This includes tasks from
+    /// [`Scheduler::schedule_idle`](crate::Scheduler::schedule_idle) and
+    /// [`Scheduler::next_tick`](crate::Scheduler::next_tick).
+    Ready,
+    /// Held back until its prerequisite has run; see
+    /// [`Scheduler::schedule_after`](crate::Scheduler::schedule_after).
+    Waiting,
+    /// Its callback is running right now.
+    Running,
+    /// Ran for the last time, whether it returned or panicked.
+    Completed,
+    /// Dropped before it could run (again).
+    Cancelled,
+    /// Never scheduled here, or finished longer ago than the scheduler
+    /// remembers; see [`SchedulerBuilder::status_history`].
+    ///
+    /// [`SchedulerBuilder::status_history`]: crate::SchedulerBuilder::status_history
+    Unknown,
+}
+
+/// The callbacks running right now and the tasks that finished last, for
+/// [`Scheduler::status`](crate::Scheduler::status).
+pub(crate) struct TaskStatuses {
+    /// One entry per worker in `run_pool()`, at most one otherwise.
+    running: Vec<TaskId>,
+    finished: HashMap<TaskId, TaskState>,
+    /// `finished` in the order the tasks finished, for forgetting the
+    /// oldest.
+    finished_order: VecDeque<TaskId>,
+    history: usize,
+}
+
+impl TaskStatuses {
+    pub(crate) fn new(history: usize) -> Self {
+        Self {
+            running: Vec::new(),
+            finished: HashMap::new(),
+            finished_order: VecDeque::new(),
+            history,
+        }
+    }
+
+    pub(crate) fn started(&mut self, id: TaskId) {
+        self.running.push(id);
+    }
+
+    /// Takes `id` off the running list, and remembers that it finished as
+    /// `state` unless it is going to run again.
+    pub(crate) fn stopped(&mut self, id: TaskId, state: Option<TaskState>) {
+        if let Some(index) = self.running.iter().position(|running| *running == id) {
+            self.running.swap_remove(index);
+        }
+        if let Some(state) = state {
+            self.finished(id, state);
+        }
+    }
+
+    pub(crate) fn cancelled(&mut self, ids: impl IntoIterator<Item = TaskId>) {
+        for id in ids {
+            self.finished(id, TaskState::Cancelled);
+        }
+    }
+
+    /// What is known about `id` outside of the queues.
+    pub(crate) fn get(&self, id: TaskId) -> Option<TaskState> {
+        if self.running.contains(&id) {
+            return Some(TaskState::Running);
+        }
+        self.finished.get(&id).copied()
+    }
+
+    fn finished(&mut self, id: TaskId, state: TaskState) {
+        if self.history == 0 {
+            return;
+        }
+        if self.finished.insert(id, state).is_none() {
+            self.finished_order.push_back(id);
+            if self.finished_order.len() > self.history {
+                let forgotten = self.finished_order.pop_front().unwrap();
+                self.finished.remove(&forgotten);
+            }
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use crate::handle::Completer;
+use crate::small::SmallFn;
+use crate::sync::Mutex;
+use crate::timers::Keyed;
+use crate::TaskId;
+use crate::{JoinHandle, Phase, Scheduler, TaskMeta};
+use std::borrow::Cow;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How urgently a ready [`Task`] should run.
+///
+/// The loop always picks the highest-priority ready task next; tasks with
+/// the same priority run in the order they became ready.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// When the next run of a periodic task is due, for
+/// [`Scheduler::schedule_interval_with_mode`](crate::Scheduler::schedule_interval_with_mode).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum IntervalMode {
+    /// One period after the previous run returned, so the time the callback
+    /// takes adds to the gap. This is what
+    /// [`Scheduler::schedule_interval`](crate::Scheduler::schedule_interval)
+    /// does.
+    #[default]
+    FixedDelay,
+    /// One period after the previous run was due, however long it took.
+    FixedRate(CatchUp),
+}
+
+/// What a [`IntervalMode::FixedRate`] task does about runs it fell behind
+/// on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CatchUp {
+    /// Runs the missed ones back to back until it is on schedule again.
+    Burst,
+    /// Drops the missed ones and carries on from the next due time still
+    /// ahead.
+    #[default]
+    Skip,
+}
+
+/// Which queue a pending task is waiting in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueuedIn {
+    /// Due, and waiting for the loop to get to it.
+    Ready,
+    /// Waiting for its delay to run out.
+    Sleeping,
+    /// Waiting for the loop to run out of other work; see
+    /// [`Scheduler::schedule_idle`](crate::Scheduler::schedule_idle).
+    Idle,
+}
+
+/// A description of a pending task, from
+/// [`Scheduler::pending_tasks`](crate::Scheduler::pending_tasks).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<Cow<'static, str>>,
+    /// When the task became due (ready tasks) or will become due (sleeping
+    /// ones).
+    pub deadline: Option<Instant>,
+    pub state: QueuedIn,
+}
+
+/// A unit of work for the [`Scheduler`](crate::Scheduler).
+///
+/// A task wraps a callback together with an optional delay. Tasks without a
+/// delay run as soon as the loop reaches them; delayed tasks wait in the
+/// sleeping queue first.
+pub struct Task {
+    pub(crate) id: TaskId,
+    pub(crate) callback: Callback,
+    pub(crate) expires: Option<Duration>,
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) priority: Priority,
+    /// Set when the task is queued, from where it is queued.
+    pub(crate) phase: Phase,
+    pub(crate) name: Option<Cow<'static, str>>,
+    /// Taken from a counter each time the task is queued, so lower means
+    /// scheduled earlier.
+    pub(crate) seq: u64,
+    /// Boxed, as most tasks have neither.
+    pub(crate) execution: Option<Box<Execution>>,
+}
+
+/// How a task is run, beyond its callback and delay.
+#[derive(Default)]
+pub(crate) struct Execution {
+    /// See [`TaskBuilder::exec_timeout`].
+    pub(crate) timeout: Option<Duration>,
+    /// See [`TaskBuilder::slack`].
+    pub(crate) slack: Duration,
+    /// Called once the callback has returned, with whether it ran past
+    /// `timeout`. Dropped uncalled if the callback panics or never runs.
+    pub(crate) on_finish: Option<Box<dyn FnOnce(bool) + Send + 'static>>,
+}
+
+pub(crate) enum Callback {
+    /// Both plain callbacks and ones from [`Scheduler::schedule_with`],
+    /// which are handed the scheduler running them.
+    ///
+    /// [`Scheduler::schedule_with`]: crate::Scheduler::schedule_with
+    Once(SmallFn<Scheduler>),
+    /// Re-enqueued with the same id after every run, due after the delay it
+    /// returns, until it returns `None` or is cancelled.
+    Repeat(Box<dyn FnMut() -> Option<Duration> + Send + 'static>),
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Task");
+        debug.field("id", &self.id);
+        if let Some(name) = &self.name {
+            debug.field("name", name);
+        }
+        debug.finish()
+    }
+}
+
+impl Keyed for Task {
+    fn key(&self) -> TaskId {
+        self.id
+    }
+}
+
+impl Task {
+    /// Starts building a task from named options instead of positional
+    /// arguments.
+    ///
+    /// ```
+    /// use revent_loop::{Priority, Scheduler, Task};
+    /// use std::time::Duration;
+    ///
+    /// let task = Task::builder()
+    ///     .callback(|| println!("polling"))
+    ///     .delay(Duration::from_millis(5))
+    ///     .name("poll")
+    ///     .priority(Priority::High)
+    ///     .build();
+    ///
+    /// let scheduler = Scheduler::new();
+    /// scheduler.schedule(task);
+    /// scheduler.run().unwrap();
+    /// ```
+    ///
+    /// A callback is required; without one there is no `build()`:
+    ///
+    /// ```compile_fail
+    /// let task = revent_loop::Task::builder().name("nothing to do").build();
+    /// ```
+    pub fn builder() -> TaskBuilder {
+        TaskBuilder {
+            callback: (),
+            delay: None,
+            name: None,
+            priority: Priority::Normal,
+            id: None,
+            exec_timeout: None,
+            slack: Duration::ZERO,
+        }
+    }
+
+    /// Creates a task that runs `callback`, either immediately (`None`) or
+    /// after the given delay.
+    pub fn new(callback: impl FnOnce() + Send + 'static, expires: Option<Duration>) -> Self {
+        Self::new_with_priority(callback, expires, Priority::Normal)
+    }
+
+    /// Like [`Task::new`], but with an explicit [`Priority`]. A delayed task
+    /// keeps its priority when it moves to the ready queue.
+    pub fn new_with_priority(
+        callback: impl FnOnce() + Send + 'static,
+        expires: Option<Duration>,
+        priority: Priority,
+    ) -> Self {
+        Self {
+            id: TaskId::new(),
+            callback: Callback::Once(SmallFn::new(move |_: &Scheduler| callback())),
+            expires,
+            deadline: None,
+            priority,
+            phase: Phase::Immediate,
+            name: None,
+            seq: 0,
+            execution: None,
+        }
+    }
+
+    /// Like [`Task::new`], but labelled with `name`, which shows up in the
+    /// task's `Debug` output and in panic reports.
+    pub fn new_named(
+        name: impl Into<Cow<'static, str>>,
+        callback: impl FnOnce() + Send + 'static,
+        expires: Option<Duration>,
+    ) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..Self::new(callback, expires)
+        }
+    }
+
+    pub(crate) fn interval(mut callback: impl FnMut() + Send + 'static, period: Duration) -> Self {
+        Self::repeat(
+            move || {
+                callback();
+                Some(period)
+            },
+            Some(period),
+        )
+    }
+
+    /// A task that runs `callback` after `expires`, then again after every
+    /// delay it returns until it returns `None`.
+    pub(crate) fn repeat(
+        callback: impl FnMut() -> Option<Duration> + Send + 'static,
+        expires: Option<Duration>,
+    ) -> Self {
+        Self {
+            callback: Callback::Repeat(Box::new(callback)),
+            ..Self::new(|| {}, expires)
+        }
+    }
+
+    pub(crate) fn with_scheduler(
+        callback: impl FnOnce(&Scheduler) + Send + 'static,
+        expires: Option<Duration>,
+    ) -> Self {
+        Self {
+            callback: Callback::Once(SmallFn::new(callback)),
+            ..Self::new(|| {}, expires)
+        }
+    }
+
+    /// The unique id assigned to this task when it was created.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// When the task became, or becomes, due. Set once it is scheduled.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub(crate) fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    /// The name given with [`Task::new_named`], if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The priority this task runs with once it is ready.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub(crate) fn info(&self, state: QueuedIn) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            name: self.name.clone(),
+            deadline: self.deadline,
+            state,
+        }
+    }
+
+    pub(crate) fn slack(&self) -> Duration {
+        self.execution
+            .as_ref()
+            .map_or(Duration::ZERO, |execution| execution.slack)
+    }
+
+    pub(crate) fn meta(&self) -> TaskMeta {
+        TaskMeta {
+            id: self.id,
+            name: self.name.clone(),
+            deadline: self.deadline,
+            phase: self.phase,
+        }
+    }
+}
+
+/// Builds a [`Task`]; see [`Task::builder`].
+///
+/// `F` is the callback, or `()` until [`TaskBuilder::callback`] has been
+/// called. Only a builder with a callback can be built.
+#[must_use]
+pub struct TaskBuilder<F = ()> {
+    callback: F,
+    delay: Option<Duration>,
+    name: Option<Cow<'static, str>>,
+    priority: Priority,
+    id: Option<TaskId>,
+    exec_timeout: Option<Duration>,
+    slack: Duration,
+}
+
+impl<F> TaskBuilder<F> {
+    /// The closure the task runs. Only one that returns `()` can be
+    /// [built](TaskBuilder::build); any other value is for
+    /// [`TaskBuilder::spawn_on`].
+    pub fn callback<C, T>(self, callback: C) -> TaskBuilder<C>
+    where
+        C: FnOnce() -> T + Send + 'static,
+    {
+        TaskBuilder {
+            callback,
+            delay: self.delay,
+            name: self.name,
+            priority: self.priority,
+            id: self.id,
+            exec_timeout: self.exec_timeout,
+            slack: self.slack,
+        }
+    }
+
+    /// Runs the task after `delay` instead of as soon as possible.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// See [`Task::new_named`].
+    pub fn name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// See [`Priority`]. Defaults to [`Priority::Normal`].
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Uses `id` instead of a freshly generated one. Ids are how tasks are
+    /// cancelled, so they should stay unique among pending tasks.
+    pub fn id(mut self, id: TaskId) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Counts the task as timed out if its callback runs for longer than
+    /// `timeout`, measured on the scheduler's clock from the moment the
+    /// callback starts.
+    ///
+    /// A running callback can't be stopped, so it still runs to the end.
+    /// Once it returns, the overrun is reported to
+    /// [`SchedulerBuilder::on_timeout`](crate::SchedulerBuilder::on_timeout)
+    /// and counted in [`Metrics::timed_out`](crate::Metrics::timed_out),
+    /// and a handle from [`TaskBuilder::spawn_on`] resolves to a
+    /// [`JoinErrorKind::TimedOut`](crate::JoinErrorKind::TimedOut) error
+    /// instead of the value.
+    pub fn exec_timeout(mut self, timeout: Duration) -> Self {
+        self.exec_timeout = Some(timeout);
+        self
+    }
+
+    /// Lets the task's timer fire up to `slack` before its deadline when the
+    /// loop is waking for another timer anyway, so that timers which don't
+    /// need to be exact share wakeups. On its own the timer still waits for
+    /// its deadline. Zero, the default, keeps it exact.
+    pub fn slack(mut self, slack: Duration) -> Self {
+        self.slack = slack;
+        self
+    }
+}
+
+impl<F> TaskBuilder<F>
+where
+    F: FnOnce() + Send + 'static,
+{
+    /// Creates the task.
+    pub fn build(self) -> Task {
+        Task {
+            id: self.id.unwrap_or_default(),
+            callback: Callback::Once(SmallFn::new({
+                let callback = self.callback;
+                move |_: &Scheduler| callback()
+            })),
+            expires: self.delay,
+            deadline: None,
+            priority: self.priority,
+            phase: Phase::Immediate,
+            name: self.name,
+            seq: 0,
+            execution: (self.exec_timeout.is_some() || !self.slack.is_zero()).then(|| {
+                Box::new(Execution {
+                    timeout: self.exec_timeout,
+                    slack: self.slack,
+                    on_finish: None,
+                })
+            }),
+        }
+    }
+}
+
+impl<F, T> TaskBuilder<F>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    /// Schedules the task on `scheduler` and hands the callback's return
+    /// value to the returned [`JoinHandle`], like [`Scheduler::spawn`].
+    ///
+    /// ```
+    /// use revent_loop::{JoinErrorKind, Scheduler, Task};
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let scheduler = Scheduler::new();
+    /// let answer = Task::builder()
+    ///     .callback(|| {
+    ///         thread::sleep(Duration::from_millis(20));
+    ///         42
+    ///     })
+    ///     .exec_timeout(Duration::from_millis(5))
+    ///     .spawn_on(&scheduler);
+    /// scheduler.run().unwrap();
+    /// assert_eq!(answer.try_join().unwrap_err().kind(), JoinErrorKind::TimedOut);
+    /// ```
+    pub fn spawn_on(self, scheduler: &Scheduler) -> JoinHandle<T> {
+        let f = self.callback;
+        let value = Arc::new(Mutex::new(None));
+        let produced = value.clone();
+        // Shared with the callback, so that a panic reaches the handle too:
+        // `on_finish` only runs for callbacks that return.
+        let shared = Arc::new(Mutex::new(None::<Completer<T>>));
+        let panicked = shared.clone();
+        let mut task = TaskBuilder {
+            callback: move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Ok(output) => *produced.lock() = Some(output),
+                Err(payload) => match panicked.lock().take() {
+                    Some(completer) => completer.panic(payload),
+                    None => panic::resume_unwind(payload),
+                },
+            },
+            delay: self.delay,
+            name: self.name,
+            priority: self.priority,
+            id: self.id,
+            exec_timeout: self.exec_timeout,
+            slack: self.slack,
+        }
+        .build();
+        let (handle, completer) = JoinHandle::new(task.id, scheduler.me());
+        *shared.lock() = Some(completer);
+        task.execution.get_or_insert_with(Box::default).on_finish =
+            Some(Box::new(move |timed_out| {
+                let value = value.lock().take();
+                let Some(completer) = shared.lock().take() else {
+                    return;
+                };
+                match value {
+                    Some(value) if !timed_out => completer.complete(value),
+                    _ => completer.time_out(),
+                }
+            }));
+        scheduler.schedule(task);
+        handle
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sync::Mutex;
+    use crate::{MockClock, Scheduler};
+    use std::sync::Arc;
+
+    #[test]
+    fn builder_honors_every_combination_of_options() {
+        let delay = Duration::from_secs(1);
+        for options in 0..16 {
+            let (with_delay, with_name, with_priority, with_id) = (
+                options & 1 != 0,
+                options & 2 != 0,
+                options & 4 != 0,
+                options & 8 != 0,
+            );
+            let clock = MockClock::new();
+            let scheduler = Scheduler::with_clock(clock.clone());
+            let order = Arc::new(Mutex::new(Vec::new()));
+
+            let built = order.clone();
+            let mut builder = Task::builder().callback(move || built.lock().push("built"));
+            if with_delay {
+                builder = builder.delay(delay);
+            }
+            if with_name {
+                builder = builder.name("built");
+            }
+            if with_priority {
+                builder = builder.priority(Priority::High);
+            }
+            let id = TaskId::new();
+            if with_id {
+                builder = builder.id(id);
+            }
+            let task = builder.build();
+
+            assert_eq!(task.id() == id, with_id);
+            assert_eq!(task.name(), with_name.then_some("built"));
+            let expected_priority = if with_priority {
+                Priority::High
+            } else {
+                Priority::Normal
+            };
+            assert_eq!(task.priority(), expected_priority);
+
+            // A plain task queued first shows whether the priority was
+            // applied; the delay decides which tick the built task runs in.
+            let plain = order.clone();
+            scheduler.schedule(Task::new(move || plain.lock().push("plain"), None));
+            let handle = scheduler.schedule(task);
+            assert_eq!(handle.id() == id, with_id);
+            if with_delay {
+                assert_eq!(scheduler.tick().executed, 1);
+                assert_eq!(*order.lock(), ["plain"]);
+                clock.advance(delay);
+                assert_eq!(scheduler.tick().executed, 1);
+                assert_eq!(*order.lock(), ["plain", "built"]);
+            } else {
+                assert_eq!(scheduler.tick().executed, 2);
+                let expected = if with_priority {
+                    ["built", "plain"]
+                } else {
+                    ["plain", "built"]
+                };
+                assert_eq!(*order.lock(), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use crate::wheel::Wheel;
+use crate::TaskId;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+/// How the sleeping queue of a [`Scheduler`] is kept; see
+/// [`SchedulerBuilder::timer_backend`].
+///
+/// [`Scheduler`]: crate::Scheduler
+/// [`SchedulerBuilder::timer_backend`]: crate::SchedulerBuilder::timer_backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TimerBackend {
+    /// A binary heap ordered by deadline. Timers fire exactly on time, but
+    /// scheduling one costs `O(log n)` and cancelling one `O(n)`.
+    #[default]
+    Heap,
+    /// A hierarchical timer wheel: scheduling and cancelling cost `O(1)`,
+    /// but a timer fires on the first `tick` at or after its deadline, so
+    /// up to one tick late. `slots` is the size of each level of the wheel;
+    /// more slots mean fewer levels for long delays, at the cost of memory.
+    Wheel { tick: Duration, slots: usize },
+}
+
+/// What a [`TimerQueue`] finds its items by.
+pub(crate) trait Keyed {
+    fn key(&self) -> TaskId;
+}
+
+/// Items waiting for a deadline: the sleeping queue of both [`Scheduler`]
+/// and [`LocalScheduler`].
+///
+/// Ordered by deadline, with ties broken by `seq` (the order in which items
+/// were scheduled) so that equal deadlines fire first-in, first-out.
+///
+/// [`Scheduler`]: crate::Scheduler
+/// [`LocalScheduler`]: crate::LocalScheduler
+pub(crate) enum TimerQueue<T> {
+    Heap(BinaryHeap<Reverse<Timer<T>>>),
+    Wheel(Box<Wheel<T>>),
+}
+
+pub(crate) struct Timer<T> {
+    pub(crate) deadline: Instant,
+    pub(crate) seq: u64,
+    pub(crate) item: T,
+}
+
+impl<T> Timer<T> {
+    pub(crate) fn key(&self) -> (Instant, u64) {
+        (self.deadline, self.seq)
+    }
+}
+
+impl<T> PartialEq for Timer<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<T> Eq for Timer<T> {}
+
+impl<T> PartialOrd for Timer<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Timer<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl<T> Default for TimerQueue<T> {
+    fn default() -> Self {
+        Self::Heap(BinaryHeap::new())
+    }
+}
+
+impl<T: Keyed> TimerQueue<T> {
+    /// A queue kept the way `backend` says, with wheel ticks counted from
+    /// `origin`.
+    pub(crate) fn new(backend: TimerBackend, origin: Instant) -> Self {
+        match backend {
+            TimerBackend::Heap => Self::default(),
+            TimerBackend::Wheel { tick, slots } => {
+                Self::Wheel(Box::new(Wheel::new(origin, tick, slots)))
+            }
+        }
+    }
+
+    pub(crate) fn push(&mut self, deadline: Instant, seq: u64, item: T) {
+        let timer = Timer {
+            deadline,
+            seq,
+            item,
+        };
+        match self {
+            Self::Heap(heap) => heap.push(Reverse(timer)),
+            Self::Wheel(wheel) => wheel.push(timer),
+        }
+    }
+
+    /// Takes out every item whose deadline is at or before `now`, in firing
+    /// order. A wheel holds on to an item until its tick has come, too.
+    pub(crate) fn pop_due(&mut self, now: Instant) -> Vec<T> {
+        let heap = match self {
+            Self::Heap(heap) => heap,
+            Self::Wheel(wheel) => return wheel.pop_due(now),
+        };
+        let mut due = Vec::new();
+        while heap
+            .peek()
+            .is_some_and(|Reverse(next)| next.deadline <= now)
+        {
+            due.push(heap.pop().unwrap().0.item);
+        }
+        due
+    }
+
+    /// Takes out the items due by `horizon` that `early` accepts, given
+    /// their deadline, in firing order.
+    pub(crate) fn pop_early(
+        &mut self,
+        horizon: Instant,
+        mut early: impl FnMut(Instant, &T) -> bool,
+    ) -> Vec<T> {
+        let heap = match self {
+            Self::Heap(heap) => heap,
+            Self::Wheel(wheel) => {
+                let mut taken = wheel.extract(|timer| {
+                    timer.deadline <= horizon && early(timer.deadline, &timer.item)
+                });
+                taken.sort();
+                return taken.into_iter().map(|timer| timer.item).collect();
+            }
+        };
+        let mut taken = Vec::new();
+        let mut kept = Vec::new();
+        while heap
+            .peek()
+            .is_some_and(|Reverse(next)| next.deadline <= horizon)
+        {
+            let Reverse(timer) = heap.pop().unwrap();
+            if early(timer.deadline, &timer.item) {
+                taken.push(timer.item);
+            } else {
+                kept.push(Reverse(timer));
+            }
+        }
+        heap.extend(kept);
+        taken
+    }
+
+    /// The earliest deadline still waiting; for a wheel, the tick it fires
+    /// on.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        match self {
+            Self::Heap(heap) => heap.peek().map(|Reverse(next)| next.deadline),
+            Self::Wheel(wheel) => wheel.next_deadline(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Heap(heap) => heap.len(),
+            Self::Wheel(wheel) => wheel.len(),
+        }
+    }
+
+    /// Makes room for `additional` more timers: in the heap, or in a
+    /// wheel's index of timers. A wheel's slots still grow as needed.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        match self {
+            Self::Heap(heap) => heap.reserve(additional),
+            Self::Wheel(wheel) => wheel.reserve(additional),
+        }
+    }
+
+    /// Gives back room beyond what the queue holds or `keep`, whichever is
+    /// more.
+    pub(crate) fn shrink_to(&mut self, keep: usize) {
+        match self {
+            Self::Heap(heap) => heap.shrink_to(keep),
+            Self::Wheel(wheel) => wheel.shrink_to(keep),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            Self::Heap(heap) => heap.capacity(),
+            Self::Wheel(wheel) => wheel.capacity(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every item, in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        let timers: Box<dyn Iterator<Item = &Timer<T>>> = match self {
+            Self::Heap(heap) => Box::new(heap.iter().map(|Reverse(timer)| timer)),
+            Self::Wheel(wheel) => Box::new(wheel.iter()),
+        };
+        timers.map(|timer| &timer.item)
+    }
+
+    /// Every item, in firing order.
+    pub(crate) fn sorted(&self) -> Vec<&T> {
+        let mut timers: Vec<&Timer<T>> = match self {
+            Self::Heap(heap) => heap.iter().map(|Reverse(timer)| timer).collect(),
+            Self::Wheel(wheel) => wheel.iter().collect(),
+        };
+        timers.sort();
+        timers.into_iter().map(|timer| &timer.item).collect()
+    }
+
+    /// Takes out every item for which `pred` returns `true`.
+    pub(crate) fn extract(&mut self, mut pred: impl FnMut(&T) -> bool) -> Vec<T> {
+        let heap = match self {
+            Self::Heap(heap) => heap,
+            Self::Wheel(wheel) => {
+                let mut gone = wheel.extract(|timer| pred(&timer.item));
+                gone.sort();
+                return gone.into_iter().map(|timer| timer.item).collect();
+            }
+        };
+        let (gone, kept): (Vec<_>, Vec<_>) = std::mem::take(heap)
+            .into_iter()
+            .partition(|Reverse(timer)| pred(&timer.item));
+        *heap = BinaryHeap::from(kept);
+        gone.into_iter().map(|Reverse(timer)| timer.item).collect()
+    }
+
+    /// Takes out the item with this id.
+    pub(crate) fn remove(&mut self, id: TaskId) -> Option<T> {
+        let heap = match self {
+            Self::Heap(heap) => heap,
+            Self::Wheel(wheel) => return wheel.remove(id),
+        };
+        if !heap.iter().any(|Reverse(timer)| timer.item.key() == id) {
+            return None;
+        }
+        // BinaryHeap can't remove an arbitrary entry, so rebuild it without
+        // the one being taken out.
+        let mut timers = std::mem::take(heap).into_vec();
+        let index = timers
+            .iter()
+            .position(|Reverse(timer)| timer.item.key() == id)
+            .unwrap();
+        let Reverse(timer) = timers.swap_remove(index);
+        *heap = BinaryHeap::from(timers);
+        Some(timer.item)
+    }
+
+    pub(crate) fn take_all(&mut self) -> Vec<T> {
+        match self {
+            Self::Heap(heap) => std::mem::take(heap)
+                .into_iter()
+                .map(|Reverse(timer)| timer.item)
+                .collect(),
+            Self::Wheel(wheel) => wheel.take_all(),
+        }
+    }
+}
+
+impl<T: Keyed> Extend<(Instant, u64, T)> for TimerQueue<T> {
+    fn extend<I: IntoIterator<Item = (Instant, u64, T)>>(&mut self, timers: I) {
+        match self {
+            Self::Heap(heap) => heap.extend(timers.into_iter().map(|(deadline, seq, item)| {
+                Reverse(Timer {
+                    deadline,
+                    seq,
+                    item,
+                })
+            })),
+            Self::Wheel(_) => {
+                for (deadline, seq, item) in timers {
+                    self.push(deadline, seq, item);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Every item here has a different length.
+    impl Keyed for &'static str {
+        fn key(&self) -> TaskId {
+            TaskId::from_index(self.len() as u64)
+        }
+    }
+
+    #[test]
+    fn pops_due_items_by_deadline_then_seq() {
+        let now = Instant::now();
+        let at = |ms| now + Duration::from_millis(ms);
+        let wheel = TimerBackend::Wheel {
+            tick: Duration::from_millis(1),
+            slots: 8,
+        };
+        for backend in [TimerBackend::Heap, wheel] {
+            let mut timers = TimerQueue::new(backend, now);
+            timers.push(at(20), 0, "late");
+            timers.push(at(10), 2, "second");
+            timers.push(at(10), 1, "first");
+            timers.push(at(30), 3, "not yet");
+
+            assert_eq!(timers.sorted(), [&"first", &"second", &"late", &"not yet"]);
+            assert_eq!(timers.pop_due(at(20)), ["first", "second", "late"]);
+            assert_eq!(timers.next_deadline(), Some(at(30)));
+            assert_eq!(timers.remove("not yet".key()), Some("not yet"));
+            assert!(timers.is_empty());
+        }
+    }
+}
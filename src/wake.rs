@@ -0,0 +1,207 @@
+#[cfg(all(feature = "io", unix))]
+use crate::io::Reactor;
+use crate::sync::{Condvar, Mutex};
+use std::fmt;
+#[cfg(all(feature = "io", unix))]
+use std::sync::{Arc, OnceLock};
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How [`Scheduler::run`] waits for its next timer when it has nothing else
+/// to do; see [`SchedulerBuilder::sleep_strategy`].
+///
+/// The wait should end at `deadline`, or earlier once `wakeup` has been
+/// notified, which [`Scheduler::schedule`] and [`Scheduler::shutdown`] do so
+/// that the loop can look at its queues again. Returning early, or late, is
+/// harmless: the loop only checks what is due and waits again if need be.
+///
+/// An embedder can wait on a platform event alongside the deadline, for
+/// example by polling both in turns of [`WakeSignal::wait_timeout`].
+///
+/// [`Scheduler::run`]: crate::Scheduler::run
+/// [`Scheduler::schedule`]: crate::Scheduler::schedule
+/// [`Scheduler::shutdown`]: crate::Scheduler::shutdown
+/// [`SchedulerBuilder::sleep_strategy`]: crate::SchedulerBuilder::sleep_strategy
+pub trait SleepStrategy: Send + Sync {
+    fn wait_until(&self, deadline: Instant, wakeup: &WakeSignal);
+}
+
+/// Blocks on the [`WakeSignal`] until the deadline or a notification. The
+/// default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParkTimeout;
+
+impl SleepStrategy for ParkTimeout {
+    fn wait_until(&self, deadline: Instant, wakeup: &WakeSignal) {
+        wakeup.wait_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Sleeps with [`thread::sleep`] until the deadline, ignoring notifications:
+/// work scheduled in the meantime waits for the sleep to end.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdSleep;
+
+impl SleepStrategy for StdSleep {
+    fn wait_until(&self, deadline: Instant, _wakeup: &WakeSignal) {
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Blocks like [`ParkTimeout`] for all but the last `spin` of the wait and
+/// spins through the rest, which ends far closer to the deadline than a
+/// timed wait does. A notification still ends the spin.
+///
+/// This is what [`SchedulerBuilder::high_resolution`] uses.
+///
+/// [`SchedulerBuilder::high_resolution`]: crate::SchedulerBuilder::high_resolution
+#[derive(Debug, Clone, Copy)]
+pub struct SpinThenPark {
+    pub spin: Duration,
+}
+
+impl SleepStrategy for SpinThenPark {
+    fn wait_until(&self, deadline: Instant, wakeup: &WakeSignal) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining > self.spin && wakeup.wait_timeout(remaining - self.spin) {
+            return;
+        }
+        while !wakeup.take_notification() && Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Wakes the loop thread when there is something new to look at.
+///
+/// `notify()` bumps a generation under the mutex before signalling, so a
+/// wake that lands between the loop deciding to wait and actually waiting is
+/// never lost: the waiter sees the change and returns straight away.
+#[derive(Default)]
+pub struct WakeSignal {
+    state: Mutex<State>,
+    condvar: Condvar,
+    /// Also interrupted on every notification, once something has been
+    /// registered with it.
+    #[cfg(all(feature = "io", unix))]
+    reactor: OnceLock<Arc<Reactor>>,
+}
+
+#[derive(Default)]
+struct State {
+    generation: u64,
+    /// The generation [`WakeSignal::wait`] and [`WakeSignal::wait_timeout`]
+    /// last returned at.
+    consumed: u64,
+    /// Left by [`Scheduler::run_async`](crate::Scheduler::run_async) for
+    /// the next notification.
+    waker: Option<Waker>,
+}
+
+impl fmt::Debug for WakeSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WakeSignal").finish_non_exhaustive()
+    }
+}
+
+impl WakeSignal {
+    pub(crate) fn notify(&self) {
+        let mut state = self.state.lock();
+        state.generation += 1;
+        let waker = state.waker.take();
+        drop(state);
+        self.condvar.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        #[cfg(all(feature = "io", unix))]
+        if let Some(reactor) = self.reactor.get() {
+            reactor.interrupt();
+        }
+    }
+
+    /// The reactor, if anything has been registered with it yet.
+    #[cfg(all(feature = "io", unix))]
+    pub(crate) fn reactor(&self) -> Option<&Arc<Reactor>> {
+        self.reactor.get()
+    }
+
+    /// The reactor, created on first use.
+    #[cfg(all(feature = "io", unix))]
+    pub(crate) fn reactor_or_init(&self) -> std::io::Result<&Arc<Reactor>> {
+        if let Some(reactor) = self.reactor.get() {
+            return Ok(reactor);
+        }
+        // Whichever thread gets there first wins; the other pipe is closed.
+        let _ = self.reactor.set(Arc::new(Reactor::new()?));
+        Ok(self.reactor.get().expect("just set"))
+    }
+
+    /// Blocks until notified, consuming the notification.
+    pub(crate) fn wait(&self) {
+        let mut state = self.state.lock();
+        while state.generation == state.consumed {
+            state = self.condvar.wait(state);
+        }
+        state.consumed = state.generation;
+    }
+
+    /// Blocks until notified or until `timeout` has passed. Returns whether
+    /// a notification was consumed.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let state = self.state.lock();
+        let (mut state, _) = self
+            .condvar
+            .wait_timeout_while(state, timeout, |state| state.generation == state.consumed);
+        let woken = state.generation != state.consumed;
+        state.consumed = state.generation;
+        woken
+    }
+
+    /// Consumes a notification, without waiting for one. Returns whether
+    /// there was one.
+    pub fn take_notification(&self) -> bool {
+        let mut state = self.state.lock();
+        let woken = state.generation != state.consumed;
+        state.consumed = state.generation;
+        woken
+    }
+
+    /// The current generation, for [`WakeSignal::wait_past`] and
+    /// [`WakeSignal::wake_past`].
+    pub(crate) fn generation(&self) -> u64 {
+        self.state.lock().generation
+    }
+
+    /// Has the next notification wake `waker`, unless there has been one
+    /// since [`WakeSignal::generation`] returned `seen`. Returns whether
+    /// `waker` was kept.
+    pub(crate) fn wake_past(&self, seen: u64, waker: &Waker) -> bool {
+        let mut state = self.state.lock();
+        if state.generation != seen {
+            return false;
+        }
+        state.waker = Some(waker.clone());
+        true
+    }
+
+    /// Blocks until there has been a notification since `seen` was read from
+    /// [`WakeSignal::generation`], or until `timeout` has passed.
+    ///
+    /// Nothing is consumed, so every thread waiting this way wakes up for
+    /// every notification.
+    pub(crate) fn wait_past(&self, seen: u64, timeout: Option<Duration>) {
+        let state = self.state.lock();
+        match timeout {
+            Some(timeout) => drop(
+                self.condvar
+                    .wait_timeout_while(state, timeout, |state| state.generation == seen),
+            ),
+            None => drop(
+                self.condvar
+                    .wait_while(state, |state| state.generation == seen),
+            ),
+        }
+    }
+}
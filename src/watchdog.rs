@@ -0,0 +1,149 @@
+use crate::sync::{Condvar, Mutex};
+use crate::TaskId;
+use std::borrow::Cow;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Called with the id and name of a task that has been running for too
+/// long, plus how long it has been running so far.
+pub(crate) type SlowTaskHandler = Arc<dyn Fn(TaskId, Option<&str>, Duration) + Send + Sync>;
+
+/// The monitor behind [`SchedulerBuilder::slow_task_threshold`].
+///
+/// Callbacks register with it while they run. A single thread sleeps until
+/// the oldest of them crosses the threshold and reports it, once, if it is
+/// still running by then. The thread exits when the watchdog is dropped.
+///
+/// [`SchedulerBuilder::slow_task_threshold`]: crate::SchedulerBuilder::slow_task_threshold
+pub(crate) struct Watchdog {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    threshold: Duration,
+    handler: SlowTaskHandler,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    running: Vec<Running>,
+    next_token: u64,
+    stopped: bool,
+}
+
+struct Running {
+    token: u64,
+    id: TaskId,
+    name: Option<Cow<'static, str>>,
+    started: Instant,
+    reported: bool,
+}
+
+impl Watchdog {
+    pub(crate) fn new(threshold: Duration, handler: SlowTaskHandler) -> Self {
+        let shared = Arc::new(Shared {
+            threshold,
+            handler,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        let monitor = shared.clone();
+        thread::Builder::new()
+            .name("revent-loop-watchdog".into())
+            .spawn(move || watch(monitor))
+            .expect("failed to spawn the watchdog thread");
+        Self { shared }
+    }
+
+    /// Starts timing a callback, until the returned guard is dropped.
+    pub(crate) fn enter(&self, id: TaskId, name: Option<Cow<'static, str>>) -> Watched<'_> {
+        let mut state = self.shared.state.lock();
+        let token = state.next_token;
+        state.next_token += 1;
+        // Later callbacks fall due later, so the monitor only needs waking
+        // when it has nothing to wait for.
+        let idle = state.running.iter().all(|running| running.reported);
+        state.running.push(Running {
+            token,
+            id,
+            name,
+            started: Instant::now(),
+            reported: false,
+        });
+        drop(state);
+        if idle {
+            self.shared.changed.notify_one();
+        }
+        Watched {
+            watchdog: self,
+            token,
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.state.lock().stopped = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+/// A callback being timed by the [`Watchdog`].
+pub(crate) struct Watched<'a> {
+    watchdog: &'a Watchdog,
+    token: u64,
+}
+
+impl Drop for Watched<'_> {
+    fn drop(&mut self) {
+        let mut state = self.watchdog.shared.state.lock();
+        if let Some(index) = state
+            .running
+            .iter()
+            .position(|running| running.token == self.token)
+        {
+            state.running.swap_remove(index);
+        }
+    }
+}
+
+fn watch(shared: Arc<Shared>) {
+    let mut state = shared.state.lock();
+    while !state.stopped {
+        let now = Instant::now();
+        let due = state
+            .running
+            .iter()
+            .filter(|running| !running.reported)
+            .map(|running| running.started + shared.threshold)
+            .min();
+        match due {
+            None => state = shared.changed.wait(state),
+            Some(due) if due > now => {
+                state = shared.changed.wait_timeout(state, due - now).0;
+            }
+            Some(_) => {
+                let mut slow = Vec::new();
+                for running in &mut state.running {
+                    let elapsed = now.saturating_duration_since(running.started);
+                    if !running.reported && elapsed >= shared.threshold {
+                        running.reported = true;
+                        slow.push((running.id, running.name.clone(), elapsed));
+                    }
+                }
+                drop(state);
+                for (id, name, elapsed) in slow {
+                    // A panicking handler must not take the monitor down.
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                        (shared.handler)(id, name.as_deref(), elapsed)
+                    }));
+                }
+                state = shared.state.lock();
+            }
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use crate::timers::{Keyed, Timer};
+use crate::TaskId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A hierarchical timer wheel, the [`TimerBackend::Wheel`] behind a
+/// [`TimerQueue`].
+///
+/// Time is cut into ticks counted from `origin`, and a timer fires on the
+/// first tick at or after its deadline. Tick numbers are written in base
+/// `slots`: a timer sits on the level of the highest digit in which its
+/// tick differs from the tick the wheel has reached, in the slot for that
+/// digit. Level 0 slots hold a single tick each; a slot on level `n` holds
+/// `slots^n` of them and is spread over the levels below once the wheel
+/// reaches it. Enough levels are kept to cover every `u64` tick, so
+/// nothing ever overflows.
+///
+/// Inserting and cancelling by id take constant time. Finding the next
+/// timer scans a bitmap per level.
+///
+/// [`TimerBackend::Wheel`]: crate::TimerBackend::Wheel
+/// [`TimerQueue`]: crate::timers::TimerQueue
+pub(crate) struct Wheel<T> {
+    origin: Instant,
+    tick_nanos: u128,
+    slots: u64,
+    /// `levels[level][slot]`.
+    levels: Vec<Vec<Vec<Entry<T>>>>,
+    /// One bit per non-empty slot, per level.
+    occupied: Vec<Vec<u64>>,
+    /// Timers whose tick the wheel has already reached.
+    expired: Vec<Entry<T>>,
+    /// Where each timer is, by id.
+    index: HashMap<TaskId, Location>,
+    /// The tick the wheel has advanced to.
+    elapsed: u64,
+    len: usize,
+}
+
+struct Entry<T> {
+    tick: u64,
+    timer: Timer<T>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Expired,
+    Slot(usize, usize),
+}
+
+impl<T: Keyed> Wheel<T> {
+    /// # Panics
+    ///
+    /// Panics if `tick` is zero or `slots` is less than 2.
+    pub(crate) fn new(origin: Instant, tick: Duration, slots: usize) -> Self {
+        assert!(!tick.is_zero(), "timer wheel tick must not be zero");
+        assert!(slots >= 2, "timer wheel needs at least 2 slots");
+        let slots = slots as u64;
+        let mut depth = 1;
+        while slots.checked_pow(depth).is_some() {
+            depth += 1;
+        }
+        let words = (slots as usize).div_ceil(64);
+        Self {
+            origin,
+            tick_nanos: tick.as_nanos(),
+            slots,
+            levels: (0..depth)
+                .map(|_| (0..slots).map(|_| Vec::new()).collect())
+                .collect(),
+            occupied: (0..depth).map(|_| vec![0; words]).collect(),
+            expired: Vec::new(),
+            index: HashMap::new(),
+            elapsed: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, timer: Timer<T>) {
+        let tick = self.tick_of(timer.deadline);
+        self.len += 1;
+        self.place(Entry { tick, timer });
+    }
+
+    /// Takes out every timer whose tick has been reached by `now`, in
+    /// firing order.
+    pub(crate) fn pop_due(&mut self, now: Instant) -> Vec<T> {
+        let now_tick = self.ticks_before(now);
+        let mut due = std::mem::take(&mut self.expired);
+        while let Some((level, slot, start)) = self.next_slot() {
+            if start > now_tick {
+                break;
+            }
+            self.elapsed = start;
+            for entry in self.take_slot(level, slot) {
+                if entry.tick <= self.elapsed {
+                    due.push(entry);
+                } else {
+                    self.place(entry);
+                }
+            }
+        }
+        // Every timer left is in a slot past `now_tick`, so they all stay
+        // where they are.
+        self.elapsed = self.elapsed.max(now_tick);
+        self.finish(due)
+    }
+
+    /// When the next timer fires: the start of its tick, which may be a
+    /// little after its deadline.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        let tick = match self.expired.iter().map(|entry| entry.tick).min() {
+            Some(tick) => tick,
+            None => {
+                let (level, slot, start) = self.next_slot()?;
+                match level {
+                    0 => start,
+                    _ => self.levels[level][slot]
+                        .iter()
+                        .map(|entry| entry.tick)
+                        .min()?,
+                }
+            }
+        };
+        Some(self.instant_of(tick))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.index.reserve(additional);
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.index.capacity()
+    }
+
+    pub(crate) fn shrink_to(&mut self, keep: usize) {
+        self.index.shrink_to(keep);
+        self.expired.shrink_to_fit();
+        for slot in self.levels.iter_mut().flatten() {
+            slot.shrink_to_fit();
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Timer<T>> {
+        self.expired
+            .iter()
+            .chain(self.levels.iter().flatten().flatten())
+            .map(|entry| &entry.timer)
+    }
+
+    /// Takes out the timer with this id. Ids are taken to be unique: of
+    /// several timers with the same one, only the last scheduled is found.
+    pub(crate) fn remove(&mut self, id: TaskId) -> Option<T> {
+        let location = *self.index.get(&id)?;
+        Some(self.remove_at(location, id)?.item)
+    }
+
+    /// Takes out every timer matching `pred`, in no particular order.
+    pub(crate) fn extract(&mut self, mut pred: impl FnMut(&Timer<T>) -> bool) -> Vec<Timer<T>> {
+        let mut gone = Vec::new();
+        let expired = std::mem::take(&mut self.expired);
+        let (taken, kept): (Vec<_>, Vec<_>) =
+            expired.into_iter().partition(|entry| pred(&entry.timer));
+        self.expired = kept;
+        gone.extend(taken);
+        for level in 0..self.levels.len() {
+            for slot in 0..self.levels[level].len() {
+                if self.levels[level][slot].is_empty() {
+                    continue;
+                }
+                let entries = self.take_slot(level, slot);
+                let (taken, kept): (Vec<_>, Vec<_>) =
+                    entries.into_iter().partition(|entry| pred(&entry.timer));
+                gone.extend(taken);
+                for entry in kept {
+                    self.put(level, slot, entry);
+                }
+            }
+        }
+        self.len -= gone.len();
+        self.forget(&gone);
+        gone.into_iter().map(|entry| entry.timer).collect()
+    }
+
+    pub(crate) fn take_all(&mut self) -> Vec<T> {
+        self.index.clear();
+        self.len = 0;
+        for words in &mut self.occupied {
+            words.fill(0);
+        }
+        std::mem::take(&mut self.expired)
+            .into_iter()
+            .chain(self.levels.iter_mut().flatten().flat_map(std::mem::take))
+            .map(|entry| entry.timer.item)
+            .collect()
+    }
+
+    /// Files `entry` by its tick, relative to where the wheel is now.
+    fn place(&mut self, entry: Entry<T>) {
+        if entry.tick <= self.elapsed {
+            self.index.insert(entry.timer.item.key(), Location::Expired);
+            self.expired.push(entry);
+            return;
+        }
+        let (mut a, mut b, mut level) = (entry.tick / self.slots, self.elapsed / self.slots, 0);
+        while a != b {
+            a /= self.slots;
+            b /= self.slots;
+            level += 1;
+        }
+        let slot = self.digit(entry.tick, level);
+        self.put(level, slot, entry);
+    }
+
+    fn put(&mut self, level: usize, slot: usize, entry: Entry<T>) {
+        self.index
+            .insert(entry.timer.item.key(), Location::Slot(level, slot));
+        self.levels[level][slot].push(entry);
+        self.occupied[level][slot / 64] |= 1 << (slot % 64);
+    }
+
+    fn take_slot(&mut self, level: usize, slot: usize) -> Vec<Entry<T>> {
+        self.occupied[level][slot / 64] &= !(1 << (slot % 64));
+        std::mem::take(&mut self.levels[level][slot])
+    }
+
+    /// Drops the index entries of timers that have been taken out, unless
+    /// they point at another timer with the same id.
+    fn forget(&mut self, taken: &[Entry<T>]) {
+        for entry in taken {
+            let id = entry.timer.item.key();
+            if let Some(&location) = self.index.get(&id) {
+                if !self.holds(location, id) {
+                    self.index.remove(&id);
+                }
+            }
+        }
+    }
+
+    fn holds(&self, location: Location, id: TaskId) -> bool {
+        let entries = match location {
+            Location::Expired => &self.expired,
+            Location::Slot(level, slot) => &self.levels[level][slot],
+        };
+        entries.iter().any(|entry| entry.timer.item.key() == id)
+    }
+
+    fn remove_at(&mut self, location: Location, id: TaskId) -> Option<Timer<T>> {
+        let entries = match location {
+            Location::Expired => &mut self.expired,
+            Location::Slot(level, slot) => &mut self.levels[level][slot],
+        };
+        let index = entries
+            .iter()
+            .position(|entry| entry.timer.item.key() == id)?;
+        let entry = entries.swap_remove(index);
+        if let (Location::Slot(level, slot), true) = (location, entries.is_empty()) {
+            self.occupied[level][slot / 64] &= !(1 << (slot % 64));
+        }
+        self.index.remove(&id);
+        self.len -= 1;
+        Some(entry.timer)
+    }
+
+    /// The first non-empty slot still ahead of the wheel, lowest level
+    /// first, with the first tick it covers.
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        for level in 0..self.levels.len() {
+            let current = self.digit(self.elapsed, level);
+            // Above level 0 the current slot has already been spread out.
+            let first = if level == 0 { current } else { current + 1 };
+            let Some(slot) = self.first_occupied(level, first) else {
+                continue;
+            };
+            let span = self.slots.pow(level as u32);
+            let base = match self.slots.checked_pow(level as u32 + 1) {
+                Some(block) => self.elapsed / block * block,
+                None => 0,
+            };
+            return Some((level, slot, base + slot as u64 * span));
+        }
+        None
+    }
+
+    fn first_occupied(&self, level: usize, from: usize) -> Option<usize> {
+        let words = &self.occupied[level];
+        let mut word = from / 64;
+        let mut bits = *words.get(word)? & (u64::MAX << (from % 64));
+        loop {
+            if bits != 0 {
+                return Some(word * 64 + bits.trailing_zeros() as usize);
+            }
+            word += 1;
+            bits = *words.get(word)?;
+        }
+    }
+
+    fn digit(&self, tick: u64, level: usize) -> usize {
+        (tick / self.slots.pow(level as u32) % self.slots) as usize
+    }
+
+    /// The first tick at or after `deadline`.
+    fn tick_of(&self, deadline: Instant) -> u64 {
+        let nanos = deadline.saturating_duration_since(self.origin).as_nanos();
+        u64::try_from(nanos.div_ceil(self.tick_nanos)).unwrap_or(u64::MAX)
+    }
+
+    /// The last tick at or before `now`.
+    fn ticks_before(&self, now: Instant) -> u64 {
+        let nanos = now.saturating_duration_since(self.origin).as_nanos();
+        u64::try_from(nanos / self.tick_nanos).unwrap_or(u64::MAX)
+    }
+
+    fn instant_of(&self, tick: u64) -> Instant {
+        let nanos = u128::from(tick) * self.tick_nanos;
+        self.origin + Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// Forgets the popped timers and orders them by deadline, then by when
+    /// they were scheduled.
+    fn finish(&mut self, mut due: Vec<Entry<T>>) -> Vec<T> {
+        self.len -= due.len();
+        self.forget(&due);
+        due.sort_by_key(|entry| entry.timer.key());
+        due.into_iter().map(|entry| entry.timer.item).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Item(TaskId, u64);
+
+    impl Keyed for Item {
+        fn key(&self) -> TaskId {
+            self.0
+        }
+    }
+
+    #[test]
+    fn long_delays_cascade_down_and_fire_on_their_tick() {
+        let origin = Instant::now();
+        let ms = Duration::from_millis;
+        let mut wheel = Wheel::new(origin, ms(1), 4);
+        let delays = [0, 1, 3, 4, 5, 15, 16, 17, 63, 64, 65, 1000, 4096, 100_000];
+        for (seq, delay) in delays.iter().enumerate() {
+            wheel.push(Timer {
+                deadline: origin + ms(*delay),
+                seq: seq as u64,
+                item: Item(TaskId::new(), *delay),
+            });
+        }
+        let cancelled = TaskId::new();
+        wheel.push(Timer {
+            deadline: origin + ms(17),
+            seq: 99,
+            item: Item(cancelled, 17),
+        });
+        assert_eq!(wheel.remove(cancelled).map(|item| item.1), Some(17));
+
+        let mut fired = Vec::new();
+        while let Some(next) = wheel.next_deadline() {
+            // Jump halfway there first; nothing may fire early.
+            let halfway =
+                wheel.instant_of(wheel.elapsed + (wheel.tick_of(next) - wheel.elapsed) / 2);
+            if halfway < next {
+                assert!(wheel.pop_due(halfway).is_empty());
+            }
+            for item in wheel.pop_due(next) {
+                fired.push((item.1, next - origin));
+            }
+        }
+        let expected: Vec<_> = delays.iter().map(|delay| (*delay, ms(*delay))).collect();
+        assert_eq!(fired, expected);
+        assert_eq!(wheel.len(), 0);
+        assert!(wheel.index.is_empty());
+    }
+}